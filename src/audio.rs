@@ -0,0 +1,235 @@
+//! Audio-backed songs
+//!
+//! WAV and Ogg Vorbis songs are decoded whole when they're loaded and mixed in
+//! next to the synth by the audio callback, through the vocal reducer and the
+//! pitch shifter. The player thread only moves the position around, see
+//! [crate::midi::MidiControl::play].
+
+use std::{fs::File, path::Path};
+
+use anyhow::Result;
+use lewton::inside_ogg::OggStreamReader;
+
+use crate::{
+    mic,
+    stretch::PitchShifter,
+    vocal::{VocalReducer, VocalReducerSettings},
+};
+
+/// Audio songs count their position in milliseconds where MIDI songs use ticks
+pub const TICKS_PER_SECOND: f64 = 1000.0;
+
+/// Song frames fed to the pitch shifter at a time
+const SHIFT_CHUNK: usize = 256;
+
+/// Files played by the audio backend instead of as MIDI
+pub fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ext.eq_ignore_ascii_case("wav") || ext.eq_ignore_ascii_case("ogg")
+        })
+}
+
+/// Reads a song as interleaved stereo, with its sample rate
+pub fn decode(path: &Path) -> Result<(Vec<f32>, u32)> {
+    let is_ogg = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ogg"));
+
+    let (channels, sample_rate, samples) = if is_ogg {
+        let mut reader = OggStreamReader::new(File::open(path)?)?;
+        let mut samples = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_itl()? {
+            samples.extend(packet.into_iter().map(|s| s as f32 / 32768.0));
+        }
+        let header = &reader.ident_hdr;
+        (header.audio_channels as usize, header.audio_sample_rate, samples)
+    } else {
+        let (spec, samples) = mic::read_wav(path)?;
+        (spec.channels as usize, spec.sample_rate, samples)
+    };
+
+    Ok((to_stereo(&samples, channels), sample_rate))
+}
+
+/// Mono is played on both sides, anything past the first two channels is dropped
+fn to_stereo(samples: &[f32], channels: usize) -> Vec<f32> {
+    match channels {
+        2 => samples.to_vec(),
+        0 => Vec::new(),
+        1 => samples.iter().flat_map(|s| [*s, *s]).collect(),
+        _ => samples
+            .chunks_exact(channels)
+            .flat_map(|frame| [frame[0], frame[1]])
+            .collect(),
+    }
+}
+
+/// A song being played by the audio callback
+pub struct AudioTrack {
+    /// Interleaved stereo
+    samples: Vec<f32>,
+    sample_rate: u32,
+    output_rate: u32,
+    /// Song frames per output frame
+    step: f64,
+    /// Position in song frames
+    pos: f64,
+    pub paused: bool,
+    vocal: VocalReducer,
+    /// Key change in semitones
+    transpose: i8,
+    speed: f32,
+    /// Only there while the key or tempo is changed
+    shifter: Option<PitchShifter>,
+    /// Shifted audio that didn't fit in the last buffer
+    shifted: Vec<f32>,
+    /// The part of the song being mixed in, kept to not allocate on the audio thread
+    block: Vec<f32>,
+}
+
+impl AudioTrack {
+    pub fn new(samples: Vec<f32>, sample_rate: u32) -> Self {
+        Self {
+            samples,
+            sample_rate,
+            output_rate: sample_rate,
+            step: 1.0,
+            pos: 0.0,
+            paused: false,
+            vocal: VocalReducer::new(VocalReducerSettings::default(), sample_rate),
+            transpose: 0,
+            speed: 1.0,
+            shifter: None,
+            shifted: Vec::new(),
+            block: Vec::new(),
+        }
+    }
+
+    /// Resamples to the rate of the output it's played on
+    pub fn set_output_rate(&mut self, sample_rate: u32) {
+        self.output_rate = sample_rate;
+        self.step = self.sample_rate as f64 / sample_rate as f64;
+        self.vocal = VocalReducer::new(self.vocal.settings.clone(), sample_rate);
+        self.reset_shifter();
+    }
+
+    /// Key change in semitones and tempo, the same as MIDI songs take
+    pub fn set_shift(&mut self, transpose: i8, speed: f32) {
+        self.transpose = transpose;
+        self.speed = speed;
+        if transpose == 0 && speed == 1.0 {
+            self.shifter = None;
+            self.shifted.clear();
+            return;
+        }
+
+        match &mut self.shifter {
+            Some(shifter) => shifter.set(transpose, speed),
+            None => self.shifter = Some(PitchShifter::new(self.output_rate, transpose, speed)),
+        }
+    }
+
+    /// Vocal reducer settings, they apply from the next buffer
+    pub fn set_vocal(&mut self, settings: &VocalReducerSettings) {
+        self.vocal.settings = settings.clone();
+    }
+
+    fn frames(&self) -> usize {
+        self.samples.len() / 2
+    }
+
+    /// Length in ticks, see [TICKS_PER_SECOND]
+    pub fn ticks(&self) -> usize {
+        (self.frames() as f64 / self.sample_rate as f64 * TICKS_PER_SECOND) as usize
+    }
+
+    pub fn tick(&self) -> usize {
+        (self.pos / self.sample_rate as f64 * TICKS_PER_SECOND) as usize
+    }
+
+    pub fn seek(&mut self, tick: usize) {
+        let pos = tick as f64 / TICKS_PER_SECOND * self.sample_rate as f64;
+        self.pos = pos.min(self.frames() as f64);
+        // what's left in the shifter is from before the seek
+        self.reset_shifter();
+    }
+
+    /// Starts the pitch shifter over, if it's in use
+    fn reset_shifter(&mut self) {
+        if self.shifter.is_some() {
+            self.shifter = Some(PitchShifter::new(self.output_rate, self.transpose, self.speed));
+            self.shifted.clear();
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.frames() as f64
+    }
+
+    /// Adds the next part of the song to an interleaved stereo buffer
+    pub fn mix_into(&mut self, out: &mut [f32]) {
+        if self.paused || self.is_done() {
+            return;
+        }
+
+        let mut block = std::mem::take(&mut self.block);
+        match self.shifter.take() {
+            Some(mut shifter) => {
+                let mut shifted = std::mem::take(&mut self.shifted);
+                while shifted.len() < out.len() && !self.is_done() {
+                    self.read(SHIFT_CHUNK, &mut block);
+                    shifter.process(&block, &mut shifted);
+                }
+                for (o, s) in out.iter_mut().zip(&shifted) {
+                    *o += s;
+                }
+                shifted.drain(..out.len().min(shifted.len()));
+                self.shifted = shifted;
+                self.shifter = Some(shifter);
+            }
+            None => {
+                self.read(out.len() / 2, &mut block);
+                for (o, s) in out.iter_mut().zip(&block) {
+                    *o += s;
+                }
+            }
+        }
+        self.block = block;
+    }
+
+    /// The next `frames` of the song at the output rate, vocals reduced
+    fn read(&mut self, frames: usize, block: &mut Vec<f32>) {
+        block.clear();
+        for _ in 0..frames {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            for ch in 0..2 {
+                let a = self.samples.get(i * 2 + ch).copied().unwrap_or(0.0);
+                let b = self.samples.get(i * 2 + 2 + ch).copied().unwrap_or(0.0);
+                block.push(a + (b - a) * t);
+            }
+            self.pos += self.step;
+        }
+        self.vocal.process(block);
+    }
+}
+
+#[test]
+fn test_audio_track() {
+    let samples = (0..8).flat_map(|i| [i as f32, -(i as f32)]).collect();
+    let mut track = AudioTrack::new(samples, 4);
+    assert_eq!(track.ticks(), 2000);
+
+    // played at twice the rate every other frame is in between
+    track.set_output_rate(8);
+    let mut out = vec![1.0; 6];
+    track.mix_into(&mut out);
+    assert_eq!(out, [1.0, 1.0, 1.5, 0.5, 2.0, 0.0]);
+    assert_eq!(track.tick(), 375);
+
+    track.seek(1750);
+    track.mix_into(&mut [0.0; 8]);
+    assert!(track.is_done());
+}
@@ -0,0 +1,1169 @@
+//! Command line interface
+//!
+//! Without a subcommand the GUI starts as usual. The subcommands don't need a display,
+//! so batch jobs can run on a server.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Parser, Subcommand};
+use log::debug;
+use midly::{Format, MetaMessage, MidiMessage as M, Smf, Timing, TrackEventKind};
+use nodi::{Event, Sheet, Timer};
+use parking_lot::RwLock;
+
+use crate::{
+    config::Config,
+    emk,
+    external::OutputTarget,
+    guide,
+    karaoke::{self, Karaoke},
+    library::{
+        self,
+        duplicates::{self, DuplicateGroup},
+        health::{self, SongHealth},
+        history::{self, PlayId},
+        playlists::Playlist,
+        Library,
+    },
+    mic,
+    midi::{self, ControlTicker, MidiMessage},
+    mixer::{ChannelMixer, MixerCommand, CHANNELS},
+    pitch::{self, PitchFrame, PitchTracker},
+    render::{self, RenderOptions},
+    score::{self, LiveScore, ScoreReport, Scorer},
+    soundfont::{self, SoundfontSetup},
+    stretch::PitchShifter,
+    sysex,
+    tick::scroll,
+    vocal::{VocalReducer, VocalReducerSettings},
+};
+
+#[derive(Debug, Parser)]
+#[command(name = "rusty-karaoke", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Play a song in the terminal, scrolling the lyrics as it goes
+    Play(PlayArgs),
+    /// Show the song metadata, tempo map, channel usage and CUR timing
+    Info(InfoArgs),
+    /// Extract the MIDI, lyrics and cursor files from an EMK file
+    Extract(ExtractArgs),
+    /// Render a song to a WAV file
+    Render(RenderArgs),
+    /// Score a WAV recording of someone singing along to a song
+    Score(ScoreArgs),
+    /// Run a WAV audio track through the effects audio songs get
+    Process(ProcessArgs),
+    /// Update the song library, only reading files that changed since the last scan
+    Scan(ScanArgs),
+    /// Find songs in the library that are copies of each other
+    Duplicates(DuplicatesArgs),
+    /// Check the CUR timing and MIDI of songs in the library
+    Check(CheckArgs),
+    /// List, export and import playlists and favourites
+    Playlist(PlaylistArgs),
+    /// Show what was sung, or export a monthly report
+    Stats(StatsArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct PlayArgs {
+    /// NCN MIDI file or EMK file
+    pub input: PathBuf,
+    /// Semitones to transpose by
+    #[arg(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    pub transpose: i8,
+    /// Tempo multiplier
+    #[arg(short, long, default_value_t = 1.0)]
+    pub speed: f32,
+    /// Channels (1-16) to mute, comma separated
+    #[arg(short, long, value_delimiter = ',')]
+    pub mute: Vec<u8>,
+    /// Mute the guide melody
+    #[arg(long)]
+    pub no_guide: bool,
+    /// Score the singing on the microphone, turns the mic on if it's off
+    #[arg(long)]
+    pub score: bool,
+    /// Record the play in the library history under this singer
+    #[arg(long)]
+    pub singer: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct InfoArgs {
+    /// NCN MIDI file or EMK file
+    pub input: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ExtractArgs {
+    /// EMK file to extract
+    pub input: PathBuf,
+    /// Folder to write to, defaults to the folder of the EMK file
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct RenderArgs {
+    /// MIDI file to render
+    pub input: PathBuf,
+    /// Output file, defaults to the input with a .wav extension
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Semitones to transpose by
+    #[arg(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    pub transpose: i8,
+    /// Tempo multiplier
+    #[arg(short, long, default_value_t = 1.0)]
+    pub speed: f32,
+    /// Channels (1-16) to mute, comma separated
+    #[arg(short, long, value_delimiter = ',')]
+    pub mute: Vec<u8>,
+    #[arg(long, default_value_t = 44100)]
+    pub sample_rate: u32,
+    /// Soundfonts to use instead of the configured ones, highest priority first
+    #[arg(long)]
+    pub soundfont: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct ScoreArgs {
+    /// NCN MIDI file or EMK file
+    pub input: PathBuf,
+    /// WAV recording of the singer, starting with the song
+    pub recording: PathBuf,
+    /// Semitones the song was transposed by
+    #[arg(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    pub transpose: i8,
+    /// Tempo multiplier the song was played at
+    #[arg(short, long, default_value_t = 1.0)]
+    pub speed: f32,
+}
+
+#[derive(Debug, Args)]
+pub struct ProcessArgs {
+    /// Stereo WAV file
+    pub input: PathBuf,
+    /// Output WAV file
+    #[arg(short, long)]
+    pub output: PathBuf,
+    /// Take away the vocals, 0-1
+    #[arg(long)]
+    pub reduce_vocals: Option<f32>,
+    /// Take away the whole centre, bass and kick included
+    #[arg(long)]
+    pub full_band: bool,
+    /// Semitones to transpose by
+    #[arg(short, long, default_value_t = 0, allow_hyphen_values = true)]
+    pub transpose: i8,
+    /// Tempo multiplier
+    #[arg(short, long, default_value_t = 1.0)]
+    pub speed: f32,
+}
+
+#[derive(Debug, Args)]
+pub struct ScanArgs {
+    /// Folders to scan instead of the configured ones, songs outside them are dropped
+    pub roots: Vec<PathBuf>,
+    /// Read every song again, for picking up edited LYR files
+    #[arg(long)]
+    pub full: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DuplicatesArgs {
+    /// Write the groups to a .json or .csv file instead of listing them
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Codes of the songs to check, all of them if none are given
+    pub codes: Vec<String>,
+    /// Write the report to a .json or .csv file instead of listing it
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// List the songs without problems too
+    #[arg(long)]
+    pub all: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct PlaylistArgs {
+    #[command(subcommand)]
+    pub command: PlaylistCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PlaylistCommand {
+    /// List the playlists and the singers with favourites
+    List,
+    /// Write a playlist to a .m3u or .json file
+    Export(PlaylistExportArgs),
+    /// Read a playlist from a .m3u or .json file, replacing the one with its name
+    Import(PlaylistImportArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct PlaylistExportArgs {
+    /// Playlist name, or the singer with --favourites
+    pub name: String,
+    pub output: PathBuf,
+    /// Export the favourites of a singer
+    #[arg(long)]
+    pub favourites: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct PlaylistImportArgs {
+    pub input: PathBuf,
+    /// Name for the playlist, instead of the one in the file
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Add the songs to the favourites of this singer instead
+    #[arg(long)]
+    pub favourites: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+    #[command(subcommand)]
+    pub command: StatsCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StatsCommand {
+    /// Songs sung the most
+    Top(TopArgs),
+    /// Latest songs of a singer
+    Singer(SingerArgs),
+    /// Songs nobody has played yet
+    NeverPlayed,
+    /// Write the plays of a month to a CSV file
+    Report(ReportArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct TopArgs {
+    /// Only count this month, like 2022-11
+    #[arg(long)]
+    pub month: Option<String>,
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub limit: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct SingerArgs {
+    pub singer: String,
+    #[arg(short = 'n', long, default_value_t = 20)]
+    pub limit: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct ReportArgs {
+    /// Month to report, like 2022-11
+    pub month: String,
+    pub output: PathBuf,
+    /// One line per song with how many times it was sung, instead of one per play
+    #[arg(long)]
+    pub by_song: bool,
+}
+
+pub fn run(command: Command, config: &Config) -> Result<()> {
+    match command {
+        Command::Play(args) => run_play(args, config),
+        Command::Info(args) => run_info(args),
+        Command::Extract(args) => run_extract(args),
+        Command::Render(args) => run_render(args, config),
+        Command::Score(args) => run_score(args),
+        Command::Process(args) => run_process(args),
+        Command::Scan(args) => run_scan(args, config),
+        Command::Duplicates(args) => run_duplicates(args),
+        Command::Check(args) => run_check(args),
+        Command::Playlist(args) => run_playlist(args),
+        Command::Stats(args) => run_stats(args),
+    }
+}
+
+/// A song opened from the command line, lyrics are optional so plain MIDI files work too
+struct Song {
+    midi: Vec<u8>,
+    karaoke: Option<Karaoke>,
+}
+
+fn load_song(path: &Path) -> Result<Song> {
+    match karaoke::read_karaoke(path) {
+        Ok(karaoke) => Ok(Song {
+            midi: karaoke.midi.clone(),
+            karaoke: Some(karaoke),
+        }),
+        Err(e) if karaoke::is_emk(path) => Err(anyhow!("{}: {}", path.display(), e)),
+        Err(e) => {
+            debug!("no lyrics for {}: {}", path.display(), e);
+            Ok(Song {
+                midi: fs::read(path)?,
+                karaoke: None,
+            })
+        }
+    }
+}
+
+fn ticks_per_beat(smf: &Smf) -> Result<u16> {
+    match smf.header.timing {
+        Timing::Metrical(ppq) => Ok(ppq.as_int()),
+        Timing::Timecode(..) => bail!("SMPTE timecode MIDI files aren't supported"),
+    }
+}
+
+fn load_sheet(smf: &Smf) -> Sheet {
+    match smf.header.format {
+        Format::SingleTrack | Format::Sequential => Sheet::sequential(&smf.tracks),
+        Format::Parallel => Sheet::parallel(&smf.tracks),
+    }
+}
+
+fn parse_mutes(mute: &[u8]) -> Result<Vec<u8>> {
+    if let Some(ch) = mute.iter().find(|ch| !(1..=16).contains(*ch)) {
+        bail!("channel {} is out of range, channels go from 1 to 16", ch);
+    }
+    Ok(mute.iter().map(|ch| ch - 1).collect())
+}
+
+fn run_play(args: PlayArgs, config: &Config) -> Result<()> {
+    let mutes = parse_mutes(&args.mute)?;
+    if args.speed <= 0.0 {
+        bail!("speed must be above 0");
+    }
+
+    let song = load_song(&args.input)?;
+    let smf = Smf::parse(&song.midi)?;
+    let ppq = ticks_per_beat(&smf)?;
+
+    let sheet = load_sheet(&smf);
+
+    // playing goes on without history if the library can't be read
+    let play = match &args.singer {
+        Some(singer) => record_play(&args.input, singer).unwrap_or_else(|e| {
+            eprintln!("not recording the play: {}", e);
+            None
+        }),
+        None => None,
+    };
+    let started = std::time::Instant::now();
+
+    let mut live = None;
+    if args.score {
+        let (scorer, lines) = song_scorer(&song, &sheet, ppq, args.transpose)?;
+        live = Some((LiveScore::new(scorer, &sheet, ppq), lines));
+    }
+
+    // lyric characters with the MIDI tick they're scrolled at
+    let mut lyrics = Vec::new();
+    if let Some(karaoke) = &song.karaoke {
+        println!("{} - {}\n", karaoke.info.title, karaoke.info.author);
+        lyrics = karaoke.timed_chars(ppq);
+    }
+
+    let (tx, rx) = crossbeam::channel::unbounded();
+    let (errtx, errrx) = crossbeam::channel::unbounded();
+    let mixer = Arc::new(RwLock::new(ChannelMixer::new()));
+    let mut midi_config = config.clone();
+    midi_config.mic.enabled |= args.score;
+    let output = Arc::new(RwLock::new(OutputTarget::Synth));
+    let midi_thread =
+        thread::spawn(move || midi::midi_thread(rx, None, mixer, midi_config, errtx, output));
+
+    let guide = song.karaoke.as_ref().and_then(|k| k.guide_channel());
+    let mut commands = vec![MixerCommand::GuideChannel(guide)];
+    if args.no_guide && guide.is_some() {
+        commands.push(MixerCommand::ToggleGuide);
+    }
+    commands.extend(mutes.into_iter().map(|ch| MixerCommand::Mute(ch, true)));
+
+    tx.send(MidiMessage::ClearNotes)?;
+    tx.send(MidiMessage::Reset)?;
+    for cmd in commands {
+        tx.send(MidiMessage::Mixer(cmd))?;
+    }
+    if let Some((live, _)) = &live {
+        tx.send(MidiMessage::MicTap(Some(live.tap.clone())))?;
+    }
+
+    // nothing pauses the terminal player
+    let (_pause, pause_rx) = crossbeam::channel::bounded(1);
+    let mut timer = ControlTicker::new(ppq, pause_rx);
+    timer.speed = args.speed;
+
+    let mut lyrics = lyrics.into_iter().peekable();
+    let mut sysex = sysex::from_smf(&smf).into_iter().peekable();
+    let mut counter = 0_u32;
+
+    for (tick, moment) in sheet.iter().enumerate() {
+        let tick = tick as u32;
+        let lyric_due = lyrics.peek().is_some_and(|(t, _)| *t <= tick);
+        let sysex_due = sysex.peek().is_some_and(|(t, _)| *t <= tick as usize);
+
+        if !moment.is_empty() || lyric_due || sysex_due {
+            timer.sleep(counter);
+            counter = 0;
+        }
+
+        while let Some((_, c)) = lyrics.next_if(|(t, _)| *t <= tick) {
+            scroll(c);
+        }
+        while let Some((_, msg)) = sysex.next_if(|(t, _)| *t <= tick as usize) {
+            tx.send(MidiMessage::SysEx(msg))?;
+        }
+
+        for event in &moment.events {
+            match event {
+                Event::Tempo(tempo) => timer.change_tempo(*tempo),
+                Event::Midi(msg) => {
+                    tx.send(MidiMessage::Event(render::transpose(*msg, args.transpose)))?
+                }
+                _ => {}
+            }
+        }
+
+        for e in errrx.try_iter() {
+            eprintln!("\nerror: {}", e);
+        }
+        if let Some((live, _)) = &mut live {
+            let now = live.seconds(tick as usize);
+            live.update(now, args.speed as f64);
+        }
+
+        counter += 1;
+    }
+
+    // let the last notes ring out
+    timer.sleep(counter);
+    thread::sleep(std::time::Duration::from_secs(1));
+    println!();
+
+    let mut score = None;
+    if let Some((mut live, lines)) = live {
+        tx.send(MidiMessage::MicTap(None))?;
+        // a second after the end of the song
+        let now = live.seconds(sheet.len()) + args.speed as f64;
+        live.update(now, args.speed as f64);
+        println!();
+        let report = live.scorer.report();
+        print_score(&report, &lines);
+        score = Some(report.overall.total());
+    }
+    if let Some((library, play)) = play {
+        library.finish_play(play.play, started.elapsed(), score, false)?;
+    }
+
+    tx.send(MidiMessage::ClearNotes)?;
+    drop(tx);
+    midi_thread
+        .join()
+        .map_err(|_| anyhow!("MIDI thread panicked"))?;
+
+    Ok(())
+}
+
+/// Starts a play in the library history, `None` if the song isn't in the library
+fn record_play(path: &Path, singer: &str) -> Result<Option<(Library, PlayId)>> {
+    let library = Library::open_default()?;
+    // the scan keeps the paths under the library folders, which are usually absolute
+    let mut play = library.record_play(path, singer)?;
+    if play.is_none() {
+        play = library.record_play(&fs::canonicalize(path)?, singer)?;
+    }
+    if play.is_none() {
+        eprintln!(
+            "{} isn't in the library, not recording the play",
+            path.display()
+        );
+    }
+    Ok(play.map(|play| (library, play)))
+}
+
+fn run_info(args: InfoArgs) -> Result<()> {
+    let song = load_song(&args.input)?;
+    let smf = Smf::parse(&song.midi)?;
+    let ppq = ticks_per_beat(&smf)?;
+
+    println!("File: {}", args.input.display());
+
+    if let Some(karaoke) = &song.karaoke {
+        let info = &karaoke.info;
+        println!("Format: {}", karaoke.header.signature);
+        println!("Code: {}", info.code.as_deref().unwrap_or("-"));
+        println!("Title: {}", info.title);
+        println!("Artist: {}", info.author);
+        println!("Key: {}", info.key);
+        println!("Language: {:?}", info.language);
+        println!("Song type: {:?}", info.song_type);
+        println!("Subtitle type: {:?}", info.subtitle_type);
+    } else {
+        println!("No lyrics or cursor file found");
+    }
+
+    println!();
+    println!(
+        "MIDI: format {:?}, {} tracks, {} ticks per beat",
+        smf.header.format,
+        smf.tracks.len(),
+        ppq
+    );
+
+    println!();
+    println!("Tempo map:");
+    for (tick, tempo) in tempo_map(&smf) {
+        println!(
+            "  tick {:>8}  {:>7.2} BPM",
+            tick,
+            60_000_000.0 / tempo as f64
+        );
+    }
+
+    println!();
+    println!("Channels:");
+    let usage = channel_usage(&smf);
+    let guide = song.karaoke.as_ref().and_then(|k| k.guide_channel());
+    for (ch, usage) in usage.iter().enumerate() {
+        if usage.notes == 0 {
+            continue;
+        }
+        let programs = usage
+            .programs
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!(
+            "  {:>2}: {:>6} notes, keys {}-{}, programs [{}]{}",
+            ch + 1,
+            usage.notes,
+            usage.lowest,
+            usage.highest,
+            programs,
+            if guide == Some(ch as u8) {
+                " (guide)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    if let Some(karaoke) = &song.karaoke {
+        let ticks = karaoke
+            .cursor
+            .data
+            .iter()
+            .map(|t| t.tick)
+            .collect::<Vec<_>>();
+        let out_of_order = ticks.windows(2).filter(|w| w[1] < w[0]).count();
+
+        println!();
+        println!("CUR:");
+        println!("  {} steps", ticks.len());
+        println!("  {} lyric characters", karaoke.lyrics.chars().count());
+        if let (Some(first), Some(last)) = (ticks.first(), ticks.last()) {
+            println!(
+                "  first {} (MIDI {}), last {} (MIDI {})",
+                first,
+                guide::cur_to_midi_tick(*first, ppq),
+                last,
+                guide::cur_to_midi_tick(*last, ppq)
+            );
+        }
+        println!("  {} steps out of order", out_of_order);
+    }
+
+    Ok(())
+}
+
+/// Tempo changes as `(tick, microseconds per beat)`, sorted by tick
+fn tempo_map(smf: &Smf) -> Vec<(u32, u32)> {
+    let mut map = Vec::new();
+    let mut start = 0;
+
+    for track in &smf.tracks {
+        let mut tick = start;
+        for event in track {
+            tick += event.delta.as_int();
+            if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                map.push((tick, tempo.as_int()));
+            }
+        }
+        // sequential tracks play one after another, from the moment after the
+        // last one like in nodi's sheet
+        if smf.header.format == Format::Sequential {
+            start = tick + 1;
+        }
+    }
+
+    map.sort_by_key(|(tick, _)| *tick);
+    map
+}
+
+#[derive(Debug, Clone, Default)]
+struct ChannelUsage {
+    notes: usize,
+    lowest: u8,
+    highest: u8,
+    programs: Vec<u8>,
+}
+
+fn channel_usage(smf: &Smf) -> Vec<ChannelUsage> {
+    let mut usage = vec![ChannelUsage::default(); CHANNELS];
+
+    for event in smf.tracks.iter().flatten() {
+        if let TrackEventKind::Midi { channel, message } = event.kind {
+            let usage = &mut usage[channel.as_int() as usize];
+            match message {
+                M::NoteOn { key, vel } if vel.as_int() > 0 => {
+                    let key = key.as_int();
+                    if usage.notes == 0 || key < usage.lowest {
+                        usage.lowest = key;
+                    }
+                    usage.highest = usage.highest.max(key);
+                    usage.notes += 1;
+                }
+                M::ProgramChange { program } if !usage.programs.contains(&program.as_int()) => {
+                    usage.programs.push(program.as_int());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    usage
+}
+
+fn run_extract(args: ExtractArgs) -> Result<()> {
+    let blocks =
+        emk::read_blocks(&args.input).map_err(|e| anyhow!("{}: {}", args.input.display(), e))?;
+
+    let dir = match args.output {
+        Some(dir) => dir,
+        None => args
+            .input
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+    };
+    fs::create_dir_all(&dir)?;
+
+    let stem = args
+        .input
+        .file_stem()
+        .ok_or_else(|| anyhow!("{} has no file name", args.input.display()))?;
+
+    for block in blocks {
+        let ext = match block.tag.as_str() {
+            "MIDI_DATA" => "mid",
+            "LYRIC_DATA" => "lyr",
+            "CUR_DATA" => "cur",
+            _ => {
+                debug!("skipping block {}", block.tag);
+                continue;
+            }
+        };
+        let path = dir.join(stem).with_extension(ext);
+        fs::write(&path, &block.data)?;
+        println!("Wrote {}", path.display());
+    }
+
+    Ok(())
+}
+
+fn run_render(args: RenderArgs, config: &Config) -> Result<()> {
+    let mutes = parse_mutes(&args.mute)?;
+
+    let output = args
+        .output
+        .unwrap_or_else(|| args.input.with_extension("wav"));
+    if output
+        .extension()
+        .is_some_and(|e| !e.eq_ignore_ascii_case("wav"))
+    {
+        bail!("renders are WAV files, name the output .wav");
+    }
+
+    let soundfonts = if args.soundfont.is_empty() {
+        soundfont::initial_setup(config)
+    } else {
+        SoundfontSetup {
+            fonts: args.soundfont,
+            ..Default::default()
+        }
+    };
+
+    let options = RenderOptions {
+        soundfonts,
+        transpose: args.transpose,
+        speed: args.speed,
+        mutes,
+        sample_rate: args.sample_rate,
+        master: config.master.clone(),
+    };
+
+    let stats = render::render_file(&args.input, &output, &options)?;
+    println!(
+        "Rendered {} ({:.1}s, {} frames)",
+        output.display(),
+        stats.duration.as_secs_f64(),
+        stats.frames
+    );
+
+    Ok(())
+}
+
+/// Scorer for the guide melody of a song, with the text of every line
+fn song_scorer(
+    song: &Song,
+    sheet: &Sheet,
+    ppq: u16,
+    transpose: i8,
+) -> Result<(Scorer, Vec<String>)> {
+    let karaoke = song
+        .karaoke
+        .as_ref()
+        .ok_or_else(|| anyhow!("scoring needs the lyrics and cursor of the song"))?;
+    score::song_scorer(karaoke, sheet, ppq, transpose)
+}
+
+fn print_score(report: &ScoreReport, lines: &[String]) {
+    for (i, score) in report.lines.iter().enumerate() {
+        if score.notes == 0 {
+            continue;
+        }
+        println!(
+            "{:>3}  timing {:>3.0}%  pitch {:>3.0}%  {}",
+            score.total(),
+            score.timing * 100.0,
+            score.pitch * 100.0,
+            lines.get(i).map_or("", String::as_str)
+        );
+    }
+
+    let overall = &report.overall;
+    println!();
+    println!(
+        "Score: {} (timing {:.0}%, pitch {:.0}%)",
+        overall.total(),
+        overall.timing * 100.0,
+        overall.pitch * 100.0
+    );
+}
+
+fn run_score(args: ScoreArgs) -> Result<()> {
+    if args.speed <= 0.0 {
+        bail!("speed must be above 0");
+    }
+
+    let song = load_song(&args.input)?;
+    let smf = Smf::parse(&song.midi)?;
+    let ppq = ticks_per_beat(&smf)?;
+    let sheet = load_sheet(&smf);
+    let (mut scorer, lines) = song_scorer(&song, &sheet, ppq, args.transpose)?;
+
+    let samples = mic::load_file(&args.recording, pitch::ANALYSIS_RATE)?;
+    let mut tracker = PitchTracker::new(pitch::ANALYSIS_RATE);
+    let speed = args.speed as f64;
+    tracker.push(&samples, |frame| {
+        scorer.push(PitchFrame {
+            time: frame.time * speed,
+            ..frame
+        })
+    });
+
+    print_score(&scorer.report(), &lines);
+    Ok(())
+}
+
+fn run_process(args: ProcessArgs) -> Result<()> {
+    if args.speed <= 0.0 {
+        bail!("speed must be above 0");
+    }
+
+    let (spec, mut samples) = mic::read_wav(&args.input)?;
+    if spec.channels != 2 {
+        bail!("{} isn't stereo", args.input.display());
+    }
+
+    if let Some(strength) = args.reduce_vocals {
+        let settings = VocalReducerSettings {
+            enabled: true,
+            strength,
+            band_limited: !args.full_band,
+        };
+        VocalReducer::new(settings, spec.sample_rate).process(&mut samples);
+    }
+
+    if args.transpose != 0 || args.speed != 1.0 {
+        let mut shifter = PitchShifter::new(spec.sample_rate, args.transpose, args.speed);
+        let mut shifted = Vec::new();
+        shifter.process(&samples, &mut shifted);
+        shifter.flush(&mut shifted);
+        samples = shifted;
+    }
+
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: spec.sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(&args.output, spec)?;
+    for s in samples {
+        writer.write_sample(s)?;
+    }
+    writer.finalize()?;
+    println!("Wrote {}", args.output.display());
+
+    Ok(())
+}
+
+fn run_scan(args: ScanArgs, config: &Config) -> Result<()> {
+    let roots = if args.roots.is_empty() {
+        config.library.roots.clone()
+    } else {
+        args.roots
+    };
+    if roots.is_empty() {
+        bail!("no library folders are configured, pass the folders to scan");
+    }
+
+    let mut library = Library::open_default()?;
+    let report = library.scan(&roots, args.full)?;
+    println!(
+        "{} added, {} updated, {} unchanged, {} removed, {} failed",
+        report.added, report.updated, report.unchanged, report.removed, report.failed
+    );
+    for (path, error) in library.failed()? {
+        println!("  {}: {}", path.display(), error);
+    }
+    println!("{} songs in the library", library.count()?);
+    Ok(())
+}
+
+fn run_duplicates(args: DuplicatesArgs) -> Result<()> {
+    let library = Library::open_default()?;
+    let songs = library.songs()?;
+    eprintln!("Reading {} songs", songs.len());
+    let groups = duplicates::find_duplicates(songs);
+
+    let output = match args.output {
+        Some(output) => output,
+        None => {
+            print!("{}", duplicates_text(&groups));
+            println!("{} songs with copies", groups.len());
+            return Ok(());
+        }
+    };
+    let ext = output
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    let data = match ext.as_deref() {
+        Some("json") => serde_json::to_string_pretty(&groups)?,
+        Some("csv") => duplicates_csv(&groups),
+        _ => bail!("unsupported output format, use a .json or .csv file"),
+    };
+    fs::write(&output, data)?;
+    println!("Wrote {} groups to {}", groups.len(), output.display());
+    Ok(())
+}
+
+fn reasons(group: &DuplicateGroup) -> String {
+    group
+        .reasons
+        .iter()
+        .map(|r| r.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn duplicates_text(groups: &[DuplicateGroup]) -> String {
+    let mut text = String::new();
+    for group in groups {
+        text += &format!("Same {}:\n", reasons(group));
+        for copy in &group.copies {
+            let song = &copy.song;
+            text += &format!(
+                "  {:<8} {} - {}  {}\n",
+                song.code,
+                song.title,
+                song.artist,
+                song.path.display()
+            );
+            text += &match &copy.cur_problem {
+                None => "           CUR timing ok\n".to_string(),
+                Some(problem) => format!("           {}\n", problem),
+            };
+        }
+    }
+    text
+}
+
+fn duplicates_csv(groups: &[DuplicateGroup]) -> String {
+    let mut csv = library::csv_line(&[
+        "group",
+        "matched",
+        "code",
+        "title",
+        "artist",
+        "format",
+        "path",
+        "hash",
+        "cur_valid",
+        "cur_problem",
+    ]);
+    for (n, group) in groups.iter().enumerate() {
+        for copy in &group.copies {
+            let song = &copy.song;
+            csv += &library::csv_line(&[
+                (n + 1).to_string(),
+                reasons(group),
+                song.code.clone(),
+                song.title.clone(),
+                song.artist.clone(),
+                song.format.as_str().to_string(),
+                song.path.display().to_string(),
+                song.hash.clone(),
+                copy.cur_valid.to_string(),
+                copy.cur_problem.clone().unwrap_or_default(),
+            ]);
+        }
+    }
+    csv
+}
+
+fn run_check(args: CheckArgs) -> Result<()> {
+    let library = Library::open_default()?;
+    let songs = if args.codes.is_empty() {
+        library.songs()?
+    } else {
+        let mut songs = Vec::new();
+        for code in &args.codes {
+            let found = library.by_code(code)?;
+            if found.is_empty() {
+                bail!("no song with code {}", code);
+            }
+            songs.extend(found);
+        }
+        songs
+    };
+    eprintln!("Checking {} songs", songs.len());
+    let report = health::check_songs(songs)
+        .into_iter()
+        .filter(|h| args.all || !h.is_ok())
+        .collect::<Vec<_>>();
+    let broken = report.iter().filter(|h| !h.is_ok()).count();
+
+    match args.output {
+        None => {
+            for health in &report {
+                let song = &health.song;
+                println!(
+                    "{:<8} {} - {}  {}",
+                    song.code,
+                    song.title,
+                    song.artist,
+                    song.path.display()
+                );
+                if health.is_ok() {
+                    println!("           ok");
+                }
+                for problem in &health.problems {
+                    println!("           {}", problem);
+                }
+            }
+        }
+        Some(output) => {
+            let ext = output
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| e.to_ascii_lowercase());
+            let data = match ext.as_deref() {
+                Some("json") => serde_json::to_string_pretty(&report)?,
+                Some("csv") => health_csv(&report),
+                _ => bail!("unsupported output format, use a .json or .csv file"),
+            };
+            fs::write(&output, data)?;
+            println!("Wrote the report to {}", output.display());
+        }
+    }
+    println!("{} songs with problems", broken);
+    Ok(())
+}
+
+fn health_csv(report: &[SongHealth]) -> String {
+    let mut csv = library::csv_line(&["code", "title", "artist", "path", "problem"]);
+    for health in report {
+        let song = &health.song;
+        let problems = if health.is_ok() {
+            vec![String::new()]
+        } else {
+            health.problems.iter().map(|p| p.to_string()).collect()
+        };
+        for problem in problems {
+            csv += &library::csv_line(&[
+                song.code.clone(),
+                song.title.clone(),
+                song.artist.clone(),
+                song.path.display().to_string(),
+                problem,
+            ]);
+        }
+    }
+    csv
+}
+
+fn run_playlist(args: PlaylistArgs) -> Result<()> {
+    let mut library = Library::open_default()?;
+    match args.command {
+        PlaylistCommand::List => {
+            println!("Playlists:");
+            for name in library.playlists()? {
+                println!("  {}", name);
+            }
+            println!("Favourites:");
+            for singer in library.singers()? {
+                println!("  {}", singer);
+            }
+        }
+        PlaylistCommand::Export(args) => {
+            let playlist = if args.favourites {
+                library.favourites(&args.name)?
+            } else {
+                library
+                    .playlist(&args.name)?
+                    .ok_or_else(|| anyhow!("no playlist named {}", args.name))?
+            };
+            playlist.export(&args.output, &library)?;
+            println!(
+                "Wrote {} songs to {}",
+                playlist.songs.len(),
+                args.output.display()
+            );
+        }
+        PlaylistCommand::Import(args) => {
+            let mut playlist = Playlist::import(&args.input, &library)?;
+            let missing = playlist
+                .songs
+                .iter()
+                .filter(|entry| matches!(library.find_entry(entry), Ok(None)))
+                .count();
+            if let Some(singer) = args.favourites {
+                for entry in &playlist.songs {
+                    library.add_favourite(&singer, entry)?;
+                }
+                println!("Added {} favourites for {}", playlist.songs.len(), singer);
+            } else {
+                if let Some(name) = args.name {
+                    playlist.name = name;
+                }
+                library.save_playlist(&playlist)?;
+                println!(
+                    "Imported {} with {} songs",
+                    playlist.name,
+                    playlist.songs.len()
+                );
+            }
+            if missing > 0 {
+                println!("{} songs aren't in the library", missing);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_stats(args: StatsArgs) -> Result<()> {
+    let library = Library::open_default()?;
+    match args.command {
+        StatsCommand::Top(args) => {
+            let (from, to) = match &args.month {
+                Some(month) => history::month_range(month)?,
+                None => (0, i64::MAX),
+            };
+            for (song, count) in library.most_sung(from, to, args.limit)? {
+                println!(
+                    "{:>5}  {:<8} {} - {}",
+                    count, song.code, song.title, song.artist
+                );
+            }
+        }
+        StatsCommand::Singer(args) => {
+            for play in library.singer_history(&args.singer, args.limit)? {
+                let score = match (play.score, play.skipped) {
+                    (_, true) => "skipped".to_string(),
+                    (Some(score), _) => format!("score {}", score),
+                    (None, _) => String::new(),
+                };
+                println!(
+                    "{}  {:<8} {} - {}  {}",
+                    play.date(),
+                    play.code,
+                    play.title,
+                    play.artist,
+                    score
+                );
+            }
+        }
+        StatsCommand::NeverPlayed => {
+            let songs = library.never_played()?;
+            for song in &songs {
+                println!("{:<8} {} - {}", song.code, song.title, song.artist);
+            }
+            println!("{} songs never played", songs.len());
+        }
+        StatsCommand::Report(args) => {
+            let csv = if args.by_song {
+                library.month_songs_csv(&args.month)?
+            } else {
+                library.month_plays_csv(&args.month)?
+            };
+            fs::write(&args.output, csv)?;
+            println!("Wrote {}", args.output.display());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tempo_map() {
+    use midly::{num::u28, Header, TrackEvent};
+
+    let tempo = |delta: u32, tempo: u32| TrackEvent {
+        delta: u28::from(delta),
+        kind: TrackEventKind::Meta(MetaMessage::Tempo(tempo.into())),
+    };
+
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(480.into())));
+    smf.tracks
+        .push(vec![tempo(0, 500_000), tempo(960, 400_000)]);
+    smf.tracks.push(vec![tempo(480, 600_000)]);
+    assert_eq!(
+        tempo_map(&smf),
+        [(0, 500_000), (480, 600_000), (960, 400_000)]
+    );
+
+    // the second track starts on the moment after the first one's last
+    smf.header.format = Format::Sequential;
+    assert_eq!(
+        tempo_map(&smf),
+        [(0, 500_000), (960, 400_000), (1441, 600_000)]
+    );
+}
@@ -0,0 +1,93 @@
+//! User configuration
+//!
+//! Stored as JSON in the platform config folder (`~/.config/rusty-karaoke/config.json` on Linux).
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dsp::MasterSettings, library::LibrarySettings, mic::MicSettings, queue::QueueSettings,
+    soundfont::SoundfontSetup, vocal::VocalReducerSettings,
+};
+
+/// Folder name used inside the platform config and data folders
+pub const APP_DIR: &str = "rusty-karaoke";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Soundfont to load, skips discovery when set
+    pub soundfont: Option<PathBuf>,
+    /// Soundfont stack and overrides, replaces `soundfont` when not empty
+    pub soundfonts: SoundfontSetup,
+    pub audio: AudioConfig,
+    pub master: MasterSettings,
+    pub mic: MicSettings,
+    pub queue: QueueSettings,
+    pub library: LibrarySettings,
+    /// Vocal reducer for audio songs
+    pub vocal: VocalReducerSettings,
+}
+
+/// Audio output settings, `None` uses the device default
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// Output device name
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    /// Buffer size in frames
+    pub buffer_size: Option<u32>,
+}
+
+impl Config {
+    /// Where the config file lives
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(APP_DIR).join("config.json"))
+    }
+
+    /// Loads the config, falling back to the defaults if it's missing or broken
+    pub fn load() -> Self {
+        match Self::path() {
+            Some(path) if path.exists() => Self::load_from(&path).unwrap_or_else(|e| {
+                warn!("failed loading config {}: {}", path.display(), e);
+                Self::default()
+            }),
+            _ => Self::default(),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("no config folder on this platform"))?;
+        self.save_to(&path)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        debug!("saved config to {}", path.display());
+        Ok(())
+    }
+}
+
+/// Folder for application data like the song queue and library, created if missing
+pub fn data_dir() -> Result<PathBuf> {
+    let dir = dirs::data_dir()
+        .ok_or_else(|| anyhow!("no data folder on this platform"))?
+        .join(APP_DIR);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
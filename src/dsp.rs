@@ -0,0 +1,315 @@
+//! Master output stage
+//!
+//! Runs on the interleaved stereo buffer after the synth has written it:
+//! master gain, then a soft-knee peak limiter so dense arrangements don't clip.
+//! Reverb and chorus are the synth's own, the settings are only stored here.
+//! The filters the other effects share live here too.
+
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::plugin::PluginSlot;
+
+/// Peak level the limiter holds the output under
+const LIMITER_THRESHOLD: f32 = 0.95;
+
+/// Width of the limiter's soft knee in dB, centred on the threshold. Peaks
+/// get pulled down gradually from half of it under the threshold.
+const LIMITER_KNEE_DB: f32 = 6.0;
+
+/// How fast the limiter lets go after a peak, per frame (~50ms at 48kHz)
+const LIMITER_RELEASE: f32 = 0.0004;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MasterSettings {
+    /// Linear gain, `1.0` leaves the synth output as is
+    pub volume: f32,
+    pub limiter: bool,
+    pub reverb: ReverbSettings,
+    pub chorus: ChorusSettings,
+    /// Effect plugins before the master gain and limiter
+    pub plugins: Vec<PluginSlot>,
+}
+
+impl Default for MasterSettings {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            limiter: true,
+            reverb: ReverbSettings::default(),
+            chorus: ChorusSettings::default(),
+            plugins: Vec::new(),
+        }
+    }
+}
+
+/// Synth reverb, defaults are FluidSynth's
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverbSettings {
+    pub enabled: bool,
+    pub room_size: f32,
+    pub damping: f32,
+    pub width: f32,
+    pub level: f32,
+}
+
+impl Default for ReverbSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            room_size: 0.2,
+            damping: 0.0,
+            width: 0.5,
+            level: 0.9,
+        }
+    }
+}
+
+/// Synth chorus, defaults are FluidSynth's
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChorusSettings {
+    pub enabled: bool,
+    pub voices: u32,
+    pub level: f32,
+    /// Modulation speed in Hz
+    pub speed: f32,
+    /// Modulation depth in ms
+    pub depth: f32,
+}
+
+impl Default for ChorusSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            voices: 3,
+            level: 2.0,
+            speed: 0.3,
+            depth: 8.0,
+        }
+    }
+}
+
+/// Gain and limiter applied to everything going out
+#[derive(Debug, Clone)]
+pub struct MasterChain {
+    pub settings: MasterSettings,
+    /// Current limiter gain reduction, `1.0` when idle
+    gain: f32,
+}
+
+impl MasterChain {
+    pub fn new(settings: MasterSettings) -> Self {
+        Self {
+            settings,
+            gain: 1.0,
+        }
+    }
+
+    /// Processes an interleaved stereo buffer in place
+    pub fn process(&mut self, buf: &mut [f32]) {
+        let volume = self.settings.volume;
+
+        for frame in buf.chunks_mut(2) {
+            for s in frame.iter_mut() {
+                *s *= volume;
+            }
+
+            if !self.settings.limiter {
+                continue;
+            }
+
+            let peak = frame.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+            let target = limiter_gain(peak);
+
+            // clamp down right away, recover slowly so it doesn't pump
+            if target < self.gain {
+                self.gain = target;
+            } else {
+                self.gain += (target - self.gain) * LIMITER_RELEASE;
+            }
+
+            for s in frame.iter_mut() {
+                *s *= self.gain;
+            }
+        }
+    }
+}
+
+/// Gain the limiter aims for at a peak level. Past the knee the output is held
+/// at the threshold, inside it the reduction eases in.
+fn limiter_gain(peak: f32) -> f32 {
+    let threshold = 20.0 * LIMITER_THRESHOLD.log10();
+    let level = 20.0 * peak.max(1e-9).log10();
+    let over = level - threshold + LIMITER_KNEE_DB / 2.0;
+    let reduction = if over <= 0.0 {
+        0.0
+    } else if over < LIMITER_KNEE_DB {
+        over * over / (2.0 * LIMITER_KNEE_DB)
+    } else {
+        level - threshold
+    };
+    10.0_f32.powf(-reduction / 20.0)
+}
+
+/// RBJ cookbook biquad
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Default for Biquad {
+    /// Passes everything through
+    fn default() -> Self {
+        Self {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+}
+
+impl Biquad {
+    fn new(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            ..Default::default()
+        }
+    }
+
+    pub fn peaking(sr: f32, freq: f32, q: f32, gain_db: f32) -> Self {
+        let a = 10_f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq / sr;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+
+        Self::new(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    pub fn low_shelf(sr: f32, freq: f32, gain_db: f32) -> Self {
+        let (a, cos, beta) = Self::shelf(sr, freq, gain_db);
+
+        Self::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + beta),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - beta),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + beta,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - beta,
+            ],
+        )
+    }
+
+    pub fn high_shelf(sr: f32, freq: f32, gain_db: f32) -> Self {
+        let (a, cos, beta) = Self::shelf(sr, freq, gain_db);
+
+        Self::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + beta),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - beta),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + beta,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - beta,
+            ],
+        )
+    }
+
+    pub fn low_pass(sr: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::pass(sr, freq, q);
+
+        Self::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn high_pass(sr: f32, freq: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::pass(sr, freq, q);
+
+        Self::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn pass(sr: f32, freq: f32, q: f32) -> (f32, f32) {
+        let w0 = 2.0 * PI * freq / sr;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    /// Takes the coefficients of `to` but keeps the filter state, so retuning doesn't click
+    pub fn retune(&mut self, to: Biquad) {
+        *self = Self {
+            z1: self.z1,
+            z2: self.z2,
+            ..to
+        };
+    }
+
+    /// `A`, `cos(w0)` and `2 * sqrt(A) * alpha` for a shelf with a slope of 1
+    fn shelf(sr: f32, freq: f32, gain_db: f32) -> (f32, f32, f32) {
+        let a = 10_f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq / sr;
+        let alpha = w0.sin() / 2.0 * 2.0_f32.sqrt();
+        (a, w0.cos(), 2.0 * a.sqrt() * alpha)
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+#[test]
+fn test_limiter() {
+    let mut chain = MasterChain::new(MasterSettings {
+        volume: 2.0,
+        ..Default::default()
+    });
+
+    let mut buf = [0.8, -0.8, 0.1, 0.1];
+    chain.process(&mut buf);
+    assert!(buf.iter().all(|s| s.abs() <= LIMITER_THRESHOLD));
+    assert!((buf[0] - LIMITER_THRESHOLD).abs() < 1e-6);
+    // still held down on the quiet frame after the peak
+    assert!(buf[2] < 0.2);
+
+    chain.settings.limiter = false;
+    let mut buf = [0.8, -0.8];
+    chain.process(&mut buf);
+    assert_eq!(buf, [1.6, -1.6]);
+
+    // in the knee, just under the threshold, it's already pulled down a little
+    let mut chain = MasterChain::new(MasterSettings::default());
+    let mut buf = [0.9, 0.9];
+    chain.process(&mut buf);
+    assert!(buf[0] < 0.9 && buf[0] > 0.8);
+}
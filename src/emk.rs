@@ -1,35 +1,36 @@
-use log::debug;
-use nodi::Sheet;
+use log::{debug, trace};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use std::{fs::File, io::Read};
+use std::{error::Error, fs, io::Read, path::Path};
 use flate2::read::ZlibDecoder;
+use encoding::{all::WINDOWS_874, DecoderTrap, Encoding};
 
 use md5::{Digest, Md5};
 
-use crate::ncn::{NcnCursor, NcnLyrics};
+use crate::{
+    karaoke::{
+        Karaoke, KaraokeHeader, KaraokeLanguage, SongType, SubtitleType,
+    },
+    ncn::{NcnCursor, NcnLyrics},
+};
 
 // EMK magic xor key, works for all EMK files.
 // credits to alula for figuring this out
 // AFF24C9CE9EA9943
 static EMK_MAGIC: u64 = 0xAFF24C9CE9EA9943;
 
-static MAGIC: u64 = 0x2e53464453;
+/// `.SFDS`, the start of every decrypted EMK file
+static FILE_MAGIC: [u8; 5] = [0x2e, 0x53, 0x46, 0x44, 0x53];
+
+/// `SFDS`, the start of every header in the header list
+static HEADER_MAGIC: [u8; 4] = [0x53, 0x46, 0x44, 0x53];
 
 fn xor(data: Vec<u8>, key: u64) -> Vec<u8> {
-    println!("XORing with key: {:X}", key);
+    trace!("XORing with key: {:X}", key);
     let key = key.to_be_bytes().to_vec();
-    println!("key: {:X?}", key);
     let mut data = data;
     for i in 0..data.len() {
-        // println!("data: {:?}", data[i]);
         data[i] ^= key[i % key.len()];
-        // println!("data xor: {:?}", data[i]);
-    }
-
-    let magic = vec![0x2e, 0x53, 0x46, 0x44, 0x53];
-    if data[0..magic.len()] != magic {
-        println!("Invalid magic");
     }
 
     data
@@ -40,6 +41,7 @@ pub struct EmkHeader {
     pub version: String,
 }
 
+#[derive(Debug, Clone, Default)]
 pub struct EmkInfo {
     /// ID of the EMK file
     pub code: String,
@@ -57,7 +59,7 @@ pub struct EmkInfo {
     pub artist: String,
     /// Language
     pub language: String,
-    /// MIDI channel with the vocals
+    /// MIDI channel with the vocals, numbered from 1 like in the player
     pub vocal_channel: u8,
     /// Original file name
     pub original_file: String,
@@ -71,6 +73,48 @@ pub struct EmkInfo {
     pub tempo: u32,
 }
 
+impl EmkInfo {
+    /// Parses a `SONG_INFO` block, a list of tagged values in the same order as the fields.
+    ///
+    /// Values missing at the end are left at their defaults.
+    pub fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = TagReader::new(data);
+        let mut values = Vec::new();
+        while !reader.is_empty() {
+            values.push(reader.read_tag()?);
+        }
+        trace!("SONG_INFO: {:?}", values);
+
+        let mut values = values.into_iter();
+        let mut next = || values.next().unwrap_or(TagOut::String(String::new()));
+
+        Ok(Self {
+            code: next().to_string(),
+            song_type: next().to_string(),
+            subtitle_type: next().to_string(),
+            title: next().to_string(),
+            key: next().to_string(),
+            artist: next().to_string(),
+            language: next().to_string(),
+            vocal_channel: next().to_u8(),
+            original_file: next().to_string(),
+            lyric_title: next().to_string(),
+            start_time: next().to_u32(),
+            end_time: next().to_u32(),
+            tempo: next().to_u32(),
+        })
+    }
+
+    pub fn karaoke_language(&self) -> KaraokeLanguage {
+        match self.language.to_ascii_uppercase().as_str() {
+            "THAI" | "TH" => KaraokeLanguage::Thai,
+            "ENGLISH" | "EN" => KaraokeLanguage::English,
+            "" => KaraokeLanguage::Undefined,
+            _ => KaraokeLanguage::Other(self.language.clone()),
+        }
+    }
+}
+
 pub struct Emk {
     pub header: EmkHeader,
     pub info: EmkInfo,
@@ -78,6 +122,59 @@ pub struct Emk {
     pub cursor: NcnCursor,
     pub midi: Vec<u8>,
 }
+
+/// A decompressed block of an EMK file
+#[derive(Debug, Clone)]
+pub struct EmkBlock {
+    /// `HEADER`, `SONG_INFO`, `MIDI_DATA`, `LYRIC_DATA` or `CUR_DATA`
+    pub tag: String,
+    pub data: Vec<u8>,
+}
+
+/// Reads and decompresses every block of an EMK file
+pub fn read_blocks(path: &Path) -> Result<Vec<EmkBlock>, Box<dyn Error>> {
+    parse_blocks(fs::read(path)?)
+}
+
+/// Decompresses every block of EMK file contents
+pub fn parse_blocks(data: Vec<u8>) -> Result<Vec<EmkBlock>, Box<dyn Error>> {
+    EmkReader::new(data)?.read_header()
+}
+
+/// Reads an EMK song, converted to the common karaoke format
+pub fn read_emk(path: &Path) -> Result<Karaoke, Box<dyn Error>> {
+    let blocks = read_blocks(path)?;
+    let block = |tag: &str| {
+        blocks
+            .iter()
+            .find(|b| b.tag == tag)
+            .map(|b| b.data.as_slice())
+            .ok_or_else(|| format!("EMK file has no {} block", tag))
+    };
+
+    let info = EmkInfo::parse(block("SONG_INFO")?)?;
+    let mut song = crate::ncn_reader::read_ncn_data(
+        block("MIDI_DATA")?.to_vec(),
+        block("LYRIC_DATA")?,
+        block("CUR_DATA")?,
+    )?;
+
+    song.header = KaraokeHeader {
+        signature: "EMK".to_string(),
+        version: String::new(),
+    };
+    song.info.code = Some(info.code.clone()).filter(|c| !c.is_empty());
+    song.info.language = info.karaoke_language();
+    song.info.song_type = SongType::Other(info.song_type);
+    song.info.subtitle_type = SubtitleType::Other(info.subtitle_type);
+    // the player numbers channels from 1, 0 means unset
+    song.info.vocal_channel = (1..=16)
+        .contains(&info.vocal_channel)
+        .then(|| info.vocal_channel - 1);
+
+    Ok(song)
+}
+
 #[derive(Debug, Clone, Copy, FromPrimitive)]
 enum Tag {
     Byte = 2,
@@ -108,204 +205,237 @@ impl TagOut {
             TagOut::Byte(b) => *b,
             TagOut::Short(s) => *s as u8,
             TagOut::Int(i) => *i as u8,
-            TagOut::String(s) => s.parse::<u8>().unwrap(),
-        }
-    }
-
-
-    pub fn to_u16(&self) -> u16 {
-        match self {
-            TagOut::Byte(b) => *b as u16,
-            TagOut::Short(s) => *s,
-            TagOut::Int(i) => *i as u16,
-            TagOut::String(s) => s.parse::<u16>().unwrap(),
+            TagOut::String(s) => s.trim().parse::<u8>().unwrap_or_default(),
         }
     }
 
-
     pub fn to_u32(&self) -> u32 {
         match self {
             TagOut::Byte(b) => *b as u32,
             TagOut::Short(s) => *s as u32,
             TagOut::Int(i) => *i,
-            TagOut::String(s) => s.parse::<u32>().unwrap(),
+            TagOut::String(s) => s.trim().parse::<u32>().unwrap_or_default(),
         }
     }
-
-
-
 }
 
-struct EmkReader {
-    data: Vec<u8>,
-    header: Vec<u8>,
+/// Reads tagged values, used by both the header list and `SONG_INFO`
+struct TagReader<'a> {
+    data: &'a [u8],
     pos: usize,
 }
 
-impl EmkReader {
-    fn new(data: Vec<u8>) -> Self {
-        let data = xor(data, EMK_MAGIC);
-
-        // header start and end is u64 little endian
-        let header_pos = u64::from_le_bytes(data[0x22..0x22 + 8].try_into().unwrap()) as usize;
-        println!("header start: {}", header_pos);
-        let header_end = u64::from_le_bytes(data[0x2a..0x2a + 8].try_into().unwrap()) as usize;
-        println!("header end: {}", header_end);
+impl<'a> TagReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
 
-        let header = data[header_pos..header_end].to_vec();
-        println!("header len: {}", header.len());
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
 
-        println!("header: {:?}", header);
-        Self {
-            data,
-            header,
-            pos: 0,
-        }
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let res = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or("unexpected end of EMK data")?;
+        self.pos += n;
+        Ok(res)
     }
 
-    fn check_magic(&mut self, magic: &[u8]) {
-        let data = self.header[self.pos..self.pos + magic.len()].to_vec();
-        if data != magic {
-            panic!("Invalid magic");
-        }
+    fn check_magic(&mut self, magic: &[u8]) -> Result<(), Box<dyn Error>> {
         // Oh yeah, we need to skip magic bytes
-        self.pos += magic.len();
+        if self.take(magic.len())? != magic {
+            return Err("invalid EMK header magic".into());
+        }
+        Ok(())
     }
 
-    fn skip(&mut self, n: usize) {
-        self.pos += n;
+    fn read_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
     }
 
-    fn read_byte(&mut self) -> u8 {
-        let byte = self.header[self.pos];
-        self.pos += 1;
-
-        byte
+    fn read_u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
     }
 
-    fn read_u16(&mut self) -> u16 {
-        let res = u16::from_le_bytes(self.header[self.pos..self.pos + 2].try_into().unwrap());
-        self.pos += 2;
-        res
+    fn read_u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let res = u32::from_le_bytes(self.header[self.pos..self.pos + 4].try_into().unwrap());
-        self.pos += 4;
-        res
+    fn read_string(&mut self) -> Result<String, Box<dyn Error>> {
+        let len = self.read_byte()? as usize;
+        // song info is in Thai, the block names are plain ASCII either way
+        let str = WINDOWS_874
+            .decode(self.take(len)?, DecoderTrap::Replace)
+            .map_err(|e| e.into_owned())?;
+        Ok(str)
     }
-    fn read_string(&mut self) -> String {
-        let len = self.read_byte() as usize;
-        let str = String::from_utf8(self.header[self.pos..self.pos + len].to_vec()).unwrap();
-        self.pos += len;
-        str
+
+    fn read_tag(&mut self) -> Result<TagOut, Box<dyn Error>> {
+        let byte = self.read_byte()?;
+
+        let tag: Option<Tag> = FromPrimitive::from_u8(byte);
+        let res = match tag {
+            Some(Tag::Byte) => TagOut::Byte(self.read_byte()?),
+            Some(Tag::Short) => TagOut::Short(self.read_u16()?),
+            Some(Tag::Int) => TagOut::Int(self.read_u32()?),
+            Some(Tag::String) => TagOut::String(self.read_string()?),
+            None => return Err(format!("unknown EMK tag type {:#04x}", byte).into()),
+        };
+        Ok(res)
     }
+}
 
-    fn read_tag(&mut self) -> TagOut {
-        let byte = self.read_byte();
+struct EmkReader {
+    data: Vec<u8>,
+    header: (usize, usize),
+}
 
-        // println!("Reading tag: {}", byte);
+impl EmkReader {
+    fn new(data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        let data = xor(data, EMK_MAGIC);
 
-        let tag: Option<Tag> = FromPrimitive::from_u8(byte);
-        match tag {
-            Some(Tag::Byte) => {
-                let v = self.read_byte();
-                // println!("Byte: {}", v);
-                TagOut::Byte(v)
-            }
-            Some(Tag::Short) => {
-                let v = self.read_u16();
-                // println!("Short: {}", v);
-                TagOut::Short(v)
-            }
-            Some(Tag::Int) => {
-                let v = self.read_u32();
-                // println!("Int: {}", v);
-                TagOut::Int(v)
-            }
-            Some(Tag::String) => {
-                let v = self.read_string();
-                // println!("String: {}", v);
-                TagOut::String(v)
-            }
+        if !data.starts_with(&FILE_MAGIC) {
+            return Err("not an EMK file".into());
+        }
 
-            None => todo!(),
+        // header start and end is u64 little endian
+        let mut offsets = TagReader::new(data.get(0x22..0x32).ok_or("EMK file is too short")?);
+        let header_pos = offsets.read_u32()? as usize;
+        offsets.take(4)?;
+        let header_end = offsets.read_u32()? as usize;
+        debug!("header list: {:#x}..{:#x}", header_pos, header_end);
+
+        if header_pos > header_end || header_end > data.len() {
+            return Err("EMK header list is out of bounds".into());
         }
+
+        Ok(Self {
+            data,
+            header: (header_pos, header_end),
+        })
     }
 
-    fn read_header(&mut self) {
-        let magic = [0x53, 0x46, 0x44, 0x53];
-        // self.decrypt();
-        while self.pos < self.header.len() {
-            println!("=== Header ===");
-            self.check_magic(&magic);
-            let tag = self.read_tag();
-            println!("Tag: {:?}", tag);
-            let uncompressed_size = self.read_tag();
-            println!("Uncompressed size: {:?}", uncompressed_size);
-            let _unk2 = self.read_tag();
-            println!("Unk2: {:?}", _unk2);
-            let data_begin = self.read_tag().to_u16();
-            println!("Data begin: {:?}", data_begin);
-            let data_end = self.read_tag().to_u16();
-            println!("Data end: {:?}", data_end);
-            let _unk5 = self.read_tag();
-            println!("Unk5: {:?}", _unk5);
-            let _unk6 = self.read_tag();
-            println!("Unk6: {:?}", _unk6);
+    fn read_header(&self) -> Result<Vec<EmkBlock>, Box<dyn Error>> {
+        let mut reader = TagReader::new(&self.data[self.header.0..self.header.1]);
+        let mut blocks = Vec::new();
+
+        while !reader.is_empty() {
+            reader.check_magic(&HEADER_MAGIC)?;
+            let tag = reader.read_tag()?.to_string();
+            let uncompressed_size = reader.read_tag()?.to_u32();
+            let _unk2 = reader.read_tag()?;
+            let data_begin = reader.read_tag()?.to_u32() as usize;
+            let data_end = reader.read_tag()?.to_u32() as usize;
+            let _unk5 = reader.read_tag()?;
+            let _unk6 = reader.read_tag()?;
             // next 16 bytes are MD5 hash of the compressed data
-            let md5_hash = {
-                let res = self.data[self.pos..self.pos + 16].to_vec();
-                self.pos += 16;
-                res
-            };
-            // get md5 hash
-            // self.skip(0x10);
-            println!("MD5 hash: {:?}", md5_hash);
-            let _unk7 = self.read_tag();
-            println!("Unk7: {:?}", _unk7);
-            let _unk8 = self.read_tag();
-            println!("Unk8: {:?}", _unk8);
+            let md5_hash = reader.take(16)?;
+            let _unk7 = reader.read_tag()?;
+            let _unk8 = reader.read_tag()?;
 
-            // compressed data
-            let mut hasher = Md5::new();
-            let compressed_data = self.data[data_begin as usize..data_end as usize].to_vec();
+            debug!(
+                "block {}: {:#x}..{:#x}, {} bytes uncompressed",
+                tag, data_begin, data_end, uncompressed_size
+            );
 
-            // check md5 hash
+            // compressed data
+            let compressed_data = self
+                .data
+                .get(data_begin..data_end)
+                .ok_or_else(|| format!("EMK block {} is out of bounds", tag))?;
 
-            // ? md5 hash is wrong for some reason
             let raw_data = {
-                let mut buf = Vec::new();
-                let mut decoder = ZlibDecoder::new(compressed_data.as_slice());
-                decoder.read_to_end(&mut buf).unwrap();
+                let mut buf = Vec::with_capacity(uncompressed_size as usize);
+                let mut decoder = ZlibDecoder::new(compressed_data);
+                decoder.read_to_end(&mut buf)?;
                 buf
             };
-            hasher.update(&raw_data.as_slice());
-            let hash = hasher.finalize_reset();
-            println!("Hash: {:?}", hash);
-            println!("Embedded Hash: {:?}", md5_hash);
-
-            if let TagOut::String(s) = tag {
-                if let "HEADER" = s.as_str() {
-                    println!("--- HEADER ---");
-                    println!("{}", String::from_utf8(raw_data).unwrap());
-                    println!("--- END HEADER ---");
-                }
+
+            // ? md5 hash is wrong for some reason
+            let hash = Md5::digest(&raw_data);
+            if hash.as_slice() != md5_hash {
+                trace!("MD5 mismatch on {}: {:x?} != {:x?}", tag, hash, md5_hash);
+            }
+
+            if tag == "HEADER" {
+                debug!("HEADER: {}", String::from_utf8_lossy(&raw_data));
             }
+
+            blocks.push(EmkBlock {
+                tag,
+                data: raw_data,
+            });
         }
+
+        Ok(blocks)
     }
 }
-#[test]
-fn read_emk() {
-    let mut file = File::open("/home/cappy/000001.emk").unwrap();
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
 
-    // header_pos is 0x22
-    // let header: Vec<u8> = data[0x22..0x2a].to_vec();
+#[test]
+fn test_song_info() {
+    let mut data = vec![6, 6];
+    data.extend_from_slice(b"000001");
+    data.extend_from_slice(&[6, 4]);
+    data.extend_from_slice(b"MIDI");
+    data.extend_from_slice(&[6, 0, 6, 5]);
+    data.extend_from_slice(b"Title");
+    data.extend_from_slice(&[6, 1, b'C', 6, 6]);
+    data.extend_from_slice(b"Artist");
+    data.extend_from_slice(&[6, 4]);
+    data.extend_from_slice(b"THAI");
+    data.extend_from_slice(&[2, 4]);
+
+    let info = EmkInfo::parse(&data).unwrap();
+    assert_eq!(info.code, "000001");
+    assert_eq!(info.title, "Title");
+    assert_eq!(info.key, "C");
+    assert_eq!(info.artist, "Artist");
+    assert_eq!(info.vocal_channel, 4);
+    assert_eq!(info.tempo, 0);
+}
 
-    {
-        let mut reader = EmkReader::new(data);
-        reader.read_header();
+#[test]
+fn test_read_blocks() {
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    let midi = b"MThd fake midi".to_vec();
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&midi).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    // one block header right after the offsets, the block data after that
+    let mut header = HEADER_MAGIC.to_vec();
+    header.extend_from_slice(&[6, 9]);
+    header.extend_from_slice(b"MIDI_DATA");
+    header.push(4);
+    header.extend_from_slice(&(midi.len() as u32).to_le_bytes());
+    header.extend_from_slice(&[2, 0]);
+    let data_begin = 0x32 + header.len() + 5 * 2 + 2 * 2 + 16 + 2 * 2;
+    for pos in [data_begin, data_begin + compressed.len()] {
+        header.push(4);
+        header.extend_from_slice(&(pos as u32).to_le_bytes());
     }
+    header.extend_from_slice(&[2, 0, 2, 0]);
+    header.extend_from_slice(&Md5::digest(&midi));
+    header.extend_from_slice(&[2, 0, 2, 0]);
+
+    let mut data = FILE_MAGIC.to_vec();
+    data.resize(0x22, 0);
+    data.extend_from_slice(&0x32u32.to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&((0x32 + header.len()) as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&header);
+    assert_eq!(data.len(), data_begin);
+    data.extend_from_slice(&compressed);
+
+    let blocks = parse_blocks(xor(data, EMK_MAGIC)).unwrap();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].tag, "MIDI_DATA");
+    assert_eq!(blocks[0].data, midi);
+
+    assert!(parse_blocks(vec![0; 0x40]).is_err());
 }
@@ -0,0 +1,146 @@
+//! Playback notifications
+//!
+//! [EventBus] is a broadcast channel: every subscriber gets its own bounded
+//! queue, so a slow or stuck consumer never holds up the player. Once a queue
+//! is full that subscriber misses events until it catches up. Only the player
+//! and controller threads publish, the audio callback never touches it.
+
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackEvent {
+    /// Song read, `ticks` is its length in MIDI ticks
+    Loaded {
+        path: PathBuf,
+        total: Duration,
+        ticks: usize,
+    },
+    Started(PathBuf),
+    Paused(PathBuf),
+    Resumed(PathBuf),
+    /// Sent a few times a second while playing, and after a seek
+    Position {
+        tick: usize,
+        elapsed: Duration,
+    },
+    /// A lyric line started, counting from 0
+    LyricLine(usize),
+    /// Song played to the end
+    Ended(PathBuf),
+    Stopped,
+    Error(String),
+}
+
+/// Where the current song is at, kept up to date from the events
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    pub song: Option<PathBuf>,
+    pub total: Duration,
+    /// Length in MIDI ticks
+    pub ticks: usize,
+    pub tick: usize,
+    pub elapsed: Duration,
+    pub line: Option<usize>,
+}
+
+impl Progress {
+    pub fn update(&mut self, event: &PlaybackEvent) {
+        match event {
+            PlaybackEvent::Loaded { path, total, ticks } => {
+                *self = Self {
+                    song: Some(path.clone()),
+                    total: *total,
+                    ticks: *ticks,
+                    ..Default::default()
+                }
+            }
+            PlaybackEvent::Position { tick, elapsed } => {
+                self.tick = *tick;
+                self.elapsed = *elapsed;
+            }
+            PlaybackEvent::LyricLine(line) => self.line = Some(*line),
+            PlaybackEvent::Stopped => *self = Self::default(),
+            _ => {}
+        }
+    }
+}
+
+/// Events a subscriber can fall behind by, about a minute and a half of
+/// positions
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Sends every published value to every subscriber
+pub struct EventBus<T> {
+    subscribers: Arc<Mutex<Vec<Sender<T>>>>,
+}
+
+// a derive would want `T: Clone`
+impl<T> Clone for EventBus<T> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: Arc::clone(&self.subscribers),
+        }
+    }
+}
+
+impl<T> fmt::Debug for EventBus<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventBus")
+            .field("subscribers", &self.subscribers.lock().len())
+            .finish()
+    }
+}
+
+impl<T> Default for EventBus<T> {
+    fn default() -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<T: Clone> EventBus<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything published from now on, dropping the receiver unsubscribes
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (tx, rx) = crossbeam::channel::bounded(SUBSCRIBER_CAPACITY);
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Sends `event` to every subscriber, skipping the ones that are full
+    pub fn publish(&self, event: T) {
+        self.subscribers
+            .lock()
+            .retain(|tx| !matches!(tx.try_send(event.clone()), Err(TrySendError::Disconnected(_))));
+    }
+}
+
+#[test]
+fn test_event_bus() {
+    let bus = EventBus::new();
+    let a = bus.subscribe();
+    bus.publish(1);
+
+    let b = bus.subscribe();
+    bus.publish(2);
+    drop(a);
+    bus.publish(3);
+
+    assert_eq!(b.try_iter().collect::<Vec<_>>(), [2, 3]);
+    assert_eq!(bus.subscribers.lock().len(), 1);
+
+    // a full subscriber misses events but stays subscribed
+    for i in 0..SUBSCRIBER_CAPACITY + 1 {
+        bus.publish(i);
+    }
+    assert_eq!(b.try_iter().count(), SUBSCRIBER_CAPACITY);
+    bus.publish(4);
+    assert_eq!(b.try_recv(), Ok(4));
+}
@@ -0,0 +1,186 @@
+//! External MIDI output
+//!
+//! Sends the event stream to a hardware or virtual MIDI port through midir
+//! (the ALSA sequencer on Linux), for sound modules that replace the soft synth.
+
+use anyhow::{anyhow, Result};
+use log::{debug, error};
+use midir::{MidiOutput, MidiOutputConnection};
+use midly::live::{LiveEvent, SystemRealtime};
+use nodi::{Connection, MidiEvent};
+
+use crate::midi::SysExConnection;
+
+/// Client name other applications see us as
+pub const CLIENT_NAME: &str = "RustyKaraoke";
+
+/// Where MIDI events end up
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum OutputTarget {
+    /// The built-in oxisynth soft synth
+    #[default]
+    Synth,
+    /// An existing MIDI output port, by name
+    Port(String),
+    /// A virtual port other applications can connect to (not available on Windows)
+    Virtual(String),
+}
+
+/// Lists the names of the available MIDI output ports
+pub fn list_output_ports() -> Result<Vec<String>> {
+    let out = MidiOutput::new(CLIENT_NAME)?;
+    let ports = out
+        .ports()
+        .iter()
+        .filter_map(|port| out.port_name(port).ok())
+        .collect();
+
+    Ok(ports)
+}
+
+/// A connection to an external MIDI output port
+pub struct ExternalOutput {
+    pub name: String,
+    conn: MidiOutputConnection,
+    buf: Vec<u8>,
+}
+
+impl ExternalOutput {
+    /// Connects to an output port by name
+    pub fn connect(port_name: &str) -> Result<Self> {
+        let out = MidiOutput::new(CLIENT_NAME)?;
+        let port = out
+            .ports()
+            .into_iter()
+            .find(|port| out.port_name(port).is_ok_and(|n| n == port_name))
+            .ok_or_else(|| anyhow!("MIDI output port {} not found", port_name))?;
+
+        let conn = out
+            .connect(&port, CLIENT_NAME)
+            .map_err(|e| anyhow!("failed connecting to {}: {}", port_name, e))?;
+
+        debug!("connected to MIDI output {}", port_name);
+
+        Ok(Self::new(port_name.to_string(), conn))
+    }
+
+    /// Creates a virtual output port other applications can read from
+    #[cfg(unix)]
+    pub fn create_virtual(name: &str) -> Result<Self> {
+        use midir::os::unix::VirtualOutput;
+
+        let out = MidiOutput::new(CLIENT_NAME)?;
+        let conn = out
+            .create_virtual(name)
+            .map_err(|e| anyhow!("failed creating virtual port {}: {}", name, e))?;
+
+        debug!("created virtual MIDI output {}", name);
+
+        Ok(Self::new(name.to_string(), conn))
+    }
+
+    #[cfg(not(unix))]
+    pub fn create_virtual(_name: &str) -> Result<Self> {
+        Err(anyhow!(
+            "virtual MIDI ports are not supported on this platform"
+        ))
+    }
+
+    fn new(name: String, conn: MidiOutputConnection) -> Self {
+        Self {
+            name,
+            conn,
+            buf: Vec::with_capacity(3),
+        }
+    }
+
+    fn send_event(&mut self, event: LiveEvent) -> bool {
+        self.buf.clear();
+        if let Err(e) = event.write_std(&mut self.buf) {
+            error!("failed encoding MIDI event: {}", e);
+            return false;
+        }
+
+        self.send_raw_buf()
+    }
+
+    /// Sends raw bytes to the port, used for SysEx
+    pub fn send_raw(&mut self, data: &[u8]) -> bool {
+        self.buf.clear();
+        self.buf.extend_from_slice(data);
+        self.send_raw_buf()
+    }
+
+    fn send_raw_buf(&mut self) -> bool {
+        match self.conn.send(&self.buf) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("failed sending to MIDI output {}: {}", self.name, e);
+                false
+            }
+        }
+    }
+}
+
+impl Connection for ExternalOutput {
+    fn play(&mut self, msg: MidiEvent) -> bool {
+        self.send_event(LiveEvent::Midi {
+            channel: msg.channel,
+            message: msg.message,
+        })
+    }
+
+    fn send_sys_rt(&mut self, msg: SystemRealtime) {
+        self.send_event(LiveEvent::Realtime(msg));
+    }
+}
+
+impl SysExConnection for ExternalOutput {
+    fn send_sys_ex(&mut self, msg: &[u8]) -> bool {
+        self.send_raw(msg)
+    }
+}
+
+// Needs the ALSA sequencer (`snd-seq` module), run with `cargo test -- --ignored`
+#[cfg(target_os = "linux")]
+#[test]
+#[ignore]
+fn test_virtual_loopback() {
+    use crossbeam::channel::unbounded;
+    use midir::MidiInput;
+    use midly::{
+        num::{u4, u7},
+        MidiMessage as M,
+    };
+
+    let port_name = "RustyKaraoke loopback test";
+    let mut output = ExternalOutput::create_virtual(port_name).unwrap();
+
+    let input = MidiInput::new("RustyKaraoke test input").unwrap();
+    let port = input
+        .ports()
+        .into_iter()
+        .find(|p| input.port_name(p).unwrap().contains(port_name))
+        .expect("virtual port to be visible");
+
+    let (tx, rx) = unbounded();
+    let _conn = input
+        .connect(
+            &port,
+            "loopback",
+            move |_, data, _| tx.send(data.to_vec()).unwrap(),
+            (),
+        )
+        .unwrap();
+
+    assert!(output.play(MidiEvent {
+        channel: u4::from(2),
+        message: M::NoteOn {
+            key: u7::from(60),
+            vel: u7::from(100),
+        },
+    }));
+
+    let received = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+    assert_eq!(received, vec![0x92, 60, 100]);
+}
@@ -0,0 +1,192 @@
+//! Guide melody detection
+//!
+//! NCN songs don't say which MIDI channel carries the vocal melody, but the
+//! melody is the part that lines up with the lyrics. We compare the NoteOn
+//! onsets of every channel against the CUR ticks and pick the channel that
+//! matches best.
+
+use midly::{Format, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::mixer::CHANNELS;
+
+/// General MIDI drum channel, never the melody
+const DRUM_CHANNEL: usize = 9;
+
+/// Minimum score for a channel to be considered the melody
+const MIN_SCORE: f32 = 0.3;
+
+/// CUR timing runs at 24 steps per quarter note
+pub const CUR_TICKS_PER_BEAT: u32 = 24;
+
+/// Converts a CUR tick into a MIDI tick for a file with the given resolution
+pub fn cur_to_midi_tick(cur: u32, ticks_per_beat: u16) -> u32 {
+    cur * ticks_per_beat as u32 / CUR_TICKS_PER_BEAT
+}
+
+/// Absolute ticks of every NoteOn, per channel
+pub fn channel_onsets(smf: &Smf) -> Vec<Vec<u32>> {
+    let mut onsets = vec![Vec::new(); CHANNELS];
+    let mut offset = 0_u32;
+
+    for track in &smf.tracks {
+        let mut tick = if smf.header.format == Format::Sequential {
+            offset
+        } else {
+            0
+        };
+
+        for event in track {
+            tick += event.delta.as_int();
+            if let TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn { vel, .. },
+            } = event.kind
+            {
+                if vel.as_int() > 0 {
+                    onsets[channel.as_int() as usize].push(tick);
+                }
+            }
+        }
+
+        // the same as nodi's sequential sheet, the next track starts after the last moment
+        offset = tick + 1;
+    }
+
+    for ch in onsets.iter_mut() {
+        ch.sort_unstable();
+    }
+
+    onsets
+}
+
+/// Finds the channel carrying the vocal melody of a song.
+///
+/// `cursor` holds the raw CUR ticks of the song.
+pub fn detect_guide_channel(smf: &Smf, cursor: &[u32]) -> Option<u8> {
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(n) => n.as_int(),
+        Timing::Timecode(..) => return None,
+    };
+
+    let lyric_ticks = cursor
+        .iter()
+        .map(|t| cur_to_midi_tick(*t, ticks_per_beat))
+        .collect::<Vec<_>>();
+
+    // a 16th note either way
+    let tolerance = (ticks_per_beat / 4) as u32;
+
+    melody_channel(&channel_onsets(smf), &lyric_ticks, tolerance)
+}
+
+/// Scores every channel's onsets against the lyric ticks and returns the best match.
+///
+/// The score is the harmonic mean of how many lyric ticks have a note nearby,
+/// and how many of the channel's notes (inside the lyric span) have a lyric tick nearby.
+pub fn melody_channel(onsets: &[Vec<u32>], lyric_ticks: &[u32], tolerance: u32) -> Option<u8> {
+    let mut lyric_ticks = lyric_ticks.to_vec();
+    lyric_ticks.sort_unstable();
+    // combining Thai vowels and tone marks share the tick of their consonant
+    lyric_ticks.dedup();
+
+    let (first, last) = match (lyric_ticks.first(), lyric_ticks.last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return None,
+    };
+
+    let mut best: Option<(u8, f32)> = None;
+
+    for (ch, notes) in onsets.iter().enumerate() {
+        if ch == DRUM_CHANNEL || notes.is_empty() {
+            continue;
+        }
+
+        let hits = lyric_ticks
+            .iter()
+            .filter(|t| has_near(notes, **t, tolerance))
+            .count();
+
+        let in_span = notes
+            .iter()
+            .filter(|n| **n + tolerance >= first && **n <= last + tolerance)
+            .collect::<Vec<_>>();
+
+        if hits == 0 || in_span.is_empty() {
+            continue;
+        }
+
+        let matched = in_span
+            .iter()
+            .filter(|n| has_near(&lyric_ticks, ***n, tolerance))
+            .count();
+
+        let recall = hits as f32 / lyric_ticks.len() as f32;
+        let precision = matched as f32 / in_span.len() as f32;
+        let score = 2.0 * precision * recall / (precision + recall);
+
+        if score >= MIN_SCORE && best.is_none_or(|(_, s)| score > s) {
+            best = Some((ch as u8, score));
+        }
+    }
+
+    best.map(|(ch, _)| ch)
+}
+
+/// Checks if a sorted list has a value within `tolerance` of `tick`
+fn has_near(sorted: &[u32], tick: u32, tolerance: u32) -> bool {
+    let i = sorted.partition_point(|t| *t < tick.saturating_sub(tolerance));
+    sorted.get(i).is_some_and(|t| *t <= tick + tolerance)
+}
+
+#[test]
+fn test_melody_channel() {
+    let lyrics = vec![0, 96, 192, 288, 384, 480];
+
+    let mut onsets = vec![Vec::new(); CHANNELS];
+    // chords every beat, too many notes to be the melody
+    onsets[0] = (0..20).map(|i| i * 48).collect();
+    // the melody, a bit late on every note
+    onsets[3] = vec![4, 100, 190, 290, 386, 482];
+    // drums hit everything
+    onsets[DRUM_CHANNEL] = lyrics.clone();
+
+    assert_eq!(melody_channel(&onsets, &lyrics, 24), Some(3));
+}
+
+#[test]
+fn test_no_melody() {
+    let mut onsets = vec![Vec::new(); CHANNELS];
+    onsets[1] = vec![1000, 2000];
+
+    assert_eq!(melody_channel(&onsets, &[0, 96, 192], 24), None);
+    assert_eq!(melody_channel(&onsets, &[], 24), None);
+}
+
+#[test]
+fn test_cur_to_midi_tick() {
+    assert_eq!(cur_to_midi_tick(24, 96), 96);
+    assert_eq!(cur_to_midi_tick(36, 480), 720);
+}
+
+#[test]
+fn test_sequential_onsets() {
+    use midly::{Header, TrackEvent};
+
+    let note = |delta: u32| TrackEvent {
+        delta: delta.into(),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOn {
+                key: 60.into(),
+                vel: 100.into(),
+            },
+        },
+    };
+    let smf = Smf {
+        header: Header::new(Format::Sequential, Timing::Metrical(96.into())),
+        tracks: vec![vec![note(0), note(96)], vec![note(0)]],
+    };
+
+    // the second track starts a tick after the last moment of the first
+    assert_eq!(channel_onsets(&smf)[0], [0, 96, 97]);
+}
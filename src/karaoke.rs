@@ -1,3 +1,7 @@
+use std::{error::Error, path::Path};
+
+use midly::Smf;
+
 /// Central struct where every karaoke file converted to
 
 pub struct Karaoke {
@@ -8,23 +12,133 @@ pub struct Karaoke {
     pub midi: Vec<u8>,
 }
 
+/// Lyric characters scrolled per CUR step, see the NCN format docs
+pub const CHARS_PER_STEP: usize = 2;
+
+pub fn is_emk(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("emk"))
+}
+
+/// Reads an EMK song, or a MIDI file with its LYR and CUR files
+pub fn read_karaoke(path: &Path) -> Result<Karaoke, Box<dyn Error>> {
+    if is_emk(path) {
+        crate::emk::read_emk(path)
+    } else {
+        crate::ncn_reader::read_ncn(path)
+    }
+}
+
+impl Karaoke {
+    /// Lyric characters with the MIDI tick they're scrolled at
+    pub fn timed_chars(&self, ticks_per_beat: u16) -> Vec<(u32, char)> {
+        let chars = self.lyrics.chars().collect::<Vec<_>>();
+        let mut timed = Vec::with_capacity(chars.len());
+        for (tick, chunk) in self.cursor.data.iter().zip(chars.chunks(CHARS_PER_STEP)) {
+            let tick = crate::guide::cur_to_midi_tick(tick.tick, ticks_per_beat);
+            timed.extend(chunk.iter().map(|c| (tick, *c)));
+        }
+        timed
+    }
+
+    /// MIDI tick every lyric line starts at
+    pub fn line_ticks(&self, ticks_per_beat: u16) -> Vec<u32> {
+        let mut lines = Vec::new();
+        let mut new_line = true;
+        for (tick, c) in self.timed_chars(ticks_per_beat) {
+            if c == '\n' {
+                new_line = true;
+            } else if new_line {
+                lines.push(tick);
+                new_line = false;
+            }
+        }
+        lines
+    }
+
+    /// MIDI channel carrying the vocal melody.
+    ///
+    /// Uses the channel from the song metadata if there is one (EMK), otherwise
+    /// guesses it by matching note onsets against the CUR timing (NCN).
+    pub fn guide_channel(&self) -> Option<u8> {
+        if let Some(ch) = self.info.vocal_channel {
+            return Some(ch);
+        }
+
+        let smf = Smf::parse(&self.midi).ok()?;
+        let ticks = self
+            .cursor
+            .data
+            .iter()
+            .map(|t| t.tick)
+            .collect::<Vec<u32>>();
+
+        crate::guide::detect_guide_channel(&smf, &ticks)
+    }
+
+    /// CUR words the lyrics need, one for every character and line end as in
+    /// the NCN format docs
+    pub fn expected_cursor_len(&self) -> usize {
+        let chars = self.lyrics.chars().filter(|c| *c != '\n').count();
+        chars + self.lyrics.lines().count()
+    }
+
+    /// What's off with the CUR timing, empty if it fits the lyrics
+    pub fn cursor_problems(&self) -> Vec<CursorProblem> {
+        let mut problems = Vec::new();
+        let expected = self.expected_cursor_len();
+        let found = self.cursor.words.len();
+        if found != expected {
+            problems.push(CursorProblem::Length { expected, found });
+        }
+        let backwards = self.cursor.words.windows(2).position(|w| w[1] < w[0]);
+        if let Some(word) = backwards {
+            problems.push(CursorProblem::Backwards { word: word + 1 });
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorProblem {
+    /// Too many or too few words, the lyrics run ahead or stall
+    Length { expected: usize, found: usize },
+    /// The tick at this word is before the one before it
+    Backwards { word: usize },
+}
+
+impl std::fmt::Display for CursorProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Length { expected, found } => {
+                write!(f, "{} CUR words, the lyrics need {}", found, expected)
+            }
+            Self::Backwards { word } => write!(f, "CUR goes back in time at word {}", word),
+        }
+    }
+}
+
 pub struct KaraokeHeader {
     pub signature: String,
     pub version: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SongType {
     // I dont know how much song type there are
     Other(String),
     Undefined,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SubtitleType {
     // I dont know how much subtitle type there are
     Other(String),
     Undefined,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum KaraokeLanguage {
     Thai,
     English,
@@ -47,9 +161,10 @@ pub struct KaraokeInfo {
     pub author: String,
     /// Language
     pub language: KaraokeLanguage,
+    /// MIDI channel with the vocals, if the format stores it
+    pub vocal_channel: Option<u8>,
     // I'm not sure about any of this, may need to discussed first
 
-    // pub vocal_channel: u8,
     // /// Original file name
     // pub original_file: String,
     // /// Lyric title
@@ -65,6 +180,8 @@ pub struct KaraokeInfo {
 pub struct KaraokeCursor {
     pub data: Vec<KaraokeCursorTick>,
     pub pos: usize,
+    /// Every word of the CUR file, `data` only has every other one
+    pub words: Vec<u32>,
 }
 
 impl KaraokeCursor {
@@ -142,8 +259,9 @@ impl KaraokeCursor {
 impl From<Vec<u32>> for KaraokeCursor {
     fn from(data: Vec<u32>) -> Self {
         Self {
-            data: data.into_iter().map(KaraokeCursorTick::new).collect(),
+            data: data.iter().copied().map(KaraokeCursorTick::new).collect(),
             pos: 0,
+            words: data,
         }
     }
 }
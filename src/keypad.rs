@@ -0,0 +1,150 @@
+//! Song code entry from a numeric keypad
+//!
+//! Works like the remote of a karaoke box: type the code of a song, Enter
+//! queues it and `+` plays it right away. `-` takes back a digit and `*`
+//! starts over, so nothing needs a mouse or the rest of the keyboard.
+
+use std::time::{Duration, Instant};
+
+use crate::library::{search::SearchIndex, Song};
+
+/// Longest code that can be typed
+pub const MAX_DIGITS: usize = 8;
+
+/// How long the result of Enter or `+` stays up
+pub const MESSAGE_TIME: Duration = Duration::from_secs(4);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeypadKey {
+    Digit(u8),
+    /// Queues the song
+    Enter,
+    /// Plays the song now
+    PlayNow,
+    Delete,
+    Clear,
+}
+
+impl KeypadKey {
+    /// Key for typed text, the numpad digits and operators come in as text
+    pub fn from_text(text: &str) -> Option<Self> {
+        match text {
+            "+" => Some(Self::PlayNow),
+            "-" => Some(Self::Delete),
+            "*" => Some(Self::Clear),
+            _ => {
+                let mut chars = text.chars();
+                let digit = chars.next()?.to_digit(10)?;
+                chars.next().is_none().then_some(Self::Digit(digit as u8))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeypadAction {
+    Queue(Song),
+    Play(Song),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CodeEntry {
+    digits: String,
+    /// What the last Enter or `+` did, and when
+    message: Option<(String, Instant)>,
+}
+
+impl CodeEntry {
+    pub fn digits(&self) -> &str {
+        &self.digits
+    }
+
+    /// What the last Enter or `+` did, until it's been up long enough
+    pub fn message(&self) -> Option<&str> {
+        self.message
+            .as_ref()
+            .filter(|(_, at)| at.elapsed() < MESSAGE_TIME)
+            .map(|(message, _)| message.as_str())
+    }
+
+    /// Whether there's anything to show
+    pub fn is_active(&self) -> bool {
+        !self.digits.is_empty() || self.message().is_some()
+    }
+
+    pub fn press(&mut self, key: KeypadKey, songs: &SearchIndex) -> Option<KeypadAction> {
+        match key {
+            KeypadKey::Digit(d) => {
+                self.message = None;
+                if self.digits.len() < MAX_DIGITS {
+                    self.digits.push(char::from(b'0' + d));
+                }
+            }
+            KeypadKey::Delete => {
+                self.digits.pop();
+            }
+            KeypadKey::Clear => {
+                self.digits.clear();
+                self.message = None;
+            }
+            KeypadKey::Enter | KeypadKey::PlayNow if !self.digits.is_empty() => {
+                let code = std::mem::take(&mut self.digits);
+                let song = songs.by_code(&code).cloned();
+                let (message, action) = match (song, key) {
+                    (None, _) => (format!("No song {}", code), None),
+                    (Some(song), KeypadKey::Enter) => (
+                        format!("Queued {} {}", song.code, song.title),
+                        Some(KeypadAction::Queue(song)),
+                    ),
+                    (Some(song), _) => (
+                        format!("Playing {} {}", song.code, song.title),
+                        Some(KeypadAction::Play(song)),
+                    ),
+                };
+                self.message = Some((message, Instant::now()));
+                return action;
+            }
+            KeypadKey::Enter | KeypadKey::PlayNow => {}
+        }
+        None
+    }
+}
+
+#[test]
+fn test_code_entry() {
+    use std::collections::HashMap;
+
+    let song = Song {
+        id: 1,
+        path: "00123.mid".into(),
+        format: crate::library::SongFormat::Ncn,
+        code: "00123".to_string(),
+        title: "Title".to_string(),
+        artist: String::new(),
+        key: String::new(),
+        language: String::new(),
+        hash: String::new(),
+    };
+    let songs = SearchIndex::new(vec![song.clone()], &HashMap::new());
+    let mut entry = CodeEntry::default();
+    let mut type_in = |text: &str| {
+        text.chars()
+            .filter_map(|c| KeypadKey::from_text(&c.to_string()))
+            .filter_map(|key| entry.press(key, &songs))
+            .last()
+    };
+
+    assert_eq!(type_in("1239-"), None);
+    assert_eq!(type_in("+"), Some(KeypadAction::Play(song.clone())));
+    assert_eq!(type_in("9*123"), None);
+    assert_eq!(
+        entry.press(KeypadKey::Enter, &songs),
+        Some(KeypadAction::Queue(song))
+    );
+    assert!(entry.digits().is_empty());
+    assert_eq!(entry.message(), Some("Queued 00123 Title"));
+
+    entry.press(KeypadKey::Digit(5), &songs);
+    assert_eq!(entry.press(KeypadKey::Enter, &songs), None);
+    assert_eq!(entry.message(), Some("No song 5"));
+}
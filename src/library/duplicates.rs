@@ -0,0 +1,377 @@
+//! Finding the same song more than once
+//!
+//! Merged collections have copies of a song with small differences: a fixed
+//! typo in the title, an extra copyright text event, another CUR file. Songs
+//! are grouped when any of these match:
+//!
+//! - title and artist, folded like for searching
+//! - the MIDI events without the meta events (names, text, tempo)
+//! - the lyrics, most of their letter triples being the same
+//!
+//! Lyrics are only compared between songs whose first or last line is the
+//! same, comparing every pair of a big library would take too long. Lines that
+//! lots of songs start or end with ("instrumental", a shop's banner) aren't
+//! used for this.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use md5::{Digest, Md5};
+use midly::Smf;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::{search, Song};
+use crate::karaoke::Karaoke;
+
+/// Share of letter triples two lyrics need in common
+pub const LYRIC_SIMILARITY: f32 = 0.8;
+
+/// Lyrics shorter than this, in folded characters, aren't compared
+const MIN_LYRICS: usize = 20;
+
+/// Characters of a line used for picking which lyrics to compare
+const LINE_KEY: usize = 16;
+
+/// Line keys shared by more songs than this are too common to pick with
+const MAX_LINE_SHARE: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchReason {
+    Title,
+    Midi,
+    Lyrics,
+}
+
+impl MatchReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Title => "title",
+            Self::Midi => "midi",
+            Self::Lyrics => "lyrics",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SongCopy {
+    #[serde(flatten)]
+    pub song: Song,
+    pub cur_valid: bool,
+    /// What's wrong with the CUR timing, or why the song couldn't be read
+    pub cur_problem: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    /// Ways copies matched, not every pair has to match every way
+    pub reasons: Vec<MatchReason>,
+    /// Copies with valid CUR timing first, then by code
+    pub copies: Vec<SongCopy>,
+}
+
+/// Hash of the MIDI events without meta events, `None` if it doesn't parse
+pub fn midi_hash(data: &[u8]) -> Option<String> {
+    let smf = Smf::parse(data).ok()?;
+    let mut events = Vec::new();
+    for track in &smf.tracks {
+        let mut tick = 0u32;
+        for event in track {
+            tick += event.delta.as_int();
+            if let Some(live) = event.kind.as_live_event() {
+                let mut bytes = Vec::new();
+                live.write_std(&mut bytes).ok()?;
+                events.push((tick, bytes));
+            }
+        }
+    }
+    if events.is_empty() {
+        return None;
+    }
+    // tracks split another way still have the same events
+    events.sort();
+
+    let mut hasher = Md5::new();
+    hasher.update(format!("{:?}", smf.header.timing));
+    for (tick, bytes) in events {
+        hasher.update(tick.to_le_bytes());
+        hasher.update(bytes);
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+fn cur_problem(karaoke: &Karaoke) -> Option<String> {
+    let problems = karaoke
+        .cursor_problems()
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>();
+    (!problems.is_empty()).then(|| problems.join(", "))
+}
+
+/// What songs get compared by
+struct Fingerprint {
+    title: Option<String>,
+    midi: Option<String>,
+    trigrams: HashSet<[char; 3]>,
+    /// Start of the first and last lyric line
+    line_keys: Vec<String>,
+    cur_problem: Option<String>,
+}
+
+impl Fingerprint {
+    fn read(song: &Song) -> Self {
+        match song.load() {
+            Ok(karaoke) => Self::new(
+                song,
+                Some(&karaoke.midi),
+                &karaoke.lyrics,
+                cur_problem(&karaoke),
+            ),
+            Err(e) => Self::new(song, None, "", Some(e.to_string())),
+        }
+    }
+
+    fn new(song: &Song, midi: Option<&[u8]>, lyrics: &str, cur_problem: Option<String>) -> Self {
+        let title = search::fold(&song.title);
+        let title = (!title.is_empty()).then(|| title + "\0" + &search::fold(&song.artist));
+
+        let folded = search::loose(&search::fold(lyrics))
+            .chars()
+            .collect::<Vec<_>>();
+        let (trigrams, line_keys) = if folded.len() < MIN_LYRICS {
+            Default::default()
+        } else {
+            let lines = lyrics
+                .lines()
+                .map(|line| search::loose(&search::fold(line)))
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>();
+            let keys = [lines.first(), lines.last()]
+                .into_iter()
+                .flatten()
+                .map(|line| line.chars().take(LINE_KEY).collect())
+                .collect();
+            let trigrams = folded.windows(3).map(|w| [w[0], w[1], w[2]]).collect();
+            (trigrams, keys)
+        };
+
+        Self {
+            title,
+            midi: midi.and_then(midi_hash),
+            trigrams,
+            line_keys,
+            cur_problem,
+        }
+    }
+
+    fn lyrics_match(&self, other: &Self) -> bool {
+        let common = self.trigrams.intersection(&other.trigrams).count();
+        let all = self.trigrams.len() + other.trigrams.len() - common;
+        all > 0 && common as f32 / all as f32 >= LYRIC_SIMILARITY
+    }
+}
+
+/// Union-find over song indexes
+struct Groups {
+    parent: Vec<usize>,
+    links: Vec<(usize, MatchReason)>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+            links: Vec::new(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize, reason: MatchReason) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[b] = a;
+        }
+        self.links.push((a, reason));
+    }
+
+    /// Joins every song to the first one with the same key
+    fn join_equal<'a>(
+        &mut self,
+        keys: impl Iterator<Item = Option<&'a String>>,
+        reason: MatchReason,
+    ) {
+        let mut first = HashMap::new();
+        for (i, key) in keys.enumerate() {
+            if let Some(key) = key {
+                let first = *first.entry(key).or_insert(i);
+                if first != i {
+                    self.join(first, i, reason);
+                }
+            }
+        }
+    }
+}
+
+fn group(songs: Vec<Song>, prints: Vec<Fingerprint>) -> Vec<DuplicateGroup> {
+    let mut groups = Groups::new(songs.len());
+    groups.join_equal(prints.iter().map(|p| p.title.as_ref()), MatchReason::Title);
+    groups.join_equal(prints.iter().map(|p| p.midi.as_ref()), MatchReason::Midi);
+
+    let mut by_line = HashMap::<&str, Vec<usize>>::new();
+    for (i, print) in prints.iter().enumerate() {
+        for key in &print.line_keys {
+            by_line.entry(key).or_default().push(i);
+        }
+    }
+    let mut compared = HashSet::new();
+    for candidates in by_line.values().filter(|c| c.len() <= MAX_LINE_SHARE) {
+        for (n, &a) in candidates.iter().enumerate() {
+            for &b in &candidates[n + 1..] {
+                if a != b && compared.insert((a, b)) && prints[a].lyrics_match(&prints[b]) {
+                    groups.join(a, b, MatchReason::Lyrics);
+                }
+            }
+        }
+    }
+
+    let mut reasons = HashMap::<usize, BTreeSet<MatchReason>>::new();
+    for (i, reason) in std::mem::take(&mut groups.links) {
+        let root = groups.find(i);
+        reasons.entry(root).or_default().insert(reason);
+    }
+    let mut members = HashMap::<usize, Vec<SongCopy>>::new();
+    for (i, (song, print)) in songs.into_iter().zip(prints).enumerate() {
+        members.entry(groups.find(i)).or_default().push(SongCopy {
+            song,
+            cur_valid: print.cur_problem.is_none(),
+            cur_problem: print.cur_problem,
+        });
+    }
+
+    let mut duplicates = members
+        .into_iter()
+        .filter(|(_, copies)| copies.len() > 1)
+        .map(|(root, mut copies)| {
+            copies.sort_by(|a, b| {
+                (!a.cur_valid, &a.song.code, a.song.id).cmp(&(
+                    !b.cur_valid,
+                    &b.song.code,
+                    b.song.id,
+                ))
+            });
+            DuplicateGroup {
+                reasons: reasons
+                    .remove(&root)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+                copies,
+            }
+        })
+        .collect::<Vec<_>>();
+    duplicates.sort_by(|a, b| a.copies[0].song.code.cmp(&b.copies[0].song.code));
+    duplicates
+}
+
+/// Groups of songs that look like copies of each other. Every song gets read,
+/// which takes a while for a big library.
+pub fn find_duplicates(songs: Vec<Song>) -> Vec<DuplicateGroup> {
+    let prints = songs.par_iter().map(Fingerprint::read).collect();
+    group(songs, prints)
+}
+
+#[test]
+fn test_duplicates() {
+    use midly::{Format, Header, MetaMessage, MidiMessage, Timing, TrackEvent, TrackEventKind};
+
+    let midi = |key: u8, text: &'static [u8]| {
+        let event = |kind| TrackEvent {
+            delta: 0.into(),
+            kind,
+        };
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(480.into()),
+        ));
+        smf.tracks.push(vec![
+            event(TrackEventKind::Meta(MetaMessage::Text(text))),
+            event(TrackEventKind::Midi {
+                channel: 0.into(),
+                message: MidiMessage::NoteOn {
+                    key: key.into(),
+                    vel: 100.into(),
+                },
+            }),
+            event(TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+        data
+    };
+    let song = |id: i64, title: &str, artist: &str| Song {
+        id,
+        path: format!("{}.mid", id).into(),
+        format: super::SongFormat::Ncn,
+        code: id.to_string(),
+        title: title.to_string(),
+        artist: artist.to_string(),
+        key: String::new(),
+        language: String::new(),
+        hash: String::new(),
+    };
+    let lyrics = "ฉันยังรักเธอไม่เปลี่ยนแปลง\nไม่ว่าวันไหนก็ยังรอ\nรอเธอกลับมา";
+    let songs = vec![
+        (
+            song(1, "Song", "Singer"),
+            midi(60, b"a"),
+            lyrics,
+            Some("bad"),
+        ),
+        // same title, typed a little differently
+        (song(2, "song ", "Singer"), midi(61, b""), "", Some("bad")),
+        // only the copyright text is different
+        (song(3, "Other", ""), midi(60, b"b"), "", None),
+        // a typo in the lyrics
+        (
+            song(4, "Another", ""),
+            midi(62, b""),
+            "ฉันยังรักเธอไม่เปลี่ยนแปลง\nไม่ว่าวันไหนก็ยังรอ\nรอเธอกลับมาา",
+            Some("bad"),
+        ),
+        (
+            song(5, "Song", "Someone else"),
+            midi(63, b""),
+            lyrics.split('\n').next().unwrap(),
+            None,
+        ),
+    ];
+    let prints = songs
+        .iter()
+        .map(|(song, midi, lyrics, cur)| {
+            Fingerprint::new(song, Some(midi), lyrics, cur.map(str::to_string))
+        })
+        .collect();
+    let songs = songs.into_iter().map(|(song, ..)| song).collect();
+
+    let groups = group(songs, prints);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(
+        groups[0].reasons,
+        [MatchReason::Title, MatchReason::Midi, MatchReason::Lyrics]
+    );
+    let ids = groups[0]
+        .copies
+        .iter()
+        .map(|c| c.song.id)
+        .collect::<Vec<_>>();
+    // the one with working timing first
+    assert_eq!(ids, [3, 1, 2, 4]);
+}
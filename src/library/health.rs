@@ -0,0 +1,203 @@
+//! Checking songs before a show
+//!
+//! Broken timing only shows when the lyrics stall halfway through a song on
+//! stage. Every song is read and checked for:
+//!
+//! - a CUR word for every lyric character and line end, see the NCN format docs
+//! - CUR ticks that never go back
+//! - CUR ticks within the MIDI
+//! - a MIDI file that parses
+
+use std::fmt;
+
+use midly::{Format, Smf, Timing};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::Song;
+use crate::{
+    guide,
+    karaoke::{CursorProblem, Karaoke},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Problem {
+    /// The song or its LYR or CUR file couldn't be read
+    Unreadable {
+        error: String,
+    },
+    Midi {
+        error: String,
+    },
+    CursorLength {
+        expected: usize,
+        found: usize,
+    },
+    Backwards {
+        word: usize,
+    },
+    /// The first CUR word after the last MIDI event, in MIDI ticks
+    PastEnd {
+        word: usize,
+        tick: u32,
+        end: u32,
+    },
+}
+
+impl From<CursorProblem> for Problem {
+    fn from(problem: CursorProblem) -> Self {
+        match problem {
+            CursorProblem::Length { expected, found } => Self::CursorLength { expected, found },
+            CursorProblem::Backwards { word } => Self::Backwards { word },
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unreadable { error } => write!(f, "couldn't read the song: {}", error),
+            Self::Midi { error } => write!(f, "bad MIDI: {}", error),
+            &Self::CursorLength { expected, found } => {
+                write!(f, "{}", CursorProblem::Length { expected, found })
+            }
+            &Self::Backwards { word } => write!(f, "{}", CursorProblem::Backwards { word }),
+            Self::PastEnd { word, tick, end } => write!(
+                f,
+                "CUR word {} is at tick {}, after the MIDI ends at {}",
+                word, tick, end
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SongHealth {
+    #[serde(flatten)]
+    pub song: Song,
+    pub problems: Vec<Problem>,
+}
+
+impl SongHealth {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Tick of the last MIDI event
+fn midi_end(smf: &Smf) -> u32 {
+    let lengths = smf
+        .tracks
+        .iter()
+        .map(|track| track.iter().map(|e| e.delta.as_int()).sum::<u32>());
+    if smf.header.format == Format::Sequential {
+        lengths.sum()
+    } else {
+        lengths.max().unwrap_or_default()
+    }
+}
+
+pub fn check_karaoke(karaoke: &Karaoke) -> Vec<Problem> {
+    let mut problems = karaoke
+        .cursor_problems()
+        .into_iter()
+        .map(Problem::from)
+        .collect::<Vec<_>>();
+
+    let smf = match Smf::parse(&karaoke.midi) {
+        Ok(smf) => smf,
+        Err(e) => {
+            problems.push(Problem::Midi {
+                error: e.to_string(),
+            });
+            return problems;
+        }
+    };
+    let ppq = match smf.header.timing {
+        Timing::Metrical(ppq) => ppq.as_int(),
+        Timing::Timecode(..) => {
+            problems.push(Problem::Midi {
+                error: "SMPTE timecode MIDI files aren't supported".to_string(),
+            });
+            return problems;
+        }
+    };
+
+    let end = midi_end(&smf);
+    let past_end = karaoke
+        .cursor
+        .words
+        .iter()
+        .map(|t| guide::cur_to_midi_tick(*t, ppq))
+        .enumerate()
+        .find(|(_, tick)| *tick > end);
+    if let Some((word, tick)) = past_end {
+        problems.push(Problem::PastEnd { word, tick, end });
+    }
+    problems
+}
+
+pub fn check_song(song: Song) -> SongHealth {
+    let problems = match song.load() {
+        Ok(karaoke) => check_karaoke(&karaoke),
+        Err(e) => vec![Problem::Unreadable {
+            error: e.to_string(),
+        }],
+    };
+    SongHealth { song, problems }
+}
+
+/// Reads and checks every song, in parallel
+pub fn check_songs(songs: Vec<Song>) -> Vec<SongHealth> {
+    songs.into_par_iter().map(check_song).collect()
+}
+
+#[test]
+fn test_check_karaoke() {
+    use midly::{Header, MetaMessage, TrackEvent, TrackEventKind};
+
+    let mut smf = Smf::new(Header::new(
+        Format::SingleTrack,
+        Timing::Metrical(96.into()),
+    ));
+    smf.tracks.push(vec![TrackEvent {
+        delta: 960.into(),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    }]);
+    let mut midi = Vec::new();
+    smf.write_std(&mut midi).unwrap();
+
+    let check = |lyrics: &str, cursor: &[u16]| {
+        let cursor = cursor
+            .iter()
+            .flat_map(|tick| tick.to_le_bytes())
+            .collect::<Vec<_>>();
+        let lyrics = format!("Title\r\nArtist\r\nC\r\n\r\n{}", lyrics);
+        let karaoke =
+            crate::ncn_reader::read_ncn_data(midi.clone(), lyrics.as_bytes(), &cursor).unwrap();
+        check_karaoke(&karaoke)
+    };
+
+    // 5 characters and 2 line ends take 7 words
+    assert_eq!(check("abc\nde", &[0, 10, 20, 30, 40, 50, 60]), []);
+    assert_eq!(
+        check("abc\nde", &[0, 10, 20, 30, 40, 50, 60, 70]),
+        [Problem::CursorLength {
+            expected: 7,
+            found: 8
+        }]
+    );
+    // 960 MIDI ticks at 96 per beat is 240 CUR ticks
+    assert_eq!(
+        check("abc\nde", &[0, 10, 20, 15, 40, 50, 250]),
+        [
+            Problem::Backwards { word: 3 },
+            Problem::PastEnd {
+                word: 6,
+                tick: 1000,
+                end: 960
+            }
+        ]
+    );
+}
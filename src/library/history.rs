@@ -0,0 +1,353 @@
+//! Play history and statistics
+//!
+//! Every play is a row in `plays`: who sang, when, how long the song ran, the
+//! score if it was scored and whether it was stopped before the end. Skipped
+//! plays are kept for the reports but don't count as sung. Plays keep the code,
+//! hash, title and artist of the song, so the history outlives its file.
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::{Local, Months, NaiveDate, TimeZone};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::Serialize;
+
+use super::{csv_line, Library, Song, SONG_COLUMNS};
+
+/// Columns [Play::from_row] reads
+const PLAY_COLUMNS: &str = "id, song_id, code, hash, title, artist, singer, played_at, duration,
+    score, skipped";
+
+/// A play that was just recorded, closed with [Library::finish_play]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayId {
+    pub play: i64,
+    pub song: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Play {
+    pub id: i64,
+    /// `None` once the song is gone from the library
+    pub song_id: Option<i64>,
+    pub code: String,
+    pub hash: String,
+    pub title: String,
+    pub artist: String,
+    pub singer: String,
+    /// Unix time in seconds
+    pub played_at: i64,
+    /// Seconds played, `None` if the play was never closed
+    pub duration: Option<f64>,
+    pub score: Option<u32>,
+    pub skipped: bool,
+}
+
+impl Play {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            song_id: row.get(1)?,
+            code: row.get(2)?,
+            hash: row.get(3)?,
+            title: row.get(4)?,
+            artist: row.get(5)?,
+            singer: row.get(6)?,
+            played_at: row.get(7)?,
+            duration: row.get(8)?,
+            score: row.get(9)?,
+            skipped: row.get(10)?,
+        })
+    }
+
+    /// Local date and time of the play
+    pub fn date(&self) -> String {
+        Local
+            .timestamp_opt(self.played_at, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Unix times the month starts and ends at, local time. `month` is like `2022-11`.
+pub fn month_range(month: &str) -> Result<(i64, i64)> {
+    let start = NaiveDate::parse_from_str(&format!("{}-01", month.trim()), "%Y-%m-%d")
+        .map_err(|_| anyhow!("{} isn't a month, write it like 2022-11", month))?;
+    let end = start
+        .checked_add_months(Months::new(1))
+        .ok_or_else(|| anyhow!("{} is out of range", month))?;
+    let time = |date: NaiveDate| {
+        Local
+            .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+            .earliest()
+            .map(|t| t.timestamp())
+    };
+    match (time(start), time(end)) {
+        (Some(start), Some(end)) => Ok((start, end)),
+        _ => Err(anyhow!("{} is out of range", month)),
+    }
+}
+
+impl Library {
+    /// Records the start of a play of the song at `path`, `None` if it isn't in
+    /// the library
+    pub fn record_play(&self, path: &Path, singer: &str) -> Result<Option<PlayId>> {
+        let song = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM songs WHERE path = ? AND error IS NULL",
+                    SONG_COLUMNS
+                ),
+                [path.to_string_lossy()],
+                Self::song_from_row,
+            )
+            .optional()?;
+        let song = match song {
+            Some(song) => song,
+            None => return Ok(None),
+        };
+        self.conn.execute(
+            "INSERT INTO plays (song_id, code, hash, title, artist, singer, played_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                song.id,
+                song.code,
+                song.hash,
+                song.title,
+                song.artist,
+                singer,
+                chrono::Utc::now().timestamp()
+            ],
+        )?;
+        Ok(Some(PlayId {
+            play: self.conn.last_insert_rowid(),
+            song: song.id,
+        }))
+    }
+
+    /// Closes a play with how long the song ran
+    pub fn finish_play(
+        &self,
+        play: i64,
+        duration: Duration,
+        score: Option<u32>,
+        skipped: bool,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE plays SET duration = ?, score = ?, skipped = ? WHERE id = ?",
+            params![duration.as_secs_f64(), score, skipped, play],
+        )?;
+        Ok(())
+    }
+
+    /// How many times each song was sung, songs never sung aren't in it
+    pub fn play_counts(&self) -> Result<HashMap<i64, usize>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT song_id, COUNT(*) FROM plays
+                WHERE NOT skipped AND song_id IS NOT NULL
+                GROUP BY song_id",
+            )?;
+        let counts = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(counts)
+    }
+
+    /// Songs sung the most between the unix times `from` and `to`, with how many times
+    pub fn most_sung(&self, from: i64, to: i64, limit: usize) -> Result<Vec<(Song, usize)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}, count FROM songs JOIN (
+                SELECT song_id, COUNT(*) AS count FROM plays
+                WHERE NOT skipped AND played_at >= ? AND played_at < ?
+                GROUP BY song_id
+            ) ON song_id = songs.id
+            WHERE error IS NULL
+            ORDER BY count DESC, code
+            LIMIT ?",
+            SONG_COLUMNS
+        ))?;
+        let songs = stmt
+            .query_map(params![from, to, limit as i64], |row| {
+                Ok((Self::song_from_row(row)?, row.get(9)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+
+    /// Plays between the unix times `from` and `to`, latest first, only the
+    /// ones of `singer` if it's given
+    pub fn plays(
+        &self,
+        singer: Option<&str>,
+        from: i64,
+        to: i64,
+        limit: usize,
+    ) -> Result<Vec<Play>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM plays
+            WHERE (?1 IS NULL OR singer = ?1) AND played_at >= ?2 AND played_at < ?3
+            ORDER BY played_at DESC, id DESC
+            LIMIT ?4",
+            PLAY_COLUMNS
+        ))?;
+        // -1 is no limit
+        let plays = stmt
+            .query_map(
+                params![singer, from, to, i64::try_from(limit).unwrap_or(-1)],
+                Play::from_row,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(plays)
+    }
+
+    /// Latest plays of a singer
+    pub fn singer_history(&self, singer: &str, limit: usize) -> Result<Vec<Play>> {
+        self.plays(Some(singer), 0, i64::MAX, limit)
+    }
+
+    /// Songs nobody has played yet, by code
+    pub fn never_played(&self) -> Result<Vec<Song>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM songs
+            WHERE error IS NULL
+                AND id NOT IN (SELECT song_id FROM plays WHERE song_id IS NOT NULL)
+            ORDER BY code, id",
+            SONG_COLUMNS
+        ))?;
+        let songs = stmt
+            .query_map([], Self::song_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+
+    /// Every play of a month as CSV, `month` is like `2022-11`
+    pub fn month_plays_csv(&self, month: &str) -> Result<String> {
+        let (from, to) = month_range(month)?;
+        let mut plays = self.plays(None, from, to, usize::MAX)?;
+        plays.reverse();
+
+        let mut csv = csv_line(&[
+            "date", "code", "title", "artist", "singer", "seconds", "score", "skipped",
+        ]);
+        for play in plays {
+            csv += &csv_line(&[
+                play.date(),
+                play.code,
+                play.title,
+                play.artist,
+                play.singer,
+                play.duration
+                    .map(|d| format!("{:.0}", d))
+                    .unwrap_or_default(),
+                play.score.map(|s| s.to_string()).unwrap_or_default(),
+                play.skipped.to_string(),
+            ]);
+        }
+        Ok(csv)
+    }
+
+    /// Plays of a month per song as CSV, most sung first
+    pub fn month_songs_csv(&self, month: &str) -> Result<String> {
+        let (from, to) = month_range(month)?;
+        let mut stmt = self.conn.prepare(
+            "SELECT code, title, artist,
+                SUM(NOT skipped), SUM(skipped), COUNT(DISTINCT singer), AVG(score)
+            FROM plays
+            WHERE played_at >= ? AND played_at < ?
+            GROUP BY code, hash
+            ORDER BY SUM(NOT skipped) DESC, code",
+        )?;
+        let mut csv = csv_line(&[
+            "code",
+            "title",
+            "artist",
+            "sung",
+            "skipped",
+            "singers",
+            "average score",
+        ]);
+        let mut rows = stmt.query([from, to])?;
+        while let Some(row) = rows.next()? {
+            let score: Option<f64> = row.get(6)?;
+            csv += &csv_line(&[
+                row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, i64>(3)?.to_string(),
+                row.get::<_, i64>(4)?.to_string(),
+                row.get::<_, i64>(5)?.to_string(),
+                score.map(|s| format!("{:.0}", s)).unwrap_or_default(),
+            ]);
+        }
+        Ok(csv)
+    }
+}
+
+#[test]
+fn test_history() {
+    let library = Library::open_in_memory().unwrap();
+    for code in ["001", "002", "003"] {
+        library
+            .conn
+            .execute(
+                "INSERT INTO songs (path, format, code, title, modified, size, hash)
+                VALUES (?1, 'ncn', ?1, ?1, 0, 0, '')",
+                [code],
+            )
+            .unwrap();
+    }
+
+    let play = |code: &str, singer: &str, skipped: bool| {
+        let play = library
+            .record_play(Path::new(code), singer)
+            .unwrap()
+            .unwrap();
+        library
+            .finish_play(play.play, Duration::from_secs(200), Some(80), skipped)
+            .unwrap();
+    };
+    play("001", "Ann", false);
+    play("002", "Bob", false);
+    play("002", "Ann", false);
+    play("001", "Bob", true);
+    assert_eq!(
+        library.record_play(Path::new("missing"), "Ann").unwrap(),
+        None
+    );
+
+    let top = library.most_sung(0, i64::MAX, 10).unwrap();
+    let top = top
+        .iter()
+        .map(|(song, count)| (song.code.as_str(), *count))
+        .collect::<Vec<_>>();
+    assert_eq!(top, [("002", 2), ("001", 1)]);
+
+    let history = library.singer_history("Ann", 10).unwrap();
+    let codes = history
+        .iter()
+        .map(|p| p.code.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(codes, ["002", "001"]);
+    assert_eq!(history[0].score, Some(80));
+
+    let never = library.never_played().unwrap();
+    assert_eq!(never.len(), 1);
+    assert_eq!(never[0].code, "003");
+
+    let month = Local::now().format("%Y-%m").to_string();
+    let csv = library.month_songs_csv(&month).unwrap();
+    assert_eq!(csv.lines().nth(1), Some("002,002,,2,0,2,80"));
+    assert_eq!(library.month_plays_csv(&month).unwrap().lines().count(), 5);
+
+    // the history outlives the song
+    library
+        .conn
+        .execute("DELETE FROM songs WHERE code = '002'", [])
+        .unwrap();
+    let history = library.singer_history("Ann", 10).unwrap();
+    assert_eq!((history[0].code.as_str(), history[0].song_id), ("002", None));
+}
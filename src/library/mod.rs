@@ -0,0 +1,312 @@
+//! Song library
+//!
+//! Every song under the configured folders, catalogued in SQLite in the data
+//! folder so a library of tens of thousands of songs doesn't have to be read
+//! on startup. [Library::scan] brings the catalogue up to date, only reading
+//! files that changed since the last scan.
+
+pub mod duplicates;
+pub mod health;
+pub mod history;
+pub mod playlists;
+pub mod scan;
+pub mod search;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config, emk,
+    karaoke::{Karaoke, KaraokeLanguage},
+    ncn_reader,
+};
+use search::SearchIndex;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibrarySettings {
+    /// Folders searched for songs
+    pub roots: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SongFormat {
+    /// MIDI file with LYR and CUR files next to it
+    Ncn,
+    Emk,
+}
+
+impl SongFormat {
+    /// Format of a song file by its extension, `None` for anything else
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "mid" | "midi" => Some(Self::Ncn),
+            "emk" => Some(Self::Emk),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ncn => "ncn",
+            Self::Emk => "emk",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ncn" => Some(Self::Ncn),
+            "emk" => Some(Self::Emk),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Song {
+    pub id: i64,
+    pub path: PathBuf,
+    pub format: SongFormat,
+    /// What people type in to pick the song: the NCN file name or the EMK code
+    pub code: String,
+    pub title: String,
+    pub artist: String,
+    pub key: String,
+    /// Empty when the song doesn't say and it can't be guessed
+    pub language: String,
+    /// MD5 of the song file in hex
+    pub hash: String,
+}
+
+impl Song {
+    /// Reads the song file, with the LYR and CUR files for NCN songs
+    pub fn load(&self) -> Result<Karaoke> {
+        match self.format {
+            SongFormat::Ncn => ncn_reader::read_ncn(&self.path),
+            SongFormat::Emk => emk::read_emk(&self.path),
+        }
+        .map_err(|e| anyhow!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// Name a language is stored under
+pub fn language_name(language: &KaraokeLanguage) -> &str {
+    match language {
+        KaraokeLanguage::Thai => "Thai",
+        KaraokeLanguage::English => "English",
+        KaraokeLanguage::Other(name) => name,
+        KaraokeLanguage::Undefined => "",
+    }
+}
+
+/// Line of a CSV file, quoting the fields that need it
+pub fn csv_line<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push('\n');
+    line
+}
+
+/// Schema changes in order, `user_version` holds how many were applied
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE songs (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        format TEXT NOT NULL,
+        code TEXT NOT NULL DEFAULT '',
+        title TEXT NOT NULL DEFAULT '',
+        artist TEXT NOT NULL DEFAULT '',
+        key TEXT NOT NULL DEFAULT '',
+        language TEXT NOT NULL DEFAULT '',
+        modified INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        -- set when the file couldn't be read, so it isn't tried on every scan
+        error TEXT
+    );
+    CREATE INDEX songs_code ON songs (code);",
+    "CREATE TABLE plays (
+        id INTEGER PRIMARY KEY,
+        song_id INTEGER REFERENCES songs (id) ON DELETE SET NULL,
+        -- unix time in seconds
+        played_at INTEGER NOT NULL
+    );
+    CREATE INDEX plays_song ON plays (song_id);",
+    "CREATE TABLE playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    -- songs are kept by code and hash, paths change when the library moves
+    CREATE TABLE playlist_songs (
+        playlist_id INTEGER NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        code TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        title TEXT NOT NULL DEFAULT '',
+        artist TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (playlist_id, position)
+    );
+    CREATE TABLE favourites (
+        singer TEXT NOT NULL,
+        code TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        title TEXT NOT NULL DEFAULT '',
+        artist TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (singer, code, hash)
+    );
+    CREATE INDEX songs_hash ON songs (hash);",
+    // plays keep what was sung, the song row goes when its file is gone
+    "ALTER TABLE plays ADD COLUMN code TEXT NOT NULL DEFAULT '';
+    ALTER TABLE plays ADD COLUMN hash TEXT NOT NULL DEFAULT '';
+    ALTER TABLE plays ADD COLUMN title TEXT NOT NULL DEFAULT '';
+    ALTER TABLE plays ADD COLUMN artist TEXT NOT NULL DEFAULT '';
+    ALTER TABLE plays ADD COLUMN singer TEXT NOT NULL DEFAULT '';
+    -- seconds played, NULL until the song ends or is stopped
+    ALTER TABLE plays ADD COLUMN duration REAL;
+    ALTER TABLE plays ADD COLUMN score INTEGER;
+    ALTER TABLE plays ADD COLUMN skipped INTEGER NOT NULL DEFAULT 0;
+    UPDATE plays SET (code, hash, title, artist) = (
+        SELECT code, hash, title, artist FROM songs WHERE songs.id = plays.song_id
+    ) WHERE song_id IS NOT NULL;
+    CREATE INDEX plays_played_at ON plays (played_at);",
+];
+
+const SONG_COLUMNS: &str = "id, path, format, code, title, artist, key, language, hash";
+
+pub struct Library {
+    conn: Connection,
+}
+
+impl Library {
+    pub fn path() -> Result<PathBuf> {
+        Ok(config::data_dir()?.join("library.db"))
+    }
+
+    /// Opens the catalogue in the data folder, creating it on the first run
+    pub fn open_default() -> Result<Self> {
+        Self::open(&Self::path()?)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        // lets the GUI read while a scan writes
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::init(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version < MIGRATIONS.len() {
+            let tx = conn.transaction()?;
+            for migration in &MIGRATIONS[version..] {
+                tx.execute_batch(migration)?;
+            }
+            tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+            tx.commit()?;
+        }
+        Ok(Self { conn })
+    }
+
+    fn song_from_row(row: &Row) -> rusqlite::Result<Song> {
+        let path: String = row.get(1)?;
+        let format: String = row.get(2)?;
+        Ok(Song {
+            id: row.get(0)?,
+            path: PathBuf::from(path),
+            format: SongFormat::parse(&format).unwrap_or(SongFormat::Ncn),
+            code: row.get(3)?,
+            title: row.get(4)?,
+            artist: row.get(5)?,
+            key: row.get(6)?,
+            language: row.get(7)?,
+            hash: row.get(8)?,
+        })
+    }
+
+    /// Number of songs that can be played
+    pub fn count(&self) -> Result<usize> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM songs WHERE error IS NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Every song that can be played, by code
+    pub fn songs(&self) -> Result<Vec<Song>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM songs WHERE error IS NULL ORDER BY code, id",
+            SONG_COLUMNS
+        ))?;
+        let songs = stmt
+            .query_map([], Self::song_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+
+    pub fn song(&self, id: i64) -> Result<Option<Song>> {
+        let song = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM songs WHERE id = ? AND error IS NULL",
+                    SONG_COLUMNS
+                ),
+                [id],
+                Self::song_from_row,
+            )
+            .optional()?;
+        Ok(song)
+    }
+
+    /// Songs with exactly this code, there can be more than one in merged collections
+    pub fn by_code(&self, code: &str) -> Result<Vec<Song>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM songs WHERE code = ? AND error IS NULL ORDER BY id",
+            SONG_COLUMNS
+        ))?;
+        let songs = stmt
+            .query_map([code], Self::song_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+
+    /// Files that couldn't be read on the last scan, with the reason
+    pub fn failed(&self) -> Result<Vec<(PathBuf, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, error FROM songs WHERE error IS NOT NULL ORDER BY path")?;
+        let failed = stmt
+            .query_map([], |row| {
+                Ok((PathBuf::from(row.get::<_, String>(0)?), row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(failed)
+    }
+
+    /// Everything in the library, ready for searching
+    pub fn search_index(&self) -> Result<SearchIndex> {
+        Ok(SearchIndex::new(self.songs()?, &self.play_counts()?))
+    }
+}
@@ -0,0 +1,403 @@
+//! Playlists and favourites
+//!
+//! Both are kept in the library database. Entries point at songs by code and
+//! file hash instead of path, so they still work after the songs move to
+//! another drive. The title and artist are kept too, for showing entries whose
+//! song isn't in the library.
+
+use std::{fs, path::Path};
+
+use anyhow::{bail, Result};
+use md5::{Digest, Md5};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use super::{Library, Song, SONG_COLUMNS};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    pub code: String,
+    /// MD5 of the song file, empty if it isn't known
+    #[serde(default)]
+    pub hash: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub artist: String,
+}
+
+impl From<&Song> for PlaylistEntry {
+    fn from(song: &Song) -> Self {
+        Self {
+            code: song.code.clone(),
+            hash: song.hash.clone(),
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+        }
+    }
+}
+
+impl PlaylistEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            code: row.get(0)?,
+            hash: row.get(1)?,
+            title: row.get(2)?,
+            artist: row.get(3)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Playlist {
+    /// Playlist name, or the singer for favourites
+    pub name: String,
+    pub songs: Vec<PlaylistEntry>,
+}
+
+/// Extended M3U line with the code and hash of the song after it
+const M3U_SONG: &str = "#EXTKARAOKE:";
+
+fn is_m3u(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| {
+            e.eq_ignore_ascii_case("m3u") || e.eq_ignore_ascii_case("m3u8")
+        })
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+impl Playlist {
+    /// Extended M3U for other players. Songs that aren't in the library get
+    /// their code in place of the path.
+    pub fn to_m3u(&self, library: &Library) -> Result<String> {
+        let mut m3u = format!("#EXTM3U\n#PLAYLIST:{}\n", self.name);
+        for entry in &self.songs {
+            let path = match library.find_entry(entry)? {
+                Some(song) => song.path.display().to_string(),
+                None => entry.code.clone(),
+            };
+            m3u += &format!("#EXTINF:-1,{} - {}\n", entry.artist, entry.title);
+            m3u += &format!("{}code={};hash={}\n", M3U_SONG, entry.code, entry.hash);
+            m3u += &path;
+            m3u.push('\n');
+        }
+        Ok(m3u)
+    }
+
+    /// Reads an M3U playlist, ours or from another player. Songs without an
+    /// `#EXTKARAOKE` line are looked up by path, relative to `dir`.
+    pub fn from_m3u(text: &str, dir: &Path, library: &Library) -> Result<Self> {
+        let mut playlist = Self::default();
+        let mut info = None;
+        let mut tagged = None;
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+                playlist.name = name.trim().to_string();
+            } else if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+                // duration, then "artist - title"
+                let text = extinf.split_once(',').map_or("", |(_, text)| text);
+                info = Some(match text.split_once(" - ") {
+                    Some((artist, title)) => (title.trim(), artist.trim()),
+                    None => (text.trim(), ""),
+                });
+            } else if let Some(tag) = line.strip_prefix(M3U_SONG) {
+                let field = |name: &str| {
+                    tag.split(';')
+                        .find_map(|f| f.strip_prefix(name)?.strip_prefix('='))
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                tagged = Some((field("code"), field("hash")));
+            } else if !line.starts_with('#') {
+                let mut entry = match tagged.take() {
+                    Some((code, hash)) => PlaylistEntry {
+                        code,
+                        hash,
+                        title: String::new(),
+                        artist: String::new(),
+                    },
+                    None => entry_for_path(&dir.join(line), library)?,
+                };
+                if let Some((title, artist)) = info.take() {
+                    if entry.title.is_empty() {
+                        entry.title = title.to_string();
+                        entry.artist = artist.to_string();
+                    }
+                }
+                playlist.songs.push(entry);
+            }
+        }
+        Ok(playlist)
+    }
+
+    /// Writes the playlist as M3U or JSON, by the extension of `path`
+    pub fn export(&self, path: &Path, library: &Library) -> Result<()> {
+        let data = if is_m3u(path) {
+            self.to_m3u(library)?
+        } else if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            bail!("unsupported playlist format, use a .m3u or .json file");
+        };
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Reads an M3U or JSON playlist, named after the file if it doesn't say
+    pub fn import(path: &Path, library: &Library) -> Result<Self> {
+        let data = fs::read(path)?;
+        let mut playlist = if is_m3u(path) {
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            Self::from_m3u(&String::from_utf8_lossy(&data), dir, library)?
+        } else if is_json(path) {
+            serde_json::from_slice(&data)?
+        } else {
+            bail!("unsupported playlist format, use a .m3u or .json file");
+        };
+        if playlist.name.is_empty() {
+            if let Some(stem) = path.file_stem() {
+                playlist.name = stem.to_string_lossy().into_owned();
+            }
+        }
+        Ok(playlist)
+    }
+}
+
+/// Entry for a path from another player's playlist
+fn entry_for_path(path: &Path, library: &Library) -> Result<PlaylistEntry> {
+    if let Some(song) = library.song_by_path(path)? {
+        return Ok(PlaylistEntry::from(&song));
+    }
+    let hash = fs::read(path)
+        .map(|data| format!("{:x}", Md5::digest(data)))
+        .unwrap_or_default();
+    Ok(PlaylistEntry {
+        code: path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        hash,
+        title: String::new(),
+        artist: String::new(),
+    })
+}
+
+impl Library {
+    /// Song for a playlist entry: the file with the same contents wherever it
+    /// is now, or if it was edited, a song with the same code
+    pub fn find_entry(&self, entry: &PlaylistEntry) -> Result<Option<Song>> {
+        if !entry.hash.is_empty() {
+            let song = self
+                .conn
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM songs WHERE hash = ? AND error IS NULL ORDER BY id",
+                        SONG_COLUMNS
+                    ),
+                    [&entry.hash],
+                    Self::song_from_row,
+                )
+                .optional()?;
+            if song.is_some() {
+                return Ok(song);
+            }
+        }
+        Ok(self.by_code(&entry.code)?.into_iter().next())
+    }
+
+    /// Names of the playlists, sorted
+    pub fn playlists(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name FROM playlists ORDER BY name COLLATE NOCASE")?;
+        let names = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    pub fn playlist(&self, name: &str) -> Result<Option<Playlist>> {
+        let id: Option<i64> = self
+            .conn
+            .query_row("SELECT id FROM playlists WHERE name = ?", [name], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let mut stmt = self.conn.prepare(
+            "SELECT code, hash, title, artist FROM playlist_songs
+            WHERE playlist_id = ? ORDER BY position",
+        )?;
+        let songs = stmt
+            .query_map([id], PlaylistEntry::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Some(Playlist {
+            name: name.to_string(),
+            songs,
+        }))
+    }
+
+    /// Creates the playlist, or replaces the songs of the one with its name
+    pub fn save_playlist(&mut self, playlist: &Playlist) -> Result<()> {
+        if playlist.name.trim().is_empty() {
+            bail!("playlists need a name");
+        }
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO playlists (name) VALUES (?) ON CONFLICT (name) DO NOTHING",
+            [&playlist.name],
+        )?;
+        let id: i64 = tx.query_row(
+            "SELECT id FROM playlists WHERE name = ?",
+            [&playlist.name],
+            |row| row.get(0),
+        )?;
+        tx.execute("DELETE FROM playlist_songs WHERE playlist_id = ?", [id])?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO playlist_songs (playlist_id, position, code, hash, title, artist)
+                VALUES (?, ?, ?, ?, ?, ?)",
+            )?;
+            for (i, entry) in playlist.songs.iter().enumerate() {
+                insert.execute(params![
+                    id,
+                    i as i64,
+                    entry.code,
+                    entry.hash,
+                    entry.title,
+                    entry.artist
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_playlist(&self, name: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM playlists WHERE name = ?", [name])?;
+        Ok(())
+    }
+
+    /// Singers with favourites, sorted
+    pub fn singers(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT singer FROM favourites ORDER BY singer COLLATE NOCASE")?;
+        let singers = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(singers)
+    }
+
+    /// Favourites of a singer in the order they were added, named after the singer
+    pub fn favourites(&self, singer: &str) -> Result<Playlist> {
+        let mut stmt = self.conn.prepare(
+            "SELECT code, hash, title, artist FROM favourites WHERE singer = ? ORDER BY rowid",
+        )?;
+        let songs = stmt
+            .query_map([singer], PlaylistEntry::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Playlist {
+            name: singer.to_string(),
+            songs,
+        })
+    }
+
+    /// Adds a favourite, nothing happens if the singer already has it
+    pub fn add_favourite(&self, singer: &str, entry: &PlaylistEntry) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO favourites (singer, code, hash, title, artist)
+            VALUES (?, ?, ?, ?, ?)",
+            params![singer, entry.code, entry.hash, entry.title, entry.artist],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_favourite(&self, singer: &str, entry: &PlaylistEntry) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM favourites WHERE singer = ? AND code = ? AND hash = ?",
+            params![singer, entry.code, entry.hash],
+        )?;
+        Ok(())
+    }
+
+    /// Song at `path` in the library, for songs picked from the file menu
+    pub fn song_by_path(&self, path: &Path) -> Result<Option<Song>> {
+        let song = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM songs WHERE path = ? AND error IS NULL",
+                    SONG_COLUMNS
+                ),
+                [path.to_string_lossy()],
+                Self::song_from_row,
+            )
+            .optional()?;
+        Ok(song)
+    }
+}
+
+#[test]
+fn test_playlists() {
+    let dir = std::env::temp_dir().join("rusty-karaoke-playlist-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("Song")).unwrap();
+    fs::create_dir_all(dir.join("Lyrics")).unwrap();
+    fs::write(dir.join("Song/00001.mid"), b"MThd").unwrap();
+    fs::write(
+        dir.join("Lyrics/00001.lyr"),
+        b"Title\r\nArtist\r\nC\r\n\r\n",
+    )
+    .unwrap();
+
+    let mut library = Library::open_in_memory().unwrap();
+    let roots = [dir.clone()];
+    library.scan(&roots, false).unwrap();
+    let song = library.by_code("00001").unwrap().remove(0);
+
+    let playlist = Playlist {
+        name: "Party".to_string(),
+        songs: vec![
+            PlaylistEntry::from(&song),
+            PlaylistEntry {
+                code: "99999".to_string(),
+                hash: String::new(),
+                title: "Missing".to_string(),
+                artist: String::new(),
+            },
+        ],
+    };
+    library.save_playlist(&playlist).unwrap();
+    assert_eq!(library.playlists().unwrap(), ["Party"]);
+    assert_eq!(library.playlist("Party").unwrap().as_ref(), Some(&playlist));
+
+    let m3u = playlist.to_m3u(&library).unwrap();
+    assert_eq!(Playlist::from_m3u(&m3u, &dir, &library).unwrap(), playlist);
+    // a plain M3U from another player, found by path
+    let plain = Playlist::from_m3u("Song/00001.mid\n", &dir, &library).unwrap();
+    assert_eq!(plain.songs, [PlaylistEntry::from(&song)]);
+
+    // the song moved, it's still found by its hash
+    fs::rename(dir.join("Song"), dir.join("Moved")).unwrap();
+    library.scan(&roots, false).unwrap();
+    let moved = library.find_entry(&playlist.songs[0]).unwrap().unwrap();
+    assert!(moved.path.starts_with(dir.join("Moved")));
+
+    library.add_favourite("Ann", &playlist.songs[0]).unwrap();
+    library.add_favourite("Ann", &playlist.songs[0]).unwrap();
+    assert_eq!(library.favourites("Ann").unwrap().songs.len(), 1);
+    assert_eq!(library.singers().unwrap(), ["Ann"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,458 @@
+//! Bringing the catalogue up to date
+//!
+//! Files are matched to the catalogue by size and modification time, only new
+//! or touched ones get read, in parallel. A touched file with the same hash
+//! keeps its row, and so does a file that moved, found by its hash. LYR files
+//! aren't watched: an edited one is only picked up by a full scan.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use rayon::prelude::*;
+use rusqlite::params;
+
+use super::{language_name, Library, SongFormat};
+use crate::{
+    emk::{self, EmkInfo},
+    ncn_reader,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    /// Files that couldn't be read this time
+    pub failed: usize,
+}
+
+/// Song and LYR files under the library folders
+#[derive(Default)]
+struct Walk {
+    songs: Vec<PathBuf>,
+    /// LYR files by folder and lowercase name
+    lyrics: HashMap<(PathBuf, String), PathBuf>,
+    /// LYR files in a `Lyrics` folder, by the folder above it and lowercase name
+    lyrics_folders: HashMap<(PathBuf, String), PathBuf>,
+    listed: HashSet<PathBuf>,
+    /// Folders that couldn't be listed, what was in them is unknown
+    unreadable: HashSet<PathBuf>,
+}
+
+impl Walk {
+    fn dir(&mut self, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("failed reading {}: {}", dir.display(), e);
+                self.unreadable.insert(dir.to_path_buf());
+                return;
+            }
+        };
+        self.listed.insert(dir.to_path_buf());
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // doesn't follow links, a linked folder could loop or be scanned twice
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                self.dir(&path);
+            } else if file_type.is_symlink() && path.is_dir() {
+                debug!("skipping linked folder {}", path.display());
+            } else if SongFormat::from_path(&path).is_some() {
+                self.songs.push(path);
+            } else if is_lyrics(&path) {
+                if let Some(stem) = lower_stem(&path) {
+                    if dir_name_is(dir, "lyrics") {
+                        if let Some(parent) = dir.parent() {
+                            let key = (parent.to_path_buf(), stem.clone());
+                            self.lyrics_folders.insert(key, path.clone());
+                        }
+                    }
+                    self.lyrics.insert((dir.to_path_buf(), stem), path);
+                }
+            }
+        }
+    }
+
+    /// Whether a catalogued song that wasn't found is gone, and not just in a
+    /// folder that couldn't be listed
+    fn is_gone(&self, path: &Path, roots: &[PathBuf]) -> bool {
+        // the folder was taken out of the settings
+        if !roots.iter().any(|root| path.starts_with(root)) {
+            return true;
+        }
+        for dir in path.ancestors().skip(1) {
+            if self.listed.contains(dir) {
+                return true;
+            }
+            if self.unreadable.contains(dir) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Same lookup as [ncn_reader::find_companion], without listing folders for every song
+    fn lyrics_for(&self, song: &Path) -> Option<&PathBuf> {
+        let stem = lower_stem(song)?;
+        let dir = song.parent()?;
+        self.lyrics
+            .get(&(dir.to_path_buf(), stem.clone()))
+            .or_else(|| {
+                self.lyrics_folders
+                    .get(&(dir.parent()?.to_path_buf(), stem))
+            })
+    }
+}
+
+fn is_lyrics(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("lyr"))
+}
+
+fn lower_stem(path: &Path) -> Option<String> {
+    Some(path.file_stem()?.to_str()?.to_lowercase())
+}
+
+fn dir_name_is(dir: &Path, name: &str) -> bool {
+    dir.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.eq_ignore_ascii_case(name))
+}
+
+/// A song file on disk
+struct Found {
+    path: PathBuf,
+    format: SongFormat,
+    /// Milliseconds since the epoch
+    modified: i64,
+    size: i64,
+    lyrics: Option<PathBuf>,
+}
+
+/// What the catalogue has on a file
+struct Known {
+    modified: i64,
+    size: i64,
+    hash: String,
+    failed: bool,
+}
+
+#[derive(Default)]
+struct Meta {
+    code: String,
+    title: String,
+    artist: String,
+    key: String,
+    language: String,
+}
+
+enum Read {
+    /// Touched but the contents are the same
+    Same,
+    Parsed {
+        hash: String,
+        meta: Meta,
+    },
+    Failed {
+        hash: String,
+        error: String,
+    },
+}
+
+fn stat(path: PathBuf, walk: &Walk) -> Option<Found> {
+    let meta = fs::metadata(&path).ok()?;
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(Found {
+        format: SongFormat::from_path(&path)?,
+        lyrics: walk.lyrics_for(&path).cloned(),
+        modified: modified.as_millis() as i64,
+        size: meta.len() as i64,
+        path,
+    })
+}
+
+fn is_thai(text: &str) -> bool {
+    text.chars().any(|c| ('\u{0e00}'..='\u{0e7f}').contains(&c))
+}
+
+fn read_meta(found: &Found, data: Vec<u8>) -> Result<Meta, Box<dyn std::error::Error>> {
+    let stem = found
+        .path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    match found.format {
+        SongFormat::Ncn => {
+            let lyrics = found.lyrics.as_ref().ok_or("lyrics file not found")?;
+            let info = ncn_reader::read_ncn_info(&fs::read(lyrics)?)?;
+            // LYR files don't say, but Thai titles are easy to spot
+            let language = if is_thai(&info.title) { "Thai" } else { "" };
+            Ok(Meta {
+                code: stem,
+                title: info.title.trim().to_string(),
+                artist: info.author.trim().to_string(),
+                key: info.key.trim().to_string(),
+                language: language.to_string(),
+            })
+        }
+        SongFormat::Emk => {
+            let blocks = emk::parse_blocks(data)?;
+            let info = blocks
+                .iter()
+                .find(|b| b.tag == "SONG_INFO")
+                .ok_or("EMK file has no SONG_INFO block")?;
+            let info = EmkInfo::parse(&info.data)?;
+            let code = info.code.trim();
+            Ok(Meta {
+                code: if code.is_empty() {
+                    stem
+                } else {
+                    code.to_string()
+                },
+                title: info.title.trim().to_string(),
+                artist: info.artist.trim().to_string(),
+                key: info.key.trim().to_string(),
+                language: language_name(&info.karaoke_language()).to_string(),
+            })
+        }
+    }
+}
+
+fn read_song(found: &Found, known: Option<&Known>) -> Read {
+    let data = match fs::read(&found.path) {
+        Ok(data) => data,
+        Err(e) => {
+            return Read::Failed {
+                hash: String::new(),
+                error: e.to_string(),
+            }
+        }
+    };
+    let hash = format!("{:x}", Md5::digest(&data));
+    if known.is_some_and(|k| k.hash == hash && !k.failed) {
+        return Read::Same;
+    }
+
+    match read_meta(found, data) {
+        Ok(meta) => Read::Parsed { hash, meta },
+        Err(e) => Read::Failed {
+            hash,
+            error: e.to_string(),
+        },
+    }
+}
+
+const UPSERT: &str = "INSERT INTO songs
+        (path, format, code, title, artist, key, language, modified, size, hash, error)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+    ON CONFLICT (path) DO UPDATE SET
+        format = ?2, code = ?3, title = ?4, artist = ?5, key = ?6, language = ?7,
+        modified = ?8, size = ?9, hash = ?10, error = ?11";
+
+impl Library {
+    /// Catalogues the songs under `roots` and drops the ones that are gone.
+    ///
+    /// Songs in a folder that couldn't be listed are kept, it's likely an
+    /// unplugged drive. Songs that have been played are only marked missing, so
+    /// their history stays. `full` reads every file again, not only the changed
+    /// ones.
+    pub fn scan(&mut self, roots: &[PathBuf], full: bool) -> Result<ScanReport> {
+        let mut walk = Walk::default();
+        for root in roots {
+            walk.dir(root);
+        }
+        let songs = std::mem::take(&mut walk.songs);
+        let found = songs
+            .into_iter()
+            .filter_map(|path| stat(path, &walk))
+            .collect::<Vec<_>>();
+        debug!("found {} song files", found.len());
+
+        let known = {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT path, modified, size, hash, error IS NOT NULL FROM songs")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    PathBuf::from(row.get::<_, String>(0)?),
+                    Known {
+                        modified: row.get(1)?,
+                        size: row.get(2)?,
+                        hash: row.get(3)?,
+                        failed: row.get(4)?,
+                    },
+                ))
+            })?;
+            rows.collect::<rusqlite::Result<HashMap<_, _>>>()?
+        };
+
+        let mut report = ScanReport::default();
+        let changed = found
+            .iter()
+            .filter(|f| {
+                full || known
+                    .get(&f.path)
+                    .is_none_or(|k| k.modified != f.modified || k.size != f.size)
+            })
+            .collect::<Vec<_>>();
+        report.unchanged = found.len() - changed.len();
+
+        let read = changed
+            .par_iter()
+            .map(|f| {
+                // a full scan doesn't trust the hash, the LYR file may have changed
+                let known = known.get(&f.path).filter(|_| !full);
+                (*f, read_song(f, known))
+            })
+            .collect::<Vec<_>>();
+
+        // songs that aren't there any more, a new file with the same hash moved
+        let on_disk = found.iter().map(|f| &f.path).collect::<HashSet<_>>();
+        let mut gone = HashMap::<&str, Vec<&PathBuf>>::new();
+        for (path, k) in &known {
+            if !on_disk.contains(path) && walk.is_gone(path, roots) {
+                gone.entry(&k.hash).or_default().push(path);
+            }
+        }
+
+        let tx = self.conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(UPSERT)?;
+            let mut touch = tx.prepare("UPDATE songs SET modified = ?, size = ? WHERE path = ?")?;
+            let mut relocate = tx.prepare("UPDATE songs SET path = ? WHERE path = ?")?;
+            for (f, read) in read {
+                let path = f.path.to_string_lossy().into_owned();
+                let (hash, meta, error) = match read {
+                    Read::Same => {
+                        touch.execute(params![f.modified, f.size, path])?;
+                        report.unchanged += 1;
+                        continue;
+                    }
+                    Read::Parsed { hash, meta } => (hash, meta, None),
+                    Read::Failed { hash, error } => {
+                        warn!("failed reading {}: {}", f.path.display(), error);
+                        (hash, Meta::default(), Some(error))
+                    }
+                };
+                let mut moved = false;
+                if !known.contains_key(&f.path) && !hash.is_empty() {
+                    if let Some(old) = gone.get_mut(hash.as_str()).and_then(|paths| paths.pop()) {
+                        debug!("{} moved to {}", old.display(), f.path.display());
+                        // the row keeps its id, and with it the plays
+                        relocate.execute(params![path, old.to_string_lossy()])?;
+                        moved = true;
+                    }
+                }
+                upsert.execute(params![
+                    path,
+                    f.format.as_str(),
+                    meta.code,
+                    meta.title,
+                    meta.artist,
+                    meta.key,
+                    meta.language,
+                    f.modified,
+                    f.size,
+                    hash,
+                    error,
+                ])?;
+                if error.is_some() {
+                    report.failed += 1;
+                } else if moved || known.contains_key(&f.path) {
+                    report.updated += 1;
+                } else {
+                    report.added += 1;
+                }
+            }
+
+            let mut delete = tx.prepare(
+                "DELETE FROM songs WHERE path = ?
+                    AND id NOT IN (SELECT song_id FROM plays WHERE song_id IS NOT NULL)",
+            )?;
+            // the size can't match, so the file is read again if it comes back
+            let mut missing = tx.prepare(
+                "UPDATE songs SET error = 'file not found', size = -1
+                    WHERE path = ? AND size != -1",
+            )?;
+            for path in gone.into_values().flatten() {
+                let path = path.to_string_lossy();
+                if delete.execute([&path])? + missing.execute([&path])? > 0 {
+                    report.removed += 1;
+                }
+            }
+        }
+        tx.commit()?;
+
+        info!("library scan: {:?}", report);
+        Ok(report)
+    }
+}
+
+#[test]
+fn test_scan() {
+    let dir = std::env::temp_dir().join("rusty-karaoke-library-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("Song")).unwrap();
+    fs::create_dir_all(dir.join("Lyrics")).unwrap();
+    fs::write(dir.join("Song/00001.MID"), b"MThd").unwrap();
+    fs::write(
+        dir.join("Lyrics/00001.lyr"),
+        b"Title\r\nArtist\r\nC\r\n\r\nla la\r\n",
+    )
+    .unwrap();
+    fs::write(dir.join("Song/00002.mid"), b"MThd").unwrap();
+
+    let mut library = Library::open_in_memory().unwrap();
+    let roots = [dir.clone()];
+    let report = library.scan(&roots, false).unwrap();
+    assert_eq!((report.added, report.failed), (1, 1));
+
+    let song = &library.by_code("00001").unwrap()[0];
+    assert_eq!(
+        (song.title.as_str(), song.artist.as_str(), song.key.as_str()),
+        ("Title", "Artist", "C")
+    );
+    assert_eq!(library.failed().unwrap().len(), 1);
+
+    // the failed one isn't tried again until it changes
+    let report = library.scan(&roots, false).unwrap();
+    assert_eq!((report.unchanged, report.failed), (2, 0));
+
+    fs::write(dir.join("Song/00001.MID"), b"MThd\0").unwrap();
+    fs::remove_file(dir.join("Song/00002.mid")).unwrap();
+    let report = library.scan(&roots, false).unwrap();
+    assert_eq!((report.updated, report.removed), (1, 1));
+    assert_eq!(library.count().unwrap(), 1);
+
+    // a moved song keeps its row, and with it the plays
+    let id = library.by_code("00001").unwrap()[0].id;
+    library.record_play(&dir.join("Song/00001.MID"), "").unwrap();
+    fs::create_dir_all(dir.join("Moved")).unwrap();
+    fs::rename(dir.join("Song/00001.MID"), dir.join("Moved/00001.MID")).unwrap();
+    let report = library.scan(&roots, false).unwrap();
+    assert_eq!((report.updated, report.removed), (1, 0));
+    assert_eq!(library.by_code("00001").unwrap()[0].id, id);
+
+    // once sung it's only marked missing
+    fs::remove_file(dir.join("Moved/00001.MID")).unwrap();
+    let report = library.scan(&roots, false).unwrap();
+    assert_eq!(report.removed, 1);
+    assert_eq!(library.count().unwrap(), 0);
+    assert_eq!(library.failed().unwrap().len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,277 @@
+//! Song search
+//!
+//! Thai doesn't put spaces between words and tone marks are often left out
+//! when typing, so titles and queries are both folded to a key without them
+//! and matched anywhere in the title. Leading vowels (เ แ โ ใ ไ) are written
+//! before the consonant they're said after and get typed in either place, a
+//! looser key leaves them out. Latin queries are matched by their consonant
+//! sounds, so `rak` finds รัก and `pleng` finds เพลง.
+//!
+//! The whole catalogue is kept in memory, a search is a scan over a few tens of
+//! thousands of short strings.
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use super::Song;
+
+/// Folds text for matching: lowercase, Thai digits as ASCII, no tone marks or
+/// other optional signs, and no spaces or punctuation
+pub fn fold(text: &str) -> String {
+    text.chars()
+        .flat_map(char::to_lowercase)
+        .filter_map(|c| match c {
+            // mai taikhu, tone marks, thanthakhat, nikhahit, yamakkan
+            '\u{0e47}'..='\u{0e4e}' => None,
+            '\u{0e50}'..='\u{0e59}' => char::from_digit(c as u32 - 0x0e50, 10),
+            '\u{0e01}'..='\u{0e3a}' | '\u{0e40}'..='\u{0e46}' => Some(c),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Folded text without leading vowels
+pub fn loose(folded: &str) -> String {
+    folded
+        .chars()
+        .filter(|c| !('\u{0e40}'..='\u{0e44}').contains(c))
+        .collect()
+}
+
+/// Consonant sound of a Thai letter, with sounds that get romanized several
+/// ways (k/g, t/d/th, p/b/ph, r/l, ch/j) put together
+fn thai_sound(c: char) -> Option<char> {
+    Some(match c {
+        'ก' | 'ข' | 'ฃ' | 'ค' | 'ฅ' | 'ฆ' => 'k',
+        'ง' => 'N',
+        'จ' | 'ฉ' | 'ช' | 'ฌ' => 'c',
+        'ซ' | 'ศ' | 'ษ' | 'ส' => 's',
+        'ญ' | 'ย' => 'y',
+        'ฎ' | 'ฏ' | 'ฐ' | 'ฑ' | 'ฒ' | 'ด' | 'ต' | 'ถ' | 'ท' | 'ธ' => 't',
+        'ณ' | 'น' => 'n',
+        'บ' | 'ป' | 'ผ' | 'พ' | 'ภ' => 'p',
+        'ฝ' | 'ฟ' => 'f',
+        'ม' => 'm',
+        'ร' | 'ล' | 'ฬ' | 'ฤ' | 'ฦ' => 'r',
+        'ว' => 'w',
+        'ห' | 'ฮ' => 'h',
+        _ => return None,
+    })
+}
+
+/// Consonant sounds of folded text, Thai or romanized, for matching one against the other
+pub fn sound_key(folded: &str) -> String {
+    let chars = folded.chars().collect::<Vec<_>>();
+    let mut key = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        i += 1;
+        let sound = match c {
+            'n' if next == Some('g') => {
+                i += 1;
+                'N'
+            }
+            // aspiration isn't written the same by everyone
+            'k' | 'g' | 'q' => 'k',
+            'c' | 'j' => 'c',
+            'd' | 't' => 't',
+            'b' | 'p' => 'p',
+            'l' | 'r' => 'r',
+            'z' | 'x' => 's',
+            'h' if key.ends_with(['k', 'c', 't', 'p', 's']) => continue,
+            's' | 'f' | 'h' | 'm' | 'n' | 'w' | 'y' => c,
+            c if c.is_ascii_digit() => c,
+            c => match thai_sound(c) {
+                Some(sound) => sound,
+                None => continue,
+            },
+        };
+        // double letters are one sound
+        if !key.ends_with(sound) || sound.is_ascii_digit() {
+            key.push(sound);
+        }
+    }
+    key
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    song: Song,
+    code: String,
+    title: String,
+    artist: String,
+    title_loose: String,
+    artist_loose: String,
+    title_sound: String,
+    artist_sound: String,
+    plays: usize,
+}
+
+/// How well a song matched, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    Code,
+    CodePrefix,
+    TitlePrefix,
+    Title,
+    Artist,
+    Loose,
+    Sound,
+}
+
+/// The catalogue ready for searching
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+    entries: Vec<Entry>,
+}
+
+impl SearchIndex {
+    /// `plays` is how many times each song id was played
+    pub fn new(songs: Vec<Song>, plays: &HashMap<i64, usize>) -> Self {
+        let entries = songs
+            .into_iter()
+            .map(|song| {
+                let title = fold(&song.title);
+                let artist = fold(&song.artist);
+                Entry {
+                    code: song.code.to_lowercase(),
+                    title_loose: loose(&title),
+                    artist_loose: loose(&artist),
+                    title_sound: sound_key(&title),
+                    artist_sound: sound_key(&artist),
+                    title,
+                    artist,
+                    plays: plays.get(&song.id).copied().unwrap_or_default(),
+                    song,
+                }
+            })
+            .collect();
+        Self { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Counts a play, so the song ranks higher from now on
+    pub fn played(&mut self, id: i64) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.song.id == id) {
+            entry.plays += 1;
+        }
+    }
+
+    /// Song with this code, the most played one if there are several. Leading
+    /// zeros don't count, so `123` finds `00123`.
+    pub fn by_code(&self, code: &str) -> Option<&Song> {
+        let code = code.trim_start_matches('0');
+        if code.is_empty() {
+            return None;
+        }
+        self.entries
+            .iter()
+            .filter(|e| e.code.trim_start_matches('0') == code)
+            .min_by_key(|e| Reverse(e.plays))
+            .map(|e| &e.song)
+    }
+
+    /// Songs matching `query`, best matches and most played first. An empty
+    /// query lists the most played songs.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&Song> {
+        let query = fold(query);
+        let query_loose = loose(&query);
+        let sound = sound_key(&query);
+        // one letter sounds match nearly everything
+        let sound = Some(sound).filter(|s| s.chars().count() >= 2);
+
+        let rank = |e: &Entry| {
+            if query.is_empty() {
+                Some(Rank::Sound)
+            } else if e.code == query {
+                Some(Rank::Code)
+            } else if e.code.starts_with(&query) {
+                Some(Rank::CodePrefix)
+            } else if e.title.starts_with(&query) {
+                Some(Rank::TitlePrefix)
+            } else if e.title.contains(&query) {
+                Some(Rank::Title)
+            } else if e.artist.contains(&query) {
+                Some(Rank::Artist)
+            } else if e.title_loose.contains(&query_loose) || e.artist_loose.contains(&query_loose)
+            {
+                Some(Rank::Loose)
+            } else if sound.as_ref().is_some_and(|s| {
+                e.title_sound.contains(s) || e.artist_sound.contains(s)
+            }) {
+                Some(Rank::Sound)
+            } else {
+                None
+            }
+        };
+
+        let mut hits = self
+            .entries
+            .iter()
+            .filter_map(|e| Some((rank(e)?, e)))
+            .collect::<Vec<_>>();
+        // karaoke song books are in code order, so ties are too
+        hits.sort_by(|(a_rank, a), (b_rank, b)| {
+            (a_rank, Reverse(a.plays), &a.code).cmp(&(b_rank, Reverse(b.plays), &b.code))
+        });
+        hits.into_iter().take(limit).map(|(_, e)| &e.song).collect()
+    }
+}
+
+#[test]
+fn test_fold() {
+    // tone marks and spaces don't matter
+    assert_eq!(fold("ใจ ร้าว"), fold("ใจราว"));
+    // leading vowels typed either way
+    assert_eq!(loose(&fold("เพลง")), loose(&fold("พเลง")));
+    assert_eq!(fold("Don't Stop"), "dontstop");
+    assert_eq!(fold("๑๒๓"), "123");
+
+    assert_eq!(sound_key(&fold("เพลง")), sound_key("phleng"));
+    assert_eq!(sound_key(&fold("รัก")), sound_key("lak"));
+}
+
+#[test]
+fn test_search() {
+    let song = |id: i64, code: &str, title: &str| Song {
+        id,
+        path: format!("{}.mid", code).into(),
+        format: super::SongFormat::Ncn,
+        code: code.to_string(),
+        title: title.to_string(),
+        artist: String::new(),
+        key: String::new(),
+        language: String::new(),
+        hash: String::new(),
+    };
+    let songs = vec![
+        song(1, "10010", "รักเธอ"),
+        song(2, "10011", "ใจร้าว"),
+        song(3, "20010", "ยังรัก"),
+    ];
+    let plays = HashMap::from([(3, 5)]);
+    let index = SearchIndex::new(songs, &plays);
+    let ids = |query: &str| {
+        index
+            .search(query, 10)
+            .iter()
+            .map(|s| s.id)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(ids("1001"), [1, 2]);
+    // a title starting with the query beats a more played one that only has it
+    assert_eq!(ids("รัก"), [1, 3]);
+    assert_eq!(ids("ใจราว"), [2]);
+    assert_eq!(ids("jai rao"), [2]);
+    assert_eq!(ids(""), [3, 1, 2]);
+}
@@ -1,16 +1,43 @@
+mod audio;
+mod cli;
+mod config;
+mod dsp;
 mod emk;
+mod events;
+mod external;
+mod guide;
 mod karaoke;
+mod keypad;
+mod library;
+mod mic;
 mod midi;
+mod mixer;
 mod ncn;
 mod ncn_reader;
+mod output;
+mod pitch;
+mod playback;
+mod plugin;
+mod queue;
+mod render;
+mod score;
+mod soundfont;
+mod stretch;
+mod sysex;
 mod tick;
 mod time;
 mod ui;
+mod vocal;
 use std::{env, path::PathBuf, sync::Arc, thread};
 
 use chrono::Duration;
+use clap::Parser;
+use crossbeam::channel::TryRecvError;
 use eframe::{run_native, App};
 use egui::{CentralPanel, Frame, ImageButton, RichText, ScrollArea, SidePanel, TopBottomPanel, Ui};
+use external::OutputTarget;
+use hhmmss::Hhmmss;
+use library::playlists::{Playlist, PlaylistEntry};
 use log::{debug, LevelFilter};
 use midly::{
     num::{u4, u7},
@@ -18,19 +45,388 @@ use midly::{
 };
 use nodi::MidiEvent;
 use parking_lot::{deadlock, Mutex, RwLock};
-use time::{Context, PlaybackContext};
+use playback::{Playback, PlaybackCommand, PlaybackState};
+use ui::{
+    history::{HistoryAction, Stats},
+    playlists::PlaylistAction,
+};
 
 struct Frontend {
-    pub msg: crossbeam::channel::Sender<time::PlaybackEvent>,
-    pub context: Arc<RwLock<PlaybackContext>>,
-    pub mptx: crossbeam::channel::Sender<()>,
+    pub playback: Playback,
+    /// Songs lined up, played after each other
+    pub queue: queue::Queue,
+    /// Playback notifications, drained every frame
+    pub events: crossbeam::channel::Receiver<events::PlaybackEvent>,
     pub midi: crossbeam::channel::Sender<midi::MidiMessage>,
+    /// Song catalogue, `None` if it couldn't be opened
+    pub library: Option<library::Library>,
+    pub mixer: Arc<RwLock<mixer::ChannelMixer>>,
+    /// Where MIDI events go, set by the MIDI thread once a switch worked
+    pub output: Arc<RwLock<OutputTarget>>,
+    /// Errors from the background threads, shown in the UI
+    pub errors: crossbeam::channel::Receiver<String>,
+    /// Scores the singer through the mic while a song plays
+    pub score: Option<score::LiveScore>,
+    pub config: config::Config,
     pub state: State,
 }
 
+impl Frontend {
+    /// Sends new master settings to the synth, they're saved with the next autosave
+    fn set_master(&mut self, master: dsp::MasterSettings) {
+        self.midi
+            .send(midi::MidiMessage::Master(master.clone()))
+            .unwrap_or_default();
+        self.config.master = master;
+        self.state.config_changed = true;
+    }
+
+    /// Sends new mic settings to the synth, they're saved with the next autosave
+    fn set_mic(&mut self, mic: mic::MicSettings) {
+        self.midi
+            .send(midi::MidiMessage::Mic(mic.clone()))
+            .unwrap_or_default();
+        self.config.mic = mic;
+        self.state.config_changed = true;
+    }
+
+    /// Sends new vocal reducer settings to the synth, they're saved with the next autosave
+    fn set_vocal(&mut self, vocal: vocal::VocalReducerSettings) {
+        self.midi
+            .send(midi::MidiMessage::Vocal(vocal.clone()))
+            .unwrap_or_default();
+        self.config.vocal = vocal;
+        self.state.config_changed = true;
+    }
+
+    /// Changes how the queue advances, saved with the next autosave
+    fn set_queue_settings(&mut self, settings: queue::QueueSettings) {
+        self.queue.set_settings(settings.clone());
+        self.config.queue = settings;
+        self.state.config_changed = true;
+    }
+
+    /// Reads the song list again, after a scan changed the library
+    fn reload_songs(&mut self) {
+        if let Some(library) = &self.library {
+            match library.search_index() {
+                Ok(songs) => self.state.songs = songs,
+                Err(e) => log::warn!("failed reading the library: {}", e),
+            }
+            self.state.search_results = None;
+        }
+    }
+
+    /// Starts a play record for the song that just started, under the singer
+    /// who queued it
+    fn record_play(&mut self, path: &std::path::Path) {
+        let singer = self
+            .queue
+            .lock()
+            .current
+            .as_ref()
+            .filter(|entry| entry.path == path)
+            .map(|entry| entry.singer.clone())
+            .unwrap_or_else(|| self.state.singer.trim().to_string());
+        if let Some(library) = &self.library {
+            match library.record_play(path, &singer) {
+                Ok(play) => self.state.play = play,
+                Err(e) => log::warn!("failed recording a play: {}", e),
+            }
+        }
+    }
+
+    /// Closes the play record of the last song, `skipped` if it was stopped
+    /// before the end. Only songs sung to the end rank higher in the search.
+    fn finish_play(&mut self, skipped: bool) {
+        let play = match (self.state.play.take(), &self.library) {
+            (Some(play), Some(library)) => {
+                let elapsed = self.state.progress.elapsed;
+                let score = self.state.last_score.map(|s| s.total());
+                library
+                    .finish_play(play.play, elapsed, score, skipped)
+                    .map(|()| play)
+            }
+            _ => return,
+        };
+        self.state.stats = None;
+        match play {
+            Ok(play) if !skipped => self.state.songs.played(play.song),
+            Ok(_) => {}
+            Err(e) => log::warn!("failed recording a play: {}", e),
+        }
+    }
+
+    /// Starts scoring the song that just started, when scoring is on
+    fn start_scoring(&mut self, path: &std::path::Path) {
+        self.stop_scoring(false);
+        // the score of the last song mustn't go on this one's play
+        self.state.last_score = None;
+        // audio songs have no guide melody to score against
+        if !self.state.scoring || audio::is_audio(path) {
+            return;
+        }
+
+        match song_score(path, self.state.transpose) {
+            Ok(live) => {
+                self.midi
+                    .send(midi::MidiMessage::MicTap(Some(live.tap.clone())))
+                    .unwrap_or_default();
+                self.score = Some(live);
+            }
+            Err(e) => self
+                .state
+                .errors
+                .push(format!("Can't score {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Stops listening to the mic, keeping the score if the song was sung to the end
+    fn stop_scoring(&mut self, ended: bool) {
+        let mut live = match self.score.take() {
+            Some(live) => live,
+            None => return,
+        };
+        self.midi
+            .send(midi::MidiMessage::MicTap(None))
+            .unwrap_or_default();
+
+        if ended {
+            let now = live.seconds(self.state.progress.ticks);
+            live.update(now, self.state.speed as f64);
+            self.state.last_score = Some(live.scorer.report().overall);
+        }
+    }
+
+    /// Scores what the mic picked up since the last frame
+    fn update_score(&mut self) {
+        let live = match &mut self.score {
+            Some(live) => live,
+            None => return,
+        };
+
+        if let PlaybackState::Playing(_) = self.playback.state() {
+            // positions only come in every 100ms, count the time since the last one
+            let since = self
+                .state
+                .position_at
+                .map_or(0.0, |at| at.elapsed().as_secs_f64());
+            let speed = self.state.speed as f64;
+            let now = live.seconds(self.state.progress.tick) + since * speed;
+            live.update(now, speed);
+        } else {
+            live.skip();
+        }
+    }
+
+    /// Adds a library song to the queue, for the singer typed in the queue window
+    fn queue_song(&mut self, song: library::Song) {
+        let singer = match self.state.singer.trim() {
+            "" => "Guest".to_string(),
+            singer => singer.to_string(),
+        };
+        self.queue.lock().add(song.path, singer);
+        self.queue.save();
+    }
+
+    /// Queues the library song of a playlist entry, `false` if it isn't in the library
+    fn queue_entry(&mut self, entry: &PlaylistEntry) -> anyhow::Result<bool> {
+        let song = self.library()?.find_entry(entry)?;
+        match song {
+            Some(song) => {
+                self.queue_song(song);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn library(&mut self) -> anyhow::Result<&mut library::Library> {
+        self.library
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The library couldn't be opened"))
+    }
+
+    /// Adds a song to the favourites of the singer typed in the queue window
+    fn add_favourite(&mut self, song: &library::Song) -> anyhow::Result<()> {
+        let singer = self.state.singer.trim().to_string();
+        if singer.is_empty() {
+            anyhow::bail!("Type a singer in the queue window first");
+        }
+        self.library()?
+            .add_favourite(&singer, &PlaylistEntry::from(song))?;
+        self.state.favourites = None;
+        Ok(())
+    }
+
+    fn playlist_action(&mut self, action: PlaylistAction) -> anyhow::Result<()> {
+        match action {
+            PlaylistAction::Queue(name) => {
+                let playlist = self.library()?.playlist(&name)?.unwrap_or_default();
+                let mut missing = 0;
+                for entry in &playlist.songs {
+                    if !self.queue_entry(entry)? {
+                        missing += 1;
+                    }
+                }
+                if missing > 0 {
+                    anyhow::bail!("{} songs of {} aren't in the library", missing, name);
+                }
+            }
+            PlaylistAction::Delete(name) => {
+                self.library()?.delete_playlist(&name)?;
+                self.state.playlists = None;
+            }
+            PlaylistAction::Export(name) => {
+                let library = self.library()?;
+                if let Some(playlist) = library.playlist(&name)? {
+                    export_playlist(&playlist, library)?;
+                }
+            }
+            PlaylistAction::SaveQueue(name) => {
+                let paths = self
+                    .queue
+                    .lock()
+                    .entries()
+                    .iter()
+                    .map(|e| e.path.clone())
+                    .collect::<Vec<_>>();
+                let library = self.library()?;
+                let mut songs = Vec::new();
+                for path in paths {
+                    if let Some(song) = library.song_by_path(&path)? {
+                        songs.push(PlaylistEntry::from(&song));
+                    }
+                }
+                library.save_playlist(&Playlist { name, songs })?;
+                self.state.playlists = None;
+            }
+            PlaylistAction::Import => {
+                let path = native_dialog::FileDialog::new()
+                    .add_filter("Playlist", &["m3u", "m3u8", "json"])
+                    .show_open_single_file()?;
+                if let Some(path) = path {
+                    let library = self.library()?;
+                    let playlist = Playlist::import(&path, library)?;
+                    library.save_playlist(&playlist)?;
+                    self.state.playlists = None;
+                }
+            }
+            PlaylistAction::QueueFavourite(entry) => {
+                if !self.queue_entry(&entry)? {
+                    anyhow::bail!("{} isn't in the library", entry.title);
+                }
+            }
+            PlaylistAction::RemoveFavourite(entry) => {
+                let singer = self.state.singer.trim().to_string();
+                self.library()?.remove_favourite(&singer, &entry)?;
+                self.state.favourites = None;
+            }
+            PlaylistAction::ExportFavourites => {
+                let singer = self.state.singer.trim().to_string();
+                let library = self.library()?;
+                export_playlist(&library.favourites(&singer)?, library)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn history_action(&mut self, action: HistoryAction) -> anyhow::Result<()> {
+        let singer = self.state.singer.trim().to_string();
+        let month = self.state.report_month.trim().to_string();
+        let library = self.library()?;
+        let (csv, name) = match action {
+            HistoryAction::Refresh => {
+                let stats = Stats::load(library, &singer)?;
+                self.state.stats = Some(stats);
+                return Ok(());
+            }
+            HistoryAction::ExportPlays => (library.month_plays_csv(&month)?, "plays"),
+            HistoryAction::ExportSongs => (library.month_songs_csv(&month)?, "songs"),
+        };
+        let path = native_dialog::FileDialog::new()
+            .set_filename(&format!("{}-{}.csv", name, month))
+            .add_filter("CSV", &["csv"])
+            .show_save_single_file()?;
+        if let Some(path) = path {
+            std::fs::write(path, csv)?;
+        }
+        Ok(())
+    }
+
+    fn play_song(&mut self, song: library::Song) {
+        self.playback.send(PlaybackCommand::Play(song.path.clone()));
+        self.state.file = Some(song.path);
+    }
+
+    /// Writes pending config edits to disk
+    fn save_config(&mut self) {
+        if self.state.config_changed {
+            match self.config.save() {
+                Ok(()) => self.state.config_changed = false,
+                Err(e) => log::warn!("failed saving config: {}", e),
+            }
+        }
+    }
+}
+
 impl App for Frontend {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         frame.set_window_title("RustyKaraoke");
+
+        self.state.errors.extend(self.errors.try_iter());
+        // handling them needs `self`
+        let events = self.events.try_iter().collect::<Vec<_>>();
+        for event in events {
+            match &event {
+                events::PlaybackEvent::Error(e) => self.state.errors.push(e.clone()),
+                events::PlaybackEvent::Started(path) => {
+                    self.record_play(path);
+                    self.start_scoring(path);
+                }
+                events::PlaybackEvent::Ended(_) => {
+                    self.stop_scoring(true);
+                    self.finish_play(false);
+                }
+                events::PlaybackEvent::Stopped | events::PlaybackEvent::Loaded { .. } => {
+                    self.stop_scoring(false);
+                    self.finish_play(true)
+                }
+                events::PlaybackEvent::Position { .. } => {
+                    self.state.position_at = Some(std::time::Instant::now())
+                }
+                _ => {}
+            }
+            self.state.progress.update(&event);
+        }
+        self.update_score();
+        if !self.state.errors.is_empty() {
+            egui::Window::new("Error").show(ctx, |ui| {
+                for e in &self.state.errors {
+                    ui.label(RichText::new(e).color(egui::Color32::LIGHT_RED));
+                }
+                if ui.button("Dismiss").clicked() {
+                    self.state.errors.clear();
+                }
+            });
+        }
+
+        for key in crate::ui::keypad::pressed_keys(ctx) {
+            match self.state.keypad.press(key, &self.state.songs) {
+                Some(keypad::KeypadAction::Queue(song)) => self.queue_song(song),
+                Some(keypad::KeypadAction::Play(song)) => self.play_song(song),
+                None => {}
+            }
+        }
+        if self.state.keypad.is_active() {
+            TopBottomPanel::bottom("keypad").show(ctx, |ui| {
+                ui.add(crate::ui::keypad::Keypad {
+                    entry: &self.state.keypad,
+                    songs: &self.state.songs,
+                });
+            });
+        }
+
         TopBottomPanel::top("top_panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 // ui.heading("RustyKaraoke");
@@ -38,6 +434,7 @@ impl App for Frontend {
                     if ui.button("Open").clicked() {
                         let file = native_dialog::FileDialog::new()
                             .add_filter("MIDI", &["mid", "midi", "MID", "MIDI"])
+                            .add_filter("Audio", &["wav", "ogg", "WAV", "OGG"])
                             .show_open_single_file()
                             .unwrap();
 
@@ -51,12 +448,113 @@ impl App for Frontend {
                         std::process::exit(0);
                     }
                 });
+                ui.menu_button("MIDI Output", |ui| {
+                    let output = self.output.read().clone();
+                    let mut target = output.clone();
+                    ui.radio_value(&mut target, OutputTarget::Synth, "Built-in synth");
+                    for port in &self.state.output_ports {
+                        ui.radio_value(
+                            &mut target,
+                            OutputTarget::Port(port.clone()),
+                            port.as_str(),
+                        );
+                    }
+                    ui.radio_value(
+                        &mut target,
+                        OutputTarget::Virtual(external::CLIENT_NAME.to_string()),
+                        "Virtual port",
+                    );
+                    // failures come back through the error channel
+                    if target != output {
+                        self.midi
+                            .send(midi::MidiMessage::Output(target))
+                            .unwrap_or_default();
+                    }
+
+                    ui.separator();
+                    if ui.button("Refresh").clicked() {
+                        self.state.output_ports = external::list_output_ports().unwrap_or_default();
+                    }
+
+                    ui.separator();
+                    ui.label("Reset before each song");
+                    let mut mode = self.state.reset_mode;
+                    for m in sysex::ResetMode::ALL {
+                        ui.radio_value(&mut mode, m, m.name());
+                    }
+                    if mode != self.state.reset_mode {
+                        self.midi
+                            .send(midi::MidiMessage::ResetMode(mode))
+                            .unwrap_or_default();
+                        self.state.reset_mode = mode;
+                    }
+                });
+                ui.menu_button("Audio", |ui| {
+                    let mut audio = self.config.audio.clone();
+
+                    ui.label("Output device");
+                    ui.radio_value(&mut audio.device, None, "Default");
+                    for dev in &self.state.audio_devices {
+                        ui.radio_value(&mut audio.device, Some(dev.clone()), dev.as_str());
+                    }
+                    if ui.button("Refresh").clicked() {
+                        self.state.audio_devices =
+                            output::list_output_devices().unwrap_or_default();
+                    }
+
+                    ui.separator();
+                    ui.label("Sample rate");
+                    ui.radio_value(&mut audio.sample_rate, None, "Default");
+                    for rate in output::SAMPLE_RATES {
+                        ui.radio_value(&mut audio.sample_rate, Some(rate), format!("{} Hz", rate));
+                    }
+
+                    ui.separator();
+                    ui.label("Buffer size");
+                    ui.radio_value(&mut audio.buffer_size, None, "Default");
+                    for frames in output::BUFFER_SIZES {
+                        ui.radio_value(
+                            &mut audio.buffer_size,
+                            Some(frames),
+                            format!("{} frames", frames),
+                        );
+                    }
+
+                    if audio != self.config.audio {
+                        self.midi
+                            .send(midi::MidiMessage::Audio(audio.clone()))
+                            .unwrap_or_default();
+                        self.config.audio = audio;
+                        if let Err(e) = self.config.save() {
+                            self.state
+                                .errors
+                                .push(format!("Failed to save config: {}", e));
+                        }
+                    }
+                });
+                ui.menu_button("Window", |ui| {
+                    ui.checkbox(&mut self.state.show_soundfonts, "Soundfonts");
+                    ui.checkbox(&mut self.state.show_master, "Master");
+                    ui.checkbox(&mut self.state.show_microphone, "Microphone");
+                    ui.checkbox(&mut self.state.show_score, "Score");
+                    ui.checkbox(&mut self.state.show_plugins, "Plugins");
+                    ui.checkbox(&mut self.state.show_queue, "Queue");
+                    ui.checkbox(&mut self.state.show_library, "Library");
+                    ui.checkbox(&mut self.state.show_playlists, "Playlists");
+                    ui.checkbox(&mut self.state.show_history, "History");
+                });
                 ui.separator();
                 ui.spacing();
                 ui.horizontal(|ui| {
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::Max), |ui| {
                         ui.label("Volume");
-                        ui.add(egui::Slider::new(&mut 0.0, 0.0..=1.0).text("Volume"));
+                        let mut volume = self.config.master.volume;
+                        let slider = egui::Slider::new(&mut volume, 0.0..=2.0).show_value(false);
+                        if ui.add(slider).changed() {
+                            let mut master = self.config.master.clone();
+                            master.volume = volume;
+                            self.set_master(master);
+                        }
                         // button to toggle sidebar
                         if ui.button("Toggle Sidebar").clicked() {
                             // side.toggle();
@@ -84,6 +582,289 @@ impl App for Frontend {
                 // }
             });
         });
+        let mut open = self.state.show_soundfonts;
+        egui::Window::new("Soundfonts")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.add(crate::ui::soundfonts::Soundfonts {
+                    setup: &mut self.state.soundfonts,
+                });
+                ui.separator();
+                if ui.button("Apply").clicked() {
+                    self.midi
+                        .send(midi::MidiMessage::Soundfonts(self.state.soundfonts.clone()))
+                        .unwrap_or_default();
+                    self.config.soundfonts = self.state.soundfonts.clone();
+                    if let Err(e) = self.config.save() {
+                        self.state
+                            .errors
+                            .push(format!("Failed to save config: {}", e));
+                    }
+                }
+            });
+        self.state.show_soundfonts = open;
+        let mut open = self.state.show_master;
+        egui::Window::new("Master")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let mut master = self.config.master.clone();
+                let response = ui.add(crate::ui::master::Master {
+                    settings: &mut master,
+                });
+                if response.changed() {
+                    self.set_master(master);
+                }
+            });
+        self.state.show_master = open;
+        let mut open = self.state.show_microphone;
+        egui::Window::new("Microphone")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let mut mic = self.config.mic.clone();
+                let response = ui.add(crate::ui::mic::Mic {
+                    settings: &mut mic,
+                    devices: &mut self.state.input_devices,
+                });
+                if response.changed() {
+                    self.set_mic(mic);
+                }
+            });
+        self.state.show_microphone = open;
+        let mut open = self.state.show_score;
+        egui::Window::new("Score")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.add(crate::ui::score::Scoring {
+                    enabled: &mut self.state.scoring,
+                    mic_enabled: self.config.mic.enabled,
+                    pitch: self.score.as_ref().and_then(|live| live.pitch),
+                    last: self.state.last_score,
+                });
+            });
+        self.state.show_score = open;
+        let mut open = self.state.show_plugins;
+        egui::Window::new("Plugins")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.heading("Master");
+                let mut master = self.config.master.clone();
+                let response = ui.add(crate::ui::plugins::PluginRack {
+                    id: "master_plugins",
+                    slots: &mut master.plugins,
+                    scan: &mut self.state.plugins,
+                });
+                if response.changed() {
+                    self.set_master(master);
+                }
+
+                ui.separator();
+                ui.heading("Microphone");
+                let mut mic = self.config.mic.clone();
+                let response = ui.add(crate::ui::plugins::PluginRack {
+                    id: "mic_plugins",
+                    slots: &mut mic.plugins,
+                    scan: &mut self.state.plugins,
+                });
+                if response.changed() {
+                    self.set_mic(mic);
+                }
+
+                let failed = self.state.plugins.errors().collect::<Vec<_>>();
+                if !failed.is_empty() {
+                    ui.separator();
+                    ui.collapsing("Failed to load", |ui| {
+                        for (path, e) in failed {
+                            ui.label(format!("{}: {}", path.display(), e));
+                        }
+                    });
+                }
+            });
+        self.state.show_plugins = open;
+        let mut open = self.state.show_queue;
+        egui::Window::new("Queue")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let mut start = false;
+                let response = ui.add(crate::ui::queue::Queue {
+                    queue: &mut self.queue.lock(),
+                    file: self.state.file.as_deref(),
+                    singer: &mut self.state.singer,
+                    start: &mut start,
+                });
+                if response.changed() {
+                    self.queue.save();
+                }
+                if start {
+                    self.queue.start_next();
+                }
+
+                ui.separator();
+                let mut settings = self.config.queue.clone();
+                ui.checkbox(
+                    &mut settings.auto_advance,
+                    "Play the next song when one ends",
+                );
+                ui.add_enabled(
+                    settings.auto_advance,
+                    egui::Slider::new(&mut settings.gap, 0.0..=60.0).text("Gap (s)"),
+                );
+                if settings != self.config.queue {
+                    self.set_queue_settings(settings);
+                }
+            });
+        self.state.show_queue = open;
+        let mut open = self.state.show_library;
+        egui::Window::new("Library")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let mut library = self.config.library.clone();
+                let mut remove = None;
+                for (i, root) in library.roots.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(root.display().to_string());
+                        if ui.button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = remove {
+                    library.roots.remove(i);
+                }
+                if ui.button("Add folder").clicked() {
+                    if let Ok(Some(dir)) = native_dialog::FileDialog::new().show_open_single_dir() {
+                        library.roots.push(dir);
+                    }
+                }
+                if library != self.config.library {
+                    self.config.library = library;
+                    self.state.config_changed = true;
+                }
+
+                ui.separator();
+                let finished = match self.state.library_scan.as_ref().map(|rx| rx.try_recv()) {
+                    Some(Ok(status)) => Some(status),
+                    Some(Err(TryRecvError::Disconnected)) => Some("Scan stopped".to_string()),
+                    _ => None,
+                };
+                if let Some(status) = finished {
+                    self.state.library_status = status;
+                    self.state.library_scan = None;
+                    self.reload_songs();
+                }
+                if self.state.library_scan.is_some() {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Scanning...");
+                    });
+                } else if ui.button("Scan").clicked() {
+                    self.state.library_scan = Some(scan_library(self.config.library.roots.clone()));
+                }
+                ui.label(&self.state.library_status);
+            });
+        self.state.show_library = open;
+        egui::Window::new("Songs")
+            .default_size(egui::vec2(500.0, 400.0))
+            .show(ctx, |ui| {
+                let mut action = None;
+                ui.add(crate::ui::search::SongSearch {
+                    index: &self.state.songs,
+                    query: &mut self.state.search,
+                    results: &mut self.state.search_results,
+                    action: &mut action,
+                });
+                match action {
+                    Some((song, crate::ui::search::SongAction::Queue)) => self.queue_song(song),
+                    Some((song, crate::ui::search::SongAction::Play)) => self.play_song(song),
+                    Some((song, crate::ui::search::SongAction::Favourite)) => {
+                        if let Err(e) = self.add_favourite(&song) {
+                            self.state.errors.push(e.to_string());
+                        }
+                    }
+                    None => {}
+                }
+            });
+        let mut open = self.state.show_playlists;
+        egui::Window::new("Playlists")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let singer = self.state.singer.trim().to_string();
+                if self
+                    .state
+                    .favourites
+                    .as_ref()
+                    .is_none_or(|f| f.name != singer)
+                {
+                    self.state.favourites = self
+                        .library
+                        .as_ref()
+                        .and_then(|library| library.favourites(&singer).ok());
+                }
+                if self.state.playlists.is_none() {
+                    self.state.playlists = self
+                        .library
+                        .as_ref()
+                        .and_then(|library| library.playlists().ok());
+                }
+
+                let mut action = None;
+                ui.add(crate::ui::playlists::Playlists {
+                    playlists: self.state.playlists.as_deref().unwrap_or_default(),
+                    favourites: self
+                        .state
+                        .favourites
+                        .as_ref()
+                        .unwrap_or(&Playlist::default()),
+                    name: &mut self.state.playlist_name,
+                    action: &mut action,
+                });
+                if let Some(action) = action {
+                    if let Err(e) = self.playlist_action(action) {
+                        self.state.errors.push(e.to_string());
+                    }
+                }
+            });
+        self.state.show_playlists = open;
+        let mut open = self.state.show_history;
+        egui::Window::new("History")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let singer = self.state.singer.trim();
+                let stale = self
+                    .state
+                    .stats
+                    .as_ref()
+                    .is_none_or(|s| s.singer != singer);
+                if stale {
+                    self.state.stats = self
+                        .library
+                        .as_ref()
+                        .and_then(|library| Stats::load(library, singer).ok());
+                }
+                if self.state.report_month.is_empty() {
+                    self.state.report_month = chrono::Local::now().format("%Y-%m").to_string();
+                }
+
+                let mut action = None;
+                ui.add(crate::ui::history::History {
+                    stats: self.state.stats.as_ref().unwrap_or(&Stats::default()),
+                    month: &mut self.state.report_month,
+                    action: &mut action,
+                });
+                if let Some(action) = action {
+                    if let Err(e) = self.history_action(action) {
+                        self.state.errors.push(e.to_string());
+                    }
+                }
+            });
+        self.state.show_history = open;
+        egui::Window::new("Mixer").show(ctx, |ui| {
+            let state = self.mixer.read().clone();
+            ui.add(crate::ui::mixer::Mixer {
+                state,
+                midi: &self.midi,
+            });
+        });
+
         // new window
 
         // side panel as an overlay to the current ui
@@ -105,18 +886,47 @@ impl App for Frontend {
 
                     ui.label(format!("Now playing: {}", picked_file));
 
+                    let mixer = self.mixer.read().clone();
+                    let mut guide = mixer.guide_enabled();
+                    let toggle = ui.add_enabled(
+                        mixer.guide_channel.is_some(),
+                        egui::Checkbox::new(&mut guide, "Guide melody"),
+                    );
+                    if toggle.changed() {
+                        self.midi
+                            .send(midi::MidiMessage::Mixer(mixer::MixerCommand::ToggleGuide))
+                            .unwrap_or_default();
+                    }
+
+                    let mut vocal = self.config.vocal.clone();
+                    if ui.add(crate::ui::vocal::Vocal { settings: &mut vocal }).changed() {
+                        self.set_vocal(vocal);
+                    }
+
+                    // MIDI and audio songs take the same key and tempo
+                    let key = ui.add(
+                        egui::Slider::new(&mut self.state.transpose, -12..=12).text("Key"),
+                    );
+                    if key.changed() {
+                        self.playback
+                            .send(PlaybackCommand::Transpose(self.state.transpose));
+                    }
+                    let speed =
+                        ui.add(egui::Slider::new(&mut self.state.speed, 0.5..=1.5).text("Speed"));
+                    if speed.changed() {
+                        self.playback.send(PlaybackCommand::Speed(self.state.speed));
+                    }
+
                     // ui.add(crate::ui::piano::Piano { state: self.state.clone() });
                     ui.horizontal(|ui| {
                         if ui.button("Play").clicked() {
                             if let Some(file) = &self.state.file {
-                                self.msg
-                                    .send(time::PlaybackEvent::Play(file.to_path_buf()))
-                                    .unwrap();
+                                self.playback
+                                    .send(PlaybackCommand::Play(file.to_path_buf()));
                             }
                         }
                         if ui.button("Pause").clicked() {
-                            // self.msg.send(time::PlaybackEvent::Pause).unwrap();
-                            self.msg.send(time::PlaybackEvent::Pause).unwrap();
+                            self.playback.send(PlaybackCommand::TogglePause);
                         }
 
                         if ui.button("Panic!").clicked() {
@@ -159,57 +969,38 @@ impl App for Frontend {
                                 .unwrap();
                         }
                         if ui.button("Stop").clicked() {
-                            // let mut context = self.context.lock();
-                            self.msg.send(time::PlaybackEvent::Stop).unwrap();
+                            self.playback.send(PlaybackCommand::Stop);
                         }
                     });
 
                     ui.horizontal(|ui| {
-                        let default = "00:00:00 / 00:00:00".to_string();
-
-                        // i need a better way to do this.
-                        // this is yandere dev level of spaghetti code
-
-                        let time_txt = if let Some(backend) = &self.context.read().backend {
-                            if let Some(time) = backend.get_time() {
-                                time
-                            } else {
-                                default
-                            }
-                        } else {
-                            default
-                        };
+                        let progress = &self.state.progress;
+                        let time_txt = format!(
+                            "{} / {}",
+                            progress.elapsed.hhmmss(),
+                            progress.total.hhmmss()
+                        );
                         ui.label(&time_txt);
-                        // let context = self.context.lock().backend.as_ref().unwrap();
-
-                        let (elapsed, total) = self
-                            .context
-                            .read()
-                            .backend
-                            .as_ref()
-                            .unwrap_or(&time::PlaybackBackend::Audio)
-                            .get_position();
-                        //
-
-                        let mut time = elapsed as f64;
 
+                        let mut time = progress.tick as f64;
                         let slider = ui.add(
-                            egui::Slider::new(&mut time, 0.0..=total as f64)
+                            egui::Slider::new(&mut time, 0.0..=progress.ticks as f64)
                                 .text(&time_txt)
                                 .show_value(false),
                         );
-                        if slider.drag_started() {
-                            self.msg.send(time::PlaybackEvent::Pause).unwrap();
+                        // hold the song while seeking, but don't resume one that was paused
+                        if slider.drag_started()
+                            && matches!(self.playback.state(), PlaybackState::Playing(_))
+                        {
+                            self.playback.send(PlaybackCommand::Pause);
+                            self.state.resume_after_seek = true;
                         }
-                        if slider.drag_released() {
-                            self.msg.send(time::PlaybackEvent::Pause).unwrap();
+                        if slider.drag_released() && self.state.resume_after_seek {
+                            self.playback.send(PlaybackCommand::Resume);
+                            self.state.resume_after_seek = false;
                         }
                         if slider.dragged() {
-                            // get value
-                            // debug!("time: {}", time);
-                            self.msg
-                                .send(time::PlaybackEvent::Position(time as usize, None, None))
-                                .unwrap();
+                            self.playback.send(PlaybackCommand::Seek(time as usize));
                         }
                     });
                 });
@@ -218,8 +1009,8 @@ impl App for Frontend {
         CentralPanel::default().show(ctx, |ui| {
             ui.label("Hello World!");
             ui.code(RichText::new("aaa").code());
-            // let ctx = self.context.lock();
-            ui.code(format!("{:#?}", *self.context.read_recursive()));
+            ui.label(format!("{:?}", self.playback.state()));
+            ui.code(format!("{:#?}", self.state.progress));
             // text centered
             ui.vertical_centered(|ui| {
                 ui.heading("RustyKaraoke");
@@ -230,13 +1021,15 @@ impl App for Frontend {
         ctx.request_repaint();
     }
 
-    fn save(&mut self, _storage: &mut dyn eframe::Storage) {}
+    fn save(&mut self, _storage: &mut dyn eframe::Storage) {
+        self.save_config();
+    }
 
     fn on_close_event(&mut self) -> bool {
         println!("Closing");
-        self.msg.send(time::PlaybackEvent::Stop).unwrap();
-        // drop(self.msg.to_owned());
-        self.msg.send(time::PlaybackEvent::Exit).unwrap_or_default();
+        self.finish_play(true);
+        self.save_config();
+        self.playback.send(PlaybackCommand::Exit);
         true
     }
 
@@ -288,9 +1081,29 @@ async fn main() {
         // .filter_level(LevelFilter::Debug)
         .init();
 
+    let config = config::Config::load();
+
+    if let Some(command) = cli::Cli::parse().command {
+        if let Err(e) = cli::run(command, &config) {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let (mtx, rx) = crossbeam::channel::unbounded();
+    let (errtx, errrx) = crossbeam::channel::unbounded();
 
-    tokio::spawn(async move { crate::midi::midi_thread(rx, None) });
+    let mixer = Arc::new(RwLock::new(mixer::ChannelMixer::new()));
+    let midi_mixer = Arc::clone(&mixer);
+    let output = Arc::new(RwLock::new(OutputTarget::Synth));
+    let midi_output = Arc::clone(&output);
+
+    let midi_config = config.clone();
+    let midi_errors = errtx.clone();
+    tokio::spawn(async move {
+        crate::midi::midi_thread(rx, None, midi_mixer, midi_config, midi_errors, midi_output)
+    });
 
     tokio::spawn(async move {
         loop {
@@ -320,14 +1133,52 @@ async fn main() {
         ..Default::default()
     };
 
-    let (tx, backrx, mptx) = crate::time::msg_send(mtx.clone());
+    let playback = Playback::spawn(mtx.clone());
+    let log_events = playback.events.subscribe();
+    thread::spawn(move || {
+        for event in log_events {
+            match event {
+                events::PlaybackEvent::Position { .. } => log::trace!("{:?}", event),
+                _ => log::info!("{:?}", event),
+            }
+        }
+    });
+
+    let queue = queue::Queue::spawn(playback.clone(), config.queue.clone());
+
+    let library = library::Library::open_default()
+        .map_err(|e| log::warn!("failed opening the library: {}", e))
+        .ok();
+    // read before the library moves into the app
+    let library_status = library_status(library.as_ref());
+    let songs = library
+        .as_ref()
+        .and_then(|library| library.search_index().ok())
+        .unwrap_or_default();
 
+    let soundfonts = soundfont::initial_setup(&config);
     let app = Frontend {
-        msg: tx,
-        context: backrx,
-        mptx,
+        events: playback.events.subscribe(),
+        queue,
+        playback,
         midi: mtx,
-        state: State::default(),
+        library,
+        mixer,
+        output,
+        errors: errrx,
+        score: None,
+        config,
+        state: State {
+            output_ports: external::list_output_ports().unwrap_or_default(),
+            audio_devices: output::list_output_devices().unwrap_or_default(),
+            input_devices: mic::list_input_devices().unwrap_or_default(),
+            plugins: scan_plugins(),
+            library_status,
+            songs,
+            soundfonts,
+            speed: 1.0,
+            ..Default::default()
+        },
     };
 
     // use funny crossbeam channel to send messages to the main thread
@@ -335,7 +1186,150 @@ async fn main() {
 
     run_native("RustyKaraoke", native_options, Box::new(|_| Box::new(app)));
 }
+
+/// Live scoring for a karaoke song, against its guide melody in the key it plays in
+fn song_score(path: &std::path::Path, transpose: i8) -> anyhow::Result<score::LiveScore> {
+    let karaoke = karaoke::read_karaoke(path).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let smf = midly::Smf::parse(&karaoke.midi)?;
+    let ppq = match smf.header.timing {
+        midly::Timing::Metrical(ppq) => ppq.as_int(),
+        midly::Timing::Timecode(..) => anyhow::bail!("SMPTE timecode MIDI files aren't supported"),
+    };
+    let sheet = match smf.header.format {
+        midly::Format::SingleTrack | midly::Format::Sequential => {
+            nodi::Sheet::sequential(&smf.tracks)
+        }
+        midly::Format::Parallel => nodi::Sheet::parallel(&smf.tracks),
+    };
+
+    let (scorer, _) = score::song_scorer(&karaoke, &sheet, ppq, transpose)?;
+    Ok(score::LiveScore::new(scorer, &sheet, ppq))
+}
+
+fn library_status(library: Option<&library::Library>) -> String {
+    match library.map(|library| library.count()) {
+        Some(Ok(count)) => format!("{} songs", count),
+        Some(Err(e)) => format!("Failed reading the library: {}", e),
+        None => "The library couldn't be opened".to_string(),
+    }
+}
+
+/// Scans the library on its own thread, the summary comes back when it's done
+fn scan_library(roots: Vec<PathBuf>) -> crossbeam::channel::Receiver<String> {
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    thread::spawn(move || {
+        let status = library::Library::open_default()
+            .and_then(|mut library| Ok((library.scan(&roots, false)?, library.count()?)))
+            .map_or_else(
+                |e| format!("Scan failed: {}", e),
+                |(report, count)| {
+                    format!(
+                        "{} songs, {} added, {} updated, {} removed, {} failed",
+                        count, report.added, report.updated, report.removed, report.failed
+                    )
+                },
+            );
+        tx.send(status).unwrap_or_default();
+    });
+    rx
+}
+
+/// Asks where to save a playlist and writes it there
+fn export_playlist(playlist: &Playlist, library: &library::Library) -> anyhow::Result<()> {
+    let path = native_dialog::FileDialog::new()
+        .set_filename(&format!("{}.m3u", playlist.name))
+        .add_filter("M3U playlist", &["m3u"])
+        .add_filter("JSON", &["json"])
+        .show_save_single_file()?;
+    if let Some(path) = path {
+        playlist.export(&path, library)?;
+    }
+    Ok(())
+}
+
+/// Looks for plugins, only the ones installed or updated since the last run get loaded
+fn scan_plugins() -> plugin::scan::ScanCache {
+    let mut cache = plugin::scan::ScanCache::load();
+    cache.rescan();
+    if let Err(e) = cache.save() {
+        log::warn!("failed saving plugin cache: {}", e);
+    }
+    cache
+}
 #[derive(Debug, Clone, Default)]
 pub struct State {
     pub file: Option<PathBuf>,
+    /// Name for the next queue entry
+    pub singer: String,
+    /// MIDI output ports found on the last refresh
+    pub output_ports: Vec<String>,
+    /// Reset sent to the synth before each song
+    pub reset_mode: sysex::ResetMode,
+    /// Audio output devices found on the last refresh
+    pub audio_devices: Vec<String>,
+    /// Audio input devices for the mic, found on the last refresh
+    pub input_devices: Vec<String>,
+    /// Installed effect plugins
+    pub plugins: plugin::scan::ScanCache,
+    /// Summary of the song library, or how the last scan went
+    pub library_status: String,
+    /// Set while the library is being scanned
+    pub library_scan: Option<crossbeam::channel::Receiver<String>>,
+    /// Library songs for searching
+    pub songs: library::search::SearchIndex,
+    pub search: String,
+    /// Songs found for `search`, `None` when it needs to run again
+    pub search_results: Option<Vec<library::Song>>,
+    /// Playlist names, `None` to read them again
+    pub playlists: Option<Vec<String>>,
+    /// Favourites of `singer`, read again when the singer changes
+    pub favourites: Option<library::playlists::Playlist>,
+    /// Name typed in for saving the queue as a playlist
+    pub playlist_name: String,
+    /// Play record of the song playing, closed when it ends or stops
+    pub play: Option<library::history::PlayId>,
+    /// Play statistics, read again when the singer changes
+    pub stats: Option<Stats>,
+    /// Month for the CSV reports, like `2022-11`
+    pub report_month: String,
+    /// Song code being typed on the numpad
+    pub keypad: keypad::CodeEntry,
+    /// Errors waiting to be dismissed
+    pub errors: Vec<String>,
+    /// Soundfont setup being edited, applied with the button
+    pub soundfonts: soundfont::SoundfontSetup,
+    /// Config edits not written to disk yet, see [Frontend::save_config]
+    pub config_changed: bool,
+    /// Song position and length, from the playback events
+    pub progress: events::Progress,
+    /// The song was paused for dragging the position slider
+    pub resume_after_seek: bool,
+    /// When the last position came in, to tell where the song is between them
+    pub position_at: Option<std::time::Instant>,
+    /// Score the singer through the mic, see [Frontend::score]
+    pub scoring: bool,
+    /// Score of the last song sung to the end
+    pub last_score: Option<score::Score>,
+    /// Key change in semitones, for every song
+    pub transpose: i8,
+    /// Tempo multiplier, for every song
+    pub speed: f32,
+    /// Soundfonts window is open
+    pub show_soundfonts: bool,
+    /// Master window is open
+    pub show_master: bool,
+    /// Microphone window is open
+    pub show_microphone: bool,
+    /// Score window is open
+    pub show_score: bool,
+    /// Plugins window is open
+    pub show_plugins: bool,
+    /// Queue window is open
+    pub show_queue: bool,
+    /// Library window is open
+    pub show_library: bool,
+    /// Playlists window is open
+    pub show_playlists: bool,
+    /// History window is open
+    pub show_history: bool,
 }
@@ -0,0 +1,682 @@
+//! Microphone input
+//!
+//! The mic is captured through cpal, run through gain, EQ, echo, reverb and any
+//! plugins and mixed into the synth output before the master chain. Instead of a
+//! device the input can loop a WAV file, which is handy for testing without a mic
+//! plugged in.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, InputCallbackInfo, Sample, SampleFormat, SampleRate, Stream, StreamConfig,
+};
+use crossbeam::{channel::Sender, queue::ArrayQueue};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dsp::Biquad,
+    plugin::{PluginChain, PluginSlot},
+};
+
+/// Captured audio kept before it's dropped to stay in time, in seconds
+const MAX_LATENCY: f32 = 0.03;
+
+/// Longest echo delay, in seconds
+const MAX_DELAY: f32 = 1.0;
+
+/// Samples a [MicTap] holds before dropping, 10 seconds at 96kHz
+const TAP_CAPACITY: usize = 960_000;
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum MicSource {
+    /// Capture device, see [MicSettings::device]
+    #[default]
+    Device,
+    /// Loops a WAV file instead of a device
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MicSettings {
+    pub enabled: bool,
+    pub source: MicSource,
+    /// Input device name, `None` uses the default
+    pub device: Option<String>,
+    /// Linear gain
+    pub gain: f32,
+    /// Play the mic through the speakers
+    pub monitor: bool,
+    pub eq: EqSettings,
+    pub echo: EchoSettings,
+    pub reverb: MicReverbSettings,
+    /// Effect plugins after the built-in effects
+    pub plugins: Vec<PluginSlot>,
+}
+
+impl Default for MicSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            source: MicSource::Device,
+            device: None,
+            gain: 1.0,
+            monitor: true,
+            eq: EqSettings::default(),
+            echo: EchoSettings::default(),
+            reverb: MicReverbSettings::default(),
+            plugins: Vec::new(),
+        }
+    }
+}
+
+/// Three band EQ, gains in dB
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EqSettings {
+    pub low: f32,
+    pub mid: f32,
+    pub high: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EchoSettings {
+    pub enabled: bool,
+    pub delay_ms: f32,
+    pub feedback: f32,
+    /// How loud the echo is next to the dry signal
+    pub mix: f32,
+}
+
+impl Default for EchoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            delay_ms: 180.0,
+            feedback: 0.35,
+            mix: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MicReverbSettings {
+    pub enabled: bool,
+    pub room_size: f32,
+    pub damping: f32,
+    pub mix: f32,
+}
+
+impl Default for MicReverbSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            room_size: 0.5,
+            damping: 0.5,
+            mix: 0.2,
+        }
+    }
+}
+
+/// Names of the available input devices
+pub fn list_input_devices() -> Result<Vec<String>> {
+    let host = cpal::default_host();
+    let names = host
+        .input_devices()?
+        .filter_map(|dev| dev.name().ok())
+        .collect();
+
+    Ok(names)
+}
+
+/// Raw mic samples, before any effects, for the pitch tracker
+pub struct MicTap {
+    queue: ArrayQueue<f32>,
+    /// Rate of the mic the tap is attached to, `0` until then
+    sample_rate: AtomicU32,
+}
+
+impl MicTap {
+    pub fn new() -> Self {
+        Self {
+            queue: ArrayQueue::new(TAP_CAPACITY),
+            sample_rate: AtomicU32::new(0),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn pop(&self) -> Option<f32> {
+        self.queue.pop()
+    }
+}
+
+impl Default for MicTap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A running mic input, mixed into the output by the audio callback
+pub struct MicInput {
+    source: Source,
+    chain: MicChain,
+    monitor: bool,
+    sample_rate: u32,
+    tap: Option<Arc<MicTap>>,
+    /// Stereo scratch for the plugins, allocated when opening so the audio
+    /// thread never has to
+    block: Vec<f32>,
+}
+
+enum Source {
+    /// Filled by the capture stream
+    Queue {
+        queue: Arc<ArrayQueue<f32>>,
+        max_latency: usize,
+    },
+    Samples {
+        samples: Vec<f32>,
+        pos: usize,
+    },
+}
+
+impl MicInput {
+    /// Opens the configured source at the output sample rate, for output
+    /// buffers of up to `max_frames`.
+    ///
+    /// Device capture also returns its stream, which has to be kept alive
+    /// but can't be moved into the audio callback.
+    pub fn open(
+        settings: &MicSettings,
+        sample_rate: u32,
+        max_frames: usize,
+        errors: Sender<String>,
+    ) -> Result<(Self, Option<Stream>)> {
+        match &settings.source {
+            MicSource::Device => {
+                let (queue, stream) = open_device(settings.device.as_deref(), sample_rate, errors)?;
+                let source = Source::Queue {
+                    queue,
+                    max_latency: (sample_rate as f32 * MAX_LATENCY) as usize,
+                };
+                let mic = Self::new(source, settings, sample_rate, max_frames);
+                Ok((mic, Some(stream)))
+            }
+            MicSource::File(path) => {
+                let samples = load_file(path, sample_rate)?;
+                let mic = Self::from_samples(samples, settings, sample_rate, max_frames);
+                Ok((mic, None))
+            }
+        }
+    }
+
+    /// Loops mono samples at the output sample rate
+    pub fn from_samples(
+        samples: Vec<f32>,
+        settings: &MicSettings,
+        sample_rate: u32,
+        max_frames: usize,
+    ) -> Self {
+        let source = Source::Samples { samples, pos: 0 };
+        Self::new(source, settings, sample_rate, max_frames)
+    }
+
+    fn new(source: Source, settings: &MicSettings, sample_rate: u32, max_frames: usize) -> Self {
+        Self {
+            source,
+            chain: MicChain::new(settings, sample_rate),
+            monitor: settings.monitor,
+            sample_rate,
+            tap: None,
+            block: vec![0.0; max_frames.max(1) * 2],
+        }
+    }
+
+    /// Starts or stops copying the raw input to a tap
+    pub fn set_tap(&mut self, tap: Option<Arc<MicTap>>) {
+        if let Some(tap) = &tap {
+            tap.sample_rate.store(self.sample_rate, Ordering::Relaxed);
+        }
+        self.tap = tap;
+    }
+
+    /// Whether the new settings can be used without reopening the source
+    pub fn same_source(a: &MicSettings, b: &MicSettings) -> bool {
+        a.enabled == b.enabled && a.source == b.source && a.device == b.device
+    }
+
+    /// Updates the effects, keeping the source and the effect tails
+    pub fn set_settings(&mut self, settings: &MicSettings) {
+        self.chain.set_settings(settings);
+        self.monitor = settings.monitor;
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        match &mut self.source {
+            Source::Queue { queue, max_latency } => {
+                // drop what piled up so the monitor stays in time
+                while queue.len() > *max_latency {
+                    queue.pop();
+                }
+                queue.pop().unwrap_or(0.0)
+            }
+            Source::Samples { samples, pos } => {
+                let s = samples.get(*pos).copied().unwrap_or(0.0);
+                *pos = if *pos + 1 < samples.len() {
+                    *pos + 1
+                } else {
+                    0
+                };
+                s
+            }
+        }
+    }
+
+    /// Adds the processed mic to an interleaved stereo buffer. A buffer bigger
+    /// than the device said it would ask for is done in parts.
+    pub fn mix_into(&mut self, out: &mut [f32], plugins: &mut PluginChain) {
+        // taken out so next_sample can borrow self, handed back below
+        let mut scratch = std::mem::take(&mut self.block);
+        for out in out.chunks_mut(scratch.len()) {
+            let block = &mut scratch[..out.len()];
+            for frame in block.chunks_mut(2) {
+                let s = self.next_sample();
+                if let Some(tap) = &self.tap {
+                    tap.queue.force_push(s);
+                }
+                frame.fill(self.chain.process(s));
+            }
+
+            plugins.process(block);
+            if self.monitor {
+                for (o, s) in out.iter_mut().zip(&*block) {
+                    *o += s;
+                }
+            }
+        }
+        self.block = scratch;
+    }
+}
+
+fn open_device(
+    name: Option<&str>,
+    sample_rate: u32,
+    errors: Sender<String>,
+) -> Result<(Arc<ArrayQueue<f32>>, Stream)> {
+    let host = cpal::default_host();
+
+    let dev = name
+        .and_then(|name| {
+            let dev = host
+                .input_devices()
+                .ok()?
+                .find(|dev| dev.name().is_ok_and(|n| n == name));
+            if dev.is_none() {
+                warn!("input device {} not found, using the default", name);
+            }
+            dev
+        })
+        .or_else(|| host.default_input_device())
+        .ok_or_else(|| anyhow!("no audio input device detected"))?;
+
+    // capture at the output rate if the device can, otherwise resample
+    let rate = SampleRate(sample_rate);
+    let supported = dev
+        .supported_input_configs()?
+        .filter(|c| c.min_sample_rate() <= rate && rate <= c.max_sample_rate())
+        .max_by_key(|c| (c.channels() == 1, c.sample_format() == SampleFormat::F32))
+        .map(|c| c.with_sample_rate(rate));
+
+    let supported = match supported {
+        Some(c) => c,
+        None => dev.default_input_config()?,
+    };
+    let format = supported.sample_format();
+    let config = supported.config();
+
+    debug!(
+        "opening input device {:?} with {:?} ({:?})",
+        dev.name(),
+        config,
+        format
+    );
+
+    let queue = Arc::new(ArrayQueue::new(sample_rate as usize));
+    let stream = match format {
+        SampleFormat::F32 => build_input::<f32>(&dev, &config, &queue, sample_rate, errors),
+        SampleFormat::I16 => build_input::<i16>(&dev, &config, &queue, sample_rate, errors),
+        SampleFormat::U16 => build_input::<u16>(&dev, &config, &queue, sample_rate, errors),
+    }?;
+    stream.play()?;
+
+    Ok((queue, stream))
+}
+
+fn build_input<T: Sample>(
+    dev: &Device,
+    config: &StreamConfig,
+    queue: &Arc<ArrayQueue<f32>>,
+    sample_rate: u32,
+    errors: Sender<String>,
+) -> Result<Stream> {
+    let channels = config.channels as usize;
+    let mut resampler = Resampler::new(config.sample_rate.0, sample_rate);
+    let queue = Arc::clone(queue);
+
+    let err_fn = move |e| {
+        error!("error [input stream]: {e}");
+        errors
+            .send(format!("Microphone error: {}", e))
+            .unwrap_or_default();
+    };
+
+    let stream = dev.build_input_stream(
+        config,
+        move |data: &[T], _: &InputCallbackInfo| {
+            for frame in data.chunks(channels) {
+                let s = frame.iter().map(|s| s.to_f32()).sum::<f32>() / channels as f32;
+                resampler.push(s, |s| {
+                    queue.force_push(s);
+                });
+            }
+        },
+        err_fn,
+    )?;
+
+    Ok(stream)
+}
+
+/// Reads a WAV file as interleaved `f32` samples
+pub fn read_wav(path: &Path) -> Result<(hound::WavSpec, Vec<f32>)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1_u32 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+
+    Ok((spec, samples))
+}
+
+/// Reads a WAV file as mono at `sample_rate`
+pub fn load_file(path: &Path, sample_rate: u32) -> Result<Vec<f32>> {
+    let (spec, samples) = read_wav(path)?;
+
+    let channels = spec.channels as usize;
+    let mut resampler = Resampler::new(spec.sample_rate, sample_rate);
+    let mut out = Vec::with_capacity(samples.len() / channels);
+    for frame in samples.chunks(channels) {
+        let s = frame.iter().sum::<f32>() / channels as f32;
+        resampler.push(s, |s| out.push(s));
+    }
+
+    Ok(out)
+}
+
+/// Linear interpolation resampler, good enough for a voice
+pub struct Resampler {
+    /// Input samples per output sample
+    step: f64,
+    /// Position between the previous and the current input sample
+    pos: f64,
+    prev: f32,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Self {
+        let step = from as f64 / to as f64;
+        // the first output is one step after the silence before the input
+        Self {
+            step,
+            pos: step,
+            prev: 0.0,
+        }
+    }
+
+    /// Feeds one input sample, `out` is called for every output sample
+    pub fn push(&mut self, s: f32, mut out: impl FnMut(f32)) {
+        while self.pos <= 1.0 {
+            out(self.prev + (s - self.prev) * self.pos as f32);
+            self.pos += self.step;
+        }
+        self.pos -= 1.0;
+        self.prev = s;
+    }
+}
+
+/// Gain, EQ, echo and reverb for the mic
+pub struct MicChain {
+    sample_rate: u32,
+    gain: f32,
+    eq: [Biquad; 3],
+    echo: EchoSettings,
+    delay: Vec<f32>,
+    delay_pos: usize,
+    reverb_settings: MicReverbSettings,
+    reverb: Reverb,
+}
+
+impl MicChain {
+    pub fn new(settings: &MicSettings, sample_rate: u32) -> Self {
+        let mut chain = Self {
+            sample_rate,
+            gain: 1.0,
+            eq: [Biquad::default(); 3],
+            echo: EchoSettings::default(),
+            delay: vec![0.0; (sample_rate as f32 * MAX_DELAY) as usize + 1],
+            delay_pos: 0,
+            reverb_settings: MicReverbSettings::default(),
+            reverb: Reverb::new(sample_rate),
+        };
+        chain.set_settings(settings);
+        chain
+    }
+
+    pub fn set_settings(&mut self, settings: &MicSettings) {
+        let sr = self.sample_rate as f32;
+        self.gain = settings.gain;
+        let eq = [
+            Biquad::low_shelf(sr, 250.0, settings.eq.low),
+            Biquad::peaking(sr, 1500.0, 1.0, settings.eq.mid),
+            Biquad::high_shelf(sr, 5000.0, settings.eq.high),
+        ];
+        for (f, to) in self.eq.iter_mut().zip(eq) {
+            f.retune(to);
+        }
+        self.echo = settings.echo.clone();
+        self.reverb_settings = settings.reverb.clone();
+        self.reverb
+            .set(settings.reverb.room_size, settings.reverb.damping);
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let mut x = x * self.gain;
+        for f in &mut self.eq {
+            x = f.process(x);
+        }
+
+        if self.echo.enabled {
+            let len = self.delay.len();
+            let delay = ((self.echo.delay_ms / 1000.0 * self.sample_rate as f32) as usize)
+                .clamp(1, len - 1);
+            let delayed = self.delay[(self.delay_pos + len - delay) % len];
+            self.delay[self.delay_pos] = x + delayed * self.echo.feedback;
+            self.delay_pos = (self.delay_pos + 1) % len;
+            x += delayed * self.echo.mix;
+        }
+
+        if self.reverb_settings.enabled {
+            x += self.reverb.process(x) * self.reverb_settings.mix;
+        }
+
+        x
+    }
+}
+
+/// Small Freeverb style reverb: parallel combs into series allpasses
+struct Reverb {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+/// Freeverb's tunings at 44.1kHz
+const COMB_TUNING: [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_TUNING: [usize; 2] = [556, 441];
+
+impl Reverb {
+    fn new(sample_rate: u32) -> Self {
+        let scale = |n: usize| (n as f32 * sample_rate as f32 / 44100.0) as usize;
+        Self {
+            combs: COMB_TUNING.iter().map(|n| Comb::new(scale(*n))).collect(),
+            allpasses: ALLPASS_TUNING
+                .iter()
+                .map(|n| Allpass::new(scale(*n)))
+                .collect(),
+        }
+    }
+
+    fn set(&mut self, room_size: f32, damping: f32) {
+        for comb in &mut self.combs {
+            comb.feedback = room_size * 0.28 + 0.7;
+            comb.damping = damping * 0.4;
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let input = x * 0.015;
+        let mut out = self.combs.iter_mut().map(|c| c.process(input)).sum::<f32>();
+        for ap in &mut self.allpasses {
+            out = ap.process(out);
+        }
+        out
+    }
+}
+
+struct Comb {
+    buf: Vec<f32>,
+    pos: usize,
+    feedback: f32,
+    damping: f32,
+    filter: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0.0; len.max(1)],
+            pos: 0,
+            feedback: 0.84,
+            damping: 0.2,
+            filter: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let out = self.buf[self.pos];
+        self.filter = out * (1.0 - self.damping) + self.filter * self.damping;
+        self.buf[self.pos] = x + self.filter * self.feedback;
+        self.pos = (self.pos + 1) % self.buf.len();
+        out
+    }
+}
+
+struct Allpass {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0.0; len.max(1)],
+            pos: 0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let delayed = self.buf[self.pos];
+        self.buf[self.pos] = x + delayed * 0.5;
+        self.pos = (self.pos + 1) % self.buf.len();
+        delayed - x
+    }
+}
+
+#[cfg(test)]
+fn dry() -> MicSettings {
+    MicSettings {
+        echo: EchoSettings {
+            enabled: false,
+            ..Default::default()
+        },
+        reverb: MicReverbSettings {
+            enabled: false,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_dry_passthrough() {
+    let settings = MicSettings { gain: 2.0, ..dry() };
+    // a smaller buffer than the output, mixed in two parts
+    let mut mic = MicInput::from_samples(vec![0.1, 0.2, 0.3], &settings, 48000, 2);
+
+    let mut out = [0.0; 8];
+    mic.mix_into(&mut out, &mut PluginChain::default());
+    let expected = [0.2, 0.2, 0.4, 0.4, 0.6, 0.6, 0.2, 0.2];
+    for (o, e) in out.iter().zip(expected) {
+        assert!((o - e).abs() < 1e-5, "{:?}", out);
+    }
+}
+
+#[test]
+fn test_echo() {
+    let settings = MicSettings {
+        echo: EchoSettings {
+            enabled: true,
+            delay_ms: 1.0,
+            feedback: 0.5,
+            mix: 1.0,
+        },
+        ..dry()
+    };
+    let mut chain = MicChain::new(&settings, 1000);
+
+    // an impulse comes back every millisecond, halving each time
+    let out = (0..4)
+        .map(|i| chain.process(if i == 0 { 1.0 } else { 0.0 }))
+        .collect::<Vec<_>>();
+    assert_eq!(out, [1.0, 1.0, 0.5, 0.25]);
+}
+
+#[test]
+fn test_resampler() {
+    let mut out = Vec::new();
+    let mut resampler = Resampler::new(24000, 48000);
+    for s in [1.0, 1.0, 0.0] {
+        resampler.push(s, |s| out.push(s));
+    }
+    assert_eq!(out, [0.5, 1.0, 1.0, 1.0, 0.5, 0.0]);
+}
@@ -12,7 +12,8 @@ use rayon::prelude::IntoParallelRefMutIterator;
 /// MIDI player code
 use std::{
     fmt,
-    fs::{self, File},
+    fs,
+    io::Cursor,
     path::{Path, PathBuf},
     sync::{Arc, atomic::AtomicUsize},
 };
@@ -24,39 +25,71 @@ use nodi::{
 };
 
 use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    BuildStreamError, DefaultStreamConfigError, OutputCallbackInfo, PlayStreamError, SampleFormat,
-    Stream,
+    BuildStreamError, DefaultStreamConfigError, PlayStreamError, Stream,
+    SupportedStreamConfigsError,
 };
 use log::{debug, error, info, trace, warn};
 use parking_lot::{Mutex, RwLock};
 
 use crate::{
+    audio::AudioTrack,
+    config::{AudioConfig, Config},
+    dsp::{MasterChain, MasterSettings},
+    events::{EventBus, PlaybackEvent},
+    external::{ExternalOutput, OutputTarget},
+    mic::{MicInput, MicSettings, MicTap},
+    mixer::{ChannelMixer, MixerCommand},
+    plugin::{self, PluginChain},
+    soundfont::SoundfontSetup,
+    sysex::{self, ResetMode, SysExMessage},
     tick::{scroll, CurData},
-    time::{PlaybackContext, PlaybackEvent},
+    time::PlaybackContext,
+    vocal::VocalReducerSettings,
 };
-const DEFAULT_SOUNDFONT: &str = {
-    if cfg!(windows) {
-        r"C:\soundfonts\default.sf2"
-    } else {
-        "/usr/share/soundfonts/default.sf2"
-    }
-};
-
 #[derive(Debug)]
 pub enum Error {
     Soundfont {
         path: PathBuf,
         error: fluidlite::Error,
     },
+    /// Soundfont file couldn't be read
+    SoundfontOpen {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// Soundfont file isn't a valid SF2/SF3
+    SoundfontLoad {
+        path: PathBuf,
+        error: anyhow::Error,
+    },
+    /// Discovery found nothing to load
+    NoSoundfont,
     Fluidlite(fluidlite::Error),
     NoOutputDevice,
     DefaultStreamConfig(DefaultStreamConfigError),
+    SupportedStreamConfigs(SupportedStreamConfigsError),
     BuildStream(BuildStreamError),
     PlayStream(PlayStreamError),
+    /// Effect plugin failed to load
+    Plugin(anyhow::Error),
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Soundfont { error, .. } => Some(error),
+            Self::SoundfontOpen { error, .. } => Some(error),
+            Self::SoundfontLoad { error, .. } => Some(error.as_ref()),
+            Self::Fluidlite(e) => Some(e),
+            Self::DefaultStreamConfig(e) => Some(e),
+            Self::SupportedStreamConfigs(e) => Some(e),
+            Self::BuildStream(e) => Some(e),
+            Self::PlayStream(e) => Some(e),
+            Self::Plugin(e) => Some(e.as_ref()),
+            Self::NoSoundfont | Self::NoOutputDevice => None,
+        }
+    }
+}
 
 impl From<fluidlite::Error> for Error {
     fn from(e: fluidlite::Error) -> Self {
@@ -73,19 +106,83 @@ impl fmt::Display for Error {
                 path.display(),
                 error
             ),
+            Self::SoundfontOpen { path, error } => write!(
+                f,
+                "failed opening the soundfont {} ({})",
+                path.display(),
+                error
+            ),
+            Self::SoundfontLoad { path, error } => write!(
+                f,
+                "{} is not a valid soundfont ({})",
+                path.display(),
+                error
+            ),
+            Self::NoSoundfont => write!(
+                f,
+                "no soundfont found, set {} or add one to the config",
+                crate::soundfont::SOUNDFONT_ENV
+            ),
             Self::Fluidlite(e) => e.fmt(f),
             Self::NoOutputDevice => f.write_str("no audio output device detected"),
             Self::DefaultStreamConfig(e) => e.fmt(f),
+            Self::SupportedStreamConfigs(e) => e.fmt(f),
             Self::BuildStream(e) => e.fmt(f),
             Self::PlayStream(e) => e.fmt(f),
+            Self::Plugin(e) => e.fmt(f),
         }
     }
 }
 
+/// Gain oxisynth starts with, put back after a reset
+const SYNTH_GAIN: f32 = 0.2;
+
+/// How often the player publishes [PlaybackEvent::Position]
+const POSITION_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
 pub struct Fluid {
     pub synth: Arc<Mutex<Synth>>,
     // pub context: Arc<Mutex<MidiContext>>,
-    _stream: Stream,
+    /// `None` when rendering offline
+    _stream: Option<Stream>,
+    /// Gain and limiter after the synth
+    master: Arc<Mutex<MasterChain>>,
+    /// Plugins in front of the master chain
+    plugins: Arc<Mutex<PluginChain>>,
+    /// Mixed in between the synth and the master chain
+    mic: Arc<Mutex<Option<MicInput>>>,
+    /// Plugins on the mic, after its built-in effects
+    mic_plugins: Arc<Mutex<PluginChain>>,
+    /// Capture stream, kept apart from `mic` since it can't go to the audio thread
+    _mic_stream: Option<Stream>,
+    mic_settings: MicSettings,
+    /// Kept here so it survives the mic being reopened
+    mic_tap: Option<Arc<MicTap>>,
+    /// Audio song mixed in next to the synth
+    track: Arc<Mutex<Option<Arc<Mutex<AudioTrack>>>>>,
+    /// Output sample rate, the mic is resampled to it
+    sample_rate: u32,
+    /// Most frames the output asks for at once, the mic's buffer is sized for it
+    max_frames: usize,
+    /// Last program on every channel, to re-apply after a drum part change
+    programs: [u8; 16],
+    /// Last bank on every channel, for matching font overrides
+    banks: [u32; 16],
+    /// Loaded fonts with their synth ids, highest priority first
+    fonts: Vec<(PathBuf, FontId)>,
+    setup: SoundfontSetup,
+}
+
+/// Id oxisynth gives a loaded soundfont
+type FontId = oxisynth::TypedIndex<SoundFont>;
+
+/// Banks after a reset, channel 10 plays drums
+const DEFAULT_BANKS: [u32; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 0, 0, 0, 0, 0, 0];
+
+/// A [Connection] that also takes System Exclusive messages
+pub trait SysExConnection: Connection {
+    /// Sends a complete SysEx message, including `F0` and `F7`
+    fn send_sys_ex(&mut self, msg: &[u8]) -> bool;
 }
 
 // MIDI display for debugging and stuff
@@ -144,94 +241,377 @@ lazy_static! {
 }
 
 impl Fluid {
-    pub fn new<P: AsRef<Path>>(sf: P) -> Result<Self, Error> {
+    /// Starts the synth with a soundfont setup.
+    ///
+    /// Fails if none of the fonts load, the ones that fail otherwise are sent to `errors`.
+    pub fn new(
+        setup: &SoundfontSetup,
+        audio: &AudioConfig,
+        master: &MasterSettings,
+        errors: Sender<String>,
+    ) -> Result<Self, Error> {
+        let mut fluid = Self::with_synth(Synth::default(), master);
+        let (stream, sample_rate, max_frames) = output_stream(&fluid, audio, errors.clone())?;
+        fluid._stream = Some(stream);
+        fluid.sample_rate = sample_rate;
+        fluid.max_frames = max_frames;
+        for e in fluid.set_master(master) {
+            errors.send(format!("{:#}", e)).unwrap_or_default();
+        }
+
+        let mut failed = fluid.set_soundfonts(setup).into_iter();
+        if fluid.fonts.is_empty() {
+            return Err(failed.next().unwrap_or(Error::NoSoundfont));
+        }
+        for e in failed {
+            errors.send(e.to_string()).unwrap_or_default();
+        }
+
+        Ok(fluid)
+    }
+
+    /// Starts the synth without an audio device, samples are pulled with [Fluid::write].
+    ///
+    /// Every font has to load so renders don't silently change.
+    pub fn offline(
+        setup: &SoundfontSetup,
+        sample_rate: u32,
+        master: &MasterSettings,
+    ) -> Result<Self, Error> {
         let mut fl = Synth::default();
+        fl.set_sample_rate(sample_rate as f32);
 
-        // Load soundfont
-        {
-            let mut file = File::open(sf.as_ref()).unwrap();
-            let font = SoundFont::load(&mut file).unwrap();
-            info!("Loading soundfont {}", sf.as_ref().display());
-            fl.add_font(font, true);
+        let mut fluid = Self::with_synth(fl, master);
+        fluid.sample_rate = sample_rate;
+        if let Some(e) = fluid.set_master(master).into_iter().next() {
+            return Err(Error::Plugin(e));
         }
+        if let Some(e) = fluid.set_soundfonts(setup).into_iter().next() {
+            return Err(e);
+        }
+        if fluid.fonts.is_empty() {
+            return Err(Error::NoSoundfont);
+        }
+
+        Ok(fluid)
+    }
 
-        // Initialize the audio stream.
-        let err_fn = |e| error!("error [audio stream]: {e}");
-        let host = cpal::default_host();
-        let dev = host.default_output_device().ok_or(Error::NoOutputDevice)?;
-
-        let config = dev
-            .default_output_config()
-            .map_err(Error::DefaultStreamConfig)?;
-        fl.set_sample_rate(config.sample_rate().0 as f32);
-        let synth = Arc::new(Mutex::new(fl));
-        let fl = Arc::clone(&synth);
-
-        let format = config.sample_format();
-        let config = config.config();
-
-        // config.buffer_size = BufferSize::Fixed(2048);
-
-        // println!("config: {:?}", config);
-
-        let stream = match format {
-            SampleFormat::F32 => {
-                let stream = dev
-                    .build_output_stream(
-                        &config,
-                        move |data: &mut [f32], _: &OutputCallbackInfo| {
-                            // debug!("{:?}", data);
-                            fl.lock().write(data);
-                        },
-                        err_fn,
-                    )
-                    .unwrap();
-                stream.play().unwrap();
-                stream
+    fn with_synth(synth: Synth, master: &MasterSettings) -> Self {
+        let mut fluid = Self {
+            synth: Arc::new(Mutex::new(synth)),
+            _stream: None,
+            master: Arc::new(Mutex::new(MasterChain::new(master.clone()))),
+            plugins: Arc::new(Mutex::new(PluginChain::default())),
+            mic: Arc::new(Mutex::new(None)),
+            mic_plugins: Arc::new(Mutex::new(PluginChain::default())),
+            _mic_stream: None,
+            mic_settings: MicSettings::default(),
+            mic_tap: None,
+            track: Arc::new(Mutex::new(None)),
+            sample_rate: 44100,
+            max_frames: 0,
+            // context: ctx,
+            programs: [0; 16],
+            banks: DEFAULT_BANKS,
+            fonts: Vec::new(),
+            setup: SoundfontSetup::default(),
+        };
+        // plugins are loaded by the callers once the sample rate is known
+        fluid.set_master(&MasterSettings {
+            plugins: Vec::new(),
+            ..master.clone()
+        });
+        fluid
+    }
+
+    /// Reopens the audio output with new settings, keeping the loaded soundfonts
+    pub fn set_audio(&mut self, audio: &AudioConfig, errors: Sender<String>) -> Result<(), Error> {
+        self.all_notes_off();
+        let (stream, sample_rate, max_frames) = output_stream(self, audio, errors.clone())?;
+        self._stream = Some(stream);
+
+        // the mic is resampled for the output and its buffer sized for it, plugins
+        // are activated at a fixed rate, so all of them have to follow a change
+        let rate_changed = sample_rate != self.sample_rate;
+        if rate_changed || max_frames != self.max_frames {
+            self.sample_rate = sample_rate;
+            self.max_frames = max_frames;
+            if let Err(e) = self.open_mic(errors.clone()) {
+                errors
+                    .send(format!("Failed to open microphone: {}", e))
+                    .unwrap_or_default();
             }
-            SampleFormat::I16 => {
-                let stream = dev
-                    .build_output_stream(
-                        &config,
-                        move |data: &mut [f32], _: &OutputCallbackInfo| {
-                            fl.lock().write(data);
-                        },
-                        err_fn,
-                    )
-                    .unwrap();
-                stream.play().unwrap();
-                stream
+        }
+        if rate_changed {
+            if let Some(track) = self.track.lock().as_ref() {
+                track.lock().set_output_rate(sample_rate);
             }
-            SampleFormat::U16 => {
-                let stream = dev
-                    .build_output_stream(
-                        &config,
-                        move |data: &mut [f32], _: &OutputCallbackInfo| {
-                            fl.lock().write(data);
-                        },
-                        err_fn,
-                    )
-                    .unwrap();
-                stream.play().unwrap();
-                stream
+
+            let master = self.master.lock().settings.plugins.clone();
+            let failed = plugin::apply(&self.plugins, &master, sample_rate)
+                .into_iter()
+                .chain(plugin::apply(&self.mic_plugins, &self.mic_settings.plugins, sample_rate));
+            for e in failed {
+                errors.send(format!("{:#}", e)).unwrap_or_default();
             }
-        };
+        }
+        Ok(())
+    }
 
-        Ok(Self {
-            synth: Arc::clone(&synth),
-            _stream: stream,
-            // context: ctx,
-        })
+    /// Applies mic settings, the input is only reopened if the source changed
+    pub fn set_mic(&mut self, settings: &MicSettings, errors: Sender<String>) -> Result<()> {
+        let same = MicInput::same_source(&self.mic_settings, settings);
+        self.mic_settings = settings.clone();
+
+        for e in plugin::apply(&self.mic_plugins, &settings.plugins, self.sample_rate) {
+            errors.send(format!("{:#}", e)).unwrap_or_default();
+        }
+
+        if same {
+            if let Some(mic) = self.mic.lock().as_mut() {
+                mic.set_settings(settings);
+                return Ok(());
+            }
+        }
+        self.open_mic(errors)
     }
+
+    /// Copies the raw mic input to `tap`, for scoring
+    pub fn set_mic_tap(&mut self, tap: Option<Arc<MicTap>>) {
+        if let Some(mic) = self.mic.lock().as_mut() {
+            mic.set_tap(tap.clone());
+        }
+        self.mic_tap = tap;
+    }
+
+    fn open_mic(&mut self, errors: Sender<String>) -> Result<()> {
+        *self.mic.lock() = None;
+        self._mic_stream = None;
+        if !self.mic_settings.enabled {
+            return Ok(());
+        }
+
+        info!("Opening microphone {:?}", self.mic_settings.source);
+        let (mut mic, stream) =
+            MicInput::open(&self.mic_settings, self.sample_rate, self.max_frames, errors)?;
+        mic.set_tap(self.mic_tap.clone());
+        *self.mic.lock() = Some(mic);
+        self._mic_stream = stream;
+        Ok(())
+    }
+
+    /// Starts or stops mixing in an audio song
+    pub fn set_track(&mut self, track: Option<Arc<Mutex<AudioTrack>>>) {
+        if let Some(track) = &track {
+            track.lock().set_output_rate(self.sample_rate);
+        }
+        *self.track.lock() = track;
+    }
+
+    /// Changes the vocal reducer of the audio song playing, if there is one
+    pub fn set_vocal(&self, settings: &VocalReducerSettings) {
+        if let Some(track) = self.track.lock().as_ref() {
+            track.lock().set_vocal(settings);
+        }
+    }
+
+    /// Applies the master gain, limiter, plugins and the synth's reverb and chorus.
+    ///
+    /// Plugins that fail to load are skipped and their errors returned.
+    pub fn set_master(&mut self, settings: &MasterSettings) -> Vec<anyhow::Error> {
+        self.master.lock().settings = settings.clone();
+        let failed = plugin::apply(&self.plugins, &settings.plugins, self.sample_rate);
+
+        let mut fl = self.synth.lock();
+        let reverb = &settings.reverb;
+        let rev = fl.get_reverb_mut();
+        rev.set_active(reverb.enabled);
+        rev.set_reverb_params(reverb.room_size, reverb.damping, reverb.width, reverb.level);
+
+        let chorus = &settings.chorus;
+        let ch = fl.chorus_mut();
+        ch.set_active(chorus.enabled);
+        // oxisynth doesn't export ChorusMode, keep whatever the synth has (sine)
+        let mode = ch.mode();
+        ch.set_chorus_params(chorus.voices, chorus.level, chorus.speed, chorus.depth, mode);
+
+        failed
+    }
+
+    /// Renders interleaved stereo through the synth, plugins and the master chain
+    pub fn write(&self, buf: &mut [f32]) {
+        self.synth.lock().write(&mut *buf);
+        self.plugins.lock().process(buf);
+        self.master.lock().process(buf);
+    }
+
+    /// Loads a soundfont on top of the stack, replacing it if it's already loaded
     pub fn add_soundfont<P: AsRef<Path>>(&mut self, sf: P) -> Result<(), Error> {
+        let path = sf.as_ref();
+        let font = load_font(path)?;
         let mut fl = self.synth.lock();
         // fl.reset();
-        let mut file = File::open(sf.as_ref()).unwrap();
-        let font = SoundFont::load(&mut file).unwrap();
-        info!("Loading soundfont {}", sf.as_ref().display());
-        fl.add_font(font, true);
+        if let Some(pos) = self.fonts.iter().position(|(p, _)| p == path) {
+            let (_, id) = self.fonts.remove(pos);
+            if fl.remove_font(id, false).is_err() {
+                warn!("soundfont {} was already unloaded", path.display());
+            }
+        }
+        info!("Loading soundfont {}", path.display());
+        let id = fl.add_font(font, true);
+        self.fonts.insert(0, (path.to_path_buf(), id));
+        self.setup.push(path.to_path_buf());
         Ok(())
     }
+
+    /// Replaces every loaded soundfont with `setup`.
+    ///
+    /// Fonts that fail to load are skipped and their errors returned.
+    pub fn set_soundfonts(&mut self, setup: &SoundfontSetup) -> Vec<Error> {
+        self.all_notes_off();
+        let mut fl = self.synth.lock();
+
+        for (path, id) in self.fonts.drain(..) {
+            debug!("Unloading soundfont {}", path.display());
+            if fl.remove_font(id, false).is_err() {
+                warn!("soundfont {} was already unloaded", path.display());
+            }
+        }
+
+        let mut errors = Vec::new();
+        for path in setup.load_order() {
+            match load_font(path) {
+                Ok(font) => {
+                    info!("Loading soundfont {}", path.display());
+                    let id = fl.add_font(font, true);
+                    self.fonts.insert(0, (path.to_path_buf(), id));
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        self.setup = setup.clone();
+        errors
+    }
+
+    /// Program change that goes to the override font if one matches
+    fn program_change(
+        &self,
+        fl: &mut Synth,
+        channel: u8,
+        program: u8,
+    ) -> Result<(), oxisynth::OxiError> {
+        let bank = self.banks[channel as usize];
+        let font = self
+            .setup
+            .override_for(channel, bank, program)
+            .and_then(|path| self.fonts.iter().find(|(p, _)| p == path))
+            .map(|(_, id)| *id);
+
+        match font {
+            Some(id) => fl.program_select(channel, id, bank, program),
+            None => fl.send_event(oxisynth::MidiEvent::ProgramChange {
+                channel,
+                program_id: program,
+            }),
+        }
+    }
+}
+
+/// Opens the audio device and starts rendering the synth and the mic into it.
+///
+/// Returns the stream with the sample rate it runs at and the most frames it
+/// asks for at once.
+fn output_stream(
+    fluid: &Fluid,
+    audio: &AudioConfig,
+    errors: Sender<String>,
+) -> Result<(Stream, u32, usize), Error> {
+    let (dev, config, format, max_frames) = crate::output::open_device(audio)?;
+    let sample_rate = config.sample_rate.0;
+    fluid.synth.lock().set_sample_rate(sample_rate as f32);
+
+    let fl = Arc::clone(&fluid.synth);
+    let track = Arc::clone(&fluid.track);
+    let mic = Arc::clone(&fluid.mic);
+    let mic_plugins = Arc::clone(&fluid.mic_plugins);
+    let plugins = Arc::clone(&fluid.plugins);
+    let master = Arc::clone(&fluid.master);
+    let stream = crate::output::build_stream(
+        &dev,
+        &config,
+        format,
+        move |data: &mut [f32]| {
+            fl.lock().write(&mut *data);
+            if let Some(track) = track.lock().as_ref() {
+                track.lock().mix_into(data);
+            }
+            if let Some(mic) = mic.lock().as_mut() {
+                mic.mix_into(data, &mut mic_plugins.lock());
+            }
+            plugins.lock().process(data);
+            master.lock().process(data);
+        },
+        errors,
+    )?;
+
+    Ok((stream, sample_rate, max_frames))
+}
+
+fn load_font(path: &Path) -> Result<SoundFont, Error> {
+    let data = fs::read(path).map_err(|error| Error::SoundfontOpen {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    let load_error = |error| Error::SoundfontLoad {
+        path: path.to_path_buf(),
+        error,
+    };
+
+    // oxisynth only logs why a font doesn't load, so check what we can first
+    let data = crate::soundfont::to_sf2(data).map_err(load_error)?;
+    SoundFont::load(&mut Cursor::new(data))
+        .map_err(|_| load_error(anyhow!("its presets or samples are damaged")))
+}
+
+/// Output that drops everything, used when there's no soundfont or audio device
+/// so the rest of the app keeps working
+pub struct NullOutput;
+
+impl Connection for NullOutput {
+    fn play(&mut self, _msg: MidiEvent) -> bool {
+        true
+    }
+}
+
+impl SysExConnection for NullOutput {
+    fn send_sys_ex(&mut self, _msg: &[u8]) -> bool {
+        true
+    }
+}
+
+/// Sets up the built-in synth with the discovered soundfont.
+///
+/// Falls back to a [NullOutput] instead of failing, the error is returned
+/// alongside so it can be shown to the user.
+pub fn init_synth(
+    soundfonts: &SoundfontSetup,
+    audio: &AudioConfig,
+    master: &MasterSettings,
+    errors: Sender<String>,
+) -> (MidiSynth, Option<Error>) {
+    match Fluid::new(soundfonts, audio, master, errors) {
+        // the audio stream keeps it on the MIDI thread, the Arc is only shared there
+        #[allow(clippy::arc_with_non_send_sync)]
+        Ok(fluid) => (MidiSynth::Oxisynth(Arc::new(Mutex::new(fluid))), None),
+        Err(e) => {
+            error!("failed starting the synth, MIDI output disabled: {}", e);
+            (MidiSynth::External(Arc::new(Mutex::new(NullOutput))), Some(e))
+        }
+    }
 }
 
 impl Connection for Fluid {
@@ -287,6 +667,10 @@ impl Connection for Fluid {
             }
             M::Controller { controller, value } => {
                 trace!("controller: {} {} {}", c, controller, value);
+                // bank select MSB, only used to match font overrides
+                if u8::from(controller) == 0 {
+                    self.banks[c as usize] = u8::from(value) as u32;
+                }
                 fl.send_event(oxisynth::MidiEvent::ControlChange {
                     channel: c as u8,
                     ctrl: u8::from(controller),
@@ -295,10 +679,8 @@ impl Connection for Fluid {
             }
             M::ProgramChange { program } => {
                 trace!("program change: {} {}", c, program);
-                fl.send_event(oxisynth::MidiEvent::ProgramChange {
-                    channel: c as u8,
-                    program_id: u8::from(program),
-                })
+                self.programs[c as usize] = u8::from(program);
+                self.program_change(&mut fl, c as u8, u8::from(program))
             }
             M::ChannelAftertouch { vel } => {
                 trace!("channel aftertouch: {} {}", c, vel);
@@ -330,6 +712,62 @@ impl Connection for Fluid {
     }
 }
 
+impl SysExConnection for Fluid {
+    fn send_sys_ex(&mut self, msg: &[u8]) -> bool {
+        let parsed = sysex::parse(msg);
+        trace!("sysex: {:02X?} ({:?})", msg, parsed);
+
+        match parsed {
+            SysExMessage::GmOn | SysExMessage::GsReset | SysExMessage::XgOn => {
+                self.all_notes_off();
+                {
+                    let mut fl = self.synth.lock();
+                    fl.program_reset();
+                    for channel in 0..16 {
+                        // reset all controllers
+                        let res = fl.send_event(oxisynth::MidiEvent::ControlChange {
+                            channel,
+                            ctrl: 121,
+                            value: 0,
+                        });
+                        if let Err(e) = res {
+                            debug!(target: "midi_event", "{e}");
+                        }
+                    }
+                    // the reset puts the master volume back to full
+                    fl.set_gain(SYNTH_GAIN);
+                }
+                self.programs = [0; 16];
+                self.banks = DEFAULT_BANKS;
+                // the reset puts reverb and chorus back to the defaults
+                let master = self.master.lock().settings.clone();
+                self.set_master(&master);
+            }
+            SysExMessage::MasterVolume(volume) => {
+                let volume = volume as f32 / 0x3FFF as f32;
+                self.synth.lock().set_gain(SYNTH_GAIN * volume);
+            }
+            SysExMessage::DrumPart { channel, drums } => {
+                // soundfonts keep their drum kits in bank 128
+                let bank = if drums { 128 } else { 0 };
+                self.banks[channel as usize] = bank;
+                let mut fl = self.synth.lock();
+                if let Err(e) = fl.bank_select(channel, bank) {
+                    debug!("failed selecting bank {} on channel {}: {:?}", bank, channel, e);
+                }
+                // the bank only applies on the next program change
+                let res = self.program_change(&mut fl, channel, self.programs[channel as usize]);
+                if let Err(e) = res {
+                    debug!(target: "midi_event", "{e}");
+                }
+            }
+            SysExMessage::Other => return false,
+        }
+
+        true
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Debug, Clone, Default)]
 pub struct MidiContext {
@@ -339,6 +777,11 @@ pub struct MidiContext {
     pub total: Option<Duration>,
     pub elapsed: Option<Duration>,
     pub seek: bool,
+    /// Key change in semitones, the player picks it up as it goes
+    pub transpose: i8,
+    /// Tempo multiplier, like [ControlTicker::speed]
+    #[derivative(Default(value = "1.0"))]
+    pub speed: f32,
 }
 
 impl MidiContext {
@@ -369,15 +812,13 @@ impl MidiContext {
 /// on a [Receiver] and toggles playback if there is one.
 ///
 /// Sending a message to [self.pause] will pause the thread until another
-/// message is received.
+/// message is received. Dropping the sender never pauses, and lets a paused
+/// thread carry on.
 ///
 /// # Notes
 /// Using [Ticker] is recommended over this, mainly because there is the
 /// overhead of [Receiver] with this type.
 ///
-/// Calling [sleep](Self::sleep) will panic if the corresponding end of the
-/// receiver is poisoned, see the [mpsc](std::sync::mpsc) documentation for
-/// more.
 #[derive(Debug)]
 pub struct ControlTicker {
     pub ticks_per_beat: u16,
@@ -446,9 +887,7 @@ impl Timer for ControlTicker {
             // Wait for the next message in order to continue, continue.
             // self.pause.recv().unwrap();
             debug!("paused");
-            self.pause
-                .recv()
-                .unwrap_or_else(|e| panic!("Ticker: pause channel receive failed: {:?}", e));
+            self.pause.recv().unwrap_or_default();
         }
 
         trace!(target: "rusty_karaoke::midi::ControlTicker","sleeping for {} ticks", n_ticks);
@@ -473,17 +912,18 @@ pub struct MidiControl {
     pub midi_context: Arc<RwLock<MidiContext>>,
     pub midi: Option<Vec<u8>>,
     pub sheet: Option<Sheet>,
-    pub midi_sender: Sender<PlaybackEvent>,
     pub playback_context: Arc<RwLock<PlaybackContext>>,
     pub sigrecv: Receiver<()>,
+    /// Where the position and lyric lines are published
+    pub events: EventBus<PlaybackEvent>,
 }
 
 impl MidiControl {
     pub fn new(
         midi_channel: Sender<MidiMessage>,
-        msg: crossbeam::channel::Sender<crate::time::PlaybackEvent>,
         ctx: Arc<RwLock<crate::time::PlaybackContext>>,
         rx: Receiver<()>,
+        events: EventBus<PlaybackEvent>,
     ) -> Self {
         let midicon = Arc::new(RwLock::new(MidiContext::new()));
         Self {
@@ -491,9 +931,9 @@ impl MidiControl {
             midi_context: midicon,
             midi: None,
             sheet: None,
-            midi_sender: msg,
             playback_context: ctx,
             sigrecv: rx,
+            events,
         }
     }
 
@@ -501,18 +941,43 @@ impl MidiControl {
         self.playback_context = ctx;
     }
 
-    //todo: async
-    //BUG: the song just perpetually stops if you try to play it again after stopping it
-    pub fn play(&mut self, path: &Path, pos: Option<usize>) {
+    /// Loads a song and plays it to the end or until [MidiContext::playing] is cleared.
+    ///
+    /// The caller sets [MidiContext::playing] beforehand, so a stop can't be lost while loading.
+    /// `started` is called once the song is loaded.
+    pub fn play(&mut self, path: &Path, started: impl FnOnce()) -> Result<()> {
+        if crate::audio::is_audio(path) {
+            return self.play_audio(path, started);
+        }
         let tick = self.midi_context.read().midi_tick;
-        self.midi_context.write().playing = true;
 
-        let data = fs::read(path).unwrap();
+        // EMK songs say which channel has the vocals, for NCN songs it's guessed from
+        // the CUR timing. Plain MIDI files play without lyrics.
+        let karaoke = match crate::karaoke::read_karaoke(path) {
+            Ok(song) => Some(song),
+            Err(e) if crate::karaoke::is_emk(path) => {
+                return Err(anyhow!("{}: {}", path.display(), e))
+            }
+            Err(_) => None,
+        };
+        let data = match &karaoke {
+            Some(song) => song.midi.clone(),
+            None => fs::read(path)?,
+        };
 
         self.midi = Some(data.clone());
 
-        self.midi = Some(fs::read(path).unwrap());
-        let smf = Smf::parse(&data).unwrap();
+        let guide = karaoke.as_ref().and_then(|song| song.guide_channel());
+        info!("guide melody channel: {:?}", guide);
+        self.midi_channel
+            .send(MidiMessage::Mixer(MixerCommand::GuideChannel(guide)))
+            .unwrap_or_default();
+
+        // start every song from a known state
+        self.midi_channel.send(MidiMessage::ClearNotes).unwrap_or_default();
+        self.midi_channel.send(MidiMessage::Reset).unwrap_or_default();
+
+        let smf = Smf::parse(&data)?;
         let timer = ControlTicker::new(timing_to_ticker(smf.header.timing), self.sigrecv.clone());
 
         let res = {
@@ -535,11 +1000,13 @@ impl MidiControl {
             timer,
             self.midi_channel.clone(),
             res,
-            self.midi_sender.clone(),
             self.playback_context.clone(),
             tick,
             self.midi_context.clone(),
+            self.events.clone(),
         );
+        player.lines = karaoke.map_or_else(Vec::new, |song| song.line_ticks(res));
+        player.sysex = sysex::from_smf(&smf);
 
         // i am stuck in a prison of my own creation
         if let Some(sheet) = &self.sheet {
@@ -549,10 +1016,88 @@ impl MidiControl {
                 Duration::from_std(Ticker::try_from(smf.header.timing).unwrap().duration(sheet))
                     .unwrap(),
             );
-            self.midi_context.write().playing = true;
+            started();
             player.play(sheet);
             // self.midi_context.write().playing = false;
         }
+        Ok(())
+    }
+}
+
+impl MidiControl {
+    /// Plays a WAV or Ogg song, the built-in synth's audio callback does the mixing
+    /// and this only follows the position, pauses and seeks
+    fn play_audio(&mut self, path: &Path, started: impl FnOnce()) -> Result<()> {
+        let (samples, sample_rate) = crate::audio::decode(path)?;
+        let track = Arc::new(Mutex::new(AudioTrack::new(samples, sample_rate)));
+        {
+            let ticks = track.lock().ticks();
+            let mut ctx = self.midi_context.write();
+            ctx.midi_tick_max = ticks;
+            ctx.total = Some(Duration::milliseconds(ticks as i64));
+        }
+        self.playback_context.write().backend = Some(crate::time::PlaybackBackend::Audio {
+            ctx: self.midi_context.clone(),
+        });
+
+        // there's no guide melody to toggle
+        self.midi_channel
+            .send(MidiMessage::Mixer(MixerCommand::GuideChannel(None)))
+            .unwrap_or_default();
+        self.midi_channel.send(MidiMessage::ClearNotes).unwrap_or_default();
+        self.midi_channel
+            .send(MidiMessage::Track(Some(track.clone())))
+            .unwrap_or_default();
+        started();
+
+        let mut published = None;
+        // key and tempo the track plays at
+        let mut applied = (0, 1.0);
+        while self.midi_context.read().playing {
+            // every message toggles pause, like on the MIDI ticker
+            while self.sigrecv.try_recv().is_ok() {
+                let mut track = track.lock();
+                track.paused = !track.paused;
+            }
+
+            let (seek, shift) = {
+                let mut ctx = self.midi_context.write();
+                let seek = std::mem::take(&mut ctx.seek).then(|| ctx.midi_tick);
+                (seek, (ctx.transpose, ctx.speed))
+            };
+            let (tick, done) = {
+                let mut track = track.lock();
+                if let Some(tick) = seek {
+                    track.seek(tick);
+                }
+                if shift != applied {
+                    track.set_shift(shift.0, shift.1);
+                    applied = shift;
+                }
+                (track.tick(), track.is_done())
+            };
+            if done {
+                break;
+            }
+
+            {
+                let mut ctx = self.midi_context.write();
+                ctx.midi_tick = tick;
+                ctx.elapsed = Some(Duration::milliseconds(tick as i64));
+            }
+            if published != Some(tick) {
+                self.events.publish(PlaybackEvent::Position {
+                    tick,
+                    elapsed: std::time::Duration::from_millis(tick as u64),
+                });
+                published = Some(tick);
+            }
+
+            std::thread::sleep(POSITION_INTERVAL);
+        }
+
+        self.midi_channel.send(MidiMessage::Track(None)).unwrap_or_default();
+        Ok(())
     }
 }
 
@@ -560,10 +1105,16 @@ impl MidiControl {
 pub struct MidPlayer {
     pub con: Sender<MidiMessage>,
     pub res: u16,
-    pub msg: crossbeam::channel::Sender<crate::time::PlaybackEvent>,
     pub ctx: Arc<RwLock<crate::time::PlaybackContext>>,
     pub pos: usize,
     pub midi_context: Arc<RwLock<MidiContext>>,
+    pub events: EventBus<PlaybackEvent>,
+    /// Tick every lyric line starts at, for [PlaybackEvent::LyricLine]
+    pub lines: Vec<u32>,
+    /// SysEx messages with their tick, see [sysex::from_smf]
+    pub sysex: Vec<(usize, Vec<u8>)>,
+    /// Key change the events are sent in, see [MidiContext::transpose]
+    transpose: i8,
     timer: ControlTicker,
     pos_lock: bool,
 }
@@ -573,19 +1124,22 @@ impl MidPlayer {
         timer: ControlTicker,
         con: Sender<MidiMessage>,
         res: u16,
-        msg: crossbeam::channel::Sender<crate::time::PlaybackEvent>,
         ctx: Arc<RwLock<crate::time::PlaybackContext>>,
         pos: usize,
         midi_context: Arc<RwLock<MidiContext>>,
+        events: EventBus<PlaybackEvent>,
     ) -> Self {
         Self {
             con,
             timer,
             res,
-            msg,
             ctx,
             pos,
             midi_context,
+            events,
+            lines: Vec::new(),
+            sysex: Vec::new(),
+            transpose: 0,
             pos_lock: false,
         }
     }
@@ -803,6 +1357,12 @@ impl MidPlayer {
 
         // rewrite above so you can scroll it
 
+        // what was last published, in microseconds and lines started
+        let mut published_time: Option<u64> = None;
+        let mut published_line = 0;
+        // next SysEx message to send
+        let mut next_sysex = self.sysex.partition_point(|(t, _)| *t < self.pos);
+
         while self.midi_context.read().playing {
             let time = (self.pos as f32) / self.res as f32;
 
@@ -828,6 +1388,7 @@ impl MidPlayer {
 
             if self.midi_context.read().seek {
                 self.pos = self.midi_context.read().midi_tick;
+                next_sysex = self.sysex.partition_point(|(t, _)| *t < self.pos);
                 if let Some(mut write) = self.midi_context.try_write() {
                     write.seek = false;
                 }
@@ -835,17 +1396,64 @@ impl MidPlayer {
                 self.midi_context.write().midi_tick = self.pos;
             }
 
+            let (transpose, speed) = {
+                let ctx = self.midi_context.read();
+                (ctx.transpose, ctx.speed)
+            };
+            self.timer.speed = speed;
+            if transpose != self.transpose {
+                // notes playing now would get their note off in the new key
+                if self.con.send(MidiMessage::ClearNotes).is_err() {
+                    return false;
+                }
+                self.transpose = transpose;
+            }
+
+            // no tempo yet means no time either
+            let due = published_time.is_none_or(|last| {
+                time < last || time - last >= POSITION_INTERVAL.as_micros() as u64
+            });
+            if bpm > 0 && due {
+                self.events.publish(PlaybackEvent::Position {
+                    tick: self.pos,
+                    elapsed: std::time::Duration::from_micros(time),
+                });
+                published_time = Some(time);
+            }
+
+            let line = self.lines.partition_point(|t| *t as usize <= self.pos);
+            if line != published_line {
+                if let Some(line) = line.checked_sub(1) {
+                    self.events.publish(PlaybackEvent::LyricLine(line));
+                }
+                published_line = line;
+            }
+
             // debug!("seek: {}", self.midi_context.try_read().unwrap().seek);
 
             // debug!("{}", self.pos);
 
 
             if let Some(moment) = sheet.get(self.pos as usize) {
-                if !moment.is_empty() {
+                let sysex_due = self
+                    .sysex
+                    .get(next_sysex)
+                    .is_some_and(|(t, _)| *t <= self.pos);
+                if !moment.is_empty() || sysex_due {
                     self.timer.sleep(counter);
                     // info!("playing moment {}", cur_time);
                     counter = 0;
 
+                    // before the notes, so resets and drum parts apply to them
+                    while let Some((_, msg)) =
+                        self.sysex.get(next_sysex).filter(|(t, _)| *t <= self.pos)
+                    {
+                        if self.con.send(MidiMessage::SysEx(msg.clone())).is_err() {
+                            return false;
+                        }
+                        next_sysex += 1;
+                    }
+
                     // get play progress
 
                     // get moment index
@@ -864,7 +1472,8 @@ impl MidPlayer {
                                 self.timer.change_tempo(*val)
                             }
                             Event::Midi(msg) => {
-                                if self.con.send(MidiMessage::Event(*msg)).is_err() {
+                                let msg = crate::render::transpose(*msg, self.transpose);
+                                if self.con.send(MidiMessage::Event(msg)).is_err() {
                                     return false;
                                 }
                             }
@@ -906,13 +1515,37 @@ impl MidPlayer {
 /// MIDI Messages to send to the MIDI device
 pub enum MidiMessage {
     Event(MidiEvent),
+    /// System Exclusive data, with or without the `F0` and `F7` framing
+    SysEx(Vec<u8>),
+    /// Sends the configured reset, done before every song
+    Reset,
+    /// Changes which reset is sent before every song
+    ResetMode(ResetMode),
     ClearNotes,
+    /// Loads a soundfont on top of the stack
     Soundfont(PathBuf),
+    /// Replaces the whole soundfont setup
+    Soundfonts(SoundfontSetup),
+    Mixer(MixerCommand),
+    /// Switches between the built-in synth and external MIDI ports
+    Output(OutputTarget),
+    /// Reopens the built-in synth's audio output with new settings
+    Audio(AudioConfig),
+    /// Master volume, limiter, reverb and chorus of the built-in synth
+    Master(MasterSettings),
+    /// Microphone input and effects, mixed into the built-in synth's output
+    Mic(MicSettings),
+    /// Copies the raw microphone input for the pitch tracker
+    MicTap(Option<Arc<MicTap>>),
+    /// Audio song to mix in next to the built-in synth, `None` stops it
+    Track(Option<Arc<Mutex<AudioTrack>>>),
+    /// Vocal reducer for audio songs
+    Vocal(VocalReducerSettings),
 }
 
 pub enum MidiSynth {
     Oxisynth(Arc<Mutex<Fluid>>),
-    External(Arc<Mutex<dyn Connection>>),
+    External(Arc<Mutex<dyn SysExConnection>>),
 }
 
 impl MidiSynth {
@@ -936,13 +1569,19 @@ impl MidiSynth {
         }
     }
 
-    pub fn as_connection(&self) -> Arc<Mutex<dyn Connection>> {
+    pub fn as_connection(&self) -> Arc<Mutex<dyn SysExConnection>> {
         match self {
             MidiSynth::Oxisynth(synth) => synth.clone(),
             MidiSynth::External(synth) => synth.clone(),
         }
     }
 
+    /// Sends a SysEx message, adding the `F0` and `F7` framing if needed
+    pub fn send_sys_ex(&mut self, data: &[u8]) -> bool {
+        let msg = sysex::normalize(data);
+        self.as_connection().lock().send_sys_ex(&msg)
+    }
+
     pub fn set_soundfont(&mut self, path: &Path) -> Result<()> {
         match self {
             MidiSynth::Oxisynth(synth) => {
@@ -968,17 +1607,121 @@ impl MidiSynth {
 pub struct MidiDevice {
     pub con: MidiSynth,
     pub msg: Receiver<MidiMessage>,
+    /// Every event goes through the mixer before reaching the synth
+    pub mixer: Arc<RwLock<ChannelMixer>>,
+    /// The built-in synth, kept around while an external output is in use
+    synth: Option<Arc<Mutex<Fluid>>>,
+    /// Output in use, only changed once a switch worked
+    output: Arc<RwLock<OutputTarget>>,
+    /// Reset sent before every song
+    pub reset_mode: ResetMode,
+    /// Soundfonts for the built-in synth
+    soundfonts: SoundfontSetup,
+    /// Audio output settings for the built-in synth
+    audio: AudioConfig,
+    /// Master chain settings for the built-in synth
+    master: MasterSettings,
+    /// Microphone settings for the built-in synth
+    mic: MicSettings,
+    /// Vocal reducer for audio songs
+    vocal: VocalReducerSettings,
+    /// Errors to show to the user
+    errors: Sender<String>,
 }
 
 impl MidiDevice {
-    pub fn new(rx: Receiver<MidiMessage>, con: Option<MidiSynth>) -> Self {
-        let con = con.unwrap_or_else(|| {
-            let default_synth = Fluid::new(DEFAULT_SOUNDFONT).unwrap();
+    pub fn new(
+        rx: Receiver<MidiMessage>,
+        con: Option<MidiSynth>,
+        mixer: Arc<RwLock<ChannelMixer>>,
+        config: &Config,
+        errors: Sender<String>,
+        output: Arc<RwLock<OutputTarget>>,
+    ) -> Self {
+        let soundfonts = crate::soundfont::initial_setup(config);
 
-            MidiSynth::Oxisynth(Arc::new(Mutex::new(default_synth)))
+        let con = con.unwrap_or_else(|| {
+            let (synth, err) =
+                init_synth(&soundfonts, &config.audio, &config.master, errors.clone());
+            if let Some(e) = err {
+                errors.send(e.to_string()).unwrap_or_default();
+            }
+            synth
         });
 
-        Self { con, msg: rx }
+        let synth = con.inner_synth().cloned();
+
+        let device = Self {
+            con,
+            msg: rx,
+            mixer,
+            synth,
+            output,
+            reset_mode: ResetMode::default(),
+            soundfonts,
+            audio: config.audio.clone(),
+            master: config.master.clone(),
+            mic: config.mic.clone(),
+            vocal: config.vocal.clone(),
+            errors,
+        };
+        device.apply_mic();
+        device
+    }
+
+    /// Hands the mic settings to the built-in synth, errors go to the user
+    fn apply_mic(&self) {
+        if let Some(synth) = &self.synth {
+            if let Err(e) = synth.lock().set_mic(&self.mic, self.errors.clone()) {
+                error!("Failed to open microphone: {}", e);
+                self.errors
+                    .send(format!("Failed to open microphone: {}", e))
+                    .unwrap_or_default();
+            }
+        }
+    }
+
+    /// Switches the output, silencing the old one first so no notes hang
+    pub fn set_output(&mut self, target: &OutputTarget) -> Result<()> {
+        let con = match target {
+            OutputTarget::Synth => {
+                let synth = match self.synth.clone() {
+                    Some(synth) => synth,
+                    None => {
+                        // only shared on this thread, see init_synth
+                        #[allow(clippy::arc_with_non_send_sync)]
+                        let synth = Arc::new(Mutex::new(Fluid::new(
+                            &self.soundfonts,
+                            &self.audio,
+                            &self.master,
+                            self.errors.clone(),
+                        )?));
+                        self.synth = Some(synth.clone());
+                        self.apply_mic();
+                        synth
+                    }
+                };
+                MidiSynth::Oxisynth(synth)
+            }
+            OutputTarget::Port(name) => {
+                MidiSynth::External(Arc::new(Mutex::new(ExternalOutput::connect(name)?)))
+            }
+            OutputTarget::Virtual(name) => {
+                MidiSynth::External(Arc::new(Mutex::new(ExternalOutput::create_virtual(name)?)))
+            }
+        };
+
+        self.con.as_connection().lock().all_notes_off();
+        self.con = con;
+
+        // bring the new output up to date with the mixer
+        let events = self.mixer.read().restore_events();
+        for event in events {
+            self.con.play(event);
+        }
+
+        *self.output.write() = target.clone();
+        Ok(())
     }
 
     pub fn listen(&mut self) {
@@ -998,7 +1741,30 @@ impl MidiDevice {
                 MidiMessage::Event(event) => {
                     trace!(target: target, "Got MIDI event: {:?}", event);
                     // let mut con = self.con.as_connection().lock();
-                    self.con.play(event);
+                    let event = self.mixer.write().filter(event);
+                    if let Some(event) = event {
+                        self.con.play(event);
+                    }
+                }
+                MidiMessage::SysEx(data) => {
+                    trace!(target: target, "Got SysEx: {:02X?}", data);
+                    if !self.con.send_sys_ex(&data) {
+                        trace!(target: target, "SysEx not handled by synth");
+                    }
+                }
+                MidiMessage::Reset => {
+                    debug!(target: target, "Sending {}", self.reset_mode.name());
+                    if let Some(msg) = self.reset_mode.message() {
+                        self.con.send_sys_ex(msg);
+                        // the reset clears volume and pan, so put the overrides back
+                        let events = self.mixer.read().restore_events();
+                        for event in events {
+                            self.con.play(event);
+                        }
+                    }
+                }
+                MidiMessage::ResetMode(mode) => {
+                    self.reset_mode = mode;
                 }
                 MidiMessage::ClearNotes => {
                     trace!(target: target, "Clearing notes");
@@ -1009,8 +1775,106 @@ impl MidiDevice {
                 }
                 MidiMessage::Soundfont(path) => {
                     trace!(target: target, "Setting soundfont to {:?}", path);
-                    if self.con.set_soundfont(&path).is_err() {
-                        error!(target: target, "Failed to set soundfont");
+                    match self.con.set_soundfont(&path) {
+                        Ok(()) => self.soundfonts.push(path),
+                        Err(e) => {
+                            error!(target: target, "Failed to set soundfont: {}", e);
+                            self.errors
+                                .send(format!("Failed to load soundfont: {}", e))
+                                .unwrap_or_default();
+                        }
+                    }
+                }
+                MidiMessage::Soundfonts(setup) => {
+                    debug!(target: target, "Changing soundfonts to {:?}", setup);
+                    if let Some(synth) = &self.synth {
+                        for e in synth.lock().set_soundfonts(&setup) {
+                            self.errors
+                                .send(format!("Failed to load soundfont: {}", e))
+                                .unwrap_or_default();
+                        }
+                        self.soundfonts = setup;
+                    } else {
+                        self.soundfonts = setup;
+                        // the synth didn't start, try again with the new fonts
+                        let output = self.output.read().clone();
+                        if output == OutputTarget::Synth {
+                            if let Err(e) = self.set_output(&output) {
+                                error!(target: target, "Failed to start the synth: {}", e);
+                                self.errors
+                                    .send(format!("Failed to start the synth: {}", e))
+                                    .unwrap_or_default();
+                            }
+                        }
+                    }
+                }
+                MidiMessage::Output(output) => {
+                    debug!(target: target, "Switching output to {:?}", output);
+                    if let Err(e) = self.set_output(&output) {
+                        error!(target: target, "Failed to switch output: {}", e);
+                        self.errors
+                            .send(format!("Failed to switch MIDI output: {}", e))
+                            .unwrap_or_default();
+                    }
+                }
+                MidiMessage::Audio(audio) => {
+                    debug!(target: target, "Changing audio output to {:?}", audio);
+                    self.audio = audio;
+                    if let Some(synth) = &self.synth {
+                        let res = synth.lock().set_audio(&self.audio, self.errors.clone());
+                        if let Err(e) = res {
+                            error!(target: target, "Failed to open audio output: {}", e);
+                            self.errors
+                                .send(format!("Failed to open audio output: {}", e))
+                                .unwrap_or_default();
+                        }
+                    }
+                }
+                MidiMessage::Master(master) => {
+                    trace!(target: target, "Master settings: {:?}", master);
+                    if let Some(synth) = &self.synth {
+                        for e in synth.lock().set_master(&master) {
+                            error!(target: target, "{:#}", e);
+                            self.errors.send(format!("{:#}", e)).unwrap_or_default();
+                        }
+                    }
+                    self.master = master;
+                }
+                MidiMessage::Mic(mic) => {
+                    trace!(target: target, "Mic settings: {:?}", mic);
+                    self.mic = mic;
+                    self.apply_mic();
+                }
+                MidiMessage::MicTap(tap) => {
+                    if let Some(synth) = &self.synth {
+                        synth.lock().set_mic_tap(tap);
+                    }
+                }
+                MidiMessage::Track(track) => match &self.synth {
+                    Some(synth) => {
+                        if let Some(track) = &track {
+                            track.lock().set_vocal(&self.vocal);
+                        }
+                        synth.lock().set_track(track);
+                    }
+                    None if track.is_some() => {
+                        let e = "Audio songs play through the built-in synth, which didn't start";
+                        self.errors.send(e.to_string()).unwrap_or_default();
+                    }
+                    None => {}
+                },
+                MidiMessage::Vocal(vocal) => {
+                    trace!(target: target, "Vocal reducer: {:?}", vocal);
+                    if let Some(synth) = &self.synth {
+                        synth.lock().set_vocal(&vocal);
+                    }
+                    self.vocal = vocal;
+                }
+                MidiMessage::Mixer(cmd) => {
+                    trace!(target: target, "Mixer command: {:?}", cmd);
+                    let events = self.mixer.write().apply(cmd);
+                    for event in events {
+                        self.con.play(event);
                     }
                 }
                 _ => {
@@ -1022,7 +1886,14 @@ impl MidiDevice {
     }
 }
 
-pub fn midi_thread(rx: Receiver<MidiMessage>, con: Option<MidiSynth>) {
-    let mut midi = MidiDevice::new(rx, con);
+pub fn midi_thread(
+    rx: Receiver<MidiMessage>,
+    con: Option<MidiSynth>,
+    mixer: Arc<RwLock<ChannelMixer>>,
+    config: Config,
+    errors: Sender<String>,
+    output: Arc<RwLock<OutputTarget>>,
+) {
+    let mut midi = MidiDevice::new(rx, con, mixer, &config, errors, output);
     midi.listen();
 }
@@ -0,0 +1,329 @@
+//! Channel mixer for MIDI playback
+//!
+//! Sits between the player and the synth, so every event sent to a [Connection](nodi::Connection)
+//! goes through [ChannelMixer::filter] first. Mutes and solos drop new notes on a channel,
+//! volume and pan overrides replace the song's own CC 7 and CC 10 values.
+
+use midly::{
+    num::{u4, u7},
+    MidiMessage as M,
+};
+use nodi::MidiEvent;
+
+/// Number of MIDI channels
+pub const CHANNELS: usize = 16;
+
+const CC_VOLUME: u8 = 7;
+const CC_PAN: u8 = 10;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+/// Default values for a channel that hasn't sent its own volume or pan yet
+const DEFAULT_VOLUME: u8 = 100;
+const DEFAULT_PAN: u8 = 64;
+
+/// Mixer settings for a single MIDI channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStrip {
+    pub mute: bool,
+    pub solo: bool,
+    /// Volume override (CC 7), `None` uses the song's own value
+    pub volume: Option<u8>,
+    /// Pan override (CC 10), `None` uses the song's own value
+    pub pan: Option<u8>,
+    /// Last volume the song asked for, restored when the override is cleared
+    pub song_volume: u8,
+    /// Last pan the song asked for, restored when the override is cleared
+    pub song_pan: u8,
+}
+
+impl Default for ChannelStrip {
+    fn default() -> Self {
+        Self {
+            mute: false,
+            solo: false,
+            volume: None,
+            pan: None,
+            song_volume: DEFAULT_VOLUME,
+            song_pan: DEFAULT_PAN,
+        }
+    }
+}
+
+impl ChannelStrip {
+    /// The volume actually sent to the synth
+    pub fn effective_volume(&self) -> u8 {
+        self.volume.unwrap_or(self.song_volume)
+    }
+
+    /// The pan actually sent to the synth
+    pub fn effective_pan(&self) -> u8 {
+        self.pan.unwrap_or(self.song_pan)
+    }
+}
+
+/// Commands sent to the MIDI thread to change the mixer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MixerCommand {
+    Mute(u8, bool),
+    Solo(u8, bool),
+    Volume(u8, Option<u8>),
+    Pan(u8, Option<u8>),
+    /// Sets the channel carrying the vocal melody (e.g. EMK `vocal_channel`)
+    GuideChannel(Option<u8>),
+    /// Mutes or unmutes the guide melody channel
+    ToggleGuide,
+    /// Clears every mute, solo and override
+    Reset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMixer {
+    pub channels: [ChannelStrip; CHANNELS],
+    /// Channel carrying the vocal melody, if known
+    pub guide_channel: Option<u8>,
+}
+
+impl Default for ChannelMixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChannelMixer {
+    pub fn new() -> Self {
+        Self {
+            channels: [ChannelStrip::default(); CHANNELS],
+            guide_channel: None,
+        }
+    }
+
+    pub fn strip(&self, channel: u8) -> Option<&ChannelStrip> {
+        self.channels.get(channel as usize)
+    }
+
+    /// Whether any channel is soloed
+    pub fn has_solo(&self) -> bool {
+        self.channels.iter().any(|c| c.solo)
+    }
+
+    /// Whether notes on a channel should be heard, taking mutes and solos into account
+    pub fn is_audible(&self, channel: u8) -> bool {
+        match self.strip(channel) {
+            Some(strip) => !strip.mute && (!self.has_solo() || strip.solo),
+            None => false,
+        }
+    }
+
+    /// Whether the guide melody is currently heard
+    pub fn guide_enabled(&self) -> bool {
+        match self.guide_channel {
+            Some(ch) => !self.channels[ch as usize].mute,
+            None => false,
+        }
+    }
+
+    /// Runs an event through the mixer.
+    ///
+    /// Returns `None` if the event should not reach the synth.
+    pub fn filter(&mut self, event: MidiEvent) -> Option<MidiEvent> {
+        let ch = event.channel.as_int();
+        let audible = self.is_audible(ch);
+        let strip = &mut self.channels[ch as usize];
+
+        match event.message {
+            // note offs always pass so nothing hangs when a channel gets muted mid-note
+            M::NoteOn { vel, .. } if vel.as_int() > 0 && !audible => None,
+            M::Controller { controller, value } if controller.as_int() == CC_VOLUME => {
+                strip.song_volume = value.as_int();
+                Some(controller_event(ch, CC_VOLUME, strip.effective_volume()))
+            }
+            M::Controller { controller, value } if controller.as_int() == CC_PAN => {
+                strip.song_pan = value.as_int();
+                Some(controller_event(ch, CC_PAN, strip.effective_pan()))
+            }
+            _ => Some(event),
+        }
+    }
+
+    /// Applies a command to the mixer.
+    ///
+    /// Returns the events that need to be sent to the synth for the change to be
+    /// heard immediately, like silencing a channel that just got muted.
+    pub fn apply(&mut self, cmd: MixerCommand) -> Vec<MidiEvent> {
+        let before = self.audible_channels();
+
+        let mut events = Vec::new();
+
+        match cmd {
+            MixerCommand::Mute(ch, mute) => {
+                if let Some(strip) = self.channels.get_mut(ch as usize) {
+                    strip.mute = mute;
+                }
+            }
+            MixerCommand::Solo(ch, solo) => {
+                if let Some(strip) = self.channels.get_mut(ch as usize) {
+                    strip.solo = solo;
+                }
+            }
+            MixerCommand::Volume(ch, volume) => {
+                if let Some(strip) = self.channels.get_mut(ch as usize) {
+                    strip.volume = volume.map(|v| v.min(127));
+                    events.push(controller_event(ch, CC_VOLUME, strip.effective_volume()));
+                }
+            }
+            MixerCommand::Pan(ch, pan) => {
+                if let Some(strip) = self.channels.get_mut(ch as usize) {
+                    strip.pan = pan.map(|v| v.min(127));
+                    events.push(controller_event(ch, CC_PAN, strip.effective_pan()));
+                }
+            }
+            MixerCommand::GuideChannel(ch) => {
+                let ch = ch.filter(|c| (*c as usize) < CHANNELS);
+                // keep the guide switched off across songs
+                if let Some(old) = self.guide_channel {
+                    if !self.guide_enabled() && ch != Some(old) {
+                        self.channels[old as usize].mute = false;
+                        if let Some(new) = ch {
+                            self.channels[new as usize].mute = true;
+                        }
+                    }
+                }
+                self.guide_channel = ch;
+            }
+            MixerCommand::ToggleGuide => {
+                if let Some(ch) = self.guide_channel {
+                    let strip = &mut self.channels[ch as usize];
+                    strip.mute = !strip.mute;
+                }
+            }
+            MixerCommand::Reset => {
+                for (ch, strip) in self.channels.iter_mut().enumerate() {
+                    let restore = strip.volume.is_some() || strip.pan.is_some();
+                    *strip = ChannelStrip {
+                        song_volume: strip.song_volume,
+                        song_pan: strip.song_pan,
+                        ..Default::default()
+                    };
+                    if restore {
+                        events.push(controller_event(ch as u8, CC_VOLUME, strip.song_volume));
+                        events.push(controller_event(ch as u8, CC_PAN, strip.song_pan));
+                    }
+                }
+            }
+        }
+
+        // silence whatever just went quiet
+        let after = self.audible_channels();
+        for ch in 0..CHANNELS {
+            if before[ch] && !after[ch] {
+                events.push(controller_event(ch as u8, CC_ALL_NOTES_OFF, 0));
+            }
+        }
+
+        events
+    }
+
+    /// Events that bring a freshly connected synth in line with the overrides
+    pub fn restore_events(&self) -> Vec<MidiEvent> {
+        let mut events = Vec::new();
+        for (ch, strip) in self.channels.iter().enumerate() {
+            if strip.volume.is_some() {
+                events.push(controller_event(
+                    ch as u8,
+                    CC_VOLUME,
+                    strip.effective_volume(),
+                ));
+            }
+            if strip.pan.is_some() {
+                events.push(controller_event(ch as u8, CC_PAN, strip.effective_pan()));
+            }
+        }
+        events
+    }
+
+    fn audible_channels(&self) -> [bool; CHANNELS] {
+        let mut res = [false; CHANNELS];
+        for (ch, audible) in res.iter_mut().enumerate() {
+            *audible = self.is_audible(ch as u8);
+        }
+        res
+    }
+}
+
+fn controller_event(channel: u8, controller: u8, value: u8) -> MidiEvent {
+    MidiEvent {
+        channel: u4::from(channel),
+        message: M::Controller {
+            controller: u7::from(controller),
+            value: u7::from(value),
+        },
+    }
+}
+
+#[cfg(test)]
+fn note_on(channel: u8, key: u8) -> MidiEvent {
+    MidiEvent {
+        channel: u4::from(channel),
+        message: M::NoteOn {
+            key: u7::from(key),
+            vel: u7::from(100),
+        },
+    }
+}
+
+#[test]
+fn test_mute_drops_notes() {
+    let mut mixer = ChannelMixer::new();
+    let events = mixer.apply(MixerCommand::Mute(3, true));
+    assert_eq!(events, vec![controller_event(3, CC_ALL_NOTES_OFF, 0)]);
+    assert!(mixer.filter(note_on(3, 60)).is_none());
+    assert!(mixer.filter(note_on(4, 60)).is_some());
+}
+
+#[test]
+fn test_solo() {
+    let mut mixer = ChannelMixer::new();
+    let events = mixer.apply(MixerCommand::Solo(0, true));
+    // every other channel just went quiet
+    assert_eq!(events.len(), CHANNELS - 1);
+    assert!(mixer.filter(note_on(0, 60)).is_some());
+    assert!(mixer.filter(note_on(1, 60)).is_none());
+}
+
+#[test]
+fn test_volume_override() {
+    let mut mixer = ChannelMixer::new();
+    mixer.apply(MixerCommand::Volume(2, Some(30)));
+
+    let out = mixer.filter(controller_event(2, CC_VOLUME, 110)).unwrap();
+    assert_eq!(out, controller_event(2, CC_VOLUME, 30));
+
+    // clearing the override restores what the song asked for
+    let events = mixer.apply(MixerCommand::Volume(2, None));
+    assert_eq!(events, vec![controller_event(2, CC_VOLUME, 110)]);
+}
+
+#[test]
+fn test_toggle_guide() {
+    let mut mixer = ChannelMixer::new();
+    mixer.apply(MixerCommand::GuideChannel(Some(5)));
+    assert!(mixer.guide_enabled());
+    mixer.apply(MixerCommand::ToggleGuide);
+    assert!(!mixer.guide_enabled());
+    assert!(mixer.filter(note_on(5, 72)).is_none());
+    mixer.apply(MixerCommand::ToggleGuide);
+    assert!(mixer.guide_enabled());
+}
+
+#[test]
+fn test_guide_off_follows_song() {
+    let mut mixer = ChannelMixer::new();
+    mixer.apply(MixerCommand::GuideChannel(Some(5)));
+    mixer.apply(MixerCommand::ToggleGuide);
+
+    // next song has the melody somewhere else
+    mixer.apply(MixerCommand::GuideChannel(Some(2)));
+    assert!(!mixer.guide_enabled());
+    assert!(!mixer.channels[5].mute);
+    assert!(mixer.channels[2].mute);
+}
@@ -1,13 +1,95 @@
 // Literally the same as NCN file except it is migrated to Karaoke
 
-use std::{error::Error, fs::File, io::Read, path::Path};
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use encoding::{all::WINDOWS_874, decode, DecoderTrap};
 
 use crate::karaoke::{
-    KaraokeCursor, KaraokeCursorTick, KaraokeInfo, KaraokeLanguage, SongType, SubtitleType,
+    Karaoke, KaraokeCursor, KaraokeCursorTick, KaraokeHeader, KaraokeInfo, KaraokeLanguage,
+    SongType, SubtitleType,
 };
 
+/// Reads an NCN song from its MIDI file.
+///
+/// The lyrics and cursor files are looked up next to the MIDI file, or in the
+/// `Lyrics` and `Cursor` folders of a standard NCN library (`Song/1234.mid`,
+/// `Lyrics/1234.lyr`, `Cursor/1234.cur`).
+pub fn read_ncn(midi_path: &Path) -> Result<Karaoke, Box<dyn Error>> {
+    let lyrics_path = find_companion(midi_path, "Lyrics", "lyr").ok_or("lyrics file not found")?;
+    let cursor_path = find_companion(midi_path, "Cursor", "cur").ok_or("cursor file not found")?;
+
+    let lyrics = fs::read(lyrics_path)?;
+    let cursor = fs::read(cursor_path)?;
+    let midi = fs::read(midi_path)?;
+
+    read_ncn_data(midi, &lyrics, &cursor)
+}
+
+/// Builds an NCN song from the contents of its files, also used for the blocks inside EMK files
+pub fn read_ncn_data(
+    midi: Vec<u8>,
+    lyrics: &[u8],
+    cursor: &[u8],
+) -> Result<Karaoke, Box<dyn Error>> {
+    let lyrics = NcnLyricsReader::from_bytes(lyrics)?;
+    let cursor = NcnCursorReader {
+        data: cursor.to_vec(),
+    };
+
+    Ok(Karaoke {
+        header: KaraokeHeader {
+            signature: "NCN".to_string(),
+            version: String::new(),
+        },
+        info: lyrics.get_info(),
+        lyrics: lyrics.get_lyrics(),
+        cursor: cursor.get_cursor(),
+        midi,
+    })
+}
+
+/// Reads only the title, artist and key from the header of a LYR file, for indexing
+pub fn read_ncn_info(lyrics: &[u8]) -> Result<KaraokeInfo, Box<dyn Error>> {
+    Ok(NcnLyricsReader::from_bytes(lyrics)?.get_info())
+}
+
+/// Finds a file with the same name as `path` but a different extension, either in the
+/// same folder or in a sibling folder named `folder`. Both are matched case-insensitively
+/// since NCN libraries usually come from Windows.
+pub fn find_companion(path: &Path, folder: &str, ext: &str) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_str()?;
+    let dir = path.parent()?;
+
+    let mut dirs = vec![dir.to_path_buf()];
+    if let Some(root) = dir.parent() {
+        if let Ok(entries) = fs::read_dir(root) {
+            dirs.extend(
+                entries
+                    .flatten()
+                    .map(|e| e.path())
+                    .filter(|p| p.is_dir() && eq_name(p.file_name(), folder)),
+            );
+        }
+    }
+
+    dirs.iter().find_map(|dir| {
+        fs::read_dir(dir)
+            .ok()?
+            .flatten()
+            .map(|e| e.path())
+            .find(|p| eq_name(p.file_stem(), stem) && eq_name(p.extension(), ext) && p.is_file())
+    })
+}
+
+fn eq_name(name: Option<&std::ffi::OsStr>, expected: &str) -> bool {
+    name.and_then(|n| n.to_str())
+        .is_some_and(|n| n.eq_ignore_ascii_case(expected))
+}
+
 struct NcnLyricsReader {
     pub title: String,
     pub author: String,
@@ -16,17 +98,13 @@ struct NcnLyricsReader {
 }
 
 impl NcnLyricsReader {
-    fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
-        let mut file = File::open(path).unwrap();
-        let mut file_data = Vec::new();
-        file.read_to_end(&mut file_data).unwrap();
-
-        let (result, enconding) = decode(&file_data, DecoderTrap::Replace, WINDOWS_874);
+    fn from_bytes(file_data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let (result, enconding) = decode(file_data, DecoderTrap::Replace, WINDOWS_874);
 
         let result_data = result?;
-        let title = result_data.lines().next().unwrap().to_string();
-        let author = result_data.lines().nth(1).unwrap().to_string();
-        let key = result_data.lines().nth(2).unwrap().to_string();
+        let title = result_data.lines().next().unwrap_or_default().to_string();
+        let author = result_data.lines().nth(1).unwrap_or_default().to_string();
+        let key = result_data.lines().nth(2).unwrap_or_default().to_string();
 
         let lyrics = result_data
             .lines()
@@ -55,6 +133,7 @@ impl NcnLyricsReader {
             key: String::from(&self.key),
             author: String::from(&self.author),
             language: KaraokeLanguage::Undefined,
+            vocal_channel: None,
         };
     }
 }
@@ -64,14 +143,6 @@ struct NcnCursorReader {
 }
 
 impl NcnCursorReader {
-    fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
-        let mut file = File::open(path).unwrap();
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).unwrap();
-
-        return Ok(Self { data });
-    }
-
     fn get_cursor(&self) -> KaraokeCursor {
         let mut ticks = Vec::new();
 
@@ -92,9 +163,16 @@ impl NcnCursorReader {
             ticks.push(KaraokeCursorTick { tick: time as u32 });
         }
 
+        let words = self
+            .data
+            .chunks(2)
+            .map(|w| u16::from_le_bytes([w[0], w.get(1).copied().unwrap_or(0)]) as u32)
+            .collect();
+
         return KaraokeCursor {
             data: ticks,
             pos: 0,
+            words,
         };
     }
 }
@@ -0,0 +1,217 @@
+//! Audio output stream
+//!
+//! Everything we render is interleaved stereo `f32`, the stream converts it to
+//! whatever sample format and channel count the device wants.
+
+use anyhow::Result;
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, Device, OutputCallbackInfo, Sample, SampleFormat, SampleRate, Stream, StreamConfig,
+    SupportedBufferSize,
+};
+use crossbeam::channel::Sender;
+use log::{debug, error, warn};
+
+use crate::{config::AudioConfig, midi::Error};
+
+/// Sample rates offered in the settings
+pub const SAMPLE_RATES: [u32; 4] = [44100, 48000, 88200, 96000];
+
+/// Buffer sizes (in frames) offered in the settings
+pub const BUFFER_SIZES: [u32; 5] = [128, 256, 512, 1024, 2048];
+
+/// Most frames expected in one callback when the device doesn't say, or says
+/// something huge
+const MAX_FRAMES: u32 = 8192;
+
+/// Names of the available output devices
+pub fn list_output_devices() -> Result<Vec<String>> {
+    let host = cpal::default_host();
+    let names = host
+        .output_devices()?
+        .filter_map(|dev| dev.name().ok())
+        .collect();
+
+    Ok(names)
+}
+
+/// Opens the configured device, or the default one if it's not set or gone.
+///
+/// Also returns the most frames a callback should ask for, so buffers can be
+/// allocated up front.
+pub fn open_device(
+    audio: &AudioConfig,
+) -> Result<(Device, StreamConfig, SampleFormat, usize), Error> {
+    let host = cpal::default_host();
+
+    let dev = audio
+        .device
+        .as_ref()
+        .and_then(|name| {
+            let dev = host
+                .output_devices()
+                .ok()?
+                .find(|dev| dev.name().is_ok_and(|n| &n == name));
+            if dev.is_none() {
+                warn!("audio device {} not found, using the default", name);
+            }
+            dev
+        })
+        .or_else(|| host.default_output_device())
+        .ok_or(Error::NoOutputDevice)?;
+
+    let default = dev
+        .default_output_config()
+        .map_err(Error::DefaultStreamConfig)?;
+
+    let mut format = default.sample_format();
+    let mut config = default.config();
+    let mut buffer_sizes = default.buffer_size().clone();
+
+    if let Some(rate) = audio.sample_rate {
+        let rate = SampleRate(rate);
+        let supported = dev
+            .supported_output_configs()
+            .map_err(Error::SupportedStreamConfigs)?
+            .filter(|c| c.min_sample_rate() <= rate && rate <= c.max_sample_rate())
+            // prefer stereo float, it needs no conversion
+            .max_by_key(|c| (c.channels() == 2, c.sample_format() == SampleFormat::F32));
+
+        match supported {
+            Some(c) => {
+                let c = c.with_sample_rate(rate);
+                format = c.sample_format();
+                config = c.config();
+                buffer_sizes = c.buffer_size().clone();
+            }
+            None => warn!(
+                "sample rate {} not supported, using {}",
+                rate.0, config.sample_rate.0
+            ),
+        }
+    }
+
+    if let Some(frames) = audio.buffer_size {
+        config.buffer_size = BufferSize::Fixed(frames);
+    }
+    let max_frames = match (&config.buffer_size, buffer_sizes) {
+        (BufferSize::Fixed(frames), _) => *frames,
+        (BufferSize::Default, SupportedBufferSize::Range { max, .. }) => max.min(MAX_FRAMES),
+        (BufferSize::Default, SupportedBufferSize::Unknown) => MAX_FRAMES,
+    };
+
+    debug!(
+        "opening audio device {:?} with {:?} ({:?})",
+        dev.name(),
+        config,
+        format
+    );
+
+    Ok((dev, config, format, max_frames as usize))
+}
+
+/// Builds and starts an output stream.
+///
+/// `render` fills an interleaved stereo buffer, stream errors are sent to `errors`.
+pub fn build_stream<F>(
+    dev: &Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    render: F,
+    errors: Sender<String>,
+) -> Result<Stream, Error>
+where
+    F: FnMut(&mut [f32]) + Send + 'static,
+{
+    let stream = match format {
+        SampleFormat::F32 => build::<f32, F>(dev, config, render, errors),
+        SampleFormat::I16 => build::<i16, F>(dev, config, render, errors),
+        SampleFormat::U16 => build::<u16, F>(dev, config, render, errors),
+    }
+    .map_err(Error::BuildStream)?;
+
+    stream.play().map_err(Error::PlayStream)?;
+
+    Ok(stream)
+}
+
+fn build<T, F>(
+    dev: &Device,
+    config: &StreamConfig,
+    mut render: F,
+    errors: Sender<String>,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: Sample,
+    F: FnMut(&mut [f32]) + Send + 'static,
+{
+    let channels = config.channels as usize;
+    let mut stereo = Vec::new();
+
+    let err_fn = move |e| {
+        error!("error [audio stream]: {e}");
+        errors
+            .send(format!("Audio device error: {}", e))
+            .unwrap_or_default();
+    };
+
+    dev.build_output_stream(
+        config,
+        move |data: &mut [T], _: &OutputCallbackInfo| {
+            let frames = data.len() / channels;
+            // only allocates when the device asks for a bigger buffer than before
+            stereo.resize(frames * 2, 0.0);
+            render(&mut stereo);
+            write_frames(data, channels, &stereo);
+        },
+        err_fn,
+    )
+}
+
+/// Copies interleaved stereo into a device buffer.
+///
+/// Mono devices get both sides mixed, extra channels are left silent.
+pub fn write_frames<T: Sample>(out: &mut [T], channels: usize, stereo: &[f32]) {
+    for (frame, lr) in out.chunks_mut(channels).zip(stereo.chunks(2)) {
+        match frame {
+            [mono] => *mono = sample((lr[0] + lr[1]) * 0.5),
+            [left, right, rest @ ..] => {
+                *left = sample(lr[0]);
+                *right = sample(lr[1]);
+                for s in rest {
+                    *s = sample(0.0);
+                }
+            }
+            [] => {}
+        }
+    }
+}
+
+fn sample<T: Sample>(value: f32) -> T {
+    <T as Sample>::from(&value)
+}
+
+#[test]
+fn test_write_mono() {
+    let mut out = [0.0_f32; 2];
+    write_frames(&mut out, 1, &[1.0, 0.0, 0.5, 0.5]);
+    assert_eq!(out, [0.5, 0.5]);
+}
+
+#[test]
+fn test_write_surround() {
+    let mut out = [1.0_f32; 8];
+    write_frames(&mut out, 4, &[0.1, 0.2, 0.3, 0.4]);
+    assert_eq!(out, [0.1, 0.2, 0.0, 0.0, 0.3, 0.4, 0.0, 0.0]);
+}
+
+#[test]
+fn test_write_integer() {
+    let mut out = [0_i16; 2];
+    write_frames(&mut out, 2, &[1.0, -1.0]);
+    assert_eq!(out, [i16::MAX, i16::MIN]);
+
+    let mut out = [0_u16; 2];
+    write_frames(&mut out, 2, &[0.0, 1.0]);
+    assert_eq!(out, [32768, u16::MAX]);
+}
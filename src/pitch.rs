@@ -0,0 +1,196 @@
+//! Pitch tracking for the mic
+//!
+//! Plain YIN (de Cheveigné & Kawahara, 2002) on mono audio downsampled to
+//! [ANALYSIS_RATE], which is plenty for a singing voice and keeps it cheap.
+
+use crate::mic::Resampler;
+
+/// Sample rate the detector runs at
+pub const ANALYSIS_RATE: u32 = 16000;
+
+/// Analysis window, in samples at [ANALYSIS_RATE]
+const WINDOW: usize = 1024;
+
+/// Distance between two windows, 16ms
+const HOP: usize = 256;
+
+/// Windows quieter than this (RMS) count as silence
+const SILENCE: f32 = 0.01;
+
+/// Singing range we look for, in Hz
+const MIN_FREQ: f32 = 70.0;
+const MAX_FREQ: f32 = 1100.0;
+
+/// YIN detector on one window of samples
+#[derive(Debug, Clone)]
+pub struct Yin {
+    sample_rate: u32,
+    /// Dip in the normalized difference needed to accept a period, lower is stricter
+    pub threshold: f32,
+}
+
+impl Yin {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            threshold: 0.15,
+        }
+    }
+
+    /// Fundamental frequency of the window in Hz, `None` for silence or noise
+    pub fn detect(&self, frame: &[f32]) -> Option<f32> {
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt();
+        if rms < SILENCE {
+            return None;
+        }
+
+        let sr = self.sample_rate as f32;
+        let tau_min = (sr / MAX_FREQ) as usize;
+        let tau_max = ((sr / MIN_FREQ) as usize).min(frame.len() / 2);
+        if tau_min + 2 >= tau_max {
+            return None;
+        }
+        let width = frame.len() - tau_max;
+
+        // cumulative mean normalized difference
+        let mut cmnd = vec![1.0_f32; tau_max];
+        let mut sum = 0.0;
+        for tau in 1..tau_max {
+            let d = (0..width)
+                .map(|j| {
+                    let delta = frame[j] - frame[j + tau];
+                    delta * delta
+                })
+                .sum::<f32>();
+            sum += d;
+            cmnd[tau] = if sum > 0.0 { d * tau as f32 / sum } else { 1.0 };
+        }
+
+        let mut tau = (tau_min..tau_max).find(|t| cmnd[*t] < self.threshold)?;
+        // walk down to the bottom of the dip
+        while tau + 1 < tau_max && cmnd[tau + 1] < cmnd[tau] {
+            tau += 1;
+        }
+
+        // parabolic interpolation around the minimum
+        let period = if tau + 1 < tau_max {
+            let (a, b, c) = (cmnd[tau - 1], cmnd[tau], cmnd[tau + 1]);
+            let denom = a - 2.0 * b + c;
+            if denom.abs() > f32::EPSILON {
+                tau as f32 + (a - c) / (2.0 * denom)
+            } else {
+                tau as f32
+            }
+        } else {
+            tau as f32
+        };
+
+        Some(sr / period)
+    }
+}
+
+/// MIDI note number (can be fractional) for a frequency
+pub fn hz_to_midi(freq: f32) -> f32 {
+    69.0 + 12.0 * (freq / 440.0).log2()
+}
+
+/// Nearest note to a MIDI note number, like `A4`
+pub fn note_name(note: f32) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    let note = note.round() as i32;
+    format!("{}{}", NAMES[note.rem_euclid(12) as usize], note.div_euclid(12) - 1)
+}
+
+/// Detected pitch at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchFrame {
+    /// Seconds since the tracker started, at the middle of the window
+    pub time: f64,
+    /// MIDI note number, `None` when nothing is sung
+    pub note: Option<f32>,
+}
+
+/// Runs [Yin] over a stream of samples, one frame every [HOP]
+pub struct PitchTracker {
+    yin: Yin,
+    resampler: Resampler,
+    buf: Vec<f32>,
+    /// Analysis samples dropped from the front of `buf` so far
+    consumed: u64,
+}
+
+impl PitchTracker {
+    /// `sample_rate` is the rate of the samples fed to [PitchTracker::push]
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            yin: Yin::new(ANALYSIS_RATE),
+            resampler: Resampler::new(sample_rate, ANALYSIS_RATE),
+            buf: Vec::with_capacity(WINDOW * 2),
+            consumed: 0,
+        }
+    }
+
+    /// Feeds mono samples, `out` is called for every frame that's done
+    pub fn push(&mut self, samples: &[f32], mut out: impl FnMut(PitchFrame)) {
+        for s in samples {
+            let buf = &mut self.buf;
+            self.resampler.push(*s, |s| buf.push(s));
+        }
+
+        while self.buf.len() >= WINDOW {
+            let center = self.consumed + WINDOW as u64 / 2;
+            out(PitchFrame {
+                time: center as f64 / ANALYSIS_RATE as f64,
+                note: self.yin.detect(&self.buf[..WINDOW]).map(hz_to_midi),
+            });
+            self.buf.drain(..HOP);
+            self.consumed += HOP as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+fn sine(freq: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| 0.5 * (2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate as f32).sin())
+        .collect()
+}
+
+#[test]
+fn test_yin() {
+    let yin = Yin::new(ANALYSIS_RATE);
+    for freq in [110.0, 220.0, 440.0, 660.0] {
+        let detected = yin.detect(&sine(freq, ANALYSIS_RATE, WINDOW)).unwrap();
+        assert!(
+            (detected - freq).abs() < freq * 0.01,
+            "{} != {}",
+            detected,
+            freq
+        );
+    }
+    assert_eq!(yin.detect(&[0.0; WINDOW]), None);
+}
+
+#[test]
+fn test_tracker() {
+    let mut tracker = PitchTracker::new(48000);
+    let mut frames = Vec::new();
+    tracker.push(&sine(440.0, 48000, 48000), |f| frames.push(f));
+
+    assert!(frames.len() > 50);
+    for frame in &frames {
+        let note = frame.note.unwrap();
+        assert!((note - 69.0).abs() < 0.2, "{:?}", frame);
+    }
+    // the first window is centered after half of it has gone by
+    assert!((frames[0].time - WINDOW as f64 / 2.0 / ANALYSIS_RATE as f64).abs() < 1e-9);
+}
+
+#[test]
+fn test_note_name() {
+    assert_eq!(note_name(69.0), "A4");
+    assert_eq!(note_name(60.4), "C4");
+    assert_eq!(note_name(12.6), "C#0");
+}
@@ -0,0 +1,401 @@
+//! Playback controller
+//!
+//! One thread owns the song player. Commands come in over a channel and are
+//! checked against the current [PlaybackState]. State changes go out on the
+//! event bus as [PlaybackEvent]s, along with the song's own. There's never more
+//! than one player thread: a new song stops and joins the old one first.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Result;
+use crossbeam::{
+    channel::{Receiver, Sender},
+    select,
+};
+use log::{debug, error, info};
+use parking_lot::RwLock;
+
+use crate::{
+    events::{EventBus, PlaybackEvent},
+    midi::{MidiContext, MidiControl, MidiMessage},
+    time::PlaybackContext,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PlaybackState {
+    #[default]
+    Stopped,
+    /// Song is being read, it starts playing on its own
+    Loading(PathBuf),
+    Playing(PathBuf),
+    Paused(PathBuf),
+    /// Song played to the end
+    Ended(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackCommand {
+    /// Plays a song, stopping the current one. Resumes if it's the paused song
+    Play(PathBuf),
+    Pause,
+    Resume,
+    TogglePause,
+    Stop,
+    /// Jumps to a MIDI tick, while playing or paused
+    Seek(usize),
+    /// Key change in semitones, for this song and the ones after it
+    Transpose(i8),
+    /// Tempo multiplier, for this song and the ones after it
+    Speed(f32),
+    /// Stops and ends the controller
+    Exit,
+}
+
+impl PlaybackState {
+    /// State after `cmd`, `None` if it doesn't apply right now
+    pub fn next(&self, cmd: &PlaybackCommand) -> Option<Self> {
+        use PlaybackCommand as Cmd;
+        use PlaybackState::*;
+
+        match (self, cmd) {
+            // a second click on Play doesn't start the song twice
+            (Loading(p) | Playing(p), Cmd::Play(q)) if p == q => None,
+            (Paused(p), Cmd::Play(q)) if p == q => Some(Playing(p.clone())),
+            (_, Cmd::Play(q)) => Some(Loading(q.clone())),
+            (Playing(p), Cmd::Pause | Cmd::TogglePause) => Some(Paused(p.clone())),
+            (Paused(p), Cmd::Resume | Cmd::TogglePause) => Some(Playing(p.clone())),
+            (Loading(_) | Playing(_) | Paused(_) | Ended(_), Cmd::Stop | Cmd::Exit) => {
+                Some(Stopped)
+            }
+            _ => None,
+        }
+    }
+
+    /// Song that's loaded, if any
+    pub fn song(&self) -> Option<&PathBuf> {
+        match self {
+            Self::Stopped => None,
+            Self::Loading(p) | Self::Playing(p) | Self::Paused(p) | Self::Ended(p) => Some(p),
+        }
+    }
+}
+
+/// Handle to the controller thread
+#[derive(Clone)]
+pub struct Playback {
+    commands: Sender<PlaybackCommand>,
+    state: Arc<RwLock<PlaybackState>>,
+    /// Notifications from the song, for any number of subscribers
+    pub events: EventBus<PlaybackEvent>,
+    /// Position and length of the song, filled in by the player
+    pub context: Arc<RwLock<PlaybackContext>>,
+}
+
+impl Playback {
+    /// Starts the controller, songs are played into `midi`
+    pub fn spawn(midi: Sender<MidiMessage>) -> Self {
+        let (commands, rx) = crossbeam::channel::unbounded();
+        let playback = Self {
+            commands,
+            state: Arc::new(RwLock::new(PlaybackState::Stopped)),
+            events: EventBus::new(),
+            context: Arc::new(RwLock::new(PlaybackContext::new())),
+        };
+
+        let (player_tx, player_rx) = crossbeam::channel::unbounded();
+        let controller = Controller {
+            midi,
+            handle: playback.clone(),
+            player: None,
+            song: 0,
+            player_tx,
+            player_rx,
+            transpose: 0,
+            speed: 1.0,
+        };
+        thread::spawn(move || controller.run(rx));
+
+        playback
+    }
+
+    pub fn send(&self, cmd: PlaybackCommand) {
+        self.commands.send(cmd).unwrap_or_default();
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.state.read().clone()
+    }
+}
+
+/// What the player thread reports back, tagged with the song number it was started for
+enum PlayerEvent {
+    Started(u64),
+    Finished(u64, Result<()>),
+}
+
+struct Player {
+    thread: JoinHandle<()>,
+    midi_context: Arc<RwLock<MidiContext>>,
+    /// Toggles pause, dropping it releases a paused player
+    pause: Sender<()>,
+}
+
+struct Controller {
+    midi: Sender<MidiMessage>,
+    handle: Playback,
+    player: Option<Player>,
+    /// Counts songs started, so events from a stopped player are ignored
+    song: u64,
+    player_tx: Sender<PlayerEvent>,
+    player_rx: Receiver<PlayerEvent>,
+    transpose: i8,
+    speed: f32,
+}
+
+impl Controller {
+    fn run(mut self, commands: Receiver<PlaybackCommand>) {
+        loop {
+            select! {
+                recv(commands) -> cmd => {
+                    let cmd = cmd.unwrap_or(PlaybackCommand::Exit);
+                    let exit = cmd == PlaybackCommand::Exit;
+                    self.command(cmd);
+                    if exit {
+                        break;
+                    }
+                }
+                recv(self.player_rx) -> event => {
+                    if let Ok(event) = event {
+                        self.player_event(event);
+                    }
+                }
+            }
+        }
+        debug!("playback controller exited");
+    }
+
+    fn command(&mut self, cmd: PlaybackCommand) {
+        match cmd {
+            PlaybackCommand::Seek(tick) => {
+                if let Some(player) = &self.player {
+                    let mut ctx = player.midi_context.write();
+                    ctx.midi_tick = tick;
+                    ctx.seek = true;
+                }
+                return;
+            }
+            PlaybackCommand::Transpose(transpose) => {
+                self.transpose = transpose;
+                if let Some(player) = &self.player {
+                    player.midi_context.write().transpose = transpose;
+                }
+                return;
+            }
+            PlaybackCommand::Speed(speed) => {
+                self.speed = speed;
+                if let Some(player) = &self.player {
+                    player.midi_context.write().speed = speed;
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        let current = self.handle.state();
+        let next = match current.next(&cmd) {
+            Some(next) => next,
+            None => {
+                debug!("ignoring {:?} while {:?}", cmd, current);
+                return;
+            }
+        };
+
+        match (&current, &next) {
+            (_, PlaybackState::Loading(path)) => {
+                self.stop_player();
+                self.start(path.clone());
+            }
+            (PlaybackState::Playing(_), PlaybackState::Paused(_)) => {
+                self.toggle_pause();
+                self.midi.send(MidiMessage::ClearNotes).unwrap_or_default();
+            }
+            (PlaybackState::Paused(_), PlaybackState::Playing(_)) => self.toggle_pause(),
+            (_, PlaybackState::Stopped) => self.stop_player(),
+            _ => {}
+        }
+        self.set_state(next);
+    }
+
+    fn player_event(&mut self, event: PlayerEvent) {
+        match event {
+            PlayerEvent::Started(song) if song == self.song => {
+                let (player, path) = match (&self.player, self.handle.state()) {
+                    (Some(player), PlaybackState::Loading(path)) => (player, path),
+                    _ => return,
+                };
+                let (total, ticks) = {
+                    let ctx = player.midi_context.read();
+                    let total = ctx.total.and_then(|t| t.to_std().ok());
+                    (total.unwrap_or_default(), ctx.midi_tick_max)
+                };
+                self.handle.events.publish(PlaybackEvent::Loaded {
+                    path: path.clone(),
+                    total,
+                    ticks,
+                });
+                self.set_state(PlaybackState::Playing(path));
+            }
+            PlayerEvent::Finished(song, res) if song == self.song && self.player.is_some() => {
+                self.stop_player();
+                let path = match self.handle.state().song() {
+                    Some(path) => path.clone(),
+                    None => return,
+                };
+                match res {
+                    Ok(()) => self.set_state(PlaybackState::Ended(path)),
+                    Err(e) => {
+                        error!("failed playing {}: {}", path.display(), e);
+                        self.handle.events.publish(PlaybackEvent::Error(format!(
+                            "Failed to play {}: {}",
+                            path.display(),
+                            e
+                        )));
+                        self.set_state(PlaybackState::Stopped);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, path: PathBuf) {
+        info!("playing {}", path.display());
+        self.song += 1;
+        let song = self.song;
+
+        let (pause, pause_rx) = crossbeam::channel::unbounded();
+        let mut control = MidiControl::new(
+            self.midi.clone(),
+            self.handle.context.clone(),
+            pause_rx,
+            self.handle.events.clone(),
+        );
+        let midi_context = control.midi_context.clone();
+        {
+            let mut ctx = midi_context.write();
+            ctx.transpose = self.transpose;
+            ctx.speed = self.speed;
+            ctx.start();
+        }
+        let player_tx = self.player_tx.clone();
+
+        let thread = thread::spawn(move || {
+            let started = || {
+                player_tx
+                    .send(PlayerEvent::Started(song))
+                    .unwrap_or_default()
+            };
+            let res = control.play(&path, started);
+            player_tx
+                .send(PlayerEvent::Finished(song, res))
+                .unwrap_or_default();
+        });
+
+        self.player = Some(Player {
+            thread,
+            midi_context,
+            pause,
+        });
+    }
+
+    fn toggle_pause(&self) {
+        if let Some(player) = &self.player {
+            player.pause.send(()).unwrap_or_default();
+        }
+    }
+
+    /// Stops the player and waits for its thread to end
+    fn stop_player(&mut self) {
+        if let Some(Player {
+            thread,
+            midi_context,
+            pause,
+        }) = self.player.take()
+        {
+            midi_context.write().stop();
+            drop(pause);
+            if thread.join().is_err() {
+                error!("player thread panicked");
+            }
+            self.midi.send(MidiMessage::ClearNotes).unwrap_or_default();
+        }
+    }
+
+    fn set_state(&self, state: PlaybackState) {
+        use PlaybackState::*;
+
+        debug!("playback state: {:?}", state);
+        self.handle.context.write().paused = matches!(state, PlaybackState::Paused(_));
+        let old = std::mem::replace(&mut *self.handle.state.write(), state.clone());
+        let event = match (old, &state) {
+            (Loading(_), Playing(p)) => Some(PlaybackEvent::Started(p.clone())),
+            (Playing(_), Paused(p)) => Some(PlaybackEvent::Paused(p.clone())),
+            (Paused(_), Playing(p)) => Some(PlaybackEvent::Resumed(p.clone())),
+            (_, Ended(p)) => Some(PlaybackEvent::Ended(p.clone())),
+            (Loading(_) | Playing(_) | Paused(_) | Ended(_), Stopped) => {
+                Some(PlaybackEvent::Stopped)
+            }
+            _ => None,
+        };
+        if let Some(event) = event {
+            self.handle.events.publish(event);
+        }
+    }
+}
+
+#[test]
+fn test_transitions() {
+    use PlaybackCommand as Cmd;
+    use PlaybackState::*;
+
+    let a = PathBuf::from("a.mid");
+    let b = PathBuf::from("b.mid");
+
+    assert_eq!(
+        Stopped.next(&Cmd::Play(a.clone())),
+        Some(Loading(a.clone()))
+    );
+    // double clicks don't restart
+    assert_eq!(Loading(a.clone()).next(&Cmd::Play(a.clone())), None);
+    assert_eq!(Playing(a.clone()).next(&Cmd::Play(a.clone())), None);
+    assert_eq!(
+        Playing(a.clone()).next(&Cmd::Play(b.clone())),
+        Some(Loading(b.clone()))
+    );
+    assert_eq!(
+        Ended(a.clone()).next(&Cmd::Play(a.clone())),
+        Some(Loading(a.clone()))
+    );
+
+    assert_eq!(
+        Playing(a.clone()).next(&Cmd::Pause),
+        Some(Paused(a.clone()))
+    );
+    assert_eq!(Paused(a.clone()).next(&Cmd::Pause), None);
+    assert_eq!(
+        Paused(a.clone()).next(&Cmd::Play(a.clone())),
+        Some(Playing(a.clone()))
+    );
+    assert_eq!(
+        Paused(a.clone()).next(&Cmd::TogglePause),
+        Some(Playing(a.clone()))
+    );
+    assert_eq!(Loading(a.clone()).next(&Cmd::Pause), None);
+
+    assert_eq!(Paused(a.clone()).next(&Cmd::Stop), Some(Stopped));
+    assert_eq!(Stopped.next(&Cmd::Stop), None);
+}
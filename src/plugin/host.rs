@@ -0,0 +1,442 @@
+//! Loading CLAP libraries and running their plugins
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    mem,
+    os::raw::{c_char, c_void},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{Arc, Weak},
+};
+
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use log::{debug, trace};
+use parking_lot::Mutex;
+
+use super::{ffi::*, ParamInfo};
+
+lazy_static! {
+    /// Open libraries, so a file used twice doesn't get initialized twice
+    static ref LIBRARIES: Mutex<HashMap<PathBuf, Weak<PluginLibrary>>> = Mutex::new(HashMap::new());
+}
+
+/// A loaded `.clap` file
+pub struct PluginLibrary {
+    entry: *const clap_plugin_entry,
+    path: PathBuf,
+    /// `None` for entries linked into the binary
+    _lib: Option<libloading::Library>,
+}
+
+// CLAP entries and factories have to be thread safe
+unsafe impl Send for PluginLibrary {}
+unsafe impl Sync for PluginLibrary {}
+
+/// What a library says about one of its plugins
+#[derive(Debug, Clone)]
+pub struct PluginDescriptor {
+    pub id: String,
+    pub name: String,
+    pub vendor: String,
+    pub version: String,
+}
+
+impl PluginLibrary {
+    /// Opens a `.clap` file, or hands out the one that's already open
+    pub fn open(path: &Path) -> Result<Arc<Self>> {
+        let mut libraries = LIBRARIES.lock();
+        if let Some(lib) = libraries.get(path).and_then(Weak::upgrade) {
+            return Ok(lib);
+        }
+
+        debug!("loading CLAP library {}", path.display());
+        let lib = unsafe { libloading::Library::new(path)? };
+        let entry = unsafe { *lib.get::<*const clap_plugin_entry>(b"clap_entry\0")? };
+        let lib = Arc::new(Self::init(entry, path, Some(lib))?);
+        libraries.insert(path.to_path_buf(), Arc::downgrade(&lib));
+
+        Ok(lib)
+    }
+
+    /// Uses an entry linked into the binary, [PluginLibrary::open] finds it under `path`
+    /// for as long as it's alive
+    #[cfg(test)]
+    pub fn from_entry(entry: &'static clap_plugin_entry, path: &Path) -> Result<Arc<Self>> {
+        let lib = Arc::new(Self::init(entry, path, None)?);
+        LIBRARIES
+            .lock()
+            .insert(path.to_path_buf(), Arc::downgrade(&lib));
+        Ok(lib)
+    }
+
+    fn init(
+        entry: *const clap_plugin_entry,
+        path: &Path,
+        lib: Option<libloading::Library>,
+    ) -> Result<Self> {
+        let e = unsafe { entry.as_ref() }.ok_or_else(|| anyhow!("clap_entry is null"))?;
+        let version = e.clap_version;
+        if !version.is_compatible() {
+            bail!(
+                "built for CLAP {}.{}.{}, which isn't supported",
+                version.major,
+                version.minor,
+                version.revision
+            );
+        }
+
+        let c_path = CString::new(path.to_string_lossy().as_bytes())?;
+        let init = e.init.ok_or_else(|| missing("entry init"))?;
+        if !unsafe { init(c_path.as_ptr()) } {
+            bail!("failed to initialize");
+        }
+
+        Ok(Self {
+            entry,
+            path: path.to_path_buf(),
+            _lib: lib,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn factory(&self) -> Result<&clap_plugin_factory> {
+        let get_factory =
+            unsafe { (*self.entry).get_factory }.ok_or_else(|| missing("get_factory"))?;
+        let factory = unsafe { get_factory(CLAP_PLUGIN_FACTORY_ID.as_ptr() as *const c_char) }
+            as *const clap_plugin_factory;
+        unsafe { factory.as_ref() }.ok_or_else(|| anyhow!("no plugin factory"))
+    }
+
+    /// Plugins in the library
+    pub fn plugins(&self) -> Result<Vec<PluginDescriptor>> {
+        let factory = self.factory()?;
+        let count = factory
+            .get_plugin_count
+            .ok_or_else(|| missing("get_plugin_count"))?;
+        let get = factory
+            .get_plugin_descriptor
+            .ok_or_else(|| missing("get_plugin_descriptor"))?;
+
+        let descriptors = (0..unsafe { count(factory) })
+            .filter_map(|i| unsafe { get(factory, i).as_ref() })
+            .map(|desc| unsafe {
+                PluginDescriptor {
+                    id: string(desc.id),
+                    name: string(desc.name),
+                    vendor: string(desc.vendor),
+                    version: string(desc.version),
+                }
+            })
+            .collect();
+
+        Ok(descriptors)
+    }
+}
+
+impl Drop for PluginLibrary {
+    fn drop(&mut self) {
+        if let Some(deinit) = unsafe { (*self.entry).deinit } {
+            unsafe { deinit() };
+        }
+    }
+}
+
+fn missing(name: &str) -> anyhow::Error {
+    anyhow!("plugin doesn't implement {}", name)
+}
+
+/// Owned copy of a C string, empty for null
+unsafe fn string(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
+struct Host(clap_host);
+
+// only points at static strings
+unsafe impl Sync for Host {}
+
+static HOST: Host = Host(clap_host {
+    clap_version: CLAP_VERSION,
+    host_data: ptr::null_mut(),
+    name: c"RustyKaraoke".as_ptr(),
+    vendor: c"RustyKaraoke".as_ptr(),
+    url: c"".as_ptr(),
+    version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+    get_extension: Some(host_get_extension),
+    request_restart: Some(host_request),
+    request_process: Some(host_request),
+    request_callback: Some(host_request),
+});
+
+/// No host extensions yet
+unsafe extern "C" fn host_get_extension(_: *const clap_host, id: *const c_char) -> *const c_void {
+    trace!("plugin asked for host extension {}", string(id));
+    ptr::null()
+}
+
+/// Restarts and main thread callbacks aren't supported, plugins keep running as they are
+unsafe extern "C" fn host_request(_: *const clap_host) {}
+
+unsafe extern "C" fn events_size(list: *const clap_input_events) -> u32 {
+    let events = &*((*list).ctx as *const Vec<clap_event_param_value>);
+    events.len() as u32
+}
+
+unsafe extern "C" fn events_get(
+    list: *const clap_input_events,
+    index: u32,
+) -> *const clap_event_header {
+    let events = &*((*list).ctx as *const Vec<clap_event_param_value>);
+    events
+        .get(index as usize)
+        .map_or(ptr::null(), |e| &e.header as *const _)
+}
+
+/// We don't listen to what plugins send back
+unsafe extern "C" fn events_discard(
+    _: *const clap_output_events,
+    _: *const clap_event_header,
+) -> bool {
+    true
+}
+
+/// An activated plugin.
+///
+/// Made on the MIDI thread and processed on the audio thread, which is the split CLAP
+/// expects between its main and audio threads.
+pub struct PluginInstance {
+    plugin: *const clap_plugin,
+    params: Vec<ParamInfo>,
+    activated: bool,
+    processing: bool,
+    steady_time: i64,
+    /// Param changes waiting for the next process call
+    pending: Vec<(u32, f64)>,
+    /// Events handed to the plugin, kept to not allocate on the audio thread
+    events: Vec<clap_event_param_value>,
+    /// Dropped after the plugin is destroyed
+    _lib: Arc<PluginLibrary>,
+}
+
+unsafe impl Send for PluginInstance {}
+
+impl PluginInstance {
+    /// Creates and activates a plugin, [PluginInstance::process] can't be given more than `max_frames`
+    pub fn new(
+        lib: Arc<PluginLibrary>,
+        id: &str,
+        sample_rate: u32,
+        max_frames: u32,
+    ) -> Result<Self> {
+        let factory = lib.factory()?;
+        let create = factory
+            .create_plugin
+            .ok_or_else(|| missing("create_plugin"))?;
+        let c_id = CString::new(id)?;
+
+        let plugin = unsafe { create(factory, &HOST.0, c_id.as_ptr()) };
+        if plugin.is_null() {
+            bail!("no plugin {}", id);
+        }
+
+        // from here on Drop cleans up
+        let mut instance = Self {
+            plugin,
+            params: Vec::new(),
+            activated: false,
+            processing: false,
+            steady_time: 0,
+            pending: Vec::new(),
+            events: Vec::new(),
+            _lib: lib,
+        };
+
+        let p = unsafe { &*plugin };
+        let init = p.init.ok_or_else(|| missing("init"))?;
+        if !unsafe { init(plugin) } {
+            bail!("{} failed to initialize", id);
+        }
+
+        instance.params = instance.read_params();
+
+        let activate = p.activate.ok_or_else(|| missing("activate"))?;
+        if !unsafe { activate(plugin, sample_rate as f64, 1, max_frames) } {
+            bail!("{} failed to activate", id);
+        }
+        instance.activated = true;
+
+        Ok(instance)
+    }
+
+    fn read_params(&self) -> Vec<ParamInfo> {
+        let p = unsafe { &*self.plugin };
+        let ext = p.get_extension.map_or(ptr::null(), |get| unsafe {
+            get(self.plugin, CLAP_EXT_PARAMS.as_ptr() as *const c_char)
+        }) as *const clap_plugin_params;
+
+        let (count, get_info) = match unsafe { ext.as_ref() } {
+            Some(clap_plugin_params {
+                count: Some(count),
+                get_info: Some(get_info),
+                ..
+            }) => (count, get_info),
+            _ => return Vec::new(),
+        };
+
+        (0..unsafe { count(self.plugin) })
+            .filter_map(|i| {
+                let mut info: clap_param_info = unsafe { mem::zeroed() };
+                if !unsafe { get_info(self.plugin, i, &mut info) } {
+                    return None;
+                }
+                Some(ParamInfo {
+                    id: info.id,
+                    name: unsafe { string(info.name.as_ptr()) },
+                    min: info.min_value,
+                    max: info.max_value,
+                    default: info.default_value,
+                })
+            })
+            .collect()
+    }
+
+    pub fn params(&self) -> &[ParamInfo] {
+        &self.params
+    }
+
+    /// Queues a param change, it's sent with the next [PluginInstance::process]
+    pub fn set_param(&mut self, id: u32, value: f64) {
+        self.pending.retain(|(i, _)| *i != id);
+        self.pending.push((id, value));
+        // room for all of them, so process doesn't have to grow it
+        self.events.reserve(self.pending.len());
+    }
+
+    /// Stops processing, called on the audio thread before the plugin is dropped
+    pub fn stop(&mut self) {
+        if self.processing {
+            let p = unsafe { &*self.plugin };
+            if let Some(stop) = p.stop_processing {
+                unsafe { stop(self.plugin) };
+            }
+            self.processing = false;
+        }
+    }
+
+    pub fn is_processing(&self) -> bool {
+        self.processing
+    }
+
+    /// Runs the plugin on planar stereo, `false` if it failed and `output` is garbage
+    pub fn process(&mut self, input: [&[f32]; 2], output: [&mut [f32]; 2]) -> bool {
+        let frames = input[0].len();
+        let p = unsafe { &*self.plugin };
+
+        if !self.processing {
+            let started = p
+                .start_processing
+                .is_none_or(|start| unsafe { start(self.plugin) });
+            if !started {
+                return false;
+            }
+            self.processing = true;
+        }
+        let process = match p.process {
+            Some(process) => process,
+            None => return false,
+        };
+
+        self.events.clear();
+        let events = self.pending.drain(..).map(|(param_id, value)| clap_event_param_value {
+            header: clap_event_header {
+                size: mem::size_of::<clap_event_param_value>() as u32,
+                time: 0,
+                space_id: CLAP_CORE_EVENT_SPACE_ID,
+                type_: CLAP_EVENT_PARAM_VALUE,
+                flags: 0,
+            },
+            param_id,
+            cookie: ptr::null_mut(),
+            note_id: -1,
+            port_index: -1,
+            channel: -1,
+            key: -1,
+            value,
+        });
+        self.events.extend(events);
+        let in_events = clap_input_events {
+            ctx: &self.events as *const Vec<_> as *mut c_void,
+            size: Some(events_size),
+            get: Some(events_get),
+        };
+        let out_events = clap_output_events {
+            ctx: ptr::null_mut(),
+            try_push: Some(events_discard),
+        };
+
+        let [left, right] = output;
+        let mut in_ptrs = [input[0].as_ptr() as *mut f32, input[1].as_ptr() as *mut f32];
+        let mut out_ptrs = [left.as_mut_ptr(), right.as_mut_ptr()];
+        let audio_in = clap_audio_buffer {
+            data32: in_ptrs.as_mut_ptr(),
+            data64: ptr::null_mut(),
+            channel_count: 2,
+            latency: 0,
+            constant_mask: 0,
+        };
+        let mut audio_out = clap_audio_buffer {
+            data32: out_ptrs.as_mut_ptr(),
+            data64: ptr::null_mut(),
+            channel_count: 2,
+            latency: 0,
+            constant_mask: 0,
+        };
+
+        let data = clap_process {
+            steady_time: self.steady_time,
+            frames_count: frames as u32,
+            transport: ptr::null(),
+            audio_inputs: &audio_in,
+            audio_outputs: &mut audio_out,
+            audio_inputs_count: 1,
+            audio_outputs_count: 1,
+            in_events: &in_events,
+            out_events: &out_events,
+        };
+
+        let status = unsafe { process(self.plugin, &data) };
+        self.steady_time += frames as i64;
+
+        status != CLAP_PROCESS_ERROR
+    }
+}
+
+impl Drop for PluginInstance {
+    fn drop(&mut self) {
+        if self.processing {
+            // should've been stopped on the audio thread, better late than never
+            debug!("plugin dropped while processing");
+            self.stop();
+        }
+        let p = unsafe { &*self.plugin };
+        unsafe {
+            if self.activated {
+                if let Some(deactivate) = p.deactivate {
+                    deactivate(self.plugin);
+                }
+            }
+            if let Some(destroy) = p.destroy {
+                destroy(self.plugin);
+            }
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Effect plugins on the master and microphone chains
+//!
+//! Only CLAP is supported. The host is small on purpose: stereo in, stereo out
+//! and parameters, no GUIs, no MIDI and no extensions the plugin could ask for.
+//! Plugins are loaded on the MIDI thread and processed on the audio thread.
+
+pub mod ffi;
+pub mod host;
+pub mod scan;
+#[cfg(test)]
+mod test_plugin;
+
+use std::{mem, path::PathBuf, thread, time::Duration};
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use host::{PluginInstance, PluginLibrary};
+
+/// Most frames handed to a plugin in one go
+pub const MAX_FRAMES: usize = 4096;
+
+/// How long [apply] waits on the audio thread to stop the plugins it replaced
+const RETIRE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A plugin in a chain, as stored in the config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginSlot {
+    /// The `.clap` file
+    pub path: PathBuf,
+    /// Plugin id inside the file
+    pub id: String,
+    pub enabled: bool,
+    /// Params moved away from the plugin's defaults
+    pub params: Vec<ParamValue>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ParamValue {
+    pub id: u32,
+    pub value: f64,
+}
+
+impl PluginSlot {
+    pub fn new(path: PathBuf, id: String) -> Self {
+        Self {
+            path,
+            id,
+            enabled: true,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn param(&self, id: u32) -> Option<f64> {
+        self.params.iter().find(|p| p.id == id).map(|p| p.value)
+    }
+
+    pub fn set_param(&mut self, id: u32, value: f64) {
+        match self.params.iter_mut().find(|p| p.id == id) {
+            Some(p) => p.value = value,
+            None => self.params.push(ParamValue { id, value }),
+        }
+    }
+
+    /// Same plugin, whatever the params
+    fn same_plugin(&self, other: &PluginSlot) -> bool {
+        self.path == other.path && self.id == other.id
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParamInfo {
+    pub id: u32,
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+}
+
+/// Plugins run one after the other on interleaved stereo
+#[derive(Default)]
+pub struct PluginChain {
+    slots: Vec<PluginSlot>,
+    /// Lines up with `slots`, `None` where the plugin failed to load
+    plugins: Vec<Option<PluginInstance>>,
+    sample_rate: u32,
+    /// Planar scratch buffers
+    input: [Vec<f32>; 2],
+    output: [Vec<f32>; 2],
+    /// Replaced plugins, stopped by the audio thread before they're dropped
+    retired: Vec<PluginInstance>,
+}
+
+impl PluginChain {
+    /// Loads the plugins of `slots`, the ones that fail are skipped while playing
+    pub fn new(slots: &[PluginSlot], sample_rate: u32) -> (Self, Vec<anyhow::Error>) {
+        let mut errors = Vec::new();
+        let plugins = slots
+            .iter()
+            .map(|slot| {
+                load(slot, sample_rate)
+                    .with_context(|| format!("failed loading plugin {}", slot.path.display()))
+                    .map_err(|e| errors.push(e))
+                    .ok()
+            })
+            .collect();
+
+        let chain = Self {
+            slots: slots.to_vec(),
+            plugins,
+            sample_rate,
+            input: [vec![0.0; MAX_FRAMES], vec![0.0; MAX_FRAMES]],
+            output: [vec![0.0; MAX_FRAMES], vec![0.0; MAX_FRAMES]],
+            retired: Vec::new(),
+        };
+        (chain, errors)
+    }
+
+    /// Whether `slots` can be applied without loading anything
+    fn can_update(&self, slots: &[PluginSlot], sample_rate: u32) -> bool {
+        self.sample_rate == sample_rate
+            && self.slots.len() == slots.len()
+            && self.slots.iter().zip(slots).all(|(a, b)| a.same_plugin(b))
+    }
+
+    /// Applies param and bypass changes
+    fn update(&mut self, slots: &[PluginSlot]) {
+        for ((old, new), plugin) in self.slots.iter().zip(slots).zip(&mut self.plugins) {
+            let plugin = match plugin {
+                Some(plugin) => plugin,
+                None => continue,
+            };
+            for info in plugin.params().to_vec() {
+                let value = new.param(info.id).unwrap_or(info.default);
+                if old.param(info.id).unwrap_or(info.default) != value {
+                    plugin.set_param(info.id, value);
+                }
+            }
+        }
+        self.slots = slots.to_vec();
+    }
+
+    /// Processes an interleaved stereo buffer in place
+    pub fn process(&mut self, buf: &mut [f32]) {
+        for plugin in &mut self.retired {
+            plugin.stop();
+        }
+
+        let active = self
+            .slots
+            .iter()
+            .zip(&self.plugins)
+            .any(|(slot, plugin)| slot.enabled && plugin.is_some());
+        if !active {
+            return;
+        }
+
+        for chunk in buf.chunks_mut(MAX_FRAMES * 2) {
+            let frames = chunk.len() / 2;
+            for (i, frame) in chunk.chunks_exact(2).enumerate() {
+                self.input[0][i] = frame[0];
+                self.input[1][i] = frame[1];
+            }
+
+            for (slot, plugin) in self.slots.iter().zip(&mut self.plugins) {
+                let plugin = match plugin {
+                    Some(plugin) if slot.enabled => plugin,
+                    _ => continue,
+                };
+                let [in_l, in_r] = &self.input;
+                let [out_l, out_r] = &mut self.output;
+                let ok = plugin.process(
+                    [&in_l[..frames], &in_r[..frames]],
+                    [&mut out_l[..frames], &mut out_r[..frames]],
+                );
+                // a failed plugin is passed by
+                if ok {
+                    mem::swap(&mut self.input, &mut self.output);
+                }
+            }
+
+            for (i, frame) in chunk.chunks_exact_mut(2).enumerate() {
+                frame[0] = self.input[0][i];
+                frame[1] = self.input[1][i];
+            }
+        }
+    }
+}
+
+fn load(slot: &PluginSlot, sample_rate: u32) -> Result<PluginInstance> {
+    let lib = PluginLibrary::open(&slot.path)?;
+    let mut plugin = PluginInstance::new(lib, &slot.id, sample_rate, MAX_FRAMES as u32)?;
+    for param in &slot.params {
+        plugin.set_param(param.id, param.value);
+    }
+    Ok(plugin)
+}
+
+/// Brings a shared chain in line with `slots`.
+///
+/// Param changes go straight in, anything else loads a new chain outside the
+/// lock so the audio keeps going while plugins start up.
+pub fn apply(
+    chain: &Mutex<PluginChain>,
+    slots: &[PluginSlot],
+    sample_rate: u32,
+) -> Vec<anyhow::Error> {
+    {
+        let mut chain = chain.lock();
+        if chain.can_update(slots, sample_rate) {
+            chain.update(slots);
+            return Vec::new();
+        }
+    }
+
+    let (mut new, errors) = PluginChain::new(slots, sample_rate);
+    let mut old = {
+        let mut chain = chain.lock();
+        new.retired = chain.plugins.drain(..).flatten().collect();
+        mem::replace(&mut *chain, new)
+    };
+
+    // CLAP wants processing stopped on the audio thread, give it a few buffers
+    let mut waited = Duration::ZERO;
+    while chain.lock().retired.iter().any(|p| p.is_processing()) && waited < RETIRE_TIMEOUT {
+        thread::sleep(Duration::from_millis(10));
+        waited += Duration::from_millis(10);
+    }
+    old.retired = mem::take(&mut chain.lock().retired);
+    // plugins are torn down here, not while holding the lock
+    drop(old);
+    errors
+}
+
+#[test]
+fn test_plugin_chain() {
+    let path = std::path::Path::new("/in-tree/test-gain.clap");
+    let lib = PluginLibrary::from_entry(&test_plugin::ENTRY, path).unwrap();
+
+    let info = scan::scan_library(&lib).unwrap();
+    assert_eq!(info.len(), 1);
+    assert_eq!(info[0].id, test_plugin::PLUGIN_ID);
+    assert_eq!(info[0].params[0].name, "Gain");
+
+    let mut slot = PluginSlot::new(path.into(), test_plugin::PLUGIN_ID.into());
+    slot.set_param(test_plugin::GAIN_PARAM, 0.5);
+    let chain = Mutex::new(PluginChain::default());
+    assert!(apply(&chain, &[slot.clone()], 48000).is_empty());
+
+    // more than one block's worth
+    let mut buf = vec![0.5; MAX_FRAMES * 3];
+    chain.lock().process(&mut buf);
+    assert!(buf.iter().all(|s| *s == 0.25));
+
+    // param changes don't reload the plugin
+    slot.set_param(test_plugin::GAIN_PARAM, 2.0);
+    assert!(apply(&chain, &[slot.clone()], 48000).is_empty());
+    chain.lock().process(&mut buf);
+    assert!(buf.iter().all(|s| *s == 0.5));
+
+    slot.enabled = false;
+    apply(&chain, &[slot.clone()], 48000);
+    chain.lock().process(&mut buf);
+    assert!(buf.iter().all(|s| *s == 0.5));
+
+    // slots survive the config
+    let json = serde_json::to_string(&slot).unwrap();
+    assert_eq!(serde_json::from_str::<PluginSlot>(&json).unwrap(), slot);
+}
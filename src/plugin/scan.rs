@@ -0,0 +1,181 @@
+//! Finding installed CLAP plugins
+//!
+//! Loading every library on startup is slow and some plugins crash doing it,
+//! so what a scan found is cached and a file is only loaded again once it changes.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    host::{PluginInstance, PluginLibrary},
+    ParamInfo, MAX_FRAMES,
+};
+use crate::config::APP_DIR;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginInfo {
+    pub path: PathBuf,
+    pub id: String,
+    pub name: String,
+    pub vendor: String,
+    pub version: String,
+    pub params: Vec<ParamInfo>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanCache {
+    files: Vec<ScannedFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScannedFile {
+    path: PathBuf,
+    /// Modification time in seconds, the file is scanned again when it changes
+    modified: u64,
+    plugins: Vec<PluginInfo>,
+    /// Why the file didn't load, kept so it isn't tried on every scan
+    error: Option<String>,
+}
+
+/// Folders searched for `.clap` files: `CLAP_PATH`, then the standard ones
+pub fn search_paths() -> Vec<PathBuf> {
+    let mut paths = std::env::var_os("CLAP_PATH")
+        .map(|p| std::env::split_paths(&p).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".clap"));
+    }
+    paths.push("/usr/lib/clap".into());
+    paths.push("/usr/local/lib/clap".into());
+    paths
+}
+
+/// `.clap` files under `dir`, they can be bundles in subfolders
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.extension().is_some_and(|ext| ext == "clap") && path.is_file() {
+            files.push(path);
+        } else if path.is_dir() {
+            find_files(&path, files);
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<u64> {
+    let time = fs::metadata(path).ok()?.modified().ok()?;
+    Some(time.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+impl ScanCache {
+    pub fn path() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join(APP_DIR).join("clap-plugins.json"))
+    }
+
+    /// Loads the cache, empty if there's none yet
+    pub fn load() -> Self {
+        let path = match Self::path() {
+            Some(path) if path.exists() => path,
+            _ => return Self::default(),
+        };
+        fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?))
+            .unwrap_or_else(|e| {
+                warn!("failed loading plugin cache {}: {}", path.display(), e);
+                Self::default()
+            })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("no cache folder on this platform"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn plugins(&self) -> impl Iterator<Item = &PluginInfo> {
+        self.files.iter().flat_map(|f| &f.plugins)
+    }
+
+    /// Files that failed to load, with the reason
+    pub fn errors(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.files
+            .iter()
+            .filter_map(|f| Some((f.path.as_path(), f.error.as_deref()?)))
+    }
+
+    /// Looks through the search paths, only loading files that are new or changed
+    pub fn rescan(&mut self) {
+        let mut paths = Vec::new();
+        for dir in search_paths() {
+            find_files(&dir, &mut paths);
+        }
+        paths.sort();
+        paths.dedup();
+
+        let files = paths
+            .into_iter()
+            .filter_map(|path| {
+                let modified = modified(&path)?;
+                if let Some(cached) = self
+                    .files
+                    .iter()
+                    .find(|f| f.path == path && f.modified == modified)
+                {
+                    return Some(cached.clone());
+                }
+
+                debug!("scanning {}", path.display());
+                let (plugins, error) =
+                    match PluginLibrary::open(&path).and_then(|lib| scan_library(&lib)) {
+                        Ok(plugins) => (plugins, None),
+                        Err(e) => {
+                            warn!("failed scanning {}: {}", path.display(), e);
+                            (Vec::new(), Some(e.to_string()))
+                        }
+                    };
+                Some(ScannedFile {
+                    path,
+                    modified,
+                    plugins,
+                    error,
+                })
+            })
+            .collect();
+        self.files = files;
+    }
+}
+
+/// Lists the plugins in a library with their params
+pub fn scan_library(lib: &Arc<PluginLibrary>) -> Result<Vec<PluginInfo>> {
+    lib.plugins()?
+        .into_iter()
+        .map(|desc| {
+            // params are only known once there's an instance
+            let plugin = PluginInstance::new(lib.clone(), &desc.id, 48000, MAX_FRAMES as u32)?;
+            Ok(PluginInfo {
+                path: lib.path().to_path_buf(),
+                id: desc.id,
+                name: desc.name,
+                vendor: desc.vendor,
+                version: desc.version,
+                params: plugin.params().to_vec(),
+            })
+        })
+        .collect()
+}
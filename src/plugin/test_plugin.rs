@@ -0,0 +1,226 @@
+//! A gain plugin built into the tests, so the host can be checked without
+//! anything installed
+
+use std::{
+    ffi::CStr,
+    os::raw::{c_char, c_void},
+    ptr,
+};
+
+use super::ffi::*;
+
+pub const PLUGIN_ID: &str = "rusty-karaoke.test-gain";
+pub const GAIN_PARAM: u32 = 0;
+
+struct Features([*const c_char; 2]);
+unsafe impl Sync for Features {}
+
+static FEATURES: Features = Features([c"audio-effect".as_ptr(), ptr::null()]);
+
+struct Descriptor(clap_plugin_descriptor);
+unsafe impl Sync for Descriptor {}
+
+static DESCRIPTOR: Descriptor = Descriptor(clap_plugin_descriptor {
+    clap_version: CLAP_VERSION,
+    id: c"rusty-karaoke.test-gain".as_ptr(),
+    name: c"Test Gain".as_ptr(),
+    vendor: c"RustyKaraoke".as_ptr(),
+    url: c"".as_ptr(),
+    manual_url: c"".as_ptr(),
+    support_url: c"".as_ptr(),
+    version: c"1.0.0".as_ptr(),
+    description: c"Multiplies the input by a gain".as_ptr(),
+    features: &FEATURES.0 as *const *const c_char,
+});
+
+pub static ENTRY: clap_plugin_entry = clap_plugin_entry {
+    clap_version: CLAP_VERSION,
+    init: Some(entry_init),
+    deinit: Some(entry_deinit),
+    get_factory: Some(entry_get_factory),
+};
+
+static FACTORY: clap_plugin_factory = clap_plugin_factory {
+    get_plugin_count: Some(factory_count),
+    get_plugin_descriptor: Some(factory_descriptor),
+    create_plugin: Some(factory_create),
+};
+
+static PARAMS: clap_plugin_params = clap_plugin_params {
+    count: Some(params_count),
+    get_info: Some(params_info),
+    get_value: Some(params_value),
+    value_to_text: None,
+    text_to_value: None,
+    flush: Some(params_flush),
+};
+
+struct Gain {
+    plugin: clap_plugin,
+    gain: f64,
+}
+
+unsafe fn gain<'a>(plugin: *const clap_plugin) -> &'a mut Gain {
+    &mut *((*plugin).plugin_data as *mut Gain)
+}
+
+unsafe extern "C" fn entry_init(_: *const c_char) -> bool {
+    true
+}
+
+unsafe extern "C" fn entry_deinit() {}
+
+unsafe extern "C" fn entry_get_factory(id: *const c_char) -> *const c_void {
+    if CStr::from_ptr(id).to_bytes_with_nul() == CLAP_PLUGIN_FACTORY_ID {
+        &FACTORY as *const _ as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn factory_count(_: *const clap_plugin_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn factory_descriptor(
+    _: *const clap_plugin_factory,
+    index: u32,
+) -> *const clap_plugin_descriptor {
+    if index == 0 {
+        &DESCRIPTOR.0
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn factory_create(
+    _: *const clap_plugin_factory,
+    _: *const clap_host,
+    id: *const c_char,
+) -> *const clap_plugin {
+    if CStr::from_ptr(id).to_bytes() != PLUGIN_ID.as_bytes() {
+        return ptr::null();
+    }
+
+    let gain = Box::into_raw(Box::new(Gain {
+        plugin: clap_plugin {
+            desc: &DESCRIPTOR.0,
+            plugin_data: ptr::null_mut(),
+            init: Some(plugin_true),
+            destroy: Some(plugin_destroy),
+            activate: Some(plugin_activate),
+            deactivate: Some(plugin_nothing),
+            start_processing: Some(plugin_true),
+            stop_processing: Some(plugin_nothing),
+            reset: Some(plugin_nothing),
+            process: Some(plugin_process),
+            get_extension: Some(plugin_extension),
+            on_main_thread: Some(plugin_nothing),
+        },
+        gain: 1.0,
+    }));
+    (*gain).plugin.plugin_data = gain as *mut c_void;
+    &(*gain).plugin
+}
+
+unsafe extern "C" fn plugin_true(_: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_nothing(_: *const clap_plugin) {}
+
+unsafe extern "C" fn plugin_destroy(plugin: *const clap_plugin) {
+    drop(Box::from_raw((*plugin).plugin_data as *mut Gain));
+}
+
+unsafe extern "C" fn plugin_activate(_: *const clap_plugin, _: f64, _: u32, _: u32) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_extension(_: *const clap_plugin, id: *const c_char) -> *const c_void {
+    if CStr::from_ptr(id).to_bytes_with_nul() == CLAP_EXT_PARAMS {
+        &PARAMS as *const _ as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe fn read_events(plugin: *const clap_plugin, events: *const clap_input_events) {
+    let events = &*events;
+    let (size, get) = (events.size.unwrap(), events.get.unwrap());
+    for i in 0..size(events) {
+        let header = &*get(events, i);
+        if header.space_id == CLAP_CORE_EVENT_SPACE_ID && header.type_ == CLAP_EVENT_PARAM_VALUE {
+            let event = &*(header as *const _ as *const clap_event_param_value);
+            if event.param_id == GAIN_PARAM {
+                gain(plugin).gain = event.value;
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn plugin_process(
+    plugin: *const clap_plugin,
+    process: *const clap_process,
+) -> clap_process_status {
+    let process = &*process;
+    read_events(plugin, process.in_events);
+
+    let frames = process.frames_count as usize;
+    let input = &*process.audio_inputs;
+    let output = &*process.audio_outputs;
+    let gain = gain(plugin).gain as f32;
+    for c in 0..2 {
+        let input = std::slice::from_raw_parts(*input.data32.add(c), frames);
+        let output = std::slice::from_raw_parts_mut(*output.data32.add(c), frames);
+        for (o, i) in output.iter_mut().zip(input) {
+            *o = i * gain;
+        }
+    }
+
+    // CLAP_PROCESS_CONTINUE
+    1
+}
+
+unsafe extern "C" fn params_count(_: *const clap_plugin) -> u32 {
+    1
+}
+
+unsafe extern "C" fn params_info(
+    _: *const clap_plugin,
+    index: u32,
+    info: *mut clap_param_info,
+) -> bool {
+    if index != 0 {
+        return false;
+    }
+    let info = &mut *info;
+    info.id = GAIN_PARAM;
+    for (dst, src) in info.name.iter_mut().zip(b"Gain\0") {
+        *dst = *src as c_char;
+    }
+    info.min_value = 0.0;
+    info.max_value = 2.0;
+    info.default_value = 1.0;
+    true
+}
+
+unsafe extern "C" fn params_value(
+    plugin: *const clap_plugin,
+    id: clap_id,
+    value: *mut f64,
+) -> bool {
+    if id != GAIN_PARAM {
+        return false;
+    }
+    *value = gain(plugin).gain;
+    true
+}
+
+unsafe extern "C" fn params_flush(
+    plugin: *const clap_plugin,
+    in_events: *const clap_input_events,
+    _: *const clap_output_events,
+) {
+    read_events(plugin, in_events);
+}
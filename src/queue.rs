@@ -0,0 +1,291 @@
+//! Song queue
+//!
+//! Karaoke nights run on reservations: songs get queued with the name of who's
+//! singing and are played in order. [Queue::spawn] watches the playback events
+//! and starts the next song once one ends, after a gap for the next singer to
+//! get to the mic. The queue is saved to the data folder on every change.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config,
+    events::PlaybackEvent,
+    playback::{Playback, PlaybackCommand},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueSettings {
+    /// Start the next song when one ends
+    pub auto_advance: bool,
+    /// Seconds between songs
+    pub gap: f32,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            auto_advance: true,
+            gap: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueEntry {
+    /// Stays the same while the entry moves around
+    pub id: u64,
+    pub path: PathBuf,
+    pub singer: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SongQueue {
+    entries: Vec<QueueEntry>,
+    next_id: u64,
+    /// Taken off the queue and playing, or about to
+    pub current: Option<QueueEntry>,
+    /// When the next song starts on its own
+    #[serde(skip)]
+    pub advance_at: Option<Instant>,
+}
+
+impl SongQueue {
+    pub fn path() -> Result<PathBuf> {
+        Ok(config::data_dir()?.join("queue.json"))
+    }
+
+    /// Loads the saved queue, empty if there's none
+    pub fn load() -> Self {
+        let path = match Self::path() {
+            Ok(path) if path.exists() => path,
+            _ => return Self::default(),
+        };
+        Self::load_from(&path).unwrap_or_else(|e| {
+            warn!("failed loading queue {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&Self::path()?)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queues a song at the end, returns the id of the entry
+    pub fn add(&mut self, path: PathBuf, singer: String) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(QueueEntry { id, path, singer });
+        id
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<QueueEntry> {
+        let i = self.index(id)?;
+        Some(self.entries.remove(i))
+    }
+
+    /// Moves an entry to `index`, or to the end when it's past it
+    pub fn move_to(&mut self, id: u64, index: usize) {
+        if let Some(i) = self.index(id) {
+            let entry = self.entries.remove(i);
+            let index = index.min(self.entries.len());
+            self.entries.insert(index, entry);
+        }
+    }
+
+    /// Moves an entry to the front, so it's the next one played
+    pub fn play_next(&mut self, id: u64) {
+        self.move_to(id, 0);
+    }
+
+    /// Takes the first entry off the queue and makes it the current one
+    pub fn pop(&mut self) -> Option<QueueEntry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let entry = self.entries.remove(0);
+        self.current = Some(entry.clone());
+        Some(entry)
+    }
+
+    fn index(&self, id: u64) -> Option<usize> {
+        self.entries.iter().position(|e| e.id == id)
+    }
+}
+
+/// Shared queue, along with the thread that plays it
+#[derive(Clone)]
+pub struct Queue {
+    songs: Arc<Mutex<SongQueue>>,
+    settings: Arc<RwLock<QueueSettings>>,
+    playback: Playback,
+}
+
+impl Queue {
+    /// Loads the saved queue and starts following `playback`
+    pub fn spawn(playback: Playback, settings: QueueSettings) -> Self {
+        let mut songs = SongQueue::load();
+        // whatever was playing when the app closed is done
+        songs.current = None;
+
+        let queue = Self {
+            songs: Arc::new(Mutex::new(songs)),
+            settings: Arc::new(RwLock::new(settings)),
+            playback,
+        };
+        let events = queue.playback.events.subscribe();
+        let runner = queue.clone();
+        thread::spawn(move || runner.run(events));
+        queue
+    }
+
+    /// The queue for editing, call [Queue::save] after changing it
+    pub fn lock(&self) -> MutexGuard<'_, SongQueue> {
+        self.songs.lock()
+    }
+
+    pub fn save(&self) {
+        if let Err(e) = self.songs.lock().save() {
+            warn!("failed saving queue: {}", e);
+        }
+    }
+
+    pub fn set_settings(&self, settings: QueueSettings) {
+        *self.settings.write() = settings;
+    }
+
+    /// Plays the first song in the queue, `false` if it's empty
+    pub fn start_next(&self) -> bool {
+        let entry = {
+            let mut songs = self.songs.lock();
+            songs.advance_at = None;
+            songs.pop()
+        };
+        self.save();
+
+        match entry {
+            Some(entry) => {
+                info!("next up: {} singing {}", entry.singer, entry.path.display());
+                self.playback.send(PlaybackCommand::Play(entry.path));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts the next entry after the gap, if there is one and auto-advance is on
+    fn schedule_next(&self) {
+        let settings = self.settings.read().clone();
+        let mut songs = self.songs.lock();
+        if settings.auto_advance && !songs.is_empty() {
+            let gap = Duration::from_secs_f32(settings.gap.max(0.0));
+            songs.advance_at = Some(Instant::now() + gap);
+        }
+    }
+
+    fn run(self, events: Receiver<PlaybackEvent>) {
+        // a song that fails to load shows up as an error and then a stop
+        let mut failed = false;
+        loop {
+            let advance_at = self.songs.lock().advance_at;
+            let event = match advance_at {
+                Some(at) => events.recv_deadline(at),
+                None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match event {
+                Ok(PlaybackEvent::Ended(_)) => self.schedule_next(),
+                Ok(PlaybackEvent::Error(_)) => failed = true,
+                // something else got played, or the host stopped it, don't cut in
+                Ok(PlaybackEvent::Loaded { path, .. }) => {
+                    failed = false;
+                    let mut songs = self.songs.lock();
+                    songs.advance_at = None;
+                    if songs.current.as_ref().is_some_and(|e| e.path != path) {
+                        songs.current = None;
+                    }
+                }
+                Ok(PlaybackEvent::Stopped) => {
+                    {
+                        let mut songs = self.songs.lock();
+                        songs.advance_at = None;
+                        songs.current = None;
+                    }
+                    // skip past the broken song instead of leaving the room waiting
+                    if std::mem::take(&mut failed) {
+                        self.schedule_next();
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    // the UI may have called it off in the meantime
+                    let due = self
+                        .songs
+                        .lock()
+                        .advance_at
+                        .is_some_and(|at| at <= Instant::now());
+                    if due {
+                        self.start_next();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        debug!("queue runner exited");
+    }
+}
+
+#[test]
+fn test_song_queue() {
+    let mut queue = SongQueue::default();
+    let a = queue.add("a.mid".into(), "Som".into());
+    let b = queue.add("b.mid".into(), "Nok".into());
+    let c = queue.add("c.mid".into(), "Som".into());
+
+    queue.play_next(c);
+    queue.move_to(a, 10);
+    let order = queue.entries().iter().map(|e| e.id).collect::<Vec<_>>();
+    assert_eq!(order, [c, b, a]);
+
+    assert_eq!(queue.remove(b).map(|e| e.singer), Some("Nok".to_string()));
+    assert_eq!(queue.remove(b), None);
+
+    let next = queue.pop().unwrap();
+    assert_eq!(next.path, PathBuf::from("c.mid"));
+    assert_eq!(queue.current, Some(next));
+    assert_eq!(queue.entries().len(), 1);
+
+    // ids aren't reused after a remove
+    assert_ne!(queue.add("d.mid".into(), "Nok".into()), b);
+}
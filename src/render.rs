@@ -0,0 +1,370 @@
+//! Offline rendering
+//!
+//! Plays a song through the built-in synth as fast as it can and writes the result
+//! to a WAV file. No audio device is opened,
+//! so the same song and soundfonts always give the same file.
+
+use std::{
+    fs,
+    io::{Seek, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use log::{debug, info};
+use midly::{num::u7, Format, MidiMessage as M, Smf, Timing};
+use nodi::{Connection, Event, MidiEvent, Sheet};
+
+use crate::{
+    dsp::MasterSettings,
+    midi::{Fluid, SysExConnection},
+    mixer::{ChannelMixer, MixerCommand},
+    soundfont::SoundfontSetup,
+    sysex::{self, ResetMode},
+};
+
+/// Frames rendered after the last event so notes can ring out
+const TAIL_SECONDS: u32 = 2;
+
+/// Frames rendered per call into the synth
+const CHUNK_FRAMES: usize = 1024;
+
+/// Channel 10 holds the drums, transposing it would change the instruments
+const DRUM_CHANNEL: u8 = 9;
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub soundfonts: SoundfontSetup,
+    /// Semitones, the drum channel is left alone
+    pub transpose: i8,
+    /// Tempo multiplier, `1.0` is the original speed
+    pub speed: f32,
+    /// Channels (0-15) to leave out
+    pub mutes: Vec<u8>,
+    pub sample_rate: u32,
+    /// Master volume, limiter, reverb and chorus
+    pub master: MasterSettings,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            soundfonts: SoundfontSetup::default(),
+            transpose: 0,
+            speed: 1.0,
+            mutes: Vec::new(),
+            sample_rate: 44100,
+            master: MasterSettings::default(),
+        }
+    }
+}
+
+/// What a render produced
+#[derive(Debug, Clone, Copy)]
+pub struct RenderStats {
+    pub frames: u64,
+    pub duration: Duration,
+}
+
+/// Renders a MIDI file to `output`
+pub fn render_file(input: &Path, output: &Path, options: &RenderOptions) -> Result<RenderStats> {
+    let data = fs::read(input)?;
+    let smf = Smf::parse(&data)?;
+    render(&smf, output, options)
+}
+
+/// Renders a parsed MIDI file to `output`
+pub fn render(smf: &Smf, output: &Path, options: &RenderOptions) -> Result<RenderStats> {
+    if options.speed <= 0.0 {
+        bail!("speed must be above 0");
+    }
+
+    let ppq = match smf.header.timing {
+        Timing::Metrical(ppq) => ppq.as_int() as f64,
+        Timing::Timecode(..) => bail!("SMPTE timecode MIDI files aren't supported"),
+    };
+
+    let sheet = match smf.header.format {
+        Format::SingleTrack | Format::Sequential => Sheet::sequential(&smf.tracks),
+        Format::Parallel => Sheet::parallel(&smf.tracks),
+    };
+
+    let mut fluid = Fluid::offline(&options.soundfonts, options.sample_rate, &options.master)?;
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: options.sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut sink = hound::WavWriter::create(output, spec)?;
+
+    let mut mixer = ChannelMixer::new();
+    for &channel in &options.mutes {
+        mixer.apply(MixerCommand::Mute(channel, true));
+    }
+
+    if let Some(msg) = ResetMode::default().message() {
+        fluid.send_sys_ex(msg);
+    }
+
+    info!("rendering {} ticks to {}", sheet.len(), output.display());
+
+    // MIDI files start at 120 BPM until told otherwise
+    let mut tick_frames = frames_per_tick(500_000, ppq, options);
+    let mut pending = 0.0;
+    let mut frames = 0_u64;
+    let mut buf = vec![0.0_f32; CHUNK_FRAMES * 2];
+
+    let mut sysex = sysex::from_smf(smf).into_iter().peekable();
+    for (tick, moment) in sheet.iter().enumerate() {
+        while let Some((_, msg)) = sysex.next_if(|(t, _)| *t <= tick) {
+            fluid.send_sys_ex(&msg);
+        }
+        for event in &moment.events {
+            match event {
+                Event::Tempo(tempo) => tick_frames = frames_per_tick(*tempo, ppq, options),
+                Event::Midi(msg) => {
+                    let msg = transpose(*msg, options.transpose);
+                    if let Some(msg) = mixer.filter(msg) {
+                        fluid.play(msg);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        pending += tick_frames;
+        let n = pending as u64;
+        pending -= n as f64;
+        frames += render_frames(&fluid, &mut sink, &mut buf, n)?;
+    }
+
+    fluid.all_notes_off();
+    let tail = (TAIL_SECONDS * options.sample_rate) as u64;
+    frames += render_frames(&fluid, &mut sink, &mut buf, tail)?;
+
+    sink.finalize()?;
+
+    let stats = RenderStats {
+        frames,
+        duration: Duration::from_secs_f64(frames as f64 / options.sample_rate as f64),
+    };
+    debug!("rendered {:?}", stats);
+    Ok(stats)
+}
+
+/// Output frames per MIDI tick at a tempo (microseconds per beat)
+fn frames_per_tick(tempo: u32, ppq: f64, options: &RenderOptions) -> f64 {
+    tempo as f64 / ppq / 1_000_000.0 * options.sample_rate as f64 / options.speed as f64
+}
+
+fn render_frames<W: Write + Seek>(
+    fluid: &Fluid,
+    sink: &mut hound::WavWriter<W>,
+    buf: &mut [f32],
+    frames: u64,
+) -> Result<u64> {
+    let mut left = frames;
+    while left > 0 {
+        let n = left.min(CHUNK_FRAMES as u64) as usize;
+        let chunk = &mut buf[..n * 2];
+        fluid.write(chunk);
+        for &s in chunk.iter() {
+            sink.write_sample(to_i16(s))?;
+        }
+        left -= n as u64;
+    }
+    Ok(frames)
+}
+
+/// Shifts note keys by `semitones`, keys pushed out of range are clamped
+pub fn transpose(mut event: MidiEvent, semitones: i8) -> MidiEvent {
+    if semitones == 0 || event.channel.as_int() == DRUM_CHANNEL {
+        return event;
+    }
+
+    let shift = |key: u7| u7::from((key.as_int() as i16 + semitones as i16).clamp(0, 127) as u8);
+
+    event.message = match event.message {
+        M::NoteOn { key, vel } => M::NoteOn {
+            key: shift(key),
+            vel,
+        },
+        M::NoteOff { key, vel } => M::NoteOff {
+            key: shift(key),
+            vel,
+        },
+        M::Aftertouch { key, vel } => M::Aftertouch {
+            key: shift(key),
+            vel,
+        },
+        msg => msg,
+    };
+    event
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[test]
+fn test_transpose() {
+    use midly::num::u4;
+
+    let note = |channel: u8, key: u8| MidiEvent {
+        channel: u4::from(channel),
+        message: M::NoteOn {
+            key: u7::from(key),
+            vel: u7::from(100),
+        },
+    };
+
+    assert_eq!(transpose(note(0, 60), 2), note(0, 62));
+    assert_eq!(transpose(note(0, 126), 5), note(0, 127));
+    assert_eq!(transpose(note(0, 1), -3), note(0, 0));
+    // drums stay where they are
+    assert_eq!(transpose(note(9, 38), 2), note(9, 38));
+}
+
+#[test]
+fn test_frames_per_tick() {
+    let options = RenderOptions::default();
+    // 120 BPM at 480 PPQ is ~1.04ms per tick
+    let frames = frames_per_tick(500_000, 480.0, &options);
+    assert!((frames - 45.9375).abs() < 1e-9);
+
+    let fast = RenderOptions {
+        speed: 2.0,
+        ..Default::default()
+    };
+    assert!((frames_per_tick(500_000, 480.0, &fast) - frames / 2.0).abs() < 1e-9);
+}
+
+/// A soundfont with one preset playing a looped sine, for tests that need sound
+#[cfg(test)]
+fn sine_font() -> Vec<u8> {
+    let chunk = |id: &[u8], data: &[u8]| {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    };
+    let list = |kind: &[u8], chunks: &[Vec<u8>]| chunk(b"LIST", &[kind, &chunks.concat()].concat());
+    let name = |name: &str| {
+        let mut field = name.as_bytes().to_vec();
+        field.resize(20, 0);
+        field
+    };
+    let words = |words: &[u16]| words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+    let dwords = |dwords: &[u32]| dwords.iter().flat_map(|d| d.to_le_bytes()).collect::<Vec<_>>();
+
+    // 441 Hz at 44.1 kHz, exactly 100 points a period
+    let mut smpl = (0..1000)
+        .map(|i| ((i as f32 / 100.0 * std::f32::consts::TAU).sin() * 16000.0) as i16)
+        .collect::<Vec<_>>();
+    smpl.resize(1000 + 46, 0);
+    let smpl = smpl.iter().flat_map(|s| s.to_le_bytes()).collect::<Vec<_>>();
+
+    let preset = |preset_name: &str, bag: u16| {
+        [name(preset_name), words(&[0, 0, bag]), dwords(&[0, 0, 0])].concat()
+    };
+    let inst = |inst_name: &str, bag: u16| [name(inst_name), words(&[bag])].concat();
+    let sample = |sample_name: &str, points: [u32; 5], kind: u16| {
+        [name(sample_name), dwords(&points), vec![60, 0], words(&[0, kind])].concat()
+    };
+    // modulators are 10 bytes, only the terminal one
+    let no_mods = vec![0; 10];
+    let pdta = [
+        chunk(b"phdr", &[preset("Sine", 0), preset("EOP", 1)].concat()),
+        chunk(b"pbag", &words(&[0, 0, 1, 0])),
+        chunk(b"pmod", &no_mods),
+        // instrument 0, then the terminal generator
+        chunk(b"pgen", &words(&[41, 0, 0, 0])),
+        chunk(b"inst", &[inst("Sine", 0), inst("EOI", 1)].concat()),
+        chunk(b"ibag", &words(&[0, 0, 2, 0])),
+        chunk(b"imod", &no_mods),
+        // loop continuously, sample 0, then the terminal generator
+        chunk(b"igen", &words(&[54, 1, 53, 0, 0, 0])),
+        chunk(
+            b"shdr",
+            &[sample("Sine", [0, 1000, 100, 900, 44100], 1), sample("EOS", [0; 5], 0)].concat(),
+        ),
+    ];
+
+    let sfbk = [
+        b"sfbk".to_vec(),
+        list(
+            b"INFO",
+            &[
+                chunk(b"ifil", &words(&[2, 1])),
+                chunk(b"isng", b"EMU8000\0"),
+                chunk(b"INAM", b"Sine\0\0"),
+            ],
+        ),
+        list(b"sdta", &[chunk(b"smpl", &smpl)]),
+        list(b"pdta", &pdta),
+    ]
+    .concat();
+    chunk(b"RIFF", &sfbk)
+}
+
+#[test]
+fn test_render_wav() {
+    use midly::{num::u28, Header, MetaMessage, TrackEvent, TrackEventKind};
+
+    let note = |delta: u32, on: bool| TrackEvent {
+        delta: u28::from(delta),
+        kind: TrackEventKind::Midi {
+            channel: 0.into(),
+            message: if on {
+                M::NoteOn {
+                    key: u7::from(60),
+                    vel: u7::from(100),
+                }
+            } else {
+                M::NoteOff {
+                    key: u7::from(60),
+                    vel: u7::from(0),
+                }
+            },
+        },
+    };
+    let end = TrackEvent {
+        delta: u28::from(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    };
+
+    let smf = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(480.into())),
+        tracks: vec![vec![note(0, true), note(480, false), end]],
+    };
+
+    let dir = std::env::temp_dir();
+    let font = dir.join("rusty-karaoke-render-test.sf2");
+    fs::write(&font, sine_font()).unwrap();
+    let options = RenderOptions {
+        soundfonts: SoundfontSetup {
+            fonts: vec![font.clone()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let path = dir.join("rusty-karaoke-render-test.wav");
+
+    let stats = render(&smf, &path, &options).unwrap();
+    // 481 ticks of one beat at 120 BPM, then the tail
+    let ticks = (481.0 * frames_per_tick(500_000, 480.0, &options)) as u64;
+    assert_eq!(stats.frames, ticks + 2 * 44100);
+
+    let mut reader = hound::WavReader::open(&path).unwrap();
+    assert_eq!(reader.duration() as u64, stats.frames);
+    let peak = reader
+        .samples::<i16>()
+        .take(ticks as usize * 2)
+        .map(|s| s.unwrap().unsigned_abs())
+        .max();
+    assert!(peak > Some(1000), "the note is silent, peak {:?}", peak);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&font).unwrap();
+}
@@ -0,0 +1,410 @@
+//! Singing score
+//!
+//! The pitch of the singer is compared against the notes of the guide melody.
+//! Every note scores for timing (did the singer come in on time) and pitch
+//! (how close the sung frames were). Octaves are ignored so nobody has to
+//! sing outside their range.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use nodi::{Event, Sheet};
+
+use crate::{
+    karaoke::Karaoke,
+    mic::MicTap,
+    pitch::{PitchFrame, PitchTracker},
+};
+
+/// How late into a note singing can start and still be on time, in seconds
+const TIMING_TOLERANCE: f64 = 0.25;
+
+/// Pitch error that still counts as spot on, in semitones
+const PITCH_EXACT: f32 = 0.5;
+
+/// Pitch error that scores nothing, in semitones
+const PITCH_MISS: f32 = 2.0;
+
+/// Share of the score that comes from pitch, the rest is timing
+const PITCH_WEIGHT: f32 = 0.6;
+
+/// Tempo until the first tempo event, 120 BPM
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// A note of the guide melody
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GuideNote {
+    /// Song time in seconds, at normal speed
+    pub start: f64,
+    pub end: f64,
+    /// Transposed key
+    pub key: u8,
+}
+
+/// Song time in seconds of every tick of a sheet, including the one after the last moment
+pub fn tick_seconds(sheet: &Sheet, ticks_per_beat: u16) -> Vec<f64> {
+    let mut times = Vec::with_capacity(sheet.len() + 1);
+    let mut tempo = DEFAULT_TEMPO;
+    let mut time = 0.0;
+
+    for moment in sheet.iter() {
+        times.push(time);
+        for event in &moment.events {
+            if let Event::Tempo(t) = event {
+                tempo = *t;
+            }
+        }
+        time += tempo as f64 / 1_000_000.0 / ticks_per_beat as f64;
+    }
+    times.push(time);
+
+    times
+}
+
+/// Notes of the guide channel, shifted by `transpose` semitones like the playback is
+pub fn guide_notes(
+    sheet: &Sheet,
+    ticks_per_beat: u16,
+    channel: u8,
+    transpose: i8,
+) -> Vec<GuideNote> {
+    use nodi::midly::MidiMessage as M;
+
+    let times = tick_seconds(sheet, ticks_per_beat);
+    let mut notes = Vec::new();
+    // start of the sounding note on every key
+    let mut playing: [Option<f64>; 128] = [None; 128];
+
+    for (tick, moment) in sheet.iter().enumerate() {
+        let time = times[tick];
+        for event in &moment.events {
+            let msg = match event {
+                Event::Midi(msg) if msg.channel.as_int() == channel => msg.message,
+                _ => continue,
+            };
+
+            let (key, on) = match msg {
+                M::NoteOn { key, vel } => (key.as_int(), vel.as_int() > 0),
+                M::NoteOff { key, .. } => (key.as_int(), false),
+                _ => continue,
+            };
+
+            // a retrigger ends the note that's already sounding
+            if let Some(start) = playing[key as usize].take() {
+                notes.push(note(start, time, key, transpose));
+            }
+            if on {
+                playing[key as usize] = Some(time);
+            }
+        }
+    }
+
+    let end = times.last().copied().unwrap_or_default();
+    for (key, start) in playing.iter().enumerate() {
+        if let Some(start) = start {
+            notes.push(note(*start, end, key as u8, transpose));
+        }
+    }
+
+    notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+    notes
+}
+
+fn note(start: f64, end: f64, key: u8, transpose: i8) -> GuideNote {
+    GuideNote {
+        start,
+        end,
+        key: (key as i16 + transpose as i16).clamp(0, 127) as u8,
+    }
+}
+
+/// Score of the whole song or of a line
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Score {
+    /// Share of the notes the singer came in on time for, 0-1
+    pub timing: f32,
+    /// How close the sung pitch was, 0-1
+    pub pitch: f32,
+    /// Notes that were scored
+    pub notes: usize,
+}
+
+impl Score {
+    /// The number shown to the singer, 0-100
+    pub fn total(&self) -> u32 {
+        ((self.pitch * PITCH_WEIGHT + self.timing * (1.0 - PITCH_WEIGHT)) * 100.0).round() as u32
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScoreReport {
+    pub overall: Score,
+    /// One per line, in the order they were given to the [Scorer]
+    pub lines: Vec<Score>,
+}
+
+/// What the singer did on one note
+#[derive(Debug, Clone, Copy, Default)]
+struct NoteStats {
+    /// When singing started within the note
+    onset: Option<f64>,
+    pitch_sum: f32,
+    frames: u32,
+}
+
+/// Collects pitch frames against the guide melody
+#[derive(Debug, Clone)]
+pub struct Scorer {
+    notes: Vec<GuideNote>,
+    stats: Vec<NoteStats>,
+    /// Start and end of every lyric line, in song seconds
+    lines: Vec<(f64, f64)>,
+}
+
+impl Scorer {
+    /// `notes` have to be sorted by start, like [guide_notes] returns them
+    pub fn new(notes: Vec<GuideNote>, lines: Vec<(f64, f64)>) -> Self {
+        Self {
+            stats: vec![NoteStats::default(); notes.len()],
+            notes,
+            lines,
+        }
+    }
+
+    /// Adds a frame, its time has to be in song seconds
+    pub fn push(&mut self, frame: PitchFrame) {
+        let sung = match frame.note {
+            Some(note) => note,
+            None => return,
+        };
+
+        // notes that could be sounding at this point
+        let end = self.notes.partition_point(|n| n.start <= frame.time);
+        for i in (0..end).rev() {
+            let note = self.notes[i];
+            if frame.time >= note.end {
+                // overlapping notes are rare in a melody, don't walk the whole song
+                if frame.time - note.end > TIMING_TOLERANCE * 4.0 {
+                    break;
+                }
+                continue;
+            }
+
+            let stats = &mut self.stats[i];
+            stats.onset.get_or_insert(frame.time);
+            stats.pitch_sum += pitch_score(sung, note.key);
+            stats.frames += 1;
+        }
+    }
+
+    pub fn report(&self) -> ScoreReport {
+        let overall = self.score(|_| true);
+        let lines = self
+            .lines
+            .iter()
+            .map(|(start, end)| self.score(|n| n.start >= *start && n.start < *end))
+            .collect();
+
+        ScoreReport { overall, lines }
+    }
+
+    fn score(&self, filter: impl Fn(&GuideNote) -> bool) -> Score {
+        let mut score = Score::default();
+        let mut on_time = 0;
+        let mut pitch = 0.0;
+
+        for (note, stats) in self.notes.iter().zip(&self.stats) {
+            if !filter(note) {
+                continue;
+            }
+            score.notes += 1;
+
+            if stats
+                .onset
+                .is_some_and(|t| t - note.start <= TIMING_TOLERANCE)
+            {
+                on_time += 1;
+            }
+            if stats.frames > 0 {
+                pitch += stats.pitch_sum / stats.frames as f32;
+            }
+        }
+
+        if score.notes > 0 {
+            score.timing = on_time as f32 / score.notes as f32;
+            score.pitch = pitch / score.notes as f32;
+        }
+        score
+    }
+}
+
+/// Scorer for the guide melody of a song, with the text of every line
+pub fn song_scorer(
+    karaoke: &Karaoke,
+    sheet: &Sheet,
+    ticks_per_beat: u16,
+    transpose: i8,
+) -> Result<(Scorer, Vec<String>)> {
+    let guide = karaoke
+        .guide_channel()
+        .ok_or_else(|| anyhow!("couldn't find the guide melody of the song"))?;
+
+    let notes = guide_notes(sheet, ticks_per_beat, guide, transpose);
+
+    let times = tick_seconds(sheet, ticks_per_beat);
+    let time = |tick: u32| times[(tick as usize).min(times.len() - 1)];
+    let starts = karaoke.line_ticks(ticks_per_beat);
+    let lines = starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| {
+            let end = starts.get(i + 1).map_or(f64::INFINITY, |end| time(*end));
+            (time(*start), end)
+        })
+        .collect();
+
+    let text = karaoke
+        .lyrics
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    Ok((Scorer::new(notes, lines), text))
+}
+
+/// Scores the mic while a song plays
+pub struct LiveScore {
+    /// Hand this to the mic, see [crate::midi::MidiMessage::MicTap]
+    pub tap: Arc<MicTap>,
+    /// Made once the tap knows the mic's sample rate
+    tracker: Option<PitchTracker>,
+    pub scorer: Scorer,
+    /// Song time of every tick, see [tick_seconds]
+    times: Vec<f64>,
+    /// Seconds of mic input the tracker got
+    input: f64,
+    /// What the singer sang last, as a MIDI note, `None` when quiet
+    pub pitch: Option<f32>,
+    buf: Vec<f32>,
+}
+
+impl LiveScore {
+    pub fn new(scorer: Scorer, sheet: &Sheet, ticks_per_beat: u16) -> Self {
+        Self {
+            tap: Arc::new(MicTap::new()),
+            tracker: None,
+            scorer,
+            times: tick_seconds(sheet, ticks_per_beat),
+            input: 0.0,
+            pitch: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Song time of a tick, in seconds at normal speed
+    pub fn seconds(&self, tick: usize) -> f64 {
+        self.times[tick.min(self.times.len() - 1)]
+    }
+
+    /// Runs everything the mic captured since the last call through the scorer.
+    ///
+    /// `now` is the song time the playback is at, `speed` how fast it plays.
+    pub fn update(&mut self, now: f64, speed: f64) {
+        let rate = self.tap.sample_rate();
+        if rate == 0 {
+            return;
+        }
+
+        self.buf.clear();
+        while let Some(s) = self.tap.pop() {
+            self.buf.push(s);
+        }
+        self.input += self.buf.len() as f64 / rate as f64;
+
+        let tracker = self.tracker.get_or_insert_with(|| PitchTracker::new(rate));
+        let (scorer, pitch, input) = (&mut self.scorer, &mut self.pitch, self.input);
+        tracker.push(&self.buf, |frame| {
+            *pitch = frame.note;
+            scorer.push(PitchFrame {
+                // the newest sample was captured at `now`
+                time: now - (input - frame.time) * speed,
+                ..frame
+            })
+        });
+    }
+
+    /// Throws away what the mic captured, while the song is paused
+    pub fn skip(&mut self) {
+        while self.tap.pop().is_some() {}
+        self.tracker = None;
+        self.input = 0.0;
+        self.pitch = None;
+    }
+}
+
+/// 1 for a sung pitch on the key, falling off to 0 at [PITCH_MISS], any octave
+fn pitch_score(sung: f32, key: u8) -> f32 {
+    let diff = (sung - key as f32).rem_euclid(12.0);
+    let error = diff.min(12.0 - diff);
+
+    if error <= PITCH_EXACT {
+        1.0
+    } else {
+        (1.0 - (error - PITCH_EXACT) / (PITCH_MISS - PITCH_EXACT)).max(0.0)
+    }
+}
+
+#[test]
+fn test_pitch_score() {
+    assert_eq!(pitch_score(60.2, 60), 1.0);
+    // an octave off is fine
+    assert_eq!(pitch_score(72.0, 60), 1.0);
+    assert_eq!(pitch_score(59.5, 48), 1.0);
+    assert!((pitch_score(61.25, 60) - 0.5).abs() < 1e-6);
+    assert_eq!(pitch_score(63.0, 60), 0.0);
+}
+
+#[test]
+fn test_scorer() {
+    let notes = vec![
+        GuideNote {
+            start: 0.0,
+            end: 1.0,
+            key: 60,
+        },
+        GuideNote {
+            start: 1.0,
+            end: 2.0,
+            key: 64,
+        },
+        GuideNote {
+            start: 2.0,
+            end: 3.0,
+            key: 67,
+        },
+    ];
+    let mut scorer = Scorer::new(notes, vec![(0.0, 2.0), (2.0, 3.0)]);
+
+    let frames = [
+        // on time and on key
+        (0.05, Some(60.0)),
+        (0.5, Some(60.1)),
+        // late and a semitone off
+        (1.6, Some(65.25)),
+        // third note never sung
+        (2.5, None),
+    ];
+    for (time, note) in frames {
+        scorer.push(PitchFrame { time, note });
+    }
+
+    let report = scorer.report();
+    assert_eq!(report.overall.notes, 3);
+    assert!((report.overall.timing - 1.0 / 3.0).abs() < 1e-6);
+    // 1 + 0.5 + 0 over three notes
+    assert!((report.overall.pitch - 0.5).abs() < 1e-6);
+    assert_eq!(report.lines[0].notes, 2);
+    assert!((report.lines[0].timing - 0.5).abs() < 1e-6);
+    assert_eq!(report.lines[1].total(), 0);
+}
@@ -0,0 +1,518 @@
+//! Soundfont discovery and setup
+//!
+//! The synth can stack several soundfonts: presets are looked up in the top font first,
+//! and specific channels or bank/program pairs can be sent to a chosen font
+//! (e.g. a better piano). When nothing is set up, a single font is discovered.
+//!
+//! Soundfonts are looked up in this order:
+//!
+//! 1. the `RUSTY_KARAOKE_SOUNDFONT` environment variable
+//! 2. the `soundfont` path in the config
+//! 3. `soundfonts` and `sounds/sf2` in the XDG data folders
+//! 4. common distro locations
+//!
+//! Inside a folder, `default.sf2` wins, then the well known GM fonts, then anything else.
+
+use std::{
+    env, fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use lewton::inside_ogg::OggStreamReader;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Environment variable pointing to a soundfont
+pub const SOUNDFONT_ENV: &str = "RUSTY_KARAOKE_SOUNDFONT";
+
+/// Subfolders of the XDG data folders that hold soundfonts
+const DATA_SUBDIRS: [&str; 2] = ["soundfonts", "sounds/sf2"];
+
+/// Soundfonts shipped by distros, in order of preference
+const KNOWN_FONTS: [&str; 5] = [
+    "default.sf2",
+    "FluidR3_GM.sf2",
+    "default-GM.sf2",
+    "GeneralUser_GS.sf2",
+    "TimGM6mb.sf2",
+];
+
+#[cfg(windows)]
+const COMMON_DIRS: [&str; 1] = [r"C:\soundfonts"];
+
+#[cfg(not(windows))]
+const COMMON_DIRS: [&str; 4] = [
+    "/usr/share/soundfonts",
+    "/usr/share/sounds/sf2",
+    "/usr/local/share/soundfonts",
+    "/usr/share/sounds/sf3",
+];
+
+/// Stack of soundfonts and overrides, persisted in the config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundfontSetup {
+    /// Loaded fonts, highest priority first
+    pub fonts: Vec<PathBuf>,
+    /// Checked in order, the first match wins
+    pub overrides: Vec<FontOverride>,
+}
+
+/// Sends matching program changes to a specific font.
+///
+/// `None` matches anything, so `channel: Some(0)` alone takes over the whole channel.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FontOverride {
+    pub font: PathBuf,
+    pub channel: Option<u8>,
+    pub bank: Option<u32>,
+    pub program: Option<u8>,
+}
+
+impl FontOverride {
+    pub fn matches(&self, channel: u8, bank: u32, program: u8) -> bool {
+        self.channel.is_none_or(|c| c == channel)
+            && self.bank.is_none_or(|b| b == bank)
+            && self.program.is_none_or(|p| p == program)
+    }
+}
+
+impl SoundfontSetup {
+    /// Puts a font on top of the stack, moving it there if it's already loaded
+    pub fn push(&mut self, path: PathBuf) {
+        self.fonts.retain(|p| p != &path);
+        self.fonts.insert(0, path);
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.fonts.retain(|p| p != path);
+        self.overrides.retain(|o| o.font != path);
+    }
+
+    /// Order to add the fonts to the synth in, the last one added is searched first.
+    ///
+    /// Fonts only used by overrides go at the bottom so they don't shadow the stack.
+    pub fn load_order(&self) -> Vec<&Path> {
+        let mut order = Vec::new();
+        for o in &self.overrides {
+            if !self.fonts.contains(&o.font) && !order.contains(&o.font.as_path()) {
+                order.push(o.font.as_path());
+            }
+        }
+        order.extend(self.fonts.iter().rev().map(PathBuf::as_path));
+        order
+    }
+
+    /// Font a program change should use instead of the normal lookup
+    pub fn override_for(&self, channel: u8, bank: u32, program: u8) -> Option<&Path> {
+        self.overrides
+            .iter()
+            .find(|o| o.matches(channel, bank, program))
+            .map(|o| o.font.as_path())
+    }
+}
+
+/// The configured setup, or the discovered soundfont if there's none
+pub fn initial_setup(config: &Config) -> SoundfontSetup {
+    if !config.soundfonts.fonts.is_empty() {
+        return config.soundfonts.clone();
+    }
+
+    SoundfontSetup {
+        fonts: find_soundfont(config).into_iter().collect(),
+        ..config.soundfonts.clone()
+    }
+}
+
+/// Finds a soundfont to load, see the module docs for the search order
+pub fn find_soundfont(config: &Config) -> Option<PathBuf> {
+    let found = candidates(config).into_iter().find(|p| p.is_file());
+    debug!("soundfont discovery found {:?}", found);
+    found
+}
+
+/// Every place a soundfont is looked for, in order
+pub fn candidates(config: &Config) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    if let Some(path) = env::var_os(SOUNDFONT_ENV) {
+        paths.push(PathBuf::from(path));
+    }
+
+    if let Some(path) = &config.soundfont {
+        paths.push(path.clone());
+    }
+
+    for dir in search_dirs() {
+        paths.extend(fonts_in(&dir));
+    }
+
+    paths
+}
+
+/// XDG data folders followed by the common locations
+fn search_dirs() -> Vec<PathBuf> {
+    let mut data_dirs = Vec::new();
+
+    match env::var_os("XDG_DATA_HOME") {
+        Some(dir) => data_dirs.push(PathBuf::from(dir)),
+        None => data_dirs.extend(dirs::home_dir().map(|h| h.join(".local/share"))),
+    }
+
+    match env::var_os("XDG_DATA_DIRS") {
+        Some(dirs) => data_dirs.extend(env::split_paths(&dirs)),
+        None if cfg!(unix) => {
+            data_dirs.push(PathBuf::from("/usr/local/share"));
+            data_dirs.push(PathBuf::from("/usr/share"));
+        }
+        None => {}
+    }
+
+    let mut res = data_dirs
+        .iter()
+        .flat_map(|dir| DATA_SUBDIRS.iter().map(move |sub| dir.join(sub)))
+        .collect::<Vec<_>>();
+
+    for dir in COMMON_DIRS {
+        let dir = PathBuf::from(dir);
+        if !res.contains(&dir) {
+            res.push(dir);
+        }
+    }
+
+    res
+}
+
+/// Soundfonts inside a folder, best first
+fn fonts_in(dir: &Path) -> Vec<PathBuf> {
+    let mut fonts = KNOWN_FONTS.iter().map(|f| dir.join(f)).collect::<Vec<_>>();
+
+    if let Ok(entries) = fs::read_dir(dir) {
+        let mut others = entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| is_soundfont(p) && !fonts.contains(p))
+            .collect::<Vec<_>>();
+        others.sort();
+        fonts.extend(others);
+    }
+
+    fonts
+}
+
+pub fn is_soundfont(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| {
+            e.eq_ignore_ascii_case("sf2") || e.eq_ignore_ascii_case("sf3")
+        })
+}
+
+/// A RIFF chunk
+struct Chunk<'a> {
+    id: &'a [u8],
+    data: &'a [u8],
+}
+
+/// Chunks following each other, like at the top of a RIFF file or inside a LIST
+fn chunks(mut data: &[u8]) -> Result<Vec<Chunk<'_>>> {
+    let mut chunks = Vec::new();
+    while data.len() >= 8 {
+        let id = &data[..4];
+        let len = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let end = len.checked_add(8);
+        let body = end.and_then(|end| data.get(8..end)).ok_or_else(|| {
+            anyhow!("the {} chunk is cut off", String::from_utf8_lossy(id))
+        })?;
+        chunks.push(Chunk { id, data: body });
+        // chunks are padded to an even length
+        data = end
+            .and_then(|end| end.checked_add(len % 2))
+            .and_then(|next| data.get(next..))
+            .unwrap_or_default();
+    }
+    Ok(chunks)
+}
+
+/// Chunks inside the LIST chunk of type `kind`
+fn list<'a>(chunks: &[Chunk<'a>], kind: &[u8]) -> Result<Vec<Chunk<'a>>> {
+    let list = chunks
+        .iter()
+        .find(|c| c.id == b"LIST" && c.data.starts_with(kind))
+        .ok_or_else(|| anyhow!("there's no {} list", String::from_utf8_lossy(kind)))?;
+    self::chunks(&list.data[4..])
+}
+
+/// Chunks of a soundfont file, checking it is one
+fn sfbk_chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>> {
+    match data {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b's', b'f', b'b', b'k', rest @ ..] => chunks(rest),
+        _ => bail!("it's not a RIFF soundfont file"),
+    }
+}
+
+/// Major and minor soundfont version from the file header
+pub fn version(data: &[u8]) -> Result<(u16, u16)> {
+    let info = list(&sfbk_chunks(data)?, b"INFO")?;
+    match info.iter().find(|c| c.id == b"ifil").map(|c| c.data) {
+        Some([major0, major1, minor0, minor1, ..]) => Ok((
+            u16::from_le_bytes([*major0, *major1]),
+            u16::from_le_bytes([*minor0, *minor1]),
+        )),
+        _ => bail!("the version is missing"),
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn write_list(out: &mut Vec<u8>, kind: &[u8], chunks: &[(&[u8], &[u8])]) {
+    let mut list = kind.to_vec();
+    for (id, data) in chunks {
+        write_chunk(&mut list, id, data);
+    }
+    write_chunk(out, b"LIST", &list);
+}
+
+/// Bytes of a sample header in the `shdr` chunk
+const SAMPLE_HEADER: usize = 46;
+
+/// Sample type flag of SF3 samples stored as Ogg Vorbis
+const VORBIS: u16 = 0x10;
+
+/// Silence after every sample, the SF2 spec asks for at least 46 points
+const SAMPLE_PADDING: usize = 46;
+
+/// A sample point as stored in a sample header
+fn sample_point(point: usize) -> Result<u32> {
+    u32::try_from(point).map_err(|_| anyhow!("the samples are too long for an SF2"))
+}
+
+/// Soundfont data oxisynth can load. SF3 fonts get their Vorbis samples
+/// decoded into an SF2, anything newer is refused.
+pub fn to_sf2(data: Vec<u8>) -> Result<Vec<u8>> {
+    match version(&data)? {
+        (2, _) => Ok(data),
+        (3, _) => decode_sf3(&data),
+        (major, minor) => bail!("version {}.{} isn't supported", major, minor),
+    }
+}
+
+fn decode_sf3(data: &[u8]) -> Result<Vec<u8>> {
+    let top = sfbk_chunks(data)?;
+    let info = list(&top, b"INFO")?;
+    let sdta = list(&top, b"sdta")?;
+    let pdta = list(&top, b"pdta")?;
+    let smpl = sdta
+        .iter()
+        .find(|c| c.id == b"smpl")
+        .map_or(&[][..], |c| c.data);
+    let shdr = pdta
+        .iter()
+        .find(|c| c.id == b"shdr")
+        .ok_or_else(|| anyhow!("the sample headers are missing"))?;
+
+    let mut samples = Vec::<i16>::new();
+    let mut headers = shdr.data.to_vec();
+    for header in headers.chunks_exact_mut(SAMPLE_HEADER) {
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let (start, end) = (field(20) as usize, field(24) as usize);
+        let (loop_start, loop_end) = (field(28), field(32));
+        let kind = u16::from_le_bytes([header[44], header[45]]);
+
+        let new_start = samples.len();
+        let (new_loop_start, new_loop_end);
+        if kind & VORBIS != 0 {
+            // start and end are byte offsets of the Ogg stream, loops count
+            // from the start of the decoded sample
+            let ogg = smpl
+                .get(start..end)
+                .ok_or_else(|| anyhow!("a sample is outside the sample data"))?;
+            let mut reader = OggStreamReader::new(Cursor::new(ogg))?;
+            while let Some(packet) = reader.read_dec_packet_itl()? {
+                samples.extend(packet);
+            }
+            let moved = |point: u32| {
+                let point = new_start.checked_add(point as usize);
+                point.ok_or_else(|| anyhow!("a sample loop is out of range"))
+            };
+            new_loop_start = moved(loop_start)?;
+            new_loop_end = moved(loop_end)?;
+            header[44..46].copy_from_slice(&(kind & !VORBIS).to_le_bytes());
+        } else if end > start {
+            // uncompressed, in sample points
+            let raw = start
+                .checked_mul(2)
+                .zip(end.checked_mul(2))
+                .and_then(|(from, to)| smpl.get(from..to))
+                .ok_or_else(|| anyhow!("a sample is outside the sample data"))?;
+            samples.extend(raw.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])));
+            let moved = |point: u32| {
+                (point as usize)
+                    .checked_sub(start)
+                    .and_then(|offset| new_start.checked_add(offset))
+                    .ok_or_else(|| anyhow!("a sample loop is outside its sample"))
+            };
+            new_loop_start = moved(loop_start)?;
+            new_loop_end = moved(loop_end)?;
+        } else {
+            // an empty sample, like the terminal record
+            (new_loop_start, new_loop_end) = (new_start, new_start);
+        }
+        let new_end = samples.len();
+        header[20..24].copy_from_slice(&sample_point(new_start)?.to_le_bytes());
+        header[24..28].copy_from_slice(&sample_point(new_end)?.to_le_bytes());
+        header[28..32].copy_from_slice(&sample_point(new_loop_start)?.to_le_bytes());
+        header[32..36].copy_from_slice(&sample_point(new_loop_end)?.to_le_bytes());
+        samples.extend([0; SAMPLE_PADDING]);
+    }
+    let smpl = samples
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    let mut sfbk = b"sfbk".to_vec();
+    // 2.04 is the last SF2 version
+    let info = info
+        .iter()
+        .map(|c| match c.id {
+            b"ifil" => (c.id, &[2, 0, 4, 0][..]),
+            _ => (c.id, c.data),
+        })
+        .collect::<Vec<_>>();
+    write_list(&mut sfbk, b"INFO", &info);
+    write_list(&mut sfbk, b"sdta", &[(b"smpl", &smpl)]);
+    let pdta = pdta
+        .iter()
+        .map(|c| match c.id {
+            b"shdr" => (c.id, &headers[..]),
+            _ => (c.id, c.data),
+        })
+        .collect::<Vec<_>>();
+    write_list(&mut sfbk, b"pdta", &pdta);
+
+    let mut out = Vec::with_capacity(sfbk.len() + 8);
+    write_chunk(&mut out, b"RIFF", &sfbk);
+    Ok(out)
+}
+
+#[test]
+fn test_config_before_discovery() {
+    let config = Config {
+        soundfont: Some(PathBuf::from("/opt/fonts/piano.sf2")),
+        ..Default::default()
+    };
+    let paths = candidates(&config);
+    let config_pos = paths
+        .iter()
+        .position(|p| p == Path::new("/opt/fonts/piano.sf2"))
+        .unwrap();
+
+    // only the environment variable goes before the config
+    assert!(config_pos <= 1);
+}
+
+#[test]
+fn test_fonts_in_folder() {
+    let dir = env::temp_dir().join("rusty-karaoke-soundfont-test");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a_piano.sf2"), b"").unwrap();
+    fs::write(dir.join("readme.txt"), b"").unwrap();
+
+    let fonts = fonts_in(&dir);
+    assert_eq!(fonts[0], dir.join("default.sf2"));
+    assert!(fonts.contains(&dir.join("a_piano.sf2")));
+    assert!(!fonts.contains(&dir.join("readme.txt")));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_setup_order() {
+    let mut setup = SoundfontSetup {
+        fonts: vec![PathBuf::from("gm.sf2"), PathBuf::from("drums.sf2")],
+        overrides: vec![FontOverride {
+            font: PathBuf::from("piano.sf3"),
+            bank: Some(0),
+            program: Some(0),
+            ..Default::default()
+        }],
+    };
+
+    assert_eq!(
+        setup.load_order(),
+        [
+            Path::new("piano.sf3"),
+            Path::new("drums.sf2"),
+            Path::new("gm.sf2")
+        ]
+    );
+    assert_eq!(setup.override_for(3, 0, 0), Some(Path::new("piano.sf3")));
+    assert_eq!(setup.override_for(3, 0, 1), None);
+
+    setup.push(PathBuf::from("drums.sf2"));
+    assert_eq!(setup.fonts[0], Path::new("drums.sf2"));
+    assert_eq!(setup.fonts.len(), 2);
+}
+
+#[test]
+fn test_version() {
+    let chunk = |id: &[u8], data: &[u8]| {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    };
+    let info = chunk(b"LIST", &[b"INFO".as_slice(), &chunk(b"ifil", &[2, 0, 1, 0])].concat());
+    let font = chunk(b"RIFF", &[b"sfbk".as_slice(), &info].concat());
+
+    assert_eq!(version(&font).unwrap(), (2, 1));
+    assert!(version(b"RIFF\0\0\0\0WAVE").is_err());
+    assert!(version(&font[..font.len() - 2]).is_err());
+
+    // an SF3 with one uncompressed sample, 4 points starting at point 2
+    let mut header = vec![0; SAMPLE_HEADER];
+    for (at, value) in [(20, 2_u32), (24, 6), (28, 3), (32, 5)] {
+        header[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    header[44] = 1;
+    header.extend([0; SAMPLE_HEADER]);
+    let smpl = (0..8_i16).flat_map(|s| s.to_le_bytes()).collect::<Vec<_>>();
+    let one_list = |kind: &[u8], id: &[u8], data: &[u8]| {
+        chunk(b"LIST", &[kind, &chunk(id, data)].concat())
+    };
+    let sf3 = |header: &[u8]| {
+        chunk(
+            b"RIFF",
+            &[
+                b"sfbk".as_slice(),
+                &one_list(b"INFO", b"ifil", &[3, 0, 1, 0]),
+                &one_list(b"sdta", b"smpl", &smpl),
+                &one_list(b"pdta", b"shdr", header),
+            ]
+            .concat(),
+        )
+    };
+
+    let sf2 = to_sf2(sf3(&header)).unwrap();
+    assert_eq!(version(&sf2).unwrap(), (2, 4));
+    let top = sfbk_chunks(&sf2).unwrap();
+    let pdta = list(&top, b"pdta").unwrap();
+    let field = |at: usize| u32::from_le_bytes(pdta[0].data[at..at + 4].try_into().unwrap());
+    assert_eq!([field(20), field(24), field(28), field(32)], [0, 4, 1, 3]);
+    let sdta = list(&top, b"sdta").unwrap();
+    assert_eq!(&sdta[0].data[..8], &smpl[4..12]);
+    // a loop before the start of its sample is an error, not a panic
+    header[28..32].copy_from_slice(&1_u32.to_le_bytes());
+    assert!(to_sf2(sf3(&header)).is_err());
+}
@@ -0,0 +1,245 @@
+//! Key and tempo changes for audio tracks
+//!
+//! Tempo is changed with WSOLA: the input is cut into overlapping windows that
+//! are laid out at a different spacing, each one nudged to where it lines up
+//! best with the last so the waveform stays continuous. A key change is a
+//! stretch by the pitch ratio, resampled back to the original length. That way
+//! audio songs take the same transpose and speed as MIDI ones.
+
+use std::f32::consts::PI;
+
+/// Window length, in seconds
+const WINDOW: f32 = 0.04;
+
+/// How far a window can be nudged to line up, in seconds
+const TOLERANCE: f32 = 0.01;
+
+/// WSOLA time stretch on interleaved stereo
+pub struct TimeStretch {
+    /// Window length in frames, the output hop is half of it
+    window: usize,
+    tolerance: usize,
+    /// Output length over input length
+    pub ratio: f64,
+    hann: Vec<f32>,
+    /// Input that's still needed, interleaved
+    input: Vec<f32>,
+    /// Where the next window starts before nudging, in frames into `input`
+    pos: f64,
+    /// Where the audio carries on after the last window, in frames into `input`
+    target: Option<usize>,
+    /// Windows added up but not output yet, interleaved
+    acc: Vec<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(sample_rate: u32, ratio: f64) -> Self {
+        let window = ((sample_rate as f32 * WINDOW) as usize / 2 * 2).max(4);
+        let tolerance = (sample_rate as f32 * TOLERANCE) as usize;
+        // periodic Hann, adds up to exactly 1 at half overlap
+        let hann = (0..window)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / window as f32).cos())
+            .collect();
+
+        Self {
+            window,
+            tolerance,
+            ratio,
+            hann,
+            // silence in front so the first window can be nudged back
+            input: vec![0.0; tolerance * 2],
+            pos: tolerance as f64,
+            target: None,
+            acc: vec![0.0; window * 2],
+        }
+    }
+
+    /// Frames added to `out` for every window
+    fn hop(&self) -> usize {
+        self.window / 2
+    }
+
+    /// Feeds interleaved stereo, whatever is done is added to `out`
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        self.input.extend_from_slice(input);
+        let hop = self.hop();
+
+        loop {
+            let frames = self.input.len() / 2;
+            let nominal = self.pos.round() as usize;
+            if nominal + self.tolerance + self.window > frames {
+                break;
+            }
+
+            let start = match self.target {
+                Some(target) => self.best_match(target, nominal),
+                None => nominal,
+            };
+
+            for i in 0..self.window * 2 {
+                self.acc[i] += self.input[start * 2 + i] * self.hann[i / 2];
+            }
+            out.extend_from_slice(&self.acc[..hop * 2]);
+            self.acc.copy_within(hop * 2.., 0);
+            let len = self.acc.len();
+            self.acc[len - hop * 2..].fill(0.0);
+
+            self.pos += hop as f64 / self.ratio;
+
+            // drop what neither the next window nor its match will look at
+            let drop = (start + hop).min(self.pos as usize - self.tolerance);
+            self.input.drain(..drop * 2);
+            self.pos -= drop as f64;
+            self.target = Some(start + hop - drop);
+        }
+    }
+
+    /// Window start around `nominal` that best continues the audio at `target`
+    fn best_match(&self, target: usize, nominal: usize) -> usize {
+        let overlap = self.hop();
+        let mono = |frame: usize| self.input[frame * 2] + self.input[frame * 2 + 1];
+
+        (nominal - self.tolerance..=nominal + self.tolerance)
+            .map(|start| {
+                let corr = (0..overlap)
+                    .map(|i| mono(start + i) * mono(target + i))
+                    .sum::<f32>();
+                (start, corr)
+            })
+            .fold(
+                (nominal, f32::MIN),
+                |best, c| if c.1 > best.1 { c } else { best },
+            )
+            .0
+    }
+
+    /// Pushes silence through so everything fed so far comes out
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        let silence = vec![0.0; (self.window + self.tolerance * 2) * 2];
+        self.process(&silence, out);
+    }
+}
+
+/// Linear resampler on interleaved stereo with a fractional ratio
+struct Resampler {
+    /// Input frames per output frame
+    step: f64,
+    pos: f64,
+    prev: [f32; 2],
+}
+
+impl Resampler {
+    fn push(&mut self, frame: [f32; 2], out: &mut Vec<f32>) {
+        while self.pos <= 1.0 {
+            let t = self.pos as f32;
+            out.push(self.prev[0] + (frame[0] - self.prev[0]) * t);
+            out.push(self.prev[1] + (frame[1] - self.prev[1]) * t);
+            self.pos += self.step;
+        }
+        self.pos -= 1.0;
+        self.prev = frame;
+    }
+}
+
+/// Transpose and speed for an audio track, can be changed while playing
+pub struct PitchShifter {
+    stretch: TimeStretch,
+    resampler: Resampler,
+    /// Stretched audio on its way to the resampler
+    buf: Vec<f32>,
+}
+
+impl PitchShifter {
+    pub fn new(sample_rate: u32, transpose: i8, speed: f32) -> Self {
+        let mut shifter = Self {
+            stretch: TimeStretch::new(sample_rate, 1.0),
+            resampler: Resampler {
+                step: 1.0,
+                pos: 1.0,
+                prev: [0.0; 2],
+            },
+            buf: Vec::new(),
+        };
+        shifter.set(transpose, speed);
+        shifter
+    }
+
+    /// `transpose` in semitones, `speed` as a tempo multiplier like the MIDI player takes
+    pub fn set(&mut self, transpose: i8, speed: f32) {
+        let pitch = 2_f64.powf(transpose as f64 / 12.0);
+        // stretch by the pitch ratio, then play it back that much faster
+        self.stretch.ratio = pitch / speed as f64;
+        self.resampler.step = pitch;
+    }
+
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        self.buf.clear();
+        self.stretch.process(input, &mut self.buf);
+        self.resample(out);
+    }
+
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        self.buf.clear();
+        self.stretch.flush(&mut self.buf);
+        self.resample(out);
+    }
+
+    fn resample(&mut self, out: &mut Vec<f32>) {
+        for frame in self.buf.chunks_exact(2) {
+            self.resampler.push([frame[0], frame[1]], out);
+        }
+    }
+}
+
+#[cfg(test)]
+fn shift(transpose: i8, speed: f32, freq: f32) -> (usize, f32) {
+    let sr = 44100;
+    let input = (0..sr)
+        .flat_map(|i| {
+            let s = 0.5 * (2.0 * PI * freq * i as f32 / sr as f32).sin();
+            [s, s]
+        })
+        .collect::<Vec<_>>();
+
+    let mut shifter = PitchShifter::new(sr, transpose, speed);
+    let mut out = Vec::new();
+    // odd sized blocks, like an audio callback would hand over
+    for block in input.chunks(2 * 333) {
+        shifter.process(block, &mut out);
+    }
+    shifter.flush(&mut out);
+
+    let frames = out.len() / 2;
+    let mid = out
+        .chunks_exact(2)
+        .skip(frames / 4)
+        .take(2048)
+        .map(|f| f[0])
+        .collect::<Vec<_>>();
+    let pitch = crate::pitch::Yin::new(sr).detect(&mid).unwrap();
+
+    (frames, pitch)
+}
+
+#[test]
+fn test_time_stretch() {
+    // a quarter of a second either way covers the window and the flush
+    let (frames, pitch) = shift(0, 2.0, 440.0);
+    assert!((frames as i64 - 22050).abs() < 11025, "{}", frames);
+    assert!((pitch - 440.0).abs() < 5.0, "{}", pitch);
+
+    let (frames, pitch) = shift(0, 0.5, 440.0);
+    assert!((frames as i64 - 88200).abs() < 11025, "{}", frames);
+    assert!((pitch - 440.0).abs() < 5.0, "{}", pitch);
+}
+
+#[test]
+fn test_pitch_shift() {
+    let (frames, pitch) = shift(12, 1.0, 220.0);
+    assert!((frames as i64 - 44100).abs() < 11025, "{}", frames);
+    assert!((pitch - 440.0).abs() < 5.0, "{}", pitch);
+
+    let (_, pitch) = shift(-5, 1.0, 440.0);
+    let expected = 440.0 * 2_f32.powf(-5.0 / 12.0);
+    assert!((pitch - expected).abs() < 5.0, "{}", pitch);
+}
@@ -0,0 +1,242 @@
+//! System Exclusive messages
+//!
+//! Thai karaoke MIDIs are usually made for Roland GS or Yamaha XG modules and rely
+//! on SysEx for resets, drum kits and part modes. External synths get the raw messages,
+//! the built-in synth only understands the handful parsed here.
+
+use midly::{Format, Smf, TrackEventKind};
+
+/// A SysEx message we know how to interpret
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysExMessage {
+    /// General MIDI System On
+    GmOn,
+    /// Roland GS Reset
+    GsReset,
+    /// Yamaha XG System On
+    XgOn,
+    /// Universal master volume, 14 bit
+    MasterVolume(u16),
+    /// Sets a channel to a normal (`false`) or drum (`true`) part
+    DrumPart { channel: u8, drums: bool },
+    /// Anything else, only forwarded to external synths
+    Other,
+}
+
+/// Which reset to send to the synth before each song
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResetMode {
+    None,
+    Gm,
+    #[default]
+    Gs,
+    Xg,
+}
+
+impl ResetMode {
+    pub const ALL: [ResetMode; 4] = [Self::None, Self::Gm, Self::Gs, Self::Xg];
+
+    /// The complete SysEx message for this reset, including `F0` and `F7`
+    pub fn message(&self) -> Option<&'static [u8]> {
+        match self {
+            Self::None => None,
+            Self::Gm => Some(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+            Self::Gs => Some(&[
+                0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41, 0xF7,
+            ]),
+            Self::Xg => Some(&[0xF0, 0x43, 0x10, 0x4C, 0x00, 0x00, 0x7E, 0x00, 0xF7]),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Gm => "GM On",
+            Self::Gs => "GS Reset",
+            Self::Xg => "XG On",
+        }
+    }
+}
+
+/// Wraps SysEx data from a MIDI file in `F0` ... `F7`.
+///
+/// SMF stores SysEx without the leading `F0`, and the trailing `F7` is optional.
+pub fn normalize(data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(data.len() + 2);
+    if data.first() != Some(&0xF0) {
+        msg.push(0xF0);
+    }
+    msg.extend_from_slice(data);
+    if msg.last() != Some(&0xF7) {
+        msg.push(0xF7);
+    }
+    msg
+}
+
+/// SysEx messages of a MIDI file with the [Sheet](nodi::Sheet) position they're at,
+/// in order. `nodi` drops SysEx when it builds a sheet, so players send these
+/// alongside it.
+pub fn from_smf(smf: &Smf) -> Vec<(usize, Vec<u8>)> {
+    let mut messages = Vec::new();
+    let mut start = 0;
+    for track in &smf.tracks {
+        let mut tick = start;
+        for event in track {
+            tick += event.delta.as_int() as usize;
+            if let TrackEventKind::SysEx(data) = event.kind {
+                messages.push((tick, normalize(data)));
+            }
+        }
+        // sequential sheets put every track after the one before, one tick
+        // past its last event
+        if smf.header.format == Format::Sequential {
+            start = tick + 1;
+        }
+    }
+    // stable, so messages at the same tick keep their order
+    messages.sort_by_key(|(tick, _)| *tick);
+    messages
+}
+
+/// Interprets a complete SysEx message (see [normalize])
+pub fn parse(msg: &[u8]) -> SysExMessage {
+    // strip F0 and F7
+    let body = match msg {
+        [0xF0, body @ .., 0xF7] => body,
+        _ => return SysExMessage::Other,
+    };
+
+    match body {
+        // universal non-realtime, any device
+        [0x7E, _, 0x09, 0x01] => SysExMessage::GmOn,
+        // universal realtime, master volume
+        [0x7F, _, 0x04, 0x01, lsb, msb] => {
+            SysExMessage::MasterVolume(((*msb as u16) << 7) | *lsb as u16)
+        }
+        // Roland, GS, DT1
+        [0x41, _, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, _] => SysExMessage::GsReset,
+        [0x41, _, 0x42, 0x12, 0x40, block, 0x15, map, _] if block & 0xF0 == 0x10 => {
+            SysExMessage::DrumPart {
+                channel: gs_block_to_channel(block & 0x0F),
+                drums: *map != 0,
+            }
+        }
+        // Yamaha, XG
+        [0x43, dev, 0x4C, 0x00, 0x00, 0x7E, 0x00] if dev & 0xF0 == 0x10 => SysExMessage::XgOn,
+        [0x43, dev, 0x4C, 0x08, part, 0x07, mode] if dev & 0xF0 == 0x10 && *part < 16 => {
+            SysExMessage::DrumPart {
+                channel: *part,
+                drums: *mode != 0,
+            }
+        }
+        _ => SysExMessage::Other,
+    }
+}
+
+/// GS numbers its parts 1-16 with part 10 (the drums) first,
+/// so block 0 is channel 10, blocks 1-9 are channels 1-9 and A-F are 11-16
+fn gs_block_to_channel(block: u8) -> u8 {
+    match block {
+        0 => 9,
+        1..=9 => block - 1,
+        _ => block,
+    }
+}
+
+#[test]
+fn test_parse_resets() {
+    for mode in [ResetMode::Gm, ResetMode::Gs, ResetMode::Xg] {
+        let expected = match mode {
+            ResetMode::Gm => SysExMessage::GmOn,
+            ResetMode::Gs => SysExMessage::GsReset,
+            _ => SysExMessage::XgOn,
+        };
+        assert_eq!(parse(mode.message().unwrap()), expected);
+    }
+    assert_eq!(ResetMode::None.message(), None);
+}
+
+#[test]
+fn test_parse_from_smf() {
+    // GS reset as stored in a MIDI file, without the F0
+    let data = [0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41, 0xF7];
+    assert_eq!(parse(&normalize(&data)), SysExMessage::GsReset);
+}
+
+#[test]
+fn test_parse_drum_part() {
+    // part 11 to drum map 1
+    let msg = [
+        0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x1A, 0x15, 0x01, 0x10, 0xF7,
+    ];
+    assert_eq!(
+        parse(&msg),
+        SysExMessage::DrumPart {
+            channel: 10,
+            drums: true
+        }
+    );
+
+    // part 2 back to normal
+    let msg = [
+        0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x12, 0x15, 0x00, 0x19, 0xF7,
+    ];
+    assert_eq!(
+        parse(&msg),
+        SysExMessage::DrumPart {
+            channel: 1,
+            drums: false
+        }
+    );
+
+    // XG part 3 to drums
+    let msg = [0xF0, 0x43, 0x10, 0x4C, 0x08, 0x02, 0x07, 0x01, 0xF7];
+    assert_eq!(
+        parse(&msg),
+        SysExMessage::DrumPart {
+            channel: 2,
+            drums: true
+        }
+    );
+}
+
+#[test]
+fn test_parse_master_volume() {
+    let msg = [0xF0, 0x7F, 0x7F, 0x04, 0x01, 0x00, 0x40, 0xF7];
+    assert_eq!(parse(&msg), SysExMessage::MasterVolume(0x40 << 7));
+}
+
+#[test]
+fn test_from_smf() {
+    use midly::{Header, MetaMessage, Timing, TrackEvent};
+
+    let gs = [0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41, 0xF7];
+    let track = |sysex_delta: u32| {
+        vec![
+            TrackEvent {
+                delta: sysex_delta.into(),
+                kind: TrackEventKind::SysEx(&gs),
+            },
+            TrackEvent {
+                delta: 10.into(),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            },
+        ]
+    };
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+    smf.tracks = vec![track(5), track(2)];
+
+    let ticks = |smf: &Smf| {
+        from_smf(smf)
+            .into_iter()
+            .map(|(tick, msg)| {
+                assert_eq!(parse(&msg), SysExMessage::GsReset);
+                tick
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ticks(&smf), [2, 5]);
+    // the first track takes 16 ticks
+    smf.header.format = Format::Sequential;
+    assert_eq!(ticks(&smf), [5, 18]);
+}
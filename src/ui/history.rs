@@ -0,0 +1,117 @@
+//! Play statistics for egui
+
+use anyhow::Result;
+use egui::Widget;
+
+use crate::library::{
+    history::{self, Play},
+    Library, Song,
+};
+
+/// Songs listed in each table
+const ROWS: usize = 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Most sung this month
+    pub top: Vec<(Song, usize)>,
+    /// Whose history is in `history`, empty for nobody
+    pub singer: String,
+    pub history: Vec<Play>,
+    pub never_played: usize,
+}
+
+impl Stats {
+    pub fn load(library: &Library, singer: &str) -> Result<Self> {
+        let month = chrono::Local::now().format("%Y-%m").to_string();
+        let (from, to) = history::month_range(&month)?;
+        Ok(Self {
+            top: library.most_sung(from, to, ROWS)?,
+            singer: singer.to_string(),
+            history: if singer.is_empty() {
+                Vec::new()
+            } else {
+                library.singer_history(singer, ROWS)?
+            },
+            never_played: library.never_played()?.len(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryAction {
+    Refresh,
+    /// Saves every play of `month` as CSV
+    ExportPlays,
+    /// Saves the plays of `month` per song as CSV
+    ExportSongs,
+}
+
+pub struct History<'a> {
+    pub stats: &'a Stats,
+    /// Month to export, like `2022-11`
+    pub month: &'a mut String,
+    /// Set when a button is clicked
+    pub action: &'a mut Option<HistoryAction>,
+}
+
+impl<'a> Widget for History<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            ui.horizontal(|ui| {
+                ui.strong("Most sung this month");
+                if ui.button("Refresh").clicked() {
+                    *self.action = Some(HistoryAction::Refresh);
+                }
+            });
+            egui::Grid::new("most_sung").striped(true).show(ui, |ui| {
+                for (song, count) in &self.stats.top {
+                    ui.label(&song.code);
+                    ui.label(&song.title);
+                    ui.label(&song.artist);
+                    ui.label(count.to_string());
+                    ui.end_row();
+                }
+            });
+            if self.stats.top.is_empty() {
+                ui.label("Nothing sung yet");
+            }
+
+            if !self.stats.singer.is_empty() {
+                ui.separator();
+                ui.strong(format!("Sung by {}", self.stats.singer));
+                egui::Grid::new("singer_history")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for play in &self.stats.history {
+                            ui.label(play.date());
+                            ui.label(&play.code);
+                            ui.label(&play.title);
+                            ui.label(match (play.score, play.skipped) {
+                                (_, true) => "skipped".to_string(),
+                                (Some(score), _) => score.to_string(),
+                                (None, _) => String::new(),
+                            });
+                            ui.end_row();
+                        }
+                    });
+            }
+
+            ui.separator();
+            ui.label(format!("{} songs never played", self.stats.never_played));
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Month");
+                ui.add(egui::TextEdit::singleline(self.month).desired_width(80.0));
+                if ui.button("Export plays").clicked() {
+                    *self.action = Some(HistoryAction::ExportPlays);
+                }
+                if ui.button("Export per song").clicked() {
+                    *self.action = Some(HistoryAction::ExportSongs);
+                }
+            });
+        })
+        .response
+    }
+}
@@ -0,0 +1,65 @@
+//! Song code display for egui, see [crate::keypad]
+
+use egui::{Event, Key, RichText, Widget};
+
+use crate::{
+    keypad::{CodeEntry, KeypadKey},
+    library::search::SearchIndex,
+};
+
+/// Keypad keys pressed this frame. Nothing is taken while a text field has
+/// focus, so typing in the search box still works.
+pub fn pressed_keys(ctx: &egui::Context) -> Vec<KeypadKey> {
+    if ctx.memory().focus().is_some() {
+        return Vec::new();
+    }
+    ctx.input()
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Text(text) => KeypadKey::from_text(text),
+            Event::Key {
+                key, pressed: true, ..
+            } => match key {
+                Key::Enter => Some(KeypadKey::Enter),
+                Key::Backspace => Some(KeypadKey::Delete),
+                Key::Escape => Some(KeypadKey::Clear),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// The code typed so far and the song it picks
+pub struct Keypad<'a> {
+    pub entry: &'a CodeEntry,
+    pub songs: &'a SearchIndex,
+}
+
+impl<'a> Widget for Keypad<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        ui.horizontal(|ui| {
+            let digits = self.entry.digits();
+            if digits.is_empty() {
+                if let Some(message) = self.entry.message() {
+                    ui.label(RichText::new(message).heading());
+                }
+                return;
+            }
+
+            ui.label(RichText::new(digits).monospace().heading().strong());
+            match self.songs.by_code(digits) {
+                Some(song) => {
+                    ui.label(RichText::new(&song.title).heading());
+                    ui.label(&song.artist);
+                    ui.label("Enter to queue, + to play now");
+                }
+                None => {
+                    ui.label("No song with this code");
+                }
+            }
+        })
+        .response
+    }
+}
@@ -0,0 +1,75 @@
+//! Master output controls for egui
+
+use egui::Widget;
+
+use crate::dsp::MasterSettings;
+
+/// Edits [MasterSettings] in place, the response is marked as changed on any edit
+pub struct Master<'a> {
+    pub settings: &'a mut MasterSettings,
+}
+
+impl<'a> Widget for Master<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let settings = self.settings;
+        let mut changed = false;
+
+        let mut response = ui
+            .vertical(|ui| {
+                changed |= ui
+                    .add(egui::Slider::new(&mut settings.volume, 0.0..=2.0).text("Volume"))
+                    .changed();
+                changed |= ui
+                    .checkbox(&mut settings.limiter, "Limiter")
+                    .on_hover_text("Keeps loud passages from clipping")
+                    .changed();
+
+                ui.separator();
+                let reverb = &mut settings.reverb;
+                changed |= ui.checkbox(&mut reverb.enabled, "Reverb").changed();
+                ui.add_enabled_ui(reverb.enabled, |ui| {
+                    changed |= ui
+                        .add(egui::Slider::new(&mut reverb.room_size, 0.0..=1.0).text("Room size"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut reverb.damping, 0.0..=1.0).text("Damping"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut reverb.width, 0.0..=100.0).text("Width"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut reverb.level, 0.0..=1.0).text("Level"))
+                        .changed();
+                });
+
+                ui.separator();
+                let chorus = &mut settings.chorus;
+                changed |= ui.checkbox(&mut chorus.enabled, "Chorus").changed();
+                ui.add_enabled_ui(chorus.enabled, |ui| {
+                    changed |= ui
+                        .add(egui::Slider::new(&mut chorus.voices, 0..=99).text("Voices"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut chorus.level, 0.0..=10.0).text("Level"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut chorus.speed, 0.1..=5.0).text("Speed (Hz)"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut chorus.depth, 0.0..=256.0).text("Depth (ms)"))
+                        .changed();
+                });
+
+                if ui.button("Defaults").clicked() {
+                    *settings = MasterSettings::default();
+                    changed = true;
+                }
+            })
+            .response;
+
+        if changed {
+            response.mark_changed();
+        }
+        response
+    }
+}
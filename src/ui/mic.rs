@@ -0,0 +1,149 @@
+//! Microphone controls for egui
+
+use egui::Widget;
+
+use crate::mic::{self, MicSettings, MicSource};
+
+/// Edits [MicSettings] in place, the response is marked as changed on any edit
+pub struct Mic<'a> {
+    pub settings: &'a mut MicSettings,
+    /// Input devices found on the last refresh
+    pub devices: &'a mut Vec<String>,
+}
+
+impl<'a> Widget for Mic<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let settings = self.settings;
+        let devices = self.devices;
+        let mut changed = false;
+
+        let mut response = ui
+            .vertical(|ui| {
+                changed |= ui.checkbox(&mut settings.enabled, "Enabled").changed();
+                changed |= ui
+                    .checkbox(&mut settings.monitor, "Monitor")
+                    .on_hover_text("Play the microphone through the speakers")
+                    .changed();
+
+                ui.horizontal(|ui| {
+                    let is_file = matches!(settings.source, MicSource::File(_));
+                    if ui.radio(!is_file, "Device").clicked() && is_file {
+                        settings.source = MicSource::Device;
+                        changed = true;
+                    }
+                    if ui
+                        .radio(is_file, "File")
+                        .on_hover_text("Loop a WAV file instead of a device")
+                        .clicked()
+                    {
+                        if let Some(path) = pick_wav() {
+                            settings.source = MicSource::File(path);
+                            changed = true;
+                        }
+                    }
+                });
+
+                match &settings.source {
+                    MicSource::Device => {
+                        ui.horizontal(|ui| {
+                            let selected = settings.device.as_deref().unwrap_or("Default");
+                            egui::ComboBox::from_id_source("mic_device")
+                                .selected_text(selected)
+                                .show_ui(ui, |ui| {
+                                    changed |= ui
+                                        .selectable_value(&mut settings.device, None, "Default")
+                                        .changed();
+                                    for dev in devices.iter() {
+                                        changed |= ui
+                                            .selectable_value(
+                                                &mut settings.device,
+                                                Some(dev.clone()),
+                                                dev.as_str(),
+                                            )
+                                            .changed();
+                                    }
+                                });
+                            if ui.button("Refresh").clicked() {
+                                *devices = mic::list_input_devices().unwrap_or_default();
+                            }
+                        });
+                    }
+                    MicSource::File(path) => {
+                        ui.label(path.display().to_string());
+                    }
+                }
+
+                changed |= ui
+                    .add(egui::Slider::new(&mut settings.gain, 0.0..=4.0).text("Gain"))
+                    .changed();
+
+                ui.separator();
+                ui.label("EQ (dB)");
+                changed |= ui
+                    .add(egui::Slider::new(&mut settings.eq.low, -12.0..=12.0).text("Low"))
+                    .changed();
+                changed |= ui
+                    .add(egui::Slider::new(&mut settings.eq.mid, -12.0..=12.0).text("Mid"))
+                    .changed();
+                changed |= ui
+                    .add(egui::Slider::new(&mut settings.eq.high, -12.0..=12.0).text("High"))
+                    .changed();
+
+                ui.separator();
+                let echo = &mut settings.echo;
+                changed |= ui.checkbox(&mut echo.enabled, "Echo").changed();
+                ui.add_enabled_ui(echo.enabled, |ui| {
+                    changed |= ui
+                        .add(
+                            egui::Slider::new(&mut echo.delay_ms, 10.0..=1000.0).text("Delay (ms)"),
+                        )
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut echo.feedback, 0.0..=0.9).text("Feedback"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut echo.mix, 0.0..=1.0).text("Level"))
+                        .changed();
+                });
+
+                ui.separator();
+                let reverb = &mut settings.reverb;
+                changed |= ui.checkbox(&mut reverb.enabled, "Reverb").changed();
+                ui.add_enabled_ui(reverb.enabled, |ui| {
+                    changed |= ui
+                        .add(egui::Slider::new(&mut reverb.room_size, 0.0..=1.0).text("Room size"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut reverb.damping, 0.0..=1.0).text("Damping"))
+                        .changed();
+                    changed |= ui
+                        .add(egui::Slider::new(&mut reverb.mix, 0.0..=1.0).text("Level"))
+                        .changed();
+                });
+
+                if ui.button("Defaults").clicked() {
+                    // keep the input, only reset the sound
+                    *settings = MicSettings {
+                        enabled: settings.enabled,
+                        source: settings.source.clone(),
+                        device: settings.device.clone(),
+                        ..Default::default()
+                    };
+                    changed = true;
+                }
+            })
+            .response;
+
+        if changed {
+            response.mark_changed();
+        }
+        response
+    }
+}
+
+fn pick_wav() -> Option<std::path::PathBuf> {
+    native_dialog::FileDialog::new()
+        .add_filter("WAV audio", &["wav", "WAV"])
+        .show_open_single_file()
+        .unwrap_or_default()
+}
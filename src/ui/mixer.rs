@@ -0,0 +1,100 @@
+//! Channel mixer widget for egui
+
+use crossbeam::channel::Sender;
+use egui::Widget;
+
+use crate::{
+    midi::MidiMessage,
+    mixer::{ChannelMixer, MixerCommand, CHANNELS},
+};
+
+/// Mute, solo, volume and pan controls for all 16 MIDI channels.
+///
+/// The widget draws from a snapshot of the mixer and sends every change
+/// to the MIDI thread as a [MixerCommand].
+pub struct Mixer<'a> {
+    pub state: ChannelMixer,
+    pub midi: &'a Sender<MidiMessage>,
+}
+
+impl<'a> Mixer<'a> {
+    fn send(&self, cmd: MixerCommand) {
+        self.midi.send(MidiMessage::Mixer(cmd)).unwrap_or_default();
+    }
+}
+
+impl<'a> Widget for Mixer<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            ui.horizontal(|ui| {
+                let mut guide = self.state.guide_enabled();
+                let label = match self.state.guide_channel {
+                    Some(ch) => format!("Guide melody (ch {})", ch + 1),
+                    None => "Guide melody (unknown)".to_string(),
+                };
+                let toggle = ui.add_enabled(
+                    self.state.guide_channel.is_some(),
+                    egui::Checkbox::new(&mut guide, label),
+                );
+                if toggle.changed() {
+                    self.send(MixerCommand::ToggleGuide);
+                }
+
+                if ui.button("Reset").clicked() {
+                    self.send(MixerCommand::Reset);
+                }
+            });
+
+            egui::Grid::new("channel_mixer")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label("Ch");
+                    ui.label("Mute");
+                    ui.label("Solo");
+                    ui.label("Volume");
+                    ui.label("Pan");
+                    ui.end_row();
+
+                    for ch in 0..CHANNELS {
+                        let strip = self.state.channels[ch];
+                        let ch = ch as u8;
+
+                        ui.label(format!("{}", ch + 1));
+
+                        let mut mute = strip.mute;
+                        if ui.toggle_value(&mut mute, "M").changed() {
+                            self.send(MixerCommand::Mute(ch, mute));
+                        }
+
+                        let mut solo = strip.solo;
+                        if ui.toggle_value(&mut solo, "S").changed() {
+                            self.send(MixerCommand::Solo(ch, solo));
+                        }
+
+                        ui.horizontal(|ui| {
+                            let mut volume = strip.effective_volume();
+                            if ui.add(egui::Slider::new(&mut volume, 0..=127)).changed() {
+                                self.send(MixerCommand::Volume(ch, Some(volume)));
+                            }
+                            if strip.volume.is_some() && ui.small_button("↺").clicked() {
+                                self.send(MixerCommand::Volume(ch, None));
+                            }
+                        });
+
+                        ui.horizontal(|ui| {
+                            let mut pan = strip.effective_pan();
+                            if ui.add(egui::Slider::new(&mut pan, 0..=127)).changed() {
+                                self.send(MixerCommand::Pan(ch, Some(pan)));
+                            }
+                            if strip.pan.is_some() && ui.small_button("↺").clicked() {
+                                self.send(MixerCommand::Pan(ch, None));
+                            }
+                        });
+
+                        ui.end_row();
+                    }
+                });
+        })
+        .response
+    }
+}
@@ -1 +1,13 @@
+pub mod history;
+pub mod keypad;
+pub mod master;
+pub mod mic;
+pub mod mixer;
 pub mod piano;
+pub mod playlists;
+pub mod plugins;
+pub mod queue;
+pub mod score;
+pub mod search;
+pub mod soundfonts;
+pub mod vocal;
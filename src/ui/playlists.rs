@@ -0,0 +1,94 @@
+//! Playlists and favourites for egui
+
+use egui::Widget;
+
+use crate::library::playlists::{Playlist, PlaylistEntry};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlaylistAction {
+    /// Queues every song of the playlist
+    Queue(String),
+    Delete(String),
+    Export(String),
+    /// Saves the queue as a playlist with this name
+    SaveQueue(String),
+    Import,
+    QueueFavourite(PlaylistEntry),
+    RemoveFavourite(PlaylistEntry),
+    ExportFavourites,
+}
+
+pub struct Playlists<'a> {
+    pub playlists: &'a [String],
+    /// Favourites of the singer typed in the queue window
+    pub favourites: &'a Playlist,
+    /// Name typed in for saving the queue
+    pub name: &'a mut String,
+    /// Set when a button is clicked
+    pub action: &'a mut Option<PlaylistAction>,
+}
+
+impl<'a> Widget for Playlists<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            for name in self.playlists {
+                ui.horizontal(|ui| {
+                    ui.label(name);
+                    if ui.button("Queue").clicked() {
+                        *self.action = Some(PlaylistAction::Queue(name.clone()));
+                    }
+                    if ui.button("Export").clicked() {
+                        *self.action = Some(PlaylistAction::Export(name.clone()));
+                    }
+                    if ui.button("Delete").clicked() {
+                        *self.action = Some(PlaylistAction::Delete(name.clone()));
+                    }
+                });
+            }
+            if self.playlists.is_empty() {
+                ui.label("No playlists yet");
+            }
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(self.name);
+                let name = self.name.trim();
+                if ui
+                    .add_enabled(!name.is_empty(), egui::Button::new("Save the queue"))
+                    .clicked()
+                {
+                    *self.action = Some(PlaylistAction::SaveQueue(name.to_string()));
+                }
+                if ui.button("Import").clicked() {
+                    *self.action = Some(PlaylistAction::Import);
+                }
+            });
+
+            ui.separator();
+            let singer = &self.favourites.name;
+            if singer.is_empty() {
+                ui.label("Type a singer in the queue window to see their favourites");
+                return;
+            }
+            ui.horizontal(|ui| {
+                ui.strong(format!("Favourites of {}", singer));
+                if ui.button("Export").clicked() {
+                    *self.action = Some(PlaylistAction::ExportFavourites);
+                }
+            });
+            for entry in &self.favourites.songs {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} {} - {}", entry.code, entry.title, entry.artist));
+                    if ui.button("Queue").clicked() {
+                        *self.action = Some(PlaylistAction::QueueFavourite(entry.clone()));
+                    }
+                    if ui.button("Remove").clicked() {
+                        *self.action = Some(PlaylistAction::RemoveFavourite(entry.clone()));
+                    }
+                });
+            }
+            if self.favourites.songs.is_empty() {
+                ui.label("No favourites yet, add them with ★ in the song list");
+            }
+        })
+        .response
+    }
+}
@@ -0,0 +1,113 @@
+//! Plugin chain editor for egui
+
+use egui::Widget;
+
+use crate::plugin::{scan::ScanCache, PluginSlot};
+
+/// Edits a plugin chain in place, the response is marked as changed on any edit
+pub struct PluginRack<'a> {
+    /// Keeps the widgets of several racks apart
+    pub id: &'a str,
+    pub slots: &'a mut Vec<PluginSlot>,
+    /// Installed plugins, for adding and for the param ranges
+    pub scan: &'a mut ScanCache,
+}
+
+impl<'a> Widget for PluginRack<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let slots = self.slots;
+        let scan = self.scan;
+        let mut changed = false;
+
+        let mut response = ui
+            .vertical(|ui| {
+                let mut remove = None;
+                let mut swap = None;
+                let count = slots.len();
+
+                for (i, slot) in slots.iter_mut().enumerate() {
+                    let info = scan
+                        .plugins()
+                        .find(|p| p.path == slot.path && p.id == slot.id);
+                    let name = info.map_or(slot.id.as_str(), |p| p.name.as_str());
+
+                    ui.horizontal(|ui| {
+                        changed |= ui.checkbox(&mut slot.enabled, name).changed();
+                        if ui.add_enabled(i > 0, egui::Button::new("⏶")).clicked() {
+                            swap = Some(i - 1);
+                        }
+                        if ui
+                            .add_enabled(i + 1 < count, egui::Button::new("⏷"))
+                            .clicked()
+                        {
+                            swap = Some(i);
+                        }
+                        if ui.button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                    });
+
+                    let info = match info {
+                        Some(info) => info,
+                        None => {
+                            ui.label(format!("Not installed: {}", slot.path.display()));
+                            continue;
+                        }
+                    };
+                    ui.indent((self.id, i), |ui| {
+                        for param in &info.params {
+                            let mut value = slot.param(param.id).unwrap_or(param.default);
+                            let slider = egui::Slider::new(&mut value, param.min..=param.max)
+                                .text(&param.name);
+                            if ui.add(slider).changed() {
+                                slot.set_param(param.id, value);
+                                changed = true;
+                            }
+                        }
+                    });
+                }
+
+                if let Some(i) = swap {
+                    slots.swap(i, i + 1);
+                    changed = true;
+                }
+                if let Some(i) = remove {
+                    slots.remove(i);
+                    changed = true;
+                }
+
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_source((self.id, "add"))
+                        .selected_text("Add plugin")
+                        .show_ui(ui, |ui| {
+                            for plugin in scan.plugins() {
+                                let label = format!("{} ({})", plugin.name, plugin.vendor);
+                                if ui.selectable_label(false, label).clicked() {
+                                    slots.push(PluginSlot::new(
+                                        plugin.path.clone(),
+                                        plugin.id.clone(),
+                                    ));
+                                    changed = true;
+                                }
+                            }
+                        });
+                    if ui
+                        .button("Rescan")
+                        .on_hover_text("Look for newly installed plugins")
+                        .clicked()
+                    {
+                        scan.rescan();
+                        if let Err(e) = scan.save() {
+                            log::warn!("failed saving plugin cache: {}", e);
+                        }
+                    }
+                });
+            })
+            .response;
+
+        if changed {
+            response.mark_changed();
+        }
+        response
+    }
+}
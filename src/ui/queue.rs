@@ -0,0 +1,129 @@
+//! Song queue editor for egui
+
+use std::{path::Path, time::Instant};
+
+use egui::Widget;
+
+use crate::queue::SongQueue;
+
+/// Edits the queue in place, the response is marked as changed on any edit
+pub struct Queue<'a> {
+    pub queue: &'a mut SongQueue,
+    /// Song picked in the file menu, for adding
+    pub file: Option<&'a Path>,
+    /// Name typed in for the next reservation
+    pub singer: &'a mut String,
+    /// Set when the first song should start right away
+    pub start: &'a mut bool,
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl<'a> Widget for Queue<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let queue = self.queue;
+        let mut changed = false;
+
+        let mut response = ui
+            .vertical(|ui| {
+                if let Some(current) = &queue.current {
+                    ui.label(format!(
+                        "Now singing: {} - {}",
+                        current.singer,
+                        file_name(&current.path)
+                    ));
+                }
+                if let Some(at) = queue.advance_at {
+                    ui.horizontal(|ui| {
+                        let left = at.saturating_duration_since(Instant::now());
+                        ui.label(format!("Next song in {}s", left.as_secs() + 1));
+                        if ui.button("Start now").clicked() {
+                            *self.start = true;
+                        }
+                        if ui.button("Wait").clicked() {
+                            queue.advance_at = None;
+                        }
+                    });
+                }
+                ui.separator();
+
+                let mut remove = None;
+                let mut moved = None;
+                let mut next = None;
+                let count = queue.entries().len();
+                for (i, entry) in queue.entries().iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "{}. {} - {}",
+                            i + 1,
+                            entry.singer,
+                            file_name(&entry.path)
+                        ))
+                        .on_hover_text(entry.path.display().to_string());
+                        if ui.add_enabled(i > 0, egui::Button::new("⏶")).clicked() {
+                            moved = Some((entry.id, i - 1));
+                        }
+                        if ui
+                            .add_enabled(i + 1 < count, egui::Button::new("⏷"))
+                            .clicked()
+                        {
+                            moved = Some((entry.id, i + 1));
+                        }
+                        if ui.add_enabled(i > 0, egui::Button::new("Next")).clicked() {
+                            next = Some(entry.id);
+                        }
+                        if ui.button("Play now").clicked() {
+                            next = Some(entry.id);
+                            *self.start = true;
+                        }
+                        if ui.button("Remove").clicked() {
+                            remove = Some(entry.id);
+                        }
+                    });
+                }
+                if count == 0 {
+                    ui.label("Nobody's queued up");
+                }
+
+                if let Some((id, index)) = moved {
+                    queue.move_to(id, index);
+                    changed = true;
+                }
+                if let Some(id) = next {
+                    queue.play_next(id);
+                    changed = true;
+                }
+                if let Some(id) = remove {
+                    queue.remove(id);
+                    changed = true;
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label("Singer");
+                    ui.text_edit_singleline(self.singer);
+                    let can_add = self.file.is_some() && !self.singer.trim().is_empty();
+                    if ui.add_enabled(can_add, egui::Button::new("Add")).clicked() {
+                        if let Some(file) = self.file {
+                            queue.add(file.to_path_buf(), self.singer.trim().to_string());
+                            changed = true;
+                        }
+                    }
+                });
+                if self.file.is_none() {
+                    ui.label("Open a song to add it");
+                }
+            })
+            .response;
+
+        if changed {
+            response.mark_changed();
+        }
+        response
+    }
+}
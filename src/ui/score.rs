@@ -0,0 +1,42 @@
+//! Singing score for egui
+
+use egui::{RichText, Widget};
+
+use crate::{pitch, score::Score};
+
+/// Turns scoring on and off, shows what's being sung and the last score
+pub struct Scoring<'a> {
+    pub enabled: &'a mut bool,
+    /// Scoring listens to the mic, it has to be on
+    pub mic_enabled: bool,
+    /// Note being sung right now
+    pub pitch: Option<f32>,
+    /// Score of the last song sung to the end
+    pub last: Option<Score>,
+}
+
+impl<'a> Widget for Scoring<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            ui.checkbox(self.enabled, "Score singing")
+                .on_hover_text("Takes effect from the next song");
+            if *self.enabled && !self.mic_enabled {
+                ui.label("Turn on the microphone to be scored");
+            }
+
+            let pitch = self.pitch.map_or_else(|| "-".to_string(), pitch::note_name);
+            ui.label(format!("Singing: {}", pitch));
+
+            if let Some(score) = self.last {
+                ui.separator();
+                ui.label(RichText::new(format!("Score: {}", score.total())).heading());
+                ui.label(format!(
+                    "Timing {:.0}%, pitch {:.0}%",
+                    score.timing * 100.0,
+                    score.pitch * 100.0
+                ));
+            }
+        })
+        .response
+    }
+}
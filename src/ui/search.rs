@@ -0,0 +1,78 @@
+//! Song search screen for egui
+
+use egui::Widget;
+
+use crate::library::{search::SearchIndex, Song};
+
+/// Most songs listed at once
+const RESULTS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SongAction {
+    Queue,
+    Play,
+    /// Adds the song to the favourites of the current singer
+    Favourite,
+}
+
+/// Search box with the results under it, searching again on every key
+pub struct SongSearch<'a> {
+    pub index: &'a SearchIndex,
+    pub query: &'a mut String,
+    /// Results for `query`, `None` to search again
+    pub results: &'a mut Option<Vec<Song>>,
+    /// Set when a button next to a song is clicked
+    pub action: &'a mut Option<(Song, SongAction)>,
+}
+
+impl<'a> Widget for SongSearch<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        ui.vertical(|ui| {
+            let edit = ui.add(
+                egui::TextEdit::singleline(self.query)
+                    .hint_text("Title, artist or code")
+                    .desired_width(f32::INFINITY),
+            );
+            if edit.changed() {
+                *self.results = None;
+            }
+            let results = self.results.get_or_insert_with(|| {
+                self.index
+                    .search(self.query, RESULTS)
+                    .into_iter()
+                    .cloned()
+                    .collect()
+            });
+
+            if self.index.is_empty() {
+                ui.label("The library is empty, add a folder and scan it");
+            } else if results.is_empty() {
+                ui.label("No songs found");
+            }
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Grid::new("song_results")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for song in results.iter() {
+                            ui.label(&song.code);
+                            ui.label(&song.title)
+                                .on_hover_text(song.path.display().to_string());
+                            ui.label(&song.artist);
+                            if ui.button("Queue").clicked() {
+                                *self.action = Some((song.clone(), SongAction::Queue));
+                            }
+                            if ui.button("Play").clicked() {
+                                *self.action = Some((song.clone(), SongAction::Play));
+                            }
+                            if ui.button("★").on_hover_text("Favourite").clicked() {
+                                *self.action = Some((song.clone(), SongAction::Favourite));
+                            }
+                            ui.end_row();
+                        }
+                    });
+            });
+        })
+        .response
+    }
+}
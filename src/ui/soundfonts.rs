@@ -0,0 +1,184 @@
+//! Soundfont stack and override editor for egui
+
+use std::{
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use egui::{emath::Numeric, Widget};
+
+use crate::soundfont::{FontOverride, SoundfontSetup};
+
+/// Edits a [SoundfontSetup] in place.
+///
+/// Nothing is sent to the synth from here, the response is marked as changed
+/// so the caller can decide when to apply it.
+pub struct Soundfonts<'a> {
+    pub setup: &'a mut SoundfontSetup,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    Up(usize),
+    Down(usize),
+    Remove(usize),
+}
+
+impl<'a> Widget for Soundfonts<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let setup = self.setup;
+        let mut changed = false;
+
+        let mut response = ui
+            .vertical(|ui| {
+                ui.label("Fonts, the top one is searched first");
+
+                let mut action = None;
+                egui::Grid::new("soundfont_stack")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        let count = setup.fonts.len();
+                        for (i, path) in setup.fonts.iter().enumerate() {
+                            ui.label(file_name(path))
+                                .on_hover_text(path.display().to_string());
+                            if ui.add_enabled(i > 0, egui::Button::new("⏶")).clicked() {
+                                action = Some(Action::Up(i));
+                            }
+                            if ui
+                                .add_enabled(i + 1 < count, egui::Button::new("⏷"))
+                                .clicked()
+                            {
+                                action = Some(Action::Down(i));
+                            }
+                            if ui.button("✖").clicked() {
+                                action = Some(Action::Remove(i));
+                            }
+                            ui.end_row();
+                        }
+                    });
+
+                if let Some(action) = action {
+                    match action {
+                        Action::Up(i) => setup.fonts.swap(i - 1, i),
+                        Action::Down(i) => setup.fonts.swap(i, i + 1),
+                        Action::Remove(i) => {
+                            let path = setup.fonts[i].clone();
+                            setup.remove(&path);
+                        }
+                    }
+                    changed = true;
+                }
+
+                if ui.button("Add...").clicked() {
+                    if let Some(path) = pick_soundfont() {
+                        setup.push(path);
+                        changed = true;
+                    }
+                }
+
+                ui.separator();
+                ui.label("Overrides, the first match wins");
+
+                let mut remove = None;
+                egui::Grid::new("soundfont_overrides")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        ui.label("Font");
+                        ui.label("Channel");
+                        ui.label("Bank");
+                        ui.label("Program");
+                        ui.end_row();
+
+                        let fonts = &setup.fonts;
+                        for (i, o) in setup.overrides.iter_mut().enumerate() {
+                            egui::ComboBox::from_id_source(("soundfont_override", i))
+                                .selected_text(file_name(&o.font))
+                                .show_ui(ui, |ui| {
+                                    for font in fonts {
+                                        changed |= ui
+                                            .selectable_value(
+                                                &mut o.font,
+                                                font.clone(),
+                                                file_name(font),
+                                            )
+                                            .changed();
+                                    }
+                                    if ui.button("Browse...").clicked() {
+                                        if let Some(path) = pick_soundfont() {
+                                            o.font = path;
+                                            changed = true;
+                                        }
+                                    }
+                                });
+
+                            // channels are shown 1-16 like everywhere else
+                            let mut channel = o.channel.map(|c| c + 1);
+                            if optional(ui, &mut channel, 1..=16) {
+                                o.channel = channel.map(|c| c - 1);
+                                changed = true;
+                            }
+                            changed |= optional(ui, &mut o.bank, 0..=128);
+                            changed |= optional(ui, &mut o.program, 0..=127);
+
+                            if ui.button("✖").clicked() {
+                                remove = Some(i);
+                            }
+                            ui.end_row();
+                        }
+                    });
+
+                if let Some(i) = remove {
+                    setup.overrides.remove(i);
+                    changed = true;
+                }
+
+                if ui.button("Add override").clicked() {
+                    setup.overrides.push(FontOverride {
+                        font: setup.fonts.first().cloned().unwrap_or_default(),
+                        ..Default::default()
+                    });
+                    changed = true;
+                }
+            })
+            .response;
+
+        if changed {
+            response.mark_changed();
+        }
+        response
+    }
+}
+
+/// "any" checkbox with a value next to it, returns whether anything changed
+fn optional<T: Numeric>(
+    ui: &mut egui::Ui,
+    value: &mut Option<T>,
+    range: RangeInclusive<T>,
+) -> bool {
+    ui.horizontal(|ui| {
+        let mut any = value.is_none();
+        let mut changed = ui.checkbox(&mut any, "any").changed();
+        if changed {
+            *value = if any { None } else { Some(*range.start()) };
+        }
+        if let Some(v) = value {
+            changed |= ui.add(egui::DragValue::new(v).clamp_range(range)).changed();
+        }
+        changed
+    })
+    .inner
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |n| n.to_string_lossy().into_owned(),
+    )
+}
+
+fn pick_soundfont() -> Option<PathBuf> {
+    native_dialog::FileDialog::new()
+        .add_filter("Soundfont", &["sf2", "sf3", "SF2", "SF3"])
+        .show_open_single_file()
+        .unwrap_or_default()
+}
@@ -0,0 +1,40 @@
+//! Vocal reducer controls for egui
+
+use egui::Widget;
+
+use crate::vocal::VocalReducerSettings;
+
+/// Edits [VocalReducerSettings] in place, the response is marked as changed on any edit
+pub struct Vocal<'a> {
+    pub settings: &'a mut VocalReducerSettings,
+}
+
+impl<'a> Widget for Vocal<'a> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        let settings = self.settings;
+        let mut changed = false;
+
+        let mut response = ui
+            .vertical(|ui| {
+                changed |= ui
+                    .checkbox(&mut settings.enabled, "Reduce vocals")
+                    .on_hover_text("Takes the centre out of audio songs")
+                    .changed();
+                ui.add_enabled_ui(settings.enabled, |ui| {
+                    changed |= ui
+                        .add(egui::Slider::new(&mut settings.strength, 0.0..=1.0).text("Strength"))
+                        .changed();
+                    changed |= ui
+                        .checkbox(&mut settings.band_limited, "Keep bass and cymbals")
+                        .on_hover_text("Only takes the centre out in the vocal range")
+                        .changed();
+                });
+            })
+            .response;
+
+        if changed {
+            response.mark_changed();
+        }
+        response
+    }
+}
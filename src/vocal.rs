@@ -0,0 +1,141 @@
+//! Vocal reduction for audio tracks
+//!
+//! Lead vocals are almost always mixed dead centre, so they live in the mid
+//! (L+R) signal while most of the band is spread out in the side (L-R).
+//! Taking the mid away takes the voice with it. The bass and kick are centred
+//! too, so the band-limited mode only takes away the part of the mid where the
+//! voice sits.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dsp::Biquad;
+
+/// Below this the mid is kept in band-limited mode, in Hz
+const BAND_LOW: f32 = 150.0;
+
+/// Above this the mid is kept in band-limited mode, in Hz
+const BAND_HIGH: f32 = 7000.0;
+
+/// Butterworth Q, cascaded twice for a 24dB/oct Linkwitz-Riley slope
+const Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VocalReducerSettings {
+    pub enabled: bool,
+    /// How much of the centre is taken away, 0-1
+    pub strength: f32,
+    /// Keep the bass, kick and cymbals by only touching the vocal range
+    pub band_limited: bool,
+}
+
+impl Default for VocalReducerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            strength: 1.0,
+            band_limited: true,
+        }
+    }
+}
+
+/// Mid/side vocal reducer on interleaved stereo
+#[derive(Debug, Clone)]
+pub struct VocalReducer {
+    pub settings: VocalReducerSettings,
+    /// Mid below the vocal band
+    low: [Biquad; 2],
+    /// Mid above the vocal band
+    high: [Biquad; 2],
+}
+
+impl VocalReducer {
+    pub fn new(settings: VocalReducerSettings, sample_rate: u32) -> Self {
+        let sr = sample_rate as f32;
+        let low = Biquad::low_pass(sr, BAND_LOW, Q);
+        let high = Biquad::high_pass(sr, BAND_HIGH.min(sr * 0.45), Q);
+
+        Self {
+            settings,
+            low: [low; 2],
+            high: [high; 2],
+        }
+    }
+
+    /// Processes an interleaved stereo buffer in place, settings can change between calls
+    pub fn process(&mut self, buf: &mut [f32]) {
+        let settings = &self.settings;
+        if !settings.enabled {
+            return;
+        }
+        let strength = settings.strength.clamp(0.0, 1.0);
+
+        for frame in buf.chunks_exact_mut(2) {
+            let mid = (frame[0] + frame[1]) * 0.5;
+            let side = (frame[0] - frame[1]) * 0.5;
+
+            // what's left of the mid with the voice gone
+            let kept = if settings.band_limited {
+                let low = self.low.iter_mut().fold(mid, |s, f| f.process(s));
+                let high = self.high.iter_mut().fold(mid, |s, f| f.process(s));
+                low + high
+            } else {
+                0.0
+            };
+
+            let mid = mid + (kept - mid) * strength;
+            frame[0] = mid + side;
+            frame[1] = mid - side;
+        }
+    }
+}
+
+#[cfg(test)]
+fn stereo(sample_rate: u32, freq: f32, left: f32, right: f32) -> Vec<f32> {
+    (0..sample_rate as usize)
+        .flat_map(|i| {
+            let s = (2.0 * std::f32::consts::PI * freq * i as f32 / sample_rate as f32).sin();
+            [s * left, s * right]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn rms(buf: &[f32]) -> f32 {
+    // skip the first half so the filters have settled
+    let buf = &buf[buf.len() / 2..];
+    (buf.iter().map(|s| s * s).sum::<f32>() / buf.len() as f32).sqrt()
+}
+
+#[test]
+fn test_vocal_reducer() {
+    let settings = VocalReducerSettings {
+        enabled: true,
+        band_limited: false,
+        ..Default::default()
+    };
+    let mut reducer = VocalReducer::new(settings, 44100);
+
+    // a centred voice goes away completely
+    let mut centre = stereo(44100, 1000.0, 0.5, 0.5);
+    reducer.process(&mut centre);
+    assert!(rms(&centre) < 1e-6);
+
+    // anything spread out in the stereo field stays
+    let mut wide = stereo(44100, 1000.0, 0.5, -0.5);
+    let expected = wide.clone();
+    reducer.process(&mut wide);
+    assert_eq!(wide, expected);
+
+    // the band-limited mode keeps a centred bass but still takes the voice
+    reducer.settings.band_limited = true;
+    let mut bass = stereo(44100, 50.0, 0.5, 0.5);
+    let before = rms(&bass);
+    reducer.process(&mut bass);
+    assert!(rms(&bass) > before * 0.8);
+
+    let mut voice = stereo(44100, 1000.0, 0.5, 0.5);
+    let before = rms(&voice);
+    reducer.process(&mut voice);
+    assert!(rms(&voice) < before * 0.2);
+}